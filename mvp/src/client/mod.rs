@@ -1,12 +1,11 @@
 use anyhow::{Context, Error, Result};
 use clap::{Parser, ValueEnum};
 use rand::Rng;
-use reqwest::multipart::{Form, Part};
-use reqwest::Client;
-use std::borrow::Cow;
+use reqwest::{Client, Request, RequestBuilder};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use tokio::{
@@ -14,7 +13,12 @@ use tokio::{
     time::Instant,
 };
 
+#[path = "../signing.rs"]
+mod signing;
+
 const MIX_RATIO: f64 = 0.8;
+/// Attempts of an upload the server keeps answering with 503 SlowDown
+const MAX_UPLOAD_ATTEMPTS: u32 = 5;
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
 pub enum Mode {
@@ -40,13 +44,53 @@ struct Args {
 
     #[arg(short, long, default_value_t = 1)]
     parallel_clients: u128,
+
+    /// Access key used to sign requests, requests are anonymous without it
+    #[arg(long, requires = "secret_key")]
+    access_key: Option<String>,
+
+    #[arg(long, requires = "access_key")]
+    secret_key: Option<String>,
+
+    /// Talk HTTPS to a server started with --tls-cert
+    #[arg(long, default_value_t = false)]
+    tls: bool,
+
+    /// PEM CA certificate to trust in addition to the system roots, such as the `ca.pem`
+    /// of the server's --generate-certificate
+    #[arg(long, requires = "tls")]
+    ca_cert: Option<PathBuf>,
+}
+
+pub struct Keys {
+    access_key: String,
+    secret_key: String,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let args = Args::parse();
-    let client = Arc::new(Client::new());
-    let url = Arc::new(format!("http://{}/parquet", args.ip.to_string()));
+    let mut builder = Client::builder();
+    if let Some(ca_cert) = &args.ca_cert {
+        let pem = std::fs::read(ca_cert)
+            .with_context(|| format!("Failed reading CA certificate at: {:?}", ca_cert))?;
+        builder = builder.add_root_certificate(
+            reqwest::Certificate::from_pem(&pem)
+                .with_context(|| format!("Invalid CA certificate at: {:?}", ca_cert))?,
+        );
+    }
+    let client = Arc::new(builder.build()?);
+    let keys = args
+        .access_key
+        .zip(args.secret_key)
+        .map(|(access_key, secret_key)| {
+            Arc::new(Keys {
+                access_key,
+                secret_key,
+            })
+        });
+    let scheme = if args.tls { "https" } else { "http" };
+    let url = Arc::new(format!("{scheme}://{}/parquet", args.ip));
 
     let file_path = PathBuf::from(args.folder).join("test_file.parquet");
     let mut file: File = File::open(&file_path)
@@ -69,11 +113,13 @@ async fn main() -> Result<(), Error> {
         let mode = args.mode;
         let duration = Duration::from_secs(args.duration);
         let file_contents = Arc::clone(&file_contents);
+        let keys = keys.clone();
 
         let client_task = task::spawn(async move {
             spawn_client(
                 client_clone,
                 url_clone,
+                keys,
                 cur_offset,
                 mode,
                 duration,
@@ -96,6 +142,7 @@ async fn main() -> Result<(), Error> {
 async fn spawn_client(
     client: Arc<Client>,
     url: Arc<String>,
+    keys: Option<Arc<Keys>>,
     file_counter_start: u128,
     mode: Mode,
     duration: Duration,
@@ -110,20 +157,32 @@ async fn spawn_client(
             Mode::Send => spawn_sender(
                 Arc::clone(&client),
                 Arc::clone(&url),
+                keys.clone(),
                 file_name,
                 Arc::clone(&file_contents),
             ),
-            Mode::Receive => spawn_receiver(Arc::clone(&client), Arc::clone(&url), file_name),
+            Mode::Receive => spawn_receiver(
+                Arc::clone(&client),
+                Arc::clone(&url),
+                keys.clone(),
+                file_name,
+            ),
             Mode::Mixed => {
                 return if sample_bernouli_var(MIX_RATIO) {
                     spawn_sender(
                         Arc::clone(&client),
                         Arc::clone(&url),
+                        keys.clone(),
                         file_name,
                         Arc::clone(&file_contents),
                     );
                 } else {
-                    spawn_receiver(Arc::clone(&client), Arc::clone(&url), file_name);
+                    spawn_receiver(
+                        Arc::clone(&client),
+                        Arc::clone(&url),
+                        keys.clone(),
+                        file_name,
+                    );
                 }
             }
         };
@@ -143,23 +202,69 @@ fn sample_bernouli_var(theta: f64) -> bool {
 fn spawn_sender(
     client: Arc<Client>,
     url: Arc<String>,
+    keys: Option<Arc<Keys>>,
     file_name: String,
     file_contents: Arc<Vec<u8>>,
 ) -> JoinHandle<Result<(), anyhow::Error>> {
-    task::spawn(async move { send_data_request(&client, &url, &file_name, file_contents).await })
+    task::spawn(async move {
+        send_data_request(&client, &url, keys.as_deref(), &file_name, file_contents).await
+    })
 }
 
 fn spawn_receiver(
     client: Arc<Client>,
     url: Arc<String>,
+    keys: Option<Arc<Keys>>,
     file_name: String,
 ) -> JoinHandle<Result<(), anyhow::Error>> {
-    task::spawn(async move { receive_data_request(&client, &url, &file_name).await })
+    task::spawn(
+        async move { receive_data_request(&client, &url, keys.as_deref(), &file_name).await },
+    )
+}
+
+/// Builds the request and adds the signature headers if keys are configured.
+fn sign(builder: RequestBuilder, keys: Option<&Keys>) -> Result<Request> {
+    let mut request = builder.build()?;
+    let Some(keys) = keys else {
+        return Ok(request);
+    };
+
+    let date = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_secs()
+        .to_string();
+    let body = request
+        .body()
+        .and_then(|body| body.as_bytes())
+        .unwrap_or_default();
+    let content_sha256 = signing::content_sha256(body);
+    let headers = request.headers_mut();
+    headers.insert(signing::DATE_HEADER, date.parse()?);
+    headers.insert(signing::CONTENT_SHA256_HEADER, content_sha256.parse()?);
+
+    let mut signed_headers = Vec::new();
+    for (name, value) in request.headers() {
+        signed_headers.push((name.as_str(), value.to_str()?));
+    }
+    let string_to_sign = signing::string_to_sign(
+        request.method().as_str(),
+        request.url().path(),
+        request.url().query().unwrap_or_default(),
+        &signing::canonical_headers(signed_headers),
+        &date,
+    );
+    let signature = signing::sign(&keys.secret_key, &string_to_sign);
+    request.headers_mut().insert(
+        reqwest::header::AUTHORIZATION,
+        signing::authorization_header(&keys.access_key, &signature).parse()?,
+    );
+    Ok(request)
 }
 
 async fn send_data_request(
     client: &Client,
     url: &str,
+    keys: Option<&Keys>,
     file_name: &str,
     file_contents: Arc<Vec<u8>>,
 ) -> Result<()> {
@@ -169,20 +274,37 @@ async fn send_data_request(
         let ptr = Arc::as_ptr(&file_contents);
         &*ptr
     };
-    let part = Part::bytes(Cow::Borrowed(static_slice)).file_name(file_name.to_string());
-    let form = Form::new().part("file", part);
-
-    let _response = client.put(url).multipart(form).send().await?;
+    for _ in 1..MAX_UPLOAD_ATTEMPTS {
+        let request = sign(client.put(&url).body(static_slice), keys)?;
+        let response = client.execute(request).await?;
+        if response.status() != reqwest::StatusCode::SERVICE_UNAVAILABLE {
+            return Ok(());
+        }
+        // Back off as long as the server asks to
+        let retry_after = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .unwrap_or(1);
+        tokio::time::sleep(Duration::from_secs(retry_after)).await;
+    }
+    let request = sign(client.put(url).body(static_slice), keys)?;
+    let _response = client.execute(request).await?;
 
     Ok(())
 }
 
-async fn receive_data_request(client: &Client, url: &str, file_name: &str) -> Result<()> {
+async fn receive_data_request(
+    client: &Client,
+    url: &str,
+    keys: Option<&Keys>,
+    file_name: &str,
+) -> Result<()> {
     let url = format!("{}/{}", &url, &file_name);
 
+    let request = sign(client.get(url), keys)?;
     let response = client
-        .get(url)
-        .send()
+        .execute(request)
         .await
         .context("Failed to send GET request")?;
 
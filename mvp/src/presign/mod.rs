@@ -0,0 +1,77 @@
+use anyhow::{anyhow, Result};
+use clap::{Parser, ValueEnum};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[path = "../signing.rs"]
+mod signing;
+
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+pub enum Method {
+    Get,
+    Put,
+}
+
+/// Prints a URL that grants one GET or PUT on one object for a limited time
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(short, long)]
+    ip: String,
+
+    #[arg(short, long, default_value_t = String::from("parquet"))]
+    bucket: String,
+
+    #[arg(short, long)]
+    key: String,
+
+    #[arg(short, long, default_value = "get")]
+    method: Method,
+
+    /// Seconds until the URL expires
+    #[arg(short, long, default_value_t = 3600)]
+    expires_in: u64,
+
+    /// Largest body in bytes an upload through the URL may have
+    #[arg(long)]
+    max_length: Option<u64>,
+
+    /// Print an https:// URL, for servers started with --tls-cert
+    #[arg(long, default_value_t = false)]
+    tls: bool,
+
+    #[arg(long)]
+    access_key: String,
+
+    #[arg(long)]
+    secret_key: String,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    if args.expires_in > signing::MAX_PRESIGN_EXPIRY_SECS {
+        return Err(anyhow!(
+            "URLs expire after at most {} seconds",
+            signing::MAX_PRESIGN_EXPIRY_SECS
+        ));
+    }
+
+    let method = match args.method {
+        Method::Get => "GET",
+        Method::Put => "PUT",
+    };
+    let expires = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() + args.expires_in;
+    let scheme = if args.tls { "https" } else { "http" };
+    let url = signing::object_url(&format!("{scheme}://{}", args.ip), &args.bucket, &args.key)
+        .map_err(|e| anyhow!(e))?;
+    let url = signing::presign(
+        url,
+        method,
+        &args.access_key,
+        &args.secret_key,
+        expires,
+        args.max_length,
+    );
+
+    println!("{url}");
+    Ok(())
+}
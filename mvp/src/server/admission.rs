@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+
+/// Limits on uploads that are being received at the same time. 0 disables a limit.
+#[derive(Clone, Debug)]
+pub struct AdmissionConfig {
+    pub max_uploads: usize,
+    pub max_upload_bytes: u64,
+    pub max_uploads_per_client: usize,
+    pub max_upload_bytes_per_client: u64,
+    /// How long an upload waits for a slot before it is rejected
+    pub queue_timeout: Duration,
+    /// Sent as `Retry-After` with rejections
+    pub retry_after: Duration,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Usage {
+    uploads: usize,
+    bytes: u64,
+}
+
+impl Usage {
+    /// A single upload is always let in, so uploads larger than a limit do not starve.
+    fn admits(&self, bytes: u64, max_uploads: usize, max_bytes: u64) -> bool {
+        self.uploads == 0
+            || ((max_uploads == 0 || self.uploads < max_uploads)
+                && (max_bytes == 0 || self.bytes + bytes <= max_bytes))
+    }
+}
+
+#[derive(Default)]
+struct InFlight {
+    node: Usage,
+    clients: HashMap<String, Usage>,
+    queued: usize,
+}
+
+#[derive(Default)]
+struct Counters {
+    admitted: AtomicU64,
+    rejected: AtomicU64,
+    queued: AtomicU64,
+    wait_micros: AtomicU64,
+}
+
+/// Admission control for uploads: admits them while the node and the uploading client
+/// are below their limits, queues them for up to `queue_timeout` otherwise.
+pub struct Admission {
+    config: AdmissionConfig,
+    in_flight: Mutex<InFlight>,
+    released: Notify,
+    counters: Counters,
+}
+
+impl Admission {
+    pub fn new(config: AdmissionConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            in_flight: Mutex::new(InFlight::default()),
+            released: Notify::new(),
+            counters: Counters::default(),
+        })
+    }
+
+    pub fn retry_after(&self) -> Duration {
+        self.config.retry_after
+    }
+
+    /// Waits until an upload of `bytes` by `client` can be admitted. Returns `None` if
+    /// that does not happen within the queue timeout.
+    pub async fn admit(self: &Arc<Self>, client: &str, bytes: u64) -> Option<Permit> {
+        if let Some(permit) = self.try_admit(client, bytes) {
+            return Some(permit);
+        }
+
+        let start = Instant::now();
+        let deadline = start + self.config.queue_timeout;
+        self.counters.queued.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.lock().unwrap().queued += 1;
+        let permit = loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            // Registered before checking, so a release in between is not missed
+            released.as_mut().enable();
+            if let Some(permit) = self.try_admit(client, bytes) {
+                break Some(permit);
+            }
+            if tokio::time::timeout_at(deadline, released).await.is_err() {
+                break None;
+            }
+        };
+        self.in_flight.lock().unwrap().queued -= 1;
+        self.counters
+            .wait_micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        if permit.is_none() {
+            self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        permit
+    }
+
+    fn try_admit(self: &Arc<Self>, client: &str, bytes: u64) -> Option<Permit> {
+        let config = &self.config;
+        let mut in_flight = self.in_flight.lock().unwrap();
+        let client_usage = in_flight.clients.get(client).copied().unwrap_or_default();
+        if !in_flight
+            .node
+            .admits(bytes, config.max_uploads, config.max_upload_bytes)
+            || !client_usage.admits(
+                bytes,
+                config.max_uploads_per_client,
+                config.max_upload_bytes_per_client,
+            )
+        {
+            return None;
+        }
+
+        in_flight.node.uploads += 1;
+        in_flight.node.bytes += bytes;
+        let usage = in_flight.clients.entry(client.to_string()).or_default();
+        usage.uploads += 1;
+        usage.bytes += bytes;
+        self.counters.admitted.fetch_add(1, Ordering::Relaxed);
+        Some(Permit {
+            admission: Arc::clone(self),
+            client: client.to_string(),
+            bytes,
+        })
+    }
+
+    /// Current state and thresholds in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let config = &self.config;
+        let (uploads, bytes, queued, clients) = {
+            let in_flight = self.in_flight.lock().unwrap();
+            (
+                in_flight.node.uploads,
+                in_flight.node.bytes,
+                in_flight.queued,
+                in_flight.clients.len(),
+            )
+        };
+        let counters = &self.counters;
+        let metrics: [(&str, &str, &str, u64); 13] = [
+            (
+                "mvp_uploads_in_flight",
+                "gauge",
+                "Uploads being received",
+                uploads as u64,
+            ),
+            (
+                "mvp_upload_bytes_in_flight",
+                "gauge",
+                "Bytes reserved by uploads being received",
+                bytes,
+            ),
+            (
+                "mvp_uploads_queued",
+                "gauge",
+                "Uploads waiting for admission",
+                queued as u64,
+            ),
+            (
+                "mvp_upload_clients",
+                "gauge",
+                "Clients with uploads in flight",
+                clients as u64,
+            ),
+            (
+                "mvp_uploads_admitted_total",
+                "counter",
+                "Uploads admitted",
+                counters.admitted.load(Ordering::Relaxed),
+            ),
+            (
+                "mvp_uploads_rejected_total",
+                "counter",
+                "Uploads rejected with 503 SlowDown",
+                counters.rejected.load(Ordering::Relaxed),
+            ),
+            (
+                "mvp_uploads_queued_total",
+                "counter",
+                "Uploads that had to wait for admission",
+                counters.queued.load(Ordering::Relaxed),
+            ),
+            (
+                "mvp_upload_queue_wait_microseconds_total",
+                "counter",
+                "Time uploads spent waiting for admission",
+                counters.wait_micros.load(Ordering::Relaxed),
+            ),
+            (
+                "mvp_admission_max_uploads",
+                "gauge",
+                "Limit on uploads in flight, 0 is unlimited",
+                config.max_uploads as u64,
+            ),
+            (
+                "mvp_admission_max_upload_bytes",
+                "gauge",
+                "Limit on bytes in flight, 0 is unlimited",
+                config.max_upload_bytes,
+            ),
+            (
+                "mvp_admission_max_uploads_per_client",
+                "gauge",
+                "Limit on uploads in flight per client, 0 is unlimited",
+                config.max_uploads_per_client as u64,
+            ),
+            (
+                "mvp_admission_max_upload_bytes_per_client",
+                "gauge",
+                "Limit on bytes in flight per client, 0 is unlimited",
+                config.max_upload_bytes_per_client,
+            ),
+            (
+                "mvp_admission_queue_timeout_milliseconds",
+                "gauge",
+                "How long uploads wait for admission",
+                config.queue_timeout.as_millis() as u64,
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+            );
+        }
+        out
+    }
+}
+
+/// An admitted upload, releases its share of the limits when dropped.
+pub struct Permit {
+    admission: Arc<Admission>,
+    client: String,
+    bytes: u64,
+}
+
+impl Permit {
+    /// Accounts for `received` bytes of the body. Uploads without Content-Length are
+    /// admitted with no bytes reserved and grow their reservation as data arrives.
+    pub fn received(&mut self, received: u64) {
+        if received <= self.bytes {
+            return;
+        }
+        let grow = received - self.bytes;
+        let mut in_flight = self.admission.in_flight.lock().unwrap();
+        in_flight.node.bytes += grow;
+        if let Some(usage) = in_flight.clients.get_mut(&self.client) {
+            usage.bytes += grow;
+        }
+        self.bytes = received;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut in_flight = self.admission.in_flight.lock().unwrap();
+        in_flight.node.uploads -= 1;
+        in_flight.node.bytes -= self.bytes;
+        if let Some(usage) = in_flight.clients.get_mut(&self.client) {
+            usage.uploads -= 1;
+            usage.bytes -= self.bytes;
+            if usage.uploads == 0 {
+                in_flight.clients.remove(&self.client);
+            }
+        }
+        drop(in_flight);
+        self.admission.released.notify_waiters();
+    }
+}
@@ -0,0 +1,413 @@
+use actix_web::dev::{Payload, ServiceRequest};
+use actix_web::error::PayloadError;
+use actix_web::{http::header, web, HttpMessage, HttpRequest};
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+use crate::error::ApiError;
+use crate::metadata::unix_now;
+use crate::{signing, AppState};
+
+const MAX_CLOCK_SKEW_SECS: u64 = 15 * 60;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    List,
+    Admin,
+}
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::List => "list",
+            Permission::Admin => "admin",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct BucketPolicy {
+    /// Lets unauthenticated requests read objects, but not list them
+    #[serde(default)]
+    pub anonymous_read: bool,
+    #[serde(default)]
+    pub grants: BTreeMap<String, BTreeSet<Permission>>,
+}
+
+impl BucketPolicy {
+    pub fn owned_by(access_key: &str) -> Self {
+        let all = [
+            Permission::Read,
+            Permission::Write,
+            Permission::List,
+            Permission::Admin,
+        ];
+        Self {
+            anonymous_read: false,
+            grants: BTreeMap::from([(access_key.to_string(), BTreeSet::from(all))]),
+        }
+    }
+
+    /// Whether `caller` could still change this policy after it is applied.
+    pub fn keeps_admin(&self, caller: &Caller) -> bool {
+        self.allows(caller, Permission::Admin)
+    }
+
+    fn allows(&self, caller: &Caller, permission: Permission) -> bool {
+        match caller {
+            Caller::Anonymous => self.anonymous_read && permission == Permission::Read,
+            Caller::Key { root: true, .. } => true,
+            Caller::Key { access_key, .. } => self.granted(access_key, permission),
+            // A presigned URL only ever grants a single object operation
+            Caller::Presigned {
+                access_key, root, ..
+            } => {
+                matches!(permission, Permission::Read | Permission::Write)
+                    && (*root || self.granted(access_key, permission))
+            }
+        }
+    }
+
+    fn granted(&self, access_key: &str, permission: Permission) -> bool {
+        self.grants
+            .get(access_key)
+            .is_some_and(|granted| granted.contains(&permission))
+    }
+}
+
+struct Credential {
+    secret: String,
+    root: bool,
+}
+
+/// Access keys loaded from the credentials file.
+///
+/// Every line holds `access_key:secret_key`, optionally followed by `:root` for keys
+/// that may do anything, including the `/admin` endpoints. `#` starts a comment.
+#[derive(Default)]
+pub struct Credentials {
+    keys: HashMap<String, Credential>,
+}
+
+impl Credentials {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Self::parse(&contents),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Credentials from the lines of a credentials file.
+    pub fn parse(contents: &str) -> io::Result<Self> {
+        let mut keys = HashMap::new();
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split(':');
+            let (Some(access_key), Some(secret)) = (fields.next(), fields.next()) else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Malformed credentials line: {line}"),
+                ));
+            };
+            let credential = Credential {
+                secret: secret.to_string(),
+                root: fields.next() == Some("root"),
+            };
+            keys.insert(access_key.to_string(), credential);
+        }
+        Ok(Self { keys })
+    }
+
+    /// Appends a freshly generated key pair to the credentials file.
+    pub fn generate(path: &Path, root: bool) -> io::Result<(String, String)> {
+        let access_key = format!("MVP{:016X}", rand::random::<u64>());
+        let secret = format!(
+            "{:032x}{:032x}",
+            rand::random::<u128>(),
+            rand::random::<u128>()
+        );
+        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+        let suffix = if root { ":root" } else { "" };
+        writeln!(file, "{access_key}:{secret}{suffix}")?;
+        Ok((access_key, secret))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn secret(&self, access_key: &str) -> Option<&str> {
+        self.keys.get(access_key).map(|c| c.secret.as_str())
+    }
+}
+
+pub enum Caller {
+    Anonymous,
+    Key {
+        access_key: String,
+        root: bool,
+    },
+    /// Request authorized by a presigned URL issued for `access_key`
+    Presigned {
+        access_key: String,
+        root: bool,
+        max_length: Option<u64>,
+    },
+}
+
+impl Caller {
+    /// Upper bound on the request body a presigned upload URL allows.
+    pub fn max_length(&self) -> Option<u64> {
+        match self {
+            Caller::Presigned { max_length, .. } => *max_length,
+            _ => None,
+        }
+    }
+
+    pub fn access_key(&self) -> Option<&str> {
+        match self {
+            Caller::Anonymous => None,
+            Caller::Key { access_key, .. } | Caller::Presigned { access_key, .. } => {
+                Some(access_key)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Caller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Caller::Anonymous => f.write_str("anonymous"),
+            Caller::Key { access_key, .. } => f.write_str(access_key),
+            Caller::Presigned { access_key, .. } => write!(f, "{access_key} (presigned)"),
+        }
+    }
+}
+
+/// Checks the request signature, requests that are neither signed nor presigned are anonymous.
+pub fn authenticate(req: &HttpRequest, credentials: &Credentials) -> Result<Caller, ApiError> {
+    let Some(authorization) = req.headers().get(header::AUTHORIZATION) else {
+        return authenticate_presigned(req, credentials);
+    };
+    let authorization = authorization
+        .to_str()
+        .map_err(|_| ApiError::access_denied("Malformed Authorization header"))?;
+    let (access_key, signature) = parse_authorization(authorization)
+        .ok_or_else(|| ApiError::access_denied("Malformed Authorization header"))?;
+
+    let date = req
+        .headers()
+        .get(signing::DATE_HEADER)
+        .and_then(|date| date.to_str().ok())
+        .ok_or_else(|| ApiError::access_denied("Missing x-mvp-date header"))?;
+    let timestamp: u64 = date
+        .parse()
+        .map_err(|_| ApiError::access_denied("Malformed x-mvp-date header"))?;
+    if unix_now().abs_diff(timestamp) > MAX_CLOCK_SKEW_SECS {
+        return Err(ApiError::access_denied("Request time too skewed"));
+    }
+    if !req
+        .headers()
+        .get(signing::CONTENT_SHA256_HEADER)
+        .and_then(|hash| hash.to_str().ok())
+        .is_some_and(|hash| hash.len() == 64 && hex::decode(hash).is_ok())
+    {
+        return Err(ApiError::access_denied(
+            "Missing or malformed x-mvp-content-sha256 header",
+        ));
+    }
+    let mut headers = Vec::new();
+    for (name, value) in req.headers() {
+        let value = value
+            .to_str()
+            .map_err(|_| ApiError::access_denied(format!("Malformed {name} header")))?;
+        headers.push((name.as_str(), value));
+    }
+
+    let credential = credentials
+        .keys
+        .get(access_key)
+        .ok_or_else(|| ApiError::access_denied("Unknown access key"))?;
+    let string_to_sign = signing::string_to_sign(
+        req.method().as_str(),
+        req.path(),
+        req.query_string(),
+        &signing::canonical_headers(headers),
+        date,
+    );
+    if !signing::verify(&credential.secret, &string_to_sign, signature) {
+        return Err(ApiError::access_denied("Signature does not match"));
+    }
+
+    Ok(Caller::Key {
+        access_key: access_key.to_string(),
+        root: credential.root,
+    })
+}
+
+fn authenticate_presigned(
+    req: &HttpRequest,
+    credentials: &Credentials,
+) -> Result<Caller, ApiError> {
+    let Ok(params) = web::Query::<HashMap<String, String>>::from_query(req.query_string()) else {
+        return Ok(Caller::Anonymous);
+    };
+    let Some(signature) = params.get(signing::SIGNATURE_PARAM) else {
+        return Ok(Caller::Anonymous);
+    };
+
+    let (Some(access_key), Some(expires)) = (
+        params.get(signing::CREDENTIAL_PARAM),
+        params.get(signing::EXPIRES_PARAM),
+    ) else {
+        return Err(ApiError::access_denied("Incomplete presigned URL"));
+    };
+    let expiry: u64 = expires
+        .parse()
+        .map_err(|_| ApiError::access_denied("Malformed presigned URL expiry"))?;
+    let now = unix_now();
+    if now > expiry {
+        return Err(ApiError::access_denied("Presigned URL has expired"));
+    }
+    // URLs signed by hand must not outlive the ones the server hands out
+    if expiry - now > signing::MAX_PRESIGN_EXPIRY_SECS {
+        return Err(ApiError::access_denied(
+            "Presigned URL expires too far in the future",
+        ));
+    }
+    let max_length = params
+        .get(signing::MAX_LENGTH_PARAM)
+        .map(|max_length| max_length.parse())
+        .transpose()
+        .map_err(|_| ApiError::access_denied("Malformed presigned URL length limit"))?;
+
+    let credential = credentials
+        .keys
+        .get(access_key)
+        .ok_or_else(|| ApiError::access_denied("Unknown access key"))?;
+    // The method and path are part of the signature, so the URL is bound to one operation
+    let string_to_sign = signing::string_to_sign(
+        req.method().as_str(),
+        req.path(),
+        req.query_string(),
+        "",
+        expires,
+    );
+    if !signing::verify(&credential.secret, &string_to_sign, signature) {
+        return Err(ApiError::access_denied("Signature does not match"));
+    }
+
+    Ok(Caller::Presigned {
+        access_key: access_key.clone(),
+        root: credential.root,
+        max_length,
+    })
+}
+
+/// Checks the body of a signed request against its `x-mvp-content-sha256` while it is read.
+/// On a mismatch the payload fails at its end, so handlers discard what they read of it.
+/// `authenticate` rejects signed requests without the header.
+pub fn check_content_sha256(req: &mut ServiceRequest) {
+    if !req.headers().contains_key(header::AUTHORIZATION) {
+        return;
+    }
+    let Some(expected) = req
+        .headers()
+        .get(signing::CONTENT_SHA256_HEADER)
+        .and_then(|hash| hex::decode(hash.as_bytes()).ok())
+    else {
+        return;
+    };
+    let payload = req.take_payload();
+    let checked = stream::unfold(
+        Some((payload, Sha256::new(), expected)),
+        |state| async move {
+            let (mut payload, mut hasher, expected) = state?;
+            match payload.next().await {
+                Some(Ok(chunk)) => {
+                    hasher.update(&chunk);
+                    Some((Ok(chunk), Some((payload, hasher, expected))))
+                }
+                Some(Err(e)) => Some((Err(e), None)),
+                None if hasher.finalize()[..] == expected[..] => None,
+                None => Some((Err(content_sha256_mismatch()), None)),
+            }
+        },
+    );
+    req.set_payload(Payload::from(checked.boxed_local()));
+}
+
+fn content_sha256_mismatch() -> PayloadError {
+    PayloadError::Io(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Body does not match x-mvp-content-sha256",
+    ))
+}
+
+/// Whether reading a body failed because it did not match its `x-mvp-content-sha256`.
+pub fn is_content_sha256_mismatch(error: &PayloadError) -> bool {
+    matches!(error, PayloadError::Io(e) if e.kind() == io::ErrorKind::InvalidData)
+}
+
+/// Authenticates the request and checks `permission` against the bucket policy.
+pub fn authorize(
+    req: &HttpRequest,
+    state: &AppState,
+    bucket: &str,
+    permission: Permission,
+) -> Result<Caller, ApiError> {
+    let caller = authenticate(req, &state.credentials)?;
+    let Some(config) = state.metadata.bucket(bucket) else {
+        // Do not reveal which buckets exist to anonymous callers
+        return Err(match caller {
+            Caller::Anonymous => ApiError::access_denied("Access denied"),
+            _ => ApiError::no_such_bucket(bucket),
+        });
+    };
+    if !config.policy.allows(&caller, permission) {
+        return Err(ApiError::access_denied(format!(
+            "{caller} lacks {permission} permission on bucket {bucket}"
+        )));
+    }
+    Ok(caller)
+}
+
+pub fn authorize_root(req: &HttpRequest, state: &AppState) -> Result<Caller, ApiError> {
+    let caller = authenticate(req, &state.credentials)?;
+    match caller {
+        Caller::Key { root: true, .. } => Ok(caller),
+        _ => Err(ApiError::access_denied(format!(
+            "{caller} is not a root credential"
+        ))),
+    }
+}
+
+fn parse_authorization(authorization: &str) -> Option<(&str, &str)> {
+    let params = authorization.strip_prefix(signing::ALGORITHM)?.trim();
+    let mut access_key = None;
+    let mut signature = None;
+    for param in params.split(',') {
+        match param.trim().split_once('=')? {
+            ("Credential", value) => access_key = Some(value),
+            ("Signature", value) => signature = Some(value),
+            _ => {}
+        }
+    }
+    Some((access_key?, signature?))
+}
@@ -0,0 +1,257 @@
+//! Parquet bloom filters for point lookups.
+//!
+//! Min/max statistics prune nothing for equality predicates on high-cardinality columns
+//! such as IDs, whose values are spread over every row group. A split-block bloom filter
+//! per column chunk tells that a value is certainly absent from a few KiB read. Queries
+//! check the bloom filters of the row groups statistics left for every `eq` predicate, and
+//! uploads can ask for a file to be written again with bloom filters on some columns when
+//! the writer did not add them.
+
+use actix_web::web::Bytes;
+use parquet::arrow::arrow_reader::{
+    ArrowReaderMetadata, ArrowReaderOptions, ParquetRecordBatchReaderBuilder,
+};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{ConvertedType, Type};
+use parquet::bloom_filter::Sbbf;
+use parquet::data_type::ByteArray;
+use parquet::errors::{ParquetError, Result};
+use parquet::file::metadata::ParquetMetaData;
+use parquet::file::properties::WriterProperties;
+use parquet::schema::types::ColumnDescriptor;
+use serde_json::Value;
+use std::sync::Arc;
+
+/// Metadata key under which the Arrow writer stores the Arrow schema itself
+const ARROW_SCHEMA_KEY: &str = "ARROW:schema";
+
+pub struct BloomConfig {
+    /// Bytes of an upload that is rewritten with bloom filters, which holds it in memory
+    /// along with its decoded row groups
+    pub max_rewrite: u64,
+}
+
+/// Whether `filter`, the bloom filter of a chunk of `column`, may hold `value`. Values of
+/// types that are not hashed like the values of the column may.
+pub fn may_contain(filter: &Sbbf, column: &ColumnDescriptor, value: &Value) -> bool {
+    use ConvertedType::{INT_16, INT_32, INT_64, INT_8, NONE, UTF8};
+    match (column.physical_type(), column.converted_type()) {
+        (Type::INT32, NONE | INT_8 | INT_16 | INT_32) => match value.as_i64() {
+            Some(value) => i32::try_from(value).is_ok_and(|value| filter.check(&value)),
+            None => true,
+        },
+        (Type::INT64, NONE | INT_64) => value.as_i64().is_none_or(|value| filter.check(&value)),
+        (Type::BYTE_ARRAY, UTF8) => value
+            .as_str()
+            .is_none_or(|value| filter.check(&ByteArray::from(value))),
+        _ => true,
+    }
+}
+
+/// The leaf column at `path`, such as `id` or `address.city`.
+pub fn leaf_column(metadata: &ParquetMetaData, path: &str) -> Option<usize> {
+    metadata
+        .file_metadata()
+        .schema_descr()
+        .columns()
+        .iter()
+        .position(|column| column.path().string() == path)
+}
+
+/// `data`, a Parquet file, with bloom filters on `columns`. Unless every row group has them
+/// already, the file is written again with the same row groups, compression and key-value
+/// metadata.
+pub fn add_bloom_filters(data: Bytes, columns: &[String]) -> Result<Bytes> {
+    let metadata = ArrowReaderMetadata::load(&data, ArrowReaderOptions::new())?;
+    let parquet = Arc::clone(metadata.metadata());
+    let mut leaves = Vec::new();
+    for column in columns {
+        let leaf = leaf_column(&parquet, column)
+            .ok_or_else(|| ParquetError::General(format!("The file has no column {column}")))?;
+        leaves.push(leaf);
+    }
+    let complete = parquet.row_groups().iter().all(|group| {
+        leaves
+            .iter()
+            .all(|&leaf| group.column(leaf).bloom_filter_offset().is_some())
+    });
+    if complete {
+        return Ok(data);
+    }
+
+    let largest_group = parquet
+        .row_groups()
+        .iter()
+        .map(|group| group.num_rows().max(1) as usize)
+        .max()
+        .unwrap_or(1);
+    let key_value_metadata = parquet.file_metadata().key_value_metadata().map(|entries| {
+        entries
+            .iter()
+            .filter(|entry| entry.key != ARROW_SCHEMA_KEY)
+            .cloned()
+            .collect()
+    });
+    let mut properties = WriterProperties::builder()
+        .set_max_row_group_size(largest_group)
+        .set_key_value_metadata(key_value_metadata);
+    if let Some(group) = parquet.row_groups().first() {
+        for chunk in group.columns() {
+            properties =
+                properties.set_column_compression(chunk.column_path().clone(), chunk.compression());
+        }
+    }
+    for &leaf in &leaves {
+        let path = parquet
+            .file_metadata()
+            .schema_descr()
+            .column(leaf)
+            .path()
+            .clone();
+        // Sized for the distinct values a row group can hold at most
+        properties = properties
+            .set_column_bloom_filter_enabled(path.clone(), true)
+            .set_column_bloom_filter_ndv(path, largest_group as u64);
+    }
+
+    let mut writer = ArrowWriter::try_new(
+        Vec::new(),
+        Arc::clone(metadata.schema()),
+        Some(properties.build()),
+    )?;
+    for group in 0..parquet.num_row_groups() {
+        let batches =
+            ParquetRecordBatchReaderBuilder::new_with_metadata(data.clone(), metadata.clone())
+                .with_row_groups(vec![group])
+                .build()?;
+        for batch in batches {
+            writer.write(&batch?)?;
+        }
+        writer.flush()?;
+    }
+    Ok(Bytes::from(writer.into_inner()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{ArrayRef, Int32Array, Int64Array, RecordBatch, StringArray};
+    use parquet::file::metadata::KeyValue;
+    use parquet::file::properties::ReaderProperties;
+    use parquet::file::reader::FileReader;
+    use parquet::file::serialized_reader::{ReadOptionsBuilder, SerializedFileReader};
+    use serde_json::json;
+
+    /// A file of 6 rows in row groups of 2 with `id`, `name` and `amount` columns, with
+    /// bloom filters on all of them if `bloom_filters`.
+    fn file(bloom_filters: bool) -> Bytes {
+        let columns: [(&str, ArrayRef); 3] = [
+            ("id", Arc::new(Int32Array::from_iter_values(1..=6))),
+            (
+                "name",
+                Arc::new(StringArray::from_iter_values([
+                    "a", "b", "c", "d", "e", "f",
+                ])),
+            ),
+            ("amount", Arc::new(Int64Array::from_iter_values(10..16))),
+        ];
+        let batch = RecordBatch::try_from_iter(columns).expect("valid batch");
+        let properties = WriterProperties::builder()
+            .set_bloom_filter_enabled(bloom_filters)
+            .set_max_row_group_size(2)
+            .set_key_value_metadata(Some(vec![KeyValue::new(
+                "origin".to_string(),
+                "tests".to_string(),
+            )]))
+            .build();
+        let mut writer =
+            ArrowWriter::try_new(Vec::new(), batch.schema(), Some(properties)).expect("writer");
+        writer.write(&batch).expect("written batch");
+        Bytes::from(writer.into_inner().expect("written file"))
+    }
+
+    fn metadata(data: &Bytes) -> Arc<ParquetMetaData> {
+        let metadata = ArrowReaderMetadata::load(data, ArrowReaderOptions::new()).expect("footer");
+        Arc::clone(metadata.metadata())
+    }
+
+    /// A reader of `data` that reads its bloom filters along with the row groups.
+    fn reader(data: Bytes) -> SerializedFileReader<Bytes> {
+        let properties = ReaderProperties::builder()
+            .set_read_bloom_filter(true)
+            .build();
+        let options = ReadOptionsBuilder::new()
+            .with_reader_properties(properties)
+            .build();
+        SerializedFileReader::new_with_options(data, options).expect("reader")
+    }
+
+    #[test]
+    fn rewritten_files_gain_bloom_filters_on_the_columns() {
+        let data = file(false);
+        let columns = ["id".to_string(), "name".to_string()];
+        let rewritten = add_bloom_filters(data, &columns).expect("rewritten file");
+
+        let parquet = metadata(&rewritten);
+        assert_eq!(parquet.num_row_groups(), 3);
+        for group in parquet.row_groups() {
+            assert_eq!(group.num_rows(), 2);
+            let offsets: Vec<bool> = group
+                .columns()
+                .iter()
+                .map(|chunk| chunk.bloom_filter_offset().is_some())
+                .collect();
+            assert_eq!(offsets, [true, true, false]);
+        }
+        let key_value_metadata = parquet.file_metadata().key_value_metadata();
+        assert!(key_value_metadata
+            .into_iter()
+            .flatten()
+            .any(|entry| entry.key == "origin" && entry.value.as_deref() == Some("tests")));
+
+        let reader = reader(rewritten);
+        let group = reader.get_row_group(1).expect("row group");
+        let schema = parquet.file_metadata().schema_descr();
+        let id = group.get_column_bloom_filter(0).expect("id bloom filter");
+        assert!(may_contain(id, &schema.column(0), &json!(3)));
+        let name = group.get_column_bloom_filter(1).expect("name bloom filter");
+        assert!(may_contain(name, &schema.column(1), &json!("d")));
+    }
+
+    #[test]
+    fn files_with_the_bloom_filters_are_kept() {
+        let data = file(true);
+        let kept = add_bloom_filters(data.clone(), &["amount".to_string()]).expect("kept file");
+        assert_eq!(kept.as_ptr(), data.as_ptr());
+        assert_eq!(kept, data);
+
+        let missing = add_bloom_filters(data, &["price".to_string()]);
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn values_are_looked_up_like_the_column_hashes_them() {
+        let data = file(true);
+        let parquet = metadata(&data);
+        let schema = parquet.file_metadata().schema_descr();
+        let reader = reader(data);
+        let group = reader.get_row_group(0).expect("row group");
+        let filter = |leaf| group.get_column_bloom_filter(leaf).expect("bloom filter");
+        let (id, name, amount) = (schema.column(0), schema.column(1), schema.column(2));
+
+        assert!(may_contain(filter(0), &id, &json!(1)));
+        assert!(may_contain(filter(2), &amount, &json!(11)));
+        assert!(may_contain(filter(1), &name, &json!("b")));
+        // An INT32 column holds no values out of its range, which must not wrap around
+        assert!(!may_contain(filter(0), &id, &json!(1_i64 << 32 | 1)));
+        assert!(!may_contain(filter(0), &id, &json!(i64::MIN)));
+
+        // Values of another type are never ruled out
+        for value in [json!("1"), json!(1.5), json!(true), json!(null)] {
+            assert!(may_contain(filter(0), &id, &value), "{value}");
+        }
+        assert!(may_contain(filter(1), &name, &json!(1)));
+        assert!(may_contain(filter(2), &amount, &json!("11")));
+        assert!(may_contain(filter(2), &amount, &json!(10.5)));
+    }
+}
@@ -0,0 +1,354 @@
+//! In-memory cache of object data that is read again and again.
+//!
+//! Analytical clients read the footer of a Parquet file, then its page indexes, then a few
+//! hot column chunks, and many of them read the same files, which on HDDs costs a seek
+//! every time. Ranges read by GETs are kept in memory up to a byte budget and the least
+//! recently used ones are evicted first. A read that reaches the end of a Parquet file
+//! loads its whole footer and page indexes along with it, so the reads that usually follow
+//! are served from memory too. Entries hold plain data keyed by blob, and blobs are never
+//! rewritten: the entries of a version are dropped when it is overwritten or deleted.
+
+use actix_web::web::Bytes;
+use parquet::file::metadata::ParquetMetaDataReader;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Length and magic at the end of a Parquet file
+pub const PARQUET_TAIL: u64 = 8;
+/// Entries listed by the admin endpoint
+const REPORTED_ENTRIES: usize = 100;
+
+#[derive(Clone, Copy, Debug)]
+pub struct CacheConfig {
+    /// Bytes of data kept at most, 0 disables the cache
+    pub budget: u64,
+    /// Larger reads bypass the cache
+    pub max_entry: u64,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntryKind {
+    /// Parquet footer including its length and magic
+    Footer,
+    /// Column and offset indexes of all row groups
+    PageIndex,
+    /// A range read by a client
+    Range,
+}
+
+struct Entry {
+    /// `bucket/key` the blob belonged to when cached, for the admin endpoint
+    object: String,
+    kind: EntryKind,
+    data: Bytes,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Entries by blob and offset
+    blobs: HashMap<String, BTreeMap<u64, Entry>>,
+    /// Blob and offset of every entry by last use
+    lru: BTreeMap<u64, (String, u64)>,
+    clock: u64,
+    bytes: u64,
+}
+
+impl Inner {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn remove(&mut self, blob: &str, offset: u64) -> Option<Entry> {
+        let entries = self.blobs.get_mut(blob)?;
+        let entry = entries.remove(&offset)?;
+        if entries.is_empty() {
+            self.blobs.remove(blob);
+        }
+        self.lru.remove(&entry.last_used);
+        self.bytes -= entry.data.len() as u64;
+        Some(entry)
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    hit_bytes: AtomicU64,
+    evictions: AtomicU64,
+}
+
+#[derive(Serialize, Default)]
+pub struct KindUsage {
+    pub entries: u64,
+    pub bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct EntryReport {
+    pub object: String,
+    pub blob: String,
+    pub kind: EntryKind,
+    pub offset: u64,
+    pub length: u64,
+}
+
+#[derive(Serialize)]
+pub struct CacheReport {
+    pub budget_bytes: u64,
+    pub max_entry_bytes: u64,
+    pub bytes: u64,
+    pub entries: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub kinds: BTreeMap<EntryKind, KindUsage>,
+    /// The most recently used entries first
+    pub recent: Vec<EntryReport>,
+}
+
+pub struct ReadCache {
+    config: CacheConfig,
+    inner: Mutex<Inner>,
+    counters: Counters,
+}
+
+impl ReadCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            inner: Mutex::default(),
+            counters: Counters::default(),
+        }
+    }
+
+    /// Whether a read of `length` bytes goes through the cache.
+    pub fn fits(&self, length: u64) -> bool {
+        length > 0 && length <= self.config.max_entry && length <= self.config.budget
+    }
+
+    /// Bytes `start..start + length` of `blob` if an entry holds them, counted as a hit or
+    /// a miss.
+    pub fn get(&self, blob: &str, start: u64, length: u64) -> Option<Bytes> {
+        let data = self.peek(blob, start, length);
+        match &data {
+            Some(_) => {
+                self.counters.hits.fetch_add(1, Ordering::Relaxed);
+                self.counters.hit_bytes.fetch_add(length, Ordering::Relaxed);
+            }
+            None => {
+                self.counters.misses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        data
+    }
+
+    /// Like `get`, for lookups on behalf of the cache itself that are not counted.
+    pub fn peek(&self, blob: &str, start: u64, length: u64) -> Option<Bytes> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = inner.tick();
+        let entries = inner.blobs.get_mut(blob)?;
+        // Entries may overlap, a footer read as part of a larger range for example
+        let (&offset, entry) = entries
+            .range_mut(..=start)
+            .rev()
+            .find(|(offset, entry)| **offset + entry.data.len() as u64 >= start + length)?;
+        let from = (start - offset) as usize;
+        let data = entry.data.slice(from..from + length as usize);
+        let last_used = std::mem::replace(&mut entry.last_used, now);
+        inner.lru.remove(&last_used);
+        inner.lru.insert(now, (blob.to_string(), offset));
+        Some(data)
+    }
+
+    /// Caches `data` as the bytes of `blob` from `offset`, evicting the least recently used
+    /// entries to stay within the budget.
+    pub fn insert(&self, blob: &str, object: &str, offset: u64, data: Bytes, kind: EntryKind) {
+        if !self.fits(data.len() as u64) {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        let existing = inner
+            .blobs
+            .get(blob)
+            .and_then(|entries| entries.get(&offset))
+            .map(|entry| entry.data.len());
+        if existing >= Some(data.len()) {
+            return;
+        }
+        inner.remove(blob, offset);
+
+        let now = inner.tick();
+        inner.bytes += data.len() as u64;
+        inner.lru.insert(now, (blob.to_string(), offset));
+        inner.blobs.entry(blob.to_string()).or_default().insert(
+            offset,
+            Entry {
+                object: object.to_string(),
+                kind,
+                data,
+                last_used: now,
+            },
+        );
+        while inner.bytes > self.config.budget {
+            let Some((_, (blob, offset))) = inner.lru.pop_first() else {
+                break;
+            };
+            if inner.remove(&blob, offset).is_some() {
+                self.counters.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Drops the entries of `blob`, whose version was overwritten or deleted.
+    pub fn invalidate(&self, blob: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entries) = inner.blobs.remove(blob) else {
+            return;
+        };
+        for entry in entries.values() {
+            inner.lru.remove(&entry.last_used);
+            inner.bytes -= entry.data.len() as u64;
+        }
+    }
+
+    /// Drops all entries and returns how many and how many bytes there were.
+    pub fn clear(&self) -> (u64, u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let entries = inner.lru.len() as u64;
+        let bytes = inner.bytes;
+        *inner = Inner {
+            clock: inner.clock,
+            ..Inner::default()
+        };
+        (entries, bytes)
+    }
+
+    pub fn report(&self) -> CacheReport {
+        let inner = self.inner.lock().unwrap();
+        let mut kinds = BTreeMap::<EntryKind, KindUsage>::new();
+        for entry in inner.blobs.values().flat_map(BTreeMap::values) {
+            let usage = kinds.entry(entry.kind).or_default();
+            usage.entries += 1;
+            usage.bytes += entry.data.len() as u64;
+        }
+        let recent = inner
+            .lru
+            .values()
+            .rev()
+            .take(REPORTED_ENTRIES)
+            .map(|(blob, offset)| {
+                let entry = &inner.blobs[blob][offset];
+                EntryReport {
+                    object: entry.object.clone(),
+                    blob: blob.clone(),
+                    kind: entry.kind,
+                    offset: *offset,
+                    length: entry.data.len() as u64,
+                }
+            })
+            .collect();
+        CacheReport {
+            budget_bytes: self.config.budget,
+            max_entry_bytes: self.config.max_entry,
+            bytes: inner.bytes,
+            entries: inner.lru.len() as u64,
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            evictions: self.counters.evictions.load(Ordering::Relaxed),
+            kinds,
+            recent,
+        }
+    }
+
+    /// Cache usage in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let (bytes, entries) = {
+            let inner = self.inner.lock().unwrap();
+            (inner.bytes, inner.lru.len() as u64)
+        };
+        let counters = &self.counters;
+        let metrics: [(&str, &str, &str, u64); 7] = [
+            (
+                "mvp_cache_hits_total",
+                "counter",
+                "Reads served from the cache",
+                counters.hits.load(Ordering::Relaxed),
+            ),
+            (
+                "mvp_cache_misses_total",
+                "counter",
+                "Cacheable reads that went to storage",
+                counters.misses.load(Ordering::Relaxed),
+            ),
+            (
+                "mvp_cache_hit_bytes_total",
+                "counter",
+                "Bytes served from the cache",
+                counters.hit_bytes.load(Ordering::Relaxed),
+            ),
+            (
+                "mvp_cache_evictions_total",
+                "counter",
+                "Entries evicted to stay within the budget",
+                counters.evictions.load(Ordering::Relaxed),
+            ),
+            ("mvp_cache_bytes", "gauge", "Bytes of data cached", bytes),
+            ("mvp_cache_entries", "gauge", "Entries cached", entries),
+            (
+                "mvp_cache_budget_bytes",
+                "gauge",
+                "Bytes the cache may hold, 0 disables it",
+                self.config.budget,
+            ),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+            );
+        }
+        out
+    }
+}
+
+/// Length of the footer that ends in `tail`, the last `PARQUET_TAIL` bytes of an object,
+/// if the object is a Parquet file.
+pub fn parquet_footer_len(tail: &[u8]) -> Option<u64> {
+    let tail = tail.try_into().ok()?;
+    ParquetMetaDataReader::decode_footer(tail)
+        .ok()
+        .map(|len| len as u64)
+}
+
+/// Offset and length of the range holding the column and offset indexes described by
+/// `metadata`, the footer without its tail.
+pub fn page_index_range(metadata: &[u8]) -> Option<(u64, u64)> {
+    let metadata = ParquetMetaDataReader::decode_metadata(metadata).ok()?;
+    let (mut start, mut end) = (u64::MAX, 0);
+    for column in metadata
+        .row_groups()
+        .iter()
+        .flat_map(|group| group.columns())
+    {
+        let indexes = [
+            (column.column_index_offset(), column.column_index_length()),
+            (column.offset_index_offset(), column.offset_index_length()),
+        ];
+        for (offset, length) in indexes {
+            if let (Some(offset), Some(length)) = (offset, length) {
+                start = start.min(offset as u64);
+                end = end.max(offset as u64 + length as u64);
+            }
+        }
+    }
+    (start < end).then(|| (start, end - start))
+}
@@ -0,0 +1,278 @@
+//! Group commit: fsyncs of concurrent requests are batched.
+//!
+//! The first request to find no batch open becomes its leader. It waits for the commit
+//! window, or until the batch is full, then syncs everything in the batch at once and
+//! hands the result to every request in it. Data files are synced in one batch and the
+//! metadata log in another, so data is always durable before the record pointing to it.
+
+use std::fmt::Write;
+use std::future::Future;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
+use tokio::time::Instant;
+
+#[derive(Clone, Copy, Debug)]
+pub struct CommitConfig {
+    /// How long a batch stays open for more requests
+    pub window: Duration,
+    /// A batch is synced right away once it has this many entries
+    pub max_batch: usize,
+}
+
+type Outcome = Result<(), (io::ErrorKind, String)>;
+
+struct Batch<T> {
+    items: Vec<T>,
+    waiters: Vec<oneshot::Sender<Outcome>>,
+    /// Whether a leader is waiting to sync the open batch
+    led: bool,
+}
+
+#[derive(Default)]
+struct Counters {
+    batches: AtomicU64,
+    items: AtomicU64,
+    sync_micros: AtomicU64,
+}
+
+/// Batches syncs of `T`s, each is acknowledged once the batch it is in is durable.
+pub struct Batcher<T> {
+    config: CommitConfig,
+    batch: Mutex<Batch<T>>,
+    full: Notify,
+    counters: Counters,
+}
+
+impl<T> Batcher<T> {
+    pub fn new(config: CommitConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            batch: Mutex::new(Batch {
+                items: Vec::new(),
+                waiters: Vec::new(),
+                led: false,
+            }),
+            full: Notify::new(),
+            counters: Counters::default(),
+        })
+    }
+
+    /// Adds `item` to the open batch and waits until `sync` made the batch durable. Only
+    /// the leader of a batch runs `sync`, on all of its items, in a task of its own so a
+    /// request that goes away does not strand the others.
+    pub async fn commit<F, Fut>(self: &Arc<Self>, item: T, sync: F) -> io::Result<()>
+    where
+        T: Send + 'static,
+        F: FnOnce(Vec<T>) -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let lead = {
+            let mut batch = self.batch.lock().unwrap();
+            batch.items.push(item);
+            batch.waiters.push(sender);
+            if batch.items.len() >= self.config.max_batch {
+                self.full.notify_one();
+            }
+            !std::mem::replace(&mut batch.led, true)
+        };
+        if lead {
+            tokio::spawn(Arc::clone(self).lead(sync));
+        }
+
+        match receiver.await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err((kind, message))) => Err(io::Error::new(kind, message)),
+            Err(_) => Err(io::Error::other("group commit leader went away")),
+        }
+    }
+
+    async fn lead<F, Fut>(self: Arc<Self>, sync: F)
+    where
+        F: FnOnce(Vec<T>) -> Fut,
+        Fut: Future<Output = io::Result<()>>,
+    {
+        let _ = tokio::time::timeout(self.config.window, self.full.notified()).await;
+        let (items, waiters) = {
+            let mut batch = self.batch.lock().unwrap();
+            batch.led = false;
+            (
+                std::mem::take(&mut batch.items),
+                std::mem::take(&mut batch.waiters),
+            )
+        };
+        // A notification for this batch must not cut the next one short
+        let _ = tokio::time::timeout(Duration::ZERO, self.full.notified()).await;
+
+        let start = Instant::now();
+        self.counters
+            .items
+            .fetch_add(items.len() as u64, Ordering::Relaxed);
+        let outcome = sync(items).await.map_err(|e| (e.kind(), e.to_string()));
+        self.counters.batches.fetch_add(1, Ordering::Relaxed);
+        self.counters
+            .sync_micros
+            .fetch_add(start.elapsed().as_micros() as u64, Ordering::Relaxed);
+        for waiter in waiters {
+            let _ = waiter.send(outcome.clone());
+        }
+    }
+
+    /// Batches, entries and microseconds spent syncing so far.
+    fn counts(&self) -> [u64; 3] {
+        let counters = &self.counters;
+        [
+            counters.batches.load(Ordering::Relaxed),
+            counters.items.load(Ordering::Relaxed),
+            counters.sync_micros.load(Ordering::Relaxed),
+        ]
+    }
+}
+
+/// The batchers of data files, by store key, and of the metadata log.
+pub struct GroupCommit {
+    pub config: CommitConfig,
+    pub blobs: Arc<Batcher<String>>,
+    /// By sequence number of the record
+    pub log: Arc<Batcher<u64>>,
+}
+
+impl GroupCommit {
+    pub fn new(config: CommitConfig) -> Arc<Self> {
+        Arc::new(Self {
+            config,
+            blobs: Batcher::new(config),
+            log: Batcher::new(config),
+        })
+    }
+
+    /// Batch counters and thresholds in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let (blobs, log) = (self.blobs.counts(), self.log.counts());
+        let counters = [
+            ("mvp_group_commit_batches_total", "Batches synced"),
+            (
+                "mvp_group_commit_entries_total",
+                "Entries made durable in batches",
+            ),
+            (
+                "mvp_group_commit_sync_microseconds_total",
+                "Time spent syncing batches",
+            ),
+        ];
+        let mut out = String::new();
+        for (i, (name, help)) in counters.into_iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} counter\n\
+                 {name}{{kind=\"blobs\"}} {}\n{name}{{kind=\"log\"}} {}",
+                blobs[i], log[i]
+            );
+        }
+
+        let gauges = [
+            (
+                "mvp_group_commit_window_microseconds",
+                "How long a batch stays open",
+                self.config.window.as_micros() as u64,
+            ),
+            (
+                "mvp_group_commit_max_batch",
+                "Entries after which a batch is synced right away",
+                self.config.max_batch as u64,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+            );
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Commits `0..count` at the same time, recording the batches `sync` was called with.
+    async fn commit_all(
+        batcher: &Arc<Batcher<u32>>,
+        count: u32,
+        fail: bool,
+    ) -> (Vec<io::Result<()>>, Vec<Vec<u32>>) {
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let commits: Vec<_> = (0..count)
+            .map(|item| {
+                let (batcher, batches) = (Arc::clone(batcher), Arc::clone(&batches));
+                tokio::spawn(async move {
+                    batcher
+                        .commit(item, move |mut items| async move {
+                            items.sort();
+                            batches.lock().unwrap().push(items);
+                            match fail {
+                                true => Err(io::Error::other("sync failed")),
+                                false => Ok(()),
+                            }
+                        })
+                        .await
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for commit in commits {
+            results.push(commit.await.expect("commit task"));
+        }
+        let batches = std::mem::take(&mut *batches.lock().unwrap());
+        (results, batches)
+    }
+
+    #[tokio::test]
+    async fn full_batches_are_synced_at_once() {
+        let batcher = Batcher::new(CommitConfig {
+            window: Duration::from_secs(60),
+            max_batch: 8,
+        });
+        let (results, batches) =
+            tokio::time::timeout(Duration::from_secs(10), commit_all(&batcher, 8, false))
+                .await
+                .expect("synced before the window closed");
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(batches, [(0..8).collect::<Vec<_>>()]);
+        assert_eq!(batcher.counts()[..2], [1, 8]);
+    }
+
+    #[tokio::test]
+    async fn batches_are_synced_when_the_window_closes() {
+        let batcher = Batcher::new(CommitConfig {
+            window: Duration::from_millis(10),
+            max_batch: 64,
+        });
+        let (results, batches) = commit_all(&batcher, 1, false).await;
+        assert!(results[0].is_ok());
+        assert_eq!(batches, [[0]]);
+
+        let (results, batches) = commit_all(&batcher, 5, false).await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(batches.concat().len(), 5);
+        assert_eq!(batcher.counts()[1], 6);
+    }
+
+    #[tokio::test]
+    async fn failed_syncs_fail_every_commit_of_the_batch() {
+        let batcher = Batcher::new(CommitConfig {
+            window: Duration::from_millis(10),
+            max_batch: 4,
+        });
+        let (results, batches) = commit_all(&batcher, 4, true).await;
+        assert_eq!(batches.concat().len(), 4);
+        for result in results {
+            let error = result.expect_err("failed sync");
+            assert_eq!(error.to_string(), "sync failed");
+        }
+    }
+}
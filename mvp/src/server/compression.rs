@@ -0,0 +1,335 @@
+//! Compression of blobs at rest.
+//!
+//! Compressed blobs are a sequence of independently compressed frames of `FRAME_SIZE`
+//! uncompressed bytes (the last one may be shorter), followed by a seek table with the
+//! compressed length of every frame as `u32` and a footer of the frame count (`u64`), the
+//! frame size (`u32`) and `FOOTER_MAGIC`, all little endian. Reads of a byte range only
+//! decompress the frames overlapping it.
+
+use actix_web::web::Bytes;
+use futures::Stream;
+use serde::{Deserialize, Serialize};
+use std::io;
+
+use crate::encryption::{BlobSink, BlobSource};
+use crate::MAX_CHUNK_SIZE;
+
+pub const FRAME_SIZE: usize = 1 << 20;
+const ZSTD_LEVEL: i32 = 3;
+const FOOTER_MAGIC: &[u8; 4] = b"MVPZ";
+const FOOTER_LEN: u64 = 16;
+const PARQUET_MAGIC: &[u8; 4] = b"PAR1";
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd => zstd::bulk::compress(data, ZSTD_LEVEL),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        }
+    }
+
+    fn decompress(self, data: &[u8], capacity: usize) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Zstd => zstd::bulk::decompress(data, capacity),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(data)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+/// How a version is stored if its blob is compressed.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Compressed {
+    pub algorithm: Compression,
+    /// Bytes on disk, including the seek table
+    pub stored_size: u64,
+}
+
+/// Writes an upload to its blob, compressed with `compression` unless it turns out to be
+/// Parquet, whose pages are compressed already.
+pub struct BlobWriter {
+    file: BlobSink,
+    compression: Compression,
+    buffer: Vec<u8>,
+    size: u64,
+    frames: Vec<u32>,
+    stored_size: u64,
+}
+
+impl BlobWriter {
+    pub fn new(file: BlobSink, compression: Compression) -> Self {
+        Self {
+            file,
+            compression,
+            buffer: Vec::new(),
+            size: 0,
+            frames: Vec::new(),
+            stored_size: 0,
+        }
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.size += data.len() as u64;
+        self.buffer.extend_from_slice(data);
+
+        if self.compression == Compression::None {
+            while self.buffer.len() >= MAX_CHUNK_SIZE {
+                let chunk_to_write = self.buffer.drain(..MAX_CHUNK_SIZE).collect::<Vec<_>>();
+                self.file.write_all(&chunk_to_write).await?;
+            }
+            return Ok(());
+        }
+        while self.buffer.len() >= FRAME_SIZE {
+            let frame = self.buffer.drain(..FRAME_SIZE).collect::<Vec<_>>();
+            self.write_frame(&frame).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if self.frames.is_empty() && frame.starts_with(PARQUET_MAGIC) {
+            self.compression = Compression::None;
+        }
+        if self.compression == Compression::None {
+            return self.file.write_all(frame).await;
+        }
+        let compressed = self.compression.compress(frame)?;
+        self.file.write_all(&compressed).await?;
+        self.frames.push(compressed.len() as u32);
+        self.stored_size += compressed.len() as u64;
+        Ok(())
+    }
+
+    /// Writes what is buffered and the seek table. Returns the
+    /// uncompressed size and how the blob is compressed, if it is.
+    pub async fn finish(mut self) -> io::Result<(u64, Option<Compressed>)> {
+        let buffer = std::mem::take(&mut self.buffer);
+        if !buffer.is_empty() {
+            self.write_frame(&buffer).await?;
+        }
+
+        let compressed = if self.compression == Compression::None {
+            None
+        } else {
+            let mut table = Vec::with_capacity(self.frames.len() * 4 + FOOTER_LEN as usize);
+            for frame in &self.frames {
+                table.extend_from_slice(&frame.to_le_bytes());
+            }
+            table.extend_from_slice(&(self.frames.len() as u64).to_le_bytes());
+            table.extend_from_slice(&(FRAME_SIZE as u32).to_le_bytes());
+            table.extend_from_slice(FOOTER_MAGIC);
+            self.file.write_all(&table).await?;
+            Some(Compressed {
+                algorithm: self.compression,
+                stored_size: self.stored_size + table.len() as u64,
+            })
+        };
+
+        self.file.finish().await?;
+        Ok((self.size, compressed))
+    }
+}
+
+/// Offsets of the frames of a compressed blob.
+struct SeekTable {
+    frame_size: u64,
+    /// Start of every frame and the end of the last one
+    offsets: Vec<u64>,
+}
+
+async fn read_seek_table(file: &mut BlobSource, stored_size: u64) -> io::Result<SeekTable> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    if stored_size < FOOTER_LEN {
+        return Err(invalid("compressed blob is too short"));
+    }
+    let footer = file
+        .read_at(stored_size - FOOTER_LEN, FOOTER_LEN as usize)
+        .await?;
+    if &footer[12..] != FOOTER_MAGIC {
+        return Err(invalid("compressed blob has no seek table"));
+    }
+    let frames = u64::from_le_bytes(footer[..8].try_into().unwrap());
+    let frame_size = u32::from_le_bytes(footer[8..12].try_into().unwrap()) as u64;
+    if frame_size == 0 {
+        return Err(invalid("seek table has frames of size 0"));
+    }
+    let table_len = frames
+        .checked_mul(4)
+        .filter(|len| len + FOOTER_LEN <= stored_size)
+        .ok_or_else(|| invalid("seek table is larger than the blob"))?;
+
+    let table = file
+        .read_at(stored_size - FOOTER_LEN - table_len, table_len as usize)
+        .await?;
+    let mut offsets = Vec::with_capacity(frames as usize + 1);
+    let mut offset = 0;
+    offsets.push(offset);
+    for length in table.chunks_exact(4) {
+        offset += u32::from_le_bytes(length.try_into().unwrap()) as u64;
+        offsets.push(offset);
+    }
+    Ok(SeekTable {
+        frame_size,
+        offsets,
+    })
+}
+
+/// Streams `length` uncompressed bytes starting at `start` out of a compressed blob.
+pub async fn read_range(
+    mut file: BlobSource,
+    compressed: Compressed,
+    start: u64,
+    length: u64,
+) -> io::Result<impl Stream<Item = io::Result<Bytes>>> {
+    let table = read_seek_table(&mut file, compressed.stored_size).await?;
+    let end = start + length;
+    let first = (start / table.frame_size) as usize;
+    let state = (file, table, first, start, end);
+
+    Ok(futures::stream::try_unfold(
+        state,
+        move |(mut file, table, frame, position, end)| async move {
+            if position >= end || frame + 1 >= table.offsets.len() {
+                return Ok(None);
+            }
+            let (from, to) = (table.offsets[frame], table.offsets[frame + 1]);
+            let data = file.read_at(from, (to - from) as usize).await?;
+            let data = compressed
+                .algorithm
+                .decompress(&data, table.frame_size as usize)?;
+
+            let frame_start = frame as u64 * table.frame_size;
+            let skip = (position - frame_start) as usize;
+            let take = data.len().min(skip + (end - position) as usize);
+            let chunk = Bytes::copy_from_slice(&data[skip.min(take)..take]);
+            let next = frame_start + data.len() as u64;
+            Ok(Some((chunk, (file, table, frame + 1, next, end))))
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+    use std::sync::Arc;
+
+    use crate::memory::MemoryStore;
+    use crate::storage::Store;
+
+    /// Bytes that compress, but not to nothing.
+    fn data(len: usize) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i / 7 % 251) as u8 ^ (i % 3) as u8)
+            .collect()
+    }
+
+    async fn write(
+        store: &dyn Store,
+        data: &[u8],
+        compression: Compression,
+    ) -> (u64, Option<Compressed>) {
+        let file = store.put("blob").await.expect("new blob");
+        let mut writer = BlobWriter::new(BlobSink::new(file, None), compression);
+        // In pieces that do not line up with frames
+        for piece in data.chunks(300_001) {
+            writer.write(piece).await.expect("written piece");
+        }
+        writer.finish().await.expect("finished blob")
+    }
+
+    async fn read(store: &dyn Store, compressed: Compressed, start: u64, length: u64) -> Vec<u8> {
+        let file = store.get("blob").await.expect("blob");
+        let source = BlobSource::new(file, None, compressed.stored_size);
+        let chunks: Vec<Bytes> = read_range(source, compressed, start, length)
+            .await
+            .expect("seek table")
+            .try_collect()
+            .await
+            .expect("decompressed range");
+        chunks.concat()
+    }
+
+    #[actix_web::test]
+    async fn ranges_round_trip_across_frames() {
+        let frame = FRAME_SIZE as u64;
+        for algorithm in [Compression::Zstd, Compression::Lz4] {
+            // Empty, within one frame, exactly full frames and a short last frame
+            for len in [0, 1, 1000, frame, 2 * frame, 2 * frame + 123] {
+                let store = Arc::new(MemoryStore::default());
+                let data = data(len as usize);
+                let (size, compressed) = write(&*store, &data, algorithm).await;
+                assert_eq!(size, len);
+                let compressed = compressed.expect("compressed blob");
+                assert_eq!(compressed.algorithm, algorithm);
+                if len == 2 * frame {
+                    assert!(compressed.stored_size < len);
+                }
+
+                let ranges = [
+                    (0, len),
+                    (0, len.min(1)),
+                    (len.saturating_sub(1), len.min(1)),
+                    (len, 0),
+                    (frame - 10, 20),
+                    (frame, 1),
+                    (frame - 1, frame + 2),
+                    (10, 2 * frame),
+                ];
+                for (start, length) in ranges {
+                    let end = (start + length).min(len);
+                    let start = start.min(end);
+                    let expected = &data[start as usize..end as usize];
+                    let read = read(&*store, compressed, start, end - start).await;
+                    assert!(
+                        read == expected,
+                        "{algorithm:?} of {len} bytes, {start}..{end}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn parquet_files_are_stored_as_they_are() {
+        let store = MemoryStore::default();
+        let mut data = PARQUET_MAGIC.to_vec();
+        data.extend(self::data(FRAME_SIZE + 10));
+        let (size, compressed) = write(&store, &data, Compression::Zstd).await;
+        assert_eq!(size, data.len() as u64);
+        assert!(compressed.is_none());
+        let mut file = store.get("blob").await.expect("blob");
+        let stored = file.read_at(0, data.len()).await.expect("stored blob");
+        assert!(stored == data);
+    }
+
+    #[actix_web::test]
+    async fn blobs_without_a_seek_table_are_rejected() {
+        let store = MemoryStore::default();
+        let data = data(100);
+        let (_, compressed) = write(&store, &data, Compression::None).await;
+        assert!(compressed.is_none());
+        let compressed = Compressed {
+            algorithm: Compression::Zstd,
+            stored_size: data.len() as u64,
+        };
+        let file = store.get("blob").await.expect("blob");
+        let source = BlobSource::new(file, None, compressed.stored_size);
+        let error = read_range(source, compressed, 0, 10)
+            .await
+            .err()
+            .expect("no seek table");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
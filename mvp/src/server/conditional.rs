@@ -0,0 +1,99 @@
+use actix_web::http::header::{
+    EntityTag, Header, HttpDate, IfMatch, IfModifiedSince, IfNoneMatch, IfUnmodifiedSince,
+};
+use actix_web::HttpRequest;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::metadata::ObjectMeta;
+
+/// Every write stores a new blob, so its ID identifies the content of a version.
+pub fn etag(meta: &ObjectMeta) -> EntityTag {
+    EntityTag::new_strong(meta.blob.clone())
+}
+
+pub fn last_modified(meta: &ObjectMeta) -> HttpDate {
+    modified(meta).into()
+}
+
+pub enum Outcome {
+    Proceed,
+    NotModified,
+    PreconditionFailed,
+}
+
+/// The conditional headers of a request, evaluated as in RFC 9110 section 13.2.2.
+#[derive(Default)]
+pub struct Preconditions {
+    if_match: Option<IfMatch>,
+    if_none_match: Option<IfNoneMatch>,
+    if_modified_since: Option<SystemTime>,
+    if_unmodified_since: Option<SystemTime>,
+}
+
+impl Preconditions {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let headers = req.headers();
+        // A list that fails to parse matches nothing, invalid dates are ignored
+        Self {
+            if_match: headers
+                .contains_key(IfMatch::name())
+                .then(|| IfMatch::parse(req).unwrap_or(IfMatch::Items(Vec::new()))),
+            if_none_match: headers
+                .contains_key(IfNoneMatch::name())
+                .then(|| IfNoneMatch::parse(req).unwrap_or(IfNoneMatch::Items(Vec::new()))),
+            if_modified_since: IfModifiedSince::parse(req).ok().map(|h| h.0.into()),
+            if_unmodified_since: IfUnmodifiedSince::parse(req).ok().map(|h| h.0.into()),
+        }
+    }
+
+    /// Evaluates the headers for a GET or HEAD of `meta`.
+    pub fn evaluate_read(&self, meta: &ObjectMeta) -> Outcome {
+        if !self.holds(Some(meta)) {
+            return Outcome::PreconditionFailed;
+        }
+        let not_modified = match (&self.if_none_match, self.if_modified_since) {
+            (Some(if_none_match), _) => matches_weak(if_none_match, meta),
+            (None, Some(since)) => modified(meta) <= since,
+            (None, None) => false,
+        };
+        if not_modified {
+            Outcome::NotModified
+        } else {
+            Outcome::Proceed
+        }
+    }
+
+    /// Whether a write may replace `current`, which is `None` if the key does not exist.
+    /// Writes never answer 304, a matching `If-None-Match` fails them instead.
+    pub fn holds_for_write(&self, current: Option<&ObjectMeta>) -> bool {
+        if !self.holds(current) {
+            return false;
+        }
+        self.if_none_match.as_ref().is_none_or(|if_none_match| {
+            current.is_none_or(|meta| !matches_weak(if_none_match, meta))
+        })
+    }
+
+    /// `If-Match` or, without it, `If-Unmodified-Since`.
+    fn holds(&self, current: Option<&ObjectMeta>) -> bool {
+        match (&self.if_match, self.if_unmodified_since) {
+            (Some(IfMatch::Any), _) => current.is_some(),
+            (Some(IfMatch::Items(tags)), _) => {
+                current.is_some_and(|meta| tags.iter().any(|tag| tag.strong_eq(&etag(meta))))
+            }
+            (None, Some(since)) => current.is_none_or(|meta| modified(meta) <= since),
+            (None, None) => true,
+        }
+    }
+}
+
+fn matches_weak(if_none_match: &IfNoneMatch, meta: &ObjectMeta) -> bool {
+    match if_none_match {
+        IfNoneMatch::Any => true,
+        IfNoneMatch::Items(tags) => tags.iter().any(|tag| tag.weak_eq(&etag(meta))),
+    }
+}
+
+fn modified(meta: &ObjectMeta) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(meta.created)
+}
@@ -0,0 +1,112 @@
+//! Counts GETs by the path that served their data.
+//!
+//! Plain objects are sent from their file with sendfile(2) or streamed from the storage
+//! backend as they are, in large chunks. Compressed and encrypted ones are decoded chunk
+//! by chunk on the way out, and ranges held by the read cache never touch storage.
+//! Telling them apart in the metrics shows where the CPU time of downloads goes. A GET is
+//! counted once it finished or was cut off, with the bytes it actually sent.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+#[derive(Clone, Copy, Debug)]
+pub enum ReadPath {
+    /// Served from the read cache
+    Cache,
+    /// Streamed from storage without decoding
+    Plain,
+    /// Decompressed or decrypted while streaming
+    Decoded,
+    /// Sent from the file by the kernel
+    Sendfile,
+}
+
+impl ReadPath {
+    const ALL: [ReadPath; 4] = [
+        ReadPath::Cache,
+        ReadPath::Plain,
+        ReadPath::Decoded,
+        ReadPath::Sendfile,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            ReadPath::Cache => "cache",
+            ReadPath::Plain => "plain",
+            ReadPath::Decoded => "decoded",
+            ReadPath::Sendfile => "sendfile",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    requests: AtomicU64,
+    bytes: AtomicU64,
+}
+
+#[derive(Default)]
+pub struct Downloads {
+    paths: [Counters; 4],
+}
+
+impl Downloads {
+    /// A GET served through `path`, counted when it is dropped.
+    pub fn start(self: &Arc<Self>, path: ReadPath) -> Download {
+        Download {
+            downloads: Arc::clone(self),
+            path,
+            sent: 0,
+        }
+    }
+
+    /// Requests and bytes per path in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let mut out = String::new();
+        for (name, help, bytes) in [
+            (
+                "mvp_downloads_total",
+                "GETs by the path that served them",
+                false,
+            ),
+            (
+                "mvp_download_bytes_total",
+                "Bytes sent by GETs by path",
+                true,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} counter");
+            for path in ReadPath::ALL {
+                let counters = &self.paths[path as usize];
+                let value = match bytes {
+                    true => counters.bytes.load(Ordering::Relaxed),
+                    false => counters.requests.load(Ordering::Relaxed),
+                };
+                let _ = writeln!(out, "{name}{{path=\"{}\"}} {value}", path.label());
+            }
+        }
+        out
+    }
+}
+
+/// A GET in flight.
+pub struct Download {
+    downloads: Arc<Downloads>,
+    path: ReadPath,
+    sent: u64,
+}
+
+impl Download {
+    pub fn sent(&mut self, bytes: usize) {
+        self.sent += bytes as u64;
+    }
+}
+
+impl Drop for Download {
+    fn drop(&mut self) {
+        let counters = &self.downloads.paths[self.path as usize];
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+        counters.bytes.fetch_add(self.sent, Ordering::Relaxed);
+    }
+}
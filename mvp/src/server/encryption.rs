@@ -0,0 +1,496 @@
+//! Server-side encryption of blobs at rest.
+//!
+//! Every encrypted version has its own random AES-256 data key, stored in its metadata
+//! wrapped (AES-256-GCM encrypted) by a master key from the key file. The blob is encrypted
+//! in chunks of `CHUNK_SIZE` bytes, each sealed with AES-256-GCM under a nonce derived from
+//! its index and followed by its tag. The index and whether it is the last chunk are
+//! authenticated with every chunk, so chunks can be neither reordered nor dropped.
+//! Ranged reads decrypt only the chunks they overlap.
+
+use actix_web::web::Bytes;
+use futures::{Stream, StreamExt};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use crate::storage::{BlobRead, BlobWrite};
+
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum ServerSideEncryption {
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    #[serde(rename = "AES256")]
+    Aes256,
+}
+
+/// How the data key of an encrypted version is stored.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Encryption {
+    /// Master key the data key is wrapped with
+    pub key_id: String,
+    /// Hex of nonce, encrypted data key and tag, bound to the blob ID
+    pub wrapped_key: String,
+}
+
+/// Master keys from the key file, one `<id>:<hex key>` per line. The last one is used for
+/// new objects, the others are kept to unwrap the data keys of older ones. The default has
+/// no keys and no key file.
+#[derive(Default)]
+pub struct MasterKeys {
+    path: PathBuf,
+    keys: RwLock<Vec<(String, [u8; KEY_LEN])>>,
+}
+
+impl MasterKeys {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            keys: RwLock::new(read_key_file(path)?),
+        })
+    }
+
+    /// Picks up keys added to the key file since the server started.
+    pub fn reload(&self) -> io::Result<()> {
+        *self.keys.write().unwrap() = read_key_file(&self.path)?;
+        Ok(())
+    }
+
+    /// Appends a fresh master key to the key file, making it the active one.
+    pub fn generate(path: &Path) -> io::Result<String> {
+        let key_id = format!("key-{:016x}", rand::random::<u64>());
+        let key: [u8; KEY_LEN] = rand::random();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)?;
+        writeln!(file, "{key_id}:{}", hex::encode(key))?;
+        file.sync_all()?;
+        Ok(key_id)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.read().unwrap().is_empty()
+    }
+
+    pub fn active_id(&self) -> Option<String> {
+        self.keys.read().unwrap().last().map(|(id, _)| id.clone())
+    }
+
+    /// A new data key for `blob`, wrapped with the active master key.
+    pub fn new_data_key(&self, blob: &str) -> io::Result<(DataKey, Encryption)> {
+        let key: [u8; KEY_LEN] = rand::random();
+        let encryption = self.wrap(blob, &key)?;
+        Ok((DataKey::new(&key), encryption))
+    }
+
+    pub fn data_key(&self, blob: &str, encryption: &Encryption) -> io::Result<DataKey> {
+        Ok(DataKey::new(&self.unwrap(blob, encryption)?))
+    }
+
+    /// The data key of `blob` wrapped with the active master key instead.
+    pub fn rewrap(&self, blob: &str, encryption: &Encryption) -> io::Result<Encryption> {
+        self.wrap(blob, &self.unwrap(blob, encryption)?)
+    }
+
+    /// The data key of `blob` bound to `to` instead, for a copy that shares its data.
+    pub fn rebind(&self, blob: &str, to: &str, encryption: &Encryption) -> io::Result<Encryption> {
+        self.wrap(to, &self.unwrap(blob, encryption)?)
+    }
+
+    fn wrap(&self, blob: &str, key: &[u8; KEY_LEN]) -> io::Result<Encryption> {
+        let keys = self.keys.read().unwrap();
+        let Some((key_id, master)) = keys.last() else {
+            return Err(io::Error::other("No master key configured"));
+        };
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let mut wrapped = key.to_vec();
+        aead_key(master)
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(blob.as_bytes()),
+                &mut wrapped,
+            )
+            .map_err(|_| io::Error::other("Failed to wrap data key"))?;
+        Ok(Encryption {
+            key_id: key_id.clone(),
+            wrapped_key: hex::encode([&nonce[..], &wrapped].concat()),
+        })
+    }
+
+    fn unwrap(&self, blob: &str, encryption: &Encryption) -> io::Result<[u8; KEY_LEN]> {
+        let keys = self.keys.read().unwrap();
+        let Some((_, master)) = keys.iter().find(|(id, _)| *id == encryption.key_id) else {
+            return Err(io::Error::other(format!(
+                "Master key {} is not in the key file",
+                encryption.key_id
+            )));
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Malformed wrapped data key");
+        let wrapped = hex::decode(&encryption.wrapped_key).map_err(|_| invalid())?;
+        if wrapped.len() != NONCE_LEN + KEY_LEN + TAG_LEN {
+            return Err(invalid());
+        }
+        let (nonce, wrapped) = wrapped.split_at(NONCE_LEN);
+        let mut key = wrapped.to_vec();
+        let key = aead_key(master)
+            .open_in_place(
+                Nonce::try_assume_unique_for_key(nonce).map_err(|_| invalid())?,
+                Aad::from(blob.as_bytes()),
+                &mut key,
+            )
+            .map_err(|_| invalid())?;
+        key.try_into().map_err(|_| invalid())
+    }
+}
+
+fn read_key_file(path: &Path) -> io::Result<Vec<(String, [u8; KEY_LEN])>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+
+    let mut keys = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let key = line
+            .split_once(':')
+            .and_then(|(id, key)| Some((id, hex::decode(key).ok()?.try_into().ok()?)));
+        let Some((id, key)) = key else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Malformed master key line in {:?}", path),
+            ));
+        };
+        keys.push((id.to_string(), key));
+    }
+    Ok(keys)
+}
+
+fn aead_key(key: &[u8; KEY_LEN]) -> LessSafeKey {
+    LessSafeKey::new(UnboundKey::new(&AES_256_GCM, key).expect("key has the AES-256 length"))
+}
+
+/// The unwrapped key of one version.
+pub struct DataKey(LessSafeKey);
+
+impl DataKey {
+    fn new(key: &[u8; KEY_LEN]) -> Self {
+        Self(aead_key(key))
+    }
+
+    fn nonce(chunk: u64) -> Nonce {
+        // Data keys encrypt a single blob, so the chunk index is a unique nonce
+        let mut nonce = [0; NONCE_LEN];
+        nonce[NONCE_LEN - 8..].copy_from_slice(&chunk.to_be_bytes());
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    fn aad(chunk: u64, last: bool) -> [u8; 9] {
+        let mut aad = [0; 9];
+        aad[..8].copy_from_slice(&chunk.to_be_bytes());
+        aad[8] = last as u8;
+        aad
+    }
+
+    fn seal(&self, chunk: u64, last: bool, data: &mut Vec<u8>) -> io::Result<()> {
+        self.0
+            .seal_in_place_append_tag(Self::nonce(chunk), Aad::from(Self::aad(chunk, last)), data)
+            .map_err(|_| io::Error::other("Failed to encrypt chunk"))
+    }
+
+    fn open(&self, chunk: u64, last: bool, mut data: Vec<u8>) -> io::Result<Vec<u8>> {
+        let len = self
+            .0
+            .open_in_place(
+                Self::nonce(chunk),
+                Aad::from(Self::aad(chunk, last)),
+                &mut data,
+            )
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Chunk {chunk} failed authentication"),
+                )
+            })?
+            .len();
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+/// Bytes on disk of `len` bytes encrypted in chunks.
+pub fn encrypted_len(len: u64) -> u64 {
+    let chunks = len.div_ceil(CHUNK_SIZE as u64).max(1);
+    len + chunks * TAG_LEN as u64
+}
+
+/// The blob file a `BlobWriter` writes to, encrypting in chunks if it has a data key.
+pub struct BlobSink {
+    file: Box<dyn BlobWrite>,
+    key: Option<DataKey>,
+    buffer: Vec<u8>,
+    chunk: u64,
+}
+
+impl BlobSink {
+    pub fn new(file: Box<dyn BlobWrite>, key: Option<DataKey>) -> Self {
+        Self {
+            file,
+            key,
+            buffer: Vec::new(),
+            chunk: 0,
+        }
+    }
+
+    pub async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.key.is_none() {
+            return self.file.write_all(data).await;
+        }
+        self.buffer.extend_from_slice(data);
+        // A full chunk is only sealed once more data follows, the last one is marked as such
+        while self.buffer.len() > CHUNK_SIZE {
+            let chunk = self.buffer.drain(..CHUNK_SIZE).collect();
+            self.write_chunk(chunk, false).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_chunk(&mut self, mut chunk: Vec<u8>, last: bool) -> io::Result<()> {
+        if let Some(key) = &self.key {
+            key.seal(self.chunk, last, &mut chunk)?;
+        }
+        self.chunk += 1;
+        self.file.write_all(&chunk).await
+    }
+
+    pub async fn finish(mut self) -> io::Result<()> {
+        if self.key.is_some() {
+            let chunk = std::mem::take(&mut self.buffer);
+            self.write_chunk(chunk, true).await?;
+        }
+        self.file.finish().await
+    }
+}
+
+/// The blob file of a version, decrypting what is read from it if it has a data key.
+pub struct BlobSource {
+    file: Box<dyn BlobRead>,
+    key: Option<DataKey>,
+    /// Length of the plaintext
+    len: u64,
+}
+
+impl BlobSource {
+    pub fn new(file: Box<dyn BlobRead>, key: Option<DataKey>, len: u64) -> Self {
+        Self { file, key, len }
+    }
+
+    /// Reads `len` bytes starting at `offset` of the plaintext.
+    pub async fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let Some(key) = &self.key else {
+            return self.file.read_at(offset, len).await;
+        };
+
+        let end = offset + len as u64;
+        if end > self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of the blob",
+            ));
+        }
+        let chunk_size = CHUNK_SIZE as u64;
+        let chunks = self.len.div_ceil(chunk_size).max(1);
+        let mut data = Vec::with_capacity(len);
+        let mut chunk = offset / chunk_size;
+        while (data.len() as u64) < len as u64 {
+            let chunk_start = chunk * chunk_size;
+            let plain_len = (self.len - chunk_start).min(chunk_size);
+            let sealed = self
+                .file
+                .read_at(
+                    chunk * (chunk_size + TAG_LEN as u64),
+                    plain_len as usize + TAG_LEN,
+                )
+                .await?;
+            let plain = key.open(chunk, chunk + 1 == chunks, sealed)?;
+
+            let from = offset.saturating_sub(chunk_start) as usize;
+            let to = plain.len().min((end - chunk_start) as usize);
+            data.extend_from_slice(&plain[from..to]);
+            chunk += 1;
+        }
+        Ok(data)
+    }
+
+    /// Streams `length` bytes of the plaintext starting at `start`.
+    pub async fn stream(
+        self,
+        start: u64,
+        length: u64,
+    ) -> io::Result<impl Stream<Item = io::Result<Bytes>>> {
+        if self.key.is_none() {
+            return Ok(self.file.stream(start, length).await?.boxed());
+        }
+        let end = start + length;
+        Ok(
+            futures::stream::try_unfold((self, start), move |(mut source, position)| async move {
+                if position >= end {
+                    return Ok(None);
+                }
+                // Up to the end of the chunk, so every chunk is only decrypted once
+                let chunk_end = (position / CHUNK_SIZE as u64 + 1) * CHUNK_SIZE as u64;
+                let len = (chunk_end.min(end) - position) as usize;
+                let data = source.read_at(position, len).await?;
+                Ok(Some((Bytes::from(data), (source, position + len as u64))))
+            })
+            .boxed(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    use crate::memory::MemoryStore;
+    use crate::storage::Store;
+
+    const KEY: [u8; KEY_LEN] = [7; KEY_LEN];
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    async fn write(store: &dyn Store, data: &[u8]) {
+        let file = store.put("blob").await.expect("new blob");
+        let mut sink = BlobSink::new(file, Some(DataKey::new(&KEY)));
+        // In pieces that do not line up with chunks
+        for piece in data.chunks(10_000) {
+            sink.write_all(piece).await.expect("written piece");
+        }
+        sink.finish().await.expect("finished blob");
+    }
+
+    async fn source(store: &dyn Store, key: &[u8; KEY_LEN], len: u64) -> BlobSource {
+        let file = store.get("blob").await.expect("blob");
+        BlobSource::new(file, Some(DataKey::new(key)), len)
+    }
+
+    async fn stored(store: &dyn Store) -> Vec<u8> {
+        let len = store.stat("blob").await.expect("stat").expect("blob").size;
+        let mut file = store.get("blob").await.expect("blob");
+        file.read_at(0, len as usize).await.expect("stored blob")
+    }
+
+    async fn replace(store: &dyn Store, data: &[u8]) {
+        let mut file = store.put("blob").await.expect("new blob");
+        file.write_all(data).await.expect("written blob");
+        file.finish().await.expect("finished blob");
+    }
+
+    #[actix_web::test]
+    async fn ranges_round_trip_across_chunks() {
+        let chunk = CHUNK_SIZE as u64;
+        // Empty, within one chunk, exactly full chunks and a short last chunk
+        for len in [0, 1, 1000, chunk, 2 * chunk, 2 * chunk + 5] {
+            let store = MemoryStore::default();
+            let data = data(len as usize);
+            write(&store, &data).await;
+            assert_eq!(stored(&store).await.len() as u64, encrypted_len(len));
+
+            let ranges = [
+                (0, len),
+                (len.saturating_sub(1), len.min(1)),
+                (len, 0),
+                (chunk - 10, 20),
+                (chunk, 1),
+                (chunk - 1, chunk + 2),
+                (10, 2 * chunk),
+            ];
+            for (start, length) in ranges {
+                let end = (start + length).min(len);
+                let start = start.min(end);
+                let expected = &data[start as usize..end as usize];
+                let mut source = source(&store, &KEY, len).await;
+                let read = source
+                    .read_at(start, (end - start) as usize)
+                    .await
+                    .expect("decrypted range");
+                assert!(read == expected, "read of {len} bytes, {start}..{end}");
+
+                let source = self::source(&store, &KEY, len).await;
+                let chunks: Vec<Bytes> = source
+                    .stream(start, end - start)
+                    .await
+                    .expect("stream")
+                    .try_collect()
+                    .await
+                    .expect("decrypted stream");
+                assert!(
+                    chunks.concat() == expected,
+                    "stream of {len} bytes, {start}..{end}"
+                );
+            }
+
+            let mut source = source(&store, &KEY, len).await;
+            let past_end = source.read_at(len, 1).await.expect_err("past the end");
+            assert_eq!(past_end.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    #[actix_web::test]
+    async fn tampered_chunks_fail_authentication() {
+        let store = MemoryStore::default();
+        let len = 2 * CHUNK_SIZE as u64 + 5;
+        let data = data(len as usize);
+        write(&store, &data).await;
+        let mut sealed = stored(&store).await;
+        sealed[CHUNK_SIZE + TAG_LEN + 100] ^= 1;
+        replace(&store, &sealed).await;
+
+        let mut source = source(&store, &KEY, len).await;
+        let first = source.read_at(0, 10).await.expect("untouched chunk");
+        assert_eq!(first, data[..10]);
+        let error = source
+            .read_at(CHUNK_SIZE as u64 - 1, 2)
+            .await
+            .expect_err("tampered chunk");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let mut source = self::source(&store, &[8; KEY_LEN], len).await;
+        let error = source.read_at(0, 10).await.expect_err("another key");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[actix_web::test]
+    async fn dropped_chunks_fail_authentication() {
+        let store = MemoryStore::default();
+        let len = 2 * CHUNK_SIZE as u64 + 5;
+        write(&store, &data(len as usize)).await;
+        // The blob cut after its second chunk, which was not sealed as the last one
+        let sealed = stored(&store).await;
+        replace(&store, &sealed[..2 * (CHUNK_SIZE + TAG_LEN)]).await;
+
+        let mut source = source(&store, &KEY, 2 * CHUNK_SIZE as u64).await;
+        assert!(source.read_at(0, 10).await.is_ok());
+        let error = source
+            .read_at(CHUNK_SIZE as u64, 10)
+            .await
+            .expect_err("dropped chunk");
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
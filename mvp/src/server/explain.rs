@@ -0,0 +1,232 @@
+//! EXPLAIN for near-storage queries.
+//!
+//! `POST /{bucket}?query&explain` plans a query like running it would, pruning files and
+//! row groups by partition, statistics and bloom filters, and reports the plan instead of
+//! scanning: for every file and row group which step pruned it, the column chunks a scan
+//! reads, and estimates of the bytes read from storage and returned. Only footers, their
+//! index entries and bloom filters are read. Bytes read are counted before the read cache,
+//! and bytes returned are an upper bound that assumes every row scanned matches.
+
+use actix_web::web;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+use crate::cache::PARQUET_TAIL;
+use crate::query::{self, Considered, PrunedBy, Pruning, Query};
+use crate::AppState;
+
+/// Assumed length of a group or aggregate value in JSON
+const AGGREGATE_VALUE_BYTES: u64 = 16;
+/// Quotes, colon and comma around a name and value in JSON
+const FIELD_OVERHEAD: u64 = 4;
+
+#[derive(Serialize)]
+pub struct Explain {
+    pub pruning: Pruning,
+    pub estimated_bytes_read: u64,
+    pub estimated_rows_scanned: u64,
+    pub estimated_rows_returned: u64,
+    pub estimated_bytes_returned: u64,
+    pub files: Vec<FileExplain>,
+}
+
+#[derive(Serialize)]
+pub struct FileExplain {
+    pub key: String,
+    pub size: u64,
+    pub pruned_by: Option<PrunedBy>,
+    pub row_groups: Vec<RowGroupExplain>,
+    pub footer_bytes: u64,
+    pub bloom_filter_bytes: u64,
+    pub bytes_read: u64,
+    pub rows_scanned: u64,
+    /// Of rows, left out for aggregate queries which return groups instead
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes_returned: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct RowGroupExplain {
+    pub index: usize,
+    pub rows: u64,
+    pub pruned_by: Option<PrunedBy>,
+    /// Chunks a scan reads, of the columns the query needs
+    pub column_chunks: Vec<ColumnChunk>,
+}
+
+#[derive(Serialize)]
+pub struct ColumnChunk {
+    pub column: String,
+    pub offset: u64,
+    pub bytes: u64,
+}
+
+/// The plan of `query` over the files below its prefix in `bucket`.
+pub async fn explain(
+    state: &web::Data<AppState>,
+    bucket: &str,
+    query: &Query,
+) -> Result<Explain, actix_web::Error> {
+    let mut considered = Vec::new();
+    let (_, pruning) = query::plan_considering(state, bucket, query, Some(&mut considered)).await?;
+    let files: Vec<FileExplain> = considered
+        .iter()
+        .map(|file| explain_file(file, query))
+        .collect();
+
+    let rows_scanned: u64 = files.iter().map(|file| file.rows_scanned).sum();
+    let (rows_returned, bytes_returned) = match query.aggregated() {
+        false => {
+            let bytes: u64 = files.iter().filter_map(|file| file.bytes_returned).sum();
+            match query.limit {
+                Some(limit) if (limit as u64) < rows_scanned => {
+                    (limit as u64, bytes * limit as u64 / rows_scanned)
+                }
+                _ => (rows_scanned, bytes),
+            }
+        }
+        true => {
+            let groups = estimated_groups(&considered, query, rows_scanned);
+            let groups = query.limit.map_or(groups, |limit| groups.min(limit as u64));
+            let names = query.group_by.len() + query.aggregates.len();
+            let width = names as u64 * (FIELD_OVERHEAD + AGGREGATE_VALUE_BYTES) + 2;
+            (groups, groups * width)
+        }
+    };
+    Ok(Explain {
+        pruning,
+        estimated_bytes_read: files.iter().map(|file| file.bytes_read).sum(),
+        estimated_rows_scanned: rows_scanned,
+        estimated_rows_returned: rows_returned,
+        estimated_bytes_returned: bytes_returned,
+        files,
+    })
+}
+
+fn explain_file(file: &Considered, query: &Query) -> FileExplain {
+    let description = file.description.as_ref().unwrap_or(&Value::Null);
+    let groups = description["row_groups"]
+        .as_array()
+        .map_or(&[][..], Vec::as_slice);
+    // Top-level columns in file order, nested ones are returned as a whole
+    let mut fields: Vec<&str> = Vec::new();
+    for leaf in description["columns"].as_array().into_iter().flatten() {
+        let field = top_level(leaf["path"].as_str().unwrap_or_default());
+        if !fields.contains(&field) {
+            fields.push(field);
+        }
+    }
+    let output: Vec<&str> = match &query.columns {
+        Some(columns) => columns.iter().map(String::as_str).collect(),
+        None => fields
+            .into_iter()
+            .chain(file.partitions.iter().map(|(name, _)| name.as_str()))
+            .collect(),
+    };
+    // Everything of a row in JSON but the values of its columns
+    let row_overhead: u64 = 2 + output
+        .iter()
+        .map(|name| {
+            let partition = file.partitions.iter().find(|(column, _)| column == name);
+            name.len() as u64
+                + FIELD_OVERHEAD
+                + partition.map_or(0, |(_, value)| value.len() as u64 + 2)
+        })
+        .sum::<u64>();
+
+    let mut explained = FileExplain {
+        key: file.key.clone(),
+        size: file.size,
+        pruned_by: file.pruned_by,
+        row_groups: Vec::new(),
+        footer_bytes: 0,
+        bloom_filter_bytes: file.bloom_filter_bytes,
+        bytes_read: file.bloom_filter_bytes,
+        rows_scanned: 0,
+        bytes_returned: None,
+    };
+    let mut bytes_returned = 0;
+    for (index, group) in groups.iter().enumerate() {
+        let rows = group["num_rows"].as_u64().unwrap_or_default();
+        let pruned_by = if !file.after_statistics.contains(&index) {
+            Some(PrunedBy::Statistics)
+        } else if !file.row_groups.contains(&index) {
+            Some(PrunedBy::BloomFilter)
+        } else {
+            None
+        };
+        let mut column_chunks = Vec::new();
+        if pruned_by.is_none() {
+            explained.rows_scanned += rows;
+            bytes_returned += rows * row_overhead;
+            for chunk in group["columns"].as_array().into_iter().flatten() {
+                let path = chunk["path"].as_str().unwrap_or_default();
+                if !query.needs(top_level(path)) {
+                    continue;
+                }
+                let bytes = chunk["compressed_size"].as_u64().unwrap_or_default();
+                explained.bytes_read += bytes;
+                if output.contains(&top_level(path)) {
+                    bytes_returned += chunk["uncompressed_size"].as_u64().unwrap_or_default();
+                }
+                let offset = match &chunk["dictionary_page_offset"] {
+                    Value::Null => &chunk["data_page_offset"],
+                    offset => offset,
+                };
+                column_chunks.push(ColumnChunk {
+                    column: path.to_string(),
+                    offset: offset.as_u64().unwrap_or_default(),
+                    bytes,
+                });
+            }
+        }
+        explained.row_groups.push(RowGroupExplain {
+            index,
+            rows,
+            pruned_by,
+            column_chunks,
+        });
+    }
+    if explained.rows_scanned > 0 || !file.row_groups.is_empty() {
+        explained.footer_bytes =
+            description["footer_length"].as_u64().unwrap_or_default() + PARQUET_TAIL;
+        explained.bytes_read += explained.footer_bytes;
+    }
+    if !query.aggregated() {
+        explained.bytes_returned = Some(bytes_returned);
+    }
+    explained
+}
+
+/// Groups an aggregate query returns at most: one without `group_by`, the combinations of
+/// partition values of the files scanned if it only names partition columns, else a group
+/// per row scanned.
+fn estimated_groups(considered: &[Considered], query: &Query, rows_scanned: u64) -> u64 {
+    if query.group_by.is_empty() {
+        return 1;
+    }
+    let mut combinations = BTreeSet::new();
+    for file in considered.iter().filter(|file| file.pruned_by.is_none()) {
+        let values: Option<Vec<&str>> = query
+            .group_by
+            .iter()
+            .map(|column| {
+                file.partitions
+                    .iter()
+                    .find(|(name, _)| name == column)
+                    .map(|(_, value)| value.as_str())
+            })
+            .collect();
+        let Some(values) = values else {
+            return rows_scanned;
+        };
+        combinations.insert(values);
+    }
+    combinations.len() as u64
+}
+
+/// The top-level column of the leaf column at `path`.
+fn top_level(path: &str) -> &str {
+    path.split('.').next().unwrap_or(path)
+}
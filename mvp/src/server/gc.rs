@@ -0,0 +1,158 @@
+use actix_web::web;
+use serde::Serialize;
+use std::io;
+use std::time::{Duration, SystemTime};
+
+use crate::storage::BlobStat;
+use crate::{AppState, BLOB_PREFIX, INDEX_PREFIX, TMP_PREFIX};
+
+pub struct GcConfig {
+    pub grace_period: Duration,
+    pub interval: Duration,
+    pub dry_run: bool,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct GcReport {
+    pub dry_run: bool,
+    pub orphaned_blobs: usize,
+    pub stale_uploads: usize,
+    pub dangling_entries: Vec<String>,
+    pub files_reclaimed: usize,
+    pub bytes_reclaimed: u64,
+}
+
+/// Reconciles the metadata index with the blobs on disk.
+///
+/// Blobs nobody references and upload temp files are only removed once they have not
+/// been modified for the grace period, so uploads that are still in flight or have not
+/// been committed to the index yet are left alone. Index entries whose blob is missing
+/// are dropped. With `dry_run` nothing is touched and the report says what would be.
+pub async fn run(state: &AppState, dry_run: bool) -> io::Result<GcReport> {
+    let mut report = GcReport {
+        dry_run,
+        ..Default::default()
+    };
+    let cutoff = SystemTime::now()
+        .checked_sub(state.gc.grace_period)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let referenced = state.metadata.referenced_blobs();
+    for blob in expired_blobs(state, BLOB_PREFIX, cutoff).await? {
+        if referenced.contains(blob.name()) {
+            continue;
+        }
+        report.orphaned_blobs += 1;
+        reclaim(state, &blob, dry_run, &mut report).await?;
+    }
+
+    for index in expired_blobs(state, INDEX_PREFIX, cutoff).await? {
+        let blob = index.name().trim_end_matches(".json");
+        if referenced.contains(blob) {
+            continue;
+        }
+        report.orphaned_blobs += 1;
+        reclaim(state, &index, dry_run, &mut report).await?;
+    }
+
+    for blob in expired_blobs(state, TMP_PREFIX, cutoff).await? {
+        report.stale_uploads += 1;
+        reclaim(state, &blob, dry_run, &mut report).await?;
+    }
+
+    let cutoff_secs = cutoff
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    for (blob, size) in state.segments.created_before(cutoff_secs) {
+        if referenced.contains(&blob) {
+            continue;
+        }
+        report.orphaned_blobs += 1;
+        if !dry_run {
+            state.cache.invalidate(&blob);
+            match state.segments.delete(&*state.storage, &blob).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        report.files_reclaimed += 1;
+        report.bytes_reclaimed += size;
+    }
+
+    for (bucket, key, meta) in state.metadata.entries() {
+        let exists = if meta.packed {
+            state.segments.contains(&meta.blob)
+        } else {
+            state
+                .storage
+                .stat(&state.blob_key(&meta.blob))
+                .await?
+                .is_some()
+        };
+        if exists {
+            continue;
+        }
+        if dry_run
+            || state
+                .metadata
+                .remove_if_blob(&bucket, &key, &meta.version, &meta.blob)
+                .await?
+        {
+            report
+                .dangling_entries
+                .push(format!("{bucket}/{key}?versionId={}", meta.version));
+        }
+    }
+
+    Ok(report)
+}
+
+/// Runs GC every `interval` until the process exits.
+pub fn spawn(state: web::Data<AppState>) {
+    if state.gc.interval.is_zero() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(state.gc.interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match run(&state, state.gc.dry_run).await {
+                Ok(report) => println!("GC: {:?}", report),
+                Err(e) => eprintln!("GC failed: {e}"),
+            }
+        }
+    });
+}
+
+async fn reclaim(
+    state: &AppState,
+    blob: &BlobStat,
+    dry_run: bool,
+    report: &mut GcReport,
+) -> io::Result<()> {
+    if !dry_run {
+        state.cache.invalidate(blob.name());
+        match state.storage.delete(&blob.key).await {
+            Ok(()) => {}
+            // Raced with a request that already cleaned it up
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+    report.files_reclaimed += 1;
+    report.bytes_reclaimed += blob.size;
+    Ok(())
+}
+
+/// All blobs below `prefix` last modified before `cutoff`.
+async fn expired_blobs(
+    state: &AppState,
+    prefix: &str,
+    cutoff: SystemTime,
+) -> io::Result<Vec<BlobStat>> {
+    let mut blobs = state.storage.list(prefix).await?;
+    blobs.retain(|blob| blob.modified < cutoff);
+    Ok(blobs)
+}
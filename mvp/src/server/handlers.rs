@@ -1,11 +1,107 @@
-use actix_web::{error::ErrorInternalServerError, web, Error, HttpResponse, Responder};
+use actix_web::{
+    error::{ErrorInternalServerError, PayloadError},
+    http::header::{
+        ContentRange, ContentRangeSpec, ETag, Header, LastModified, Range, ACCEPT_RANGES,
+    },
+    http::StatusCode,
+    web, Error, HttpRequest, HttpResponse, HttpResponseBuilder, Responder,
+};
+use futures::stream::{self, BoxStream, LocalBoxStream};
 use futures::StreamExt;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::path::PathBuf;
-use tokio::fs::{create_dir_all, File};
-use tokio::io::AsyncWriteExt;
+use std::collections::{BTreeMap, HashSet};
+use std::io;
+use std::sync::Arc;
 
-use crate::{MAX_CHUNK_SIZE, PARQUET_FOLDER};
+use crate::admission::Permit;
+use crate::auth::{self, BucketPolicy, Caller, Permission};
+use crate::bloom;
+use crate::cache::{self, EntryKind, PARQUET_TAIL};
+use crate::compression::{self, BlobWriter, Compressed, Compression};
+use crate::conditional::{self, Outcome, Preconditions};
+use crate::downloads::ReadPath;
+use crate::encryption::{BlobSink, BlobSource, ServerSideEncryption};
+use crate::error::ApiError;
+use crate::explain;
+use crate::jobs;
+use crate::lifecycle::{self, LifecycleConfig};
+use crate::metadata::{
+    new_id, unix_now, BucketConfig, ObjectMeta, PutOutcome, RenameOutcome, Versioning,
+    DEFAULT_BUCKET, NULL_VERSION,
+};
+use crate::parquet_index::{self, Tail};
+use crate::query;
+use crate::segments::PackBuffer;
+use crate::sendfile;
+use crate::storage::BlobWrite;
+use crate::{gc, signing, AppState};
+
+const RESERVED_BUCKETS: [&str; 2] = ["admin", "api"];
+const VERSION_ID_HEADER: &str = "x-mvp-version-id";
+const DELETE_MARKER_HEADER: &str = "x-mvp-delete-marker";
+const TAGGING_HEADER: &str = "x-mvp-tagging";
+const ENCRYPTION_HEADER: &str = "x-mvp-server-side-encryption";
+const COPY_SOURCE_HEADER: &str = "x-mvp-copy-source";
+const COPY_SOURCE_VERSION_HEADER: &str = "x-mvp-copy-source-version-id";
+const METADATA_DIRECTIVE_HEADER: &str = "x-mvp-metadata-directive";
+const RENAME_SOURCE_HEADER: &str = "x-mvp-rename-source";
+const BLOOM_FILTER_COLUMNS_HEADER: &str = "x-mvp-bloom-filter-columns";
+
+#[derive(Deserialize)]
+pub struct DryRunQuery {
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Deserialize)]
+pub struct BucketQuery {
+    policy: Option<String>,
+    versioning: Option<String>,
+    versions: Option<String>,
+    usage: Option<String>,
+    lifecycle: Option<String>,
+    compression: Option<String>,
+    encryption: Option<String>,
+    query: Option<String>,
+    partial: Option<String>,
+    explain: Option<String>,
+    job: Option<String>,
+    #[serde(default)]
+    prefix: String,
+}
+
+#[derive(Deserialize)]
+pub struct ObjectQuery {
+    #[serde(rename = "versionId")]
+    version_id: Option<String>,
+    metadata: Option<String>,
+    rename: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct VersioningRequest {
+    status: Versioning,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CompressionRequest {
+    algorithm: Compression,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EncryptionRequest {
+    algorithm: ServerSideEncryption,
+}
+
+#[derive(Deserialize)]
+pub struct PresignRequest {
+    method: String,
+    bucket: String,
+    key: String,
+    expires_in: u64,
+    max_length: Option<u64>,
+}
 
 pub async fn health_checker_handler() -> impl Responder {
     let response = json!({
@@ -15,68 +111,2002 @@ pub async fn health_checker_handler() -> impl Responder {
     HttpResponse::Ok().json(response)
 }
 
-pub async fn get_parquet_file(path: web::Path<String>) -> Result<HttpResponse, Error> {
-    let file_name = path.into_inner();
-    let file_path = PathBuf::from(PARQUET_FOLDER).join(&file_name);
+pub async fn create_bucket(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<BucketQuery>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+
+    if query.policy.is_some() {
+        let caller = auth::authorize(&req, &state, &bucket, Permission::Admin)?;
+        let policy: BucketPolicy = serde_json::from_slice(&body).map_err(|e| {
+            ApiError::new(StatusCode::BAD_REQUEST, "MalformedPolicy", e.to_string())
+        })?;
+        if !policy.keeps_admin(&caller) {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "MalformedPolicy",
+                "The policy would revoke your own admin permission",
+            )
+            .into());
+        }
+        return update_bucket(&state, &bucket, |config| config.policy = policy).await;
+    }
+
+    if query.lifecycle.is_some() {
+        auth::authorize(&req, &state, &bucket, Permission::Admin)?;
+        let lifecycle: LifecycleConfig = serde_json::from_slice(&body).map_err(|e| {
+            ApiError::new(StatusCode::BAD_REQUEST, "MalformedLifecycle", e.to_string())
+        })?;
+        return update_bucket(&state, &bucket, |config| config.lifecycle = lifecycle).await;
+    }
+
+    if query.compression.is_some() {
+        auth::authorize(&req, &state, &bucket, Permission::Admin)?;
+        let request: CompressionRequest = serde_json::from_slice(&body).map_err(|e| {
+            ApiError::new(StatusCode::BAD_REQUEST, "MalformedRequest", e.to_string())
+        })?;
+        return update_bucket(&state, &bucket, |config| {
+            config.compression = request.algorithm
+        })
+        .await;
+    }
 
-    if !file_path.exists() {
-        return Ok(HttpResponse::NotFound().finish());
+    if query.encryption.is_some() {
+        auth::authorize(&req, &state, &bucket, Permission::Admin)?;
+        let request: EncryptionRequest = serde_json::from_slice(&body).map_err(|e| {
+            ApiError::new(StatusCode::BAD_REQUEST, "MalformedRequest", e.to_string())
+        })?;
+        if request.algorithm != ServerSideEncryption::None && state.master_keys.is_empty() {
+            return Err(encryption_unavailable().into());
+        }
+        return update_bucket(&state, &bucket, |config| {
+            config.encryption = request.algorithm
+        })
+        .await;
     }
 
-    let file = File::open(&file_path)
+    if query.versioning.is_some() {
+        auth::authorize(&req, &state, &bucket, Permission::Admin)?;
+        let request: VersioningRequest = serde_json::from_slice(&body).map_err(|e| {
+            ApiError::new(StatusCode::BAD_REQUEST, "MalformedRequest", e.to_string())
+        })?;
+        if request.status == Versioning::Disabled {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "InvalidVersioningStatus",
+                "Versioning can only be enabled or suspended",
+            )
+            .into());
+        }
+        return update_bucket(&state, &bucket, |config| {
+            // Buckets that never had versioning enabled have nothing to suspend
+            if request.status == Versioning::Enabled || config.versioning != Versioning::Disabled {
+                config.versioning = request.status;
+            }
+        })
+        .await;
+    }
+
+    let caller = auth::authenticate(&req, &state.credentials)?;
+    let Caller::Key {
+        access_key: owner, ..
+    } = &caller
+    else {
+        return Err(ApiError::access_denied("Only signed requests can create buckets").into());
+    };
+    if !is_valid_bucket_name(&bucket) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidBucketName",
+            format!("{bucket} is not a valid bucket name"),
+        )
+        .into());
+    }
+
+    let config = BucketConfig {
+        created: unix_now(),
+        policy: BucketPolicy::owned_by(owner),
+        ..Default::default()
+    };
+    let created = state
+        .metadata
+        .create_bucket(&bucket, config)
         .await
-        .map_err(ErrorInternalServerError)?;
-    let file_stream = tokio_util::io::ReaderStream::new(file);
+        .map_err(|e| ErrorInternalServerError(format!("Failed to update metadata: {e}")))?;
+    if !created {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "BucketAlreadyExists",
+            format!("Bucket {bucket} already exists"),
+        )
+        .into());
+    }
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn get_bucket(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<BucketQuery>,
+) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+
+    if query.policy.is_some() || query.lifecycle.is_some() {
+        auth::authorize(&req, &state, &bucket, Permission::Admin)?;
+        let config = state
+            .metadata
+            .bucket(&bucket)
+            .ok_or_else(|| ApiError::no_such_bucket(&bucket))?;
+        if query.lifecycle.is_some() {
+            return Ok(HttpResponse::Ok().json(config.lifecycle));
+        }
+        return Ok(HttpResponse::Ok().json(config.policy));
+    }
+
+    auth::authorize(&req, &state, &bucket, Permission::List)?;
+
+    if query.versioning.is_some() || query.compression.is_some() || query.encryption.is_some() {
+        let config = state
+            .metadata
+            .bucket(&bucket)
+            .ok_or_else(|| ApiError::no_such_bucket(&bucket))?;
+        if query.encryption.is_some() {
+            return Ok(HttpResponse::Ok().json(EncryptionRequest {
+                algorithm: config.encryption,
+            }));
+        }
+        if query.compression.is_some() {
+            return Ok(HttpResponse::Ok().json(CompressionRequest {
+                algorithm: config.compression,
+            }));
+        }
+        return Ok(HttpResponse::Ok().json(json!({ "status": config.versioning })));
+    }
+
+    if query.versions.is_some() {
+        let versions: Vec<_> = state
+            .metadata
+            .list_versions(&bucket, &query.prefix)
+            .into_iter()
+            .flat_map(|(key, versions)| {
+                let latest = versions.len() - 1;
+                versions
+                    .into_iter()
+                    .enumerate()
+                    .rev()
+                    .map(move |(i, meta)| {
+                        json!({
+                            "key": key,
+                            "version_id": meta.version,
+                            "is_latest": i == latest,
+                            "delete_marker": meta.delete_marker,
+                            "size": meta.size,
+                            "created": meta.created,
+                        })
+                    })
+            })
+            .collect();
+        return Ok(HttpResponse::Ok().json(json!({
+            "bucket": bucket,
+            "prefix": query.prefix,
+            "versions": versions,
+        })));
+    }
+
+    if query.usage.is_some() {
+        let mut objects = 0;
+        let mut versions = 0;
+        let mut delete_markers = 0;
+        let mut current_bytes = 0;
+        let mut noncurrent_bytes = 0;
+        let mut stored_bytes = 0;
+        let mut compressed_versions = 0;
+        let mut encrypted_versions = 0;
+        let mut packed_versions = 0;
+        for (_, history) in state.metadata.list_versions(&bucket, &query.prefix) {
+            let latest = history.len() - 1;
+            for (i, meta) in history.into_iter().enumerate() {
+                stored_bytes += meta.stored_size();
+                compressed_versions += meta.compressed.is_some() as u64;
+                encrypted_versions += meta.encryption.is_some() as u64;
+                packed_versions += meta.packed as u64;
+                if meta.delete_marker {
+                    delete_markers += 1;
+                } else if i == latest {
+                    objects += 1;
+                    versions += 1;
+                    current_bytes += meta.size;
+                } else {
+                    versions += 1;
+                    noncurrent_bytes += meta.size;
+                }
+            }
+        }
+        return Ok(HttpResponse::Ok().json(json!({
+            "bucket": bucket,
+            "prefix": query.prefix,
+            "objects": objects,
+            "versions": versions,
+            "delete_markers": delete_markers,
+            "current_bytes": current_bytes,
+            "noncurrent_bytes": noncurrent_bytes,
+            "bytes": current_bytes + noncurrent_bytes,
+            "stored_bytes": stored_bytes,
+            "compressed_versions": compressed_versions,
+            "encrypted_versions": encrypted_versions,
+            "packed_versions": packed_versions,
+            // Logical over stored bytes, 1 without compression
+            "compression_ratio": if stored_bytes == 0 {
+                1.0
+            } else {
+                (current_bytes + noncurrent_bytes) as f64 / stored_bytes as f64
+            },
+        })));
+    }
+    let objects: Vec<_> = state
+        .metadata
+        .list(&bucket, &query.prefix)
+        .into_iter()
+        .map(|(key, meta)| {
+            json!({
+                "key": key,
+                "size": meta.size,
+                "created": meta.created,
+                "etag": conditional::etag(&meta).to_string(),
+                "tags": meta.tags,
+            })
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "bucket": bucket,
+        "prefix": query.prefix,
+        "objects": objects,
+    })))
+}
+
+/// `POST /{bucket}?query`, a query over the Parquet files below a prefix, see `query`.
+/// What pruning left is reported in headers before the rows are streamed as JSON lines.
+pub async fn query_bucket(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<BucketQuery>,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let bucket = path.into_inner();
+    if query.query.is_none() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidRequest",
+            "POST on a bucket needs ?query",
+        )
+        .into());
+    }
+    let caller = auth::authorize(&req, &state, &bucket, Permission::Read)?;
+    let request: query::Query = serde_json::from_slice(&body)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "MalformedQuery", e.to_string()))?;
+
+    if query.explain.is_some() {
+        return Ok(HttpResponse::Ok().json(explain::explain(&state, &bucket, &request).await?));
+    }
+    // Rows are streamed, also into the result file of a job, so they can only be sorted
+    // when a limit bounds them
+    if !request.aggregated() && !request.order_by.is_empty() && request.limit.is_none() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidRequest",
+            "order_by needs a limit",
+        )
+        .into());
+    }
+    if query.job.is_some() {
+        if query.partial.is_some() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "InvalidRequest",
+                "?partial cannot run as a job",
+            )
+            .into());
+        }
+        return submit_job(&req, &state, &caller, bucket, request, body);
+    }
+    if request.aggregated() {
+        return aggregate_bucket(&state, &bucket, &request, query.partial.is_some(), &body).await;
+    }
+    if query.partial.is_some() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidRequest",
+            "?partial needs a query with group_by or aggregates",
+        )
+        .into());
+    }
+
+    let (plans, pruning) = query::plan(&state, &bucket, &request).await?;
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(("x-mvp-query-files", pruning.files))
+        .insert_header(("x-mvp-query-not-parquet", pruning.not_parquet))
+        .insert_header((
+            "x-mvp-query-pruned-by-partition",
+            pruning.pruned_by_partition,
+        ))
+        .insert_header((
+            "x-mvp-query-pruned-by-statistics",
+            pruning.pruned_by_statistics,
+        ))
+        .insert_header(("x-mvp-query-row-groups", pruning.row_groups))
+        .insert_header(("x-mvp-query-row-groups-pruned", pruning.row_groups_pruned))
+        .insert_header((
+            "x-mvp-query-pruned-by-bloom-filter",
+            pruning.pruned_by_bloom_filter,
+        ))
+        .insert_header((
+            "x-mvp-query-row-groups-pruned-by-bloom-filter",
+            pruning.row_groups_pruned_by_bloom_filter,
+        ))
+        .content_type("application/x-ndjson");
+    let Some(limit) = request.limit else {
+        return Ok(response.streaming(query::scan(
+            state.clone(),
+            bucket,
+            plans,
+            Arc::new(request),
+        )));
+    };
+
+    let (rows, skipped) = query::top_k(
+        state.clone(),
+        &bucket,
+        plans,
+        &request,
+        limit,
+        Arc::default(),
+    )
+    .await
+    .map_err(|e| ErrorInternalServerError(format!("Failed to run query: {e}")))?;
+    let mut body = Vec::new();
+    for row in rows {
+        // Serializing a map of JSON values cannot fail
+        let _ = serde_json::to_writer(&mut body, &row);
+        body.push(b'\n');
+    }
+    Ok(response
+        .insert_header(("x-mvp-query-row-groups-skipped-by-top-k", skipped))
+        .body(body))
+}
+
+/// `POST /{bucket}?query&job`, runs the query in the background, see `jobs`. Submitting
+/// needs write permission on the result bucket, which the job writes to.
+fn submit_job(
+    req: &HttpRequest,
+    state: &web::Data<AppState>,
+    caller: &Caller,
+    bucket: String,
+    request: query::Query,
+    body: web::Bytes,
+) -> Result<HttpResponse, Error> {
+    let Some(owner) = caller.access_key() else {
+        return Err(ApiError::access_denied("Only signed requests can submit jobs").into());
+    };
+    auth::authorize(
+        req,
+        state,
+        &state.jobs.config.result_bucket,
+        Permission::Write,
+    )?;
+    let status = jobs::submit(state, owner.to_string(), bucket, request, body);
+    Ok(HttpResponse::Accepted()
+        .insert_header((
+            actix_web::http::header::LOCATION,
+            format!("/api/jobs/{}", status.id),
+        ))
+        .json(status))
+}
+
+/// `GET /api/jobs`, the query jobs of the caller, or of everyone for root credentials.
+pub async fn list_jobs(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    let caller = auth::authenticate(&req, &state.credentials)?;
+    Ok(HttpResponse::Ok().json(json!({ "jobs": state.jobs.list(&caller) })))
+}
+
+/// `GET /api/jobs/{id}`, the state and progress of a query job.
+pub async fn get_job(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let caller = auth::authenticate(&req, &state.credentials)?;
+    let id = path.into_inner();
+    let status = state
+        .jobs
+        .status(&id, &caller)
+        .ok_or_else(|| no_such_job(&id))?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+/// `DELETE /api/jobs/{id}`, cancels a query job. Finished jobs are left as they are.
+pub async fn cancel_job(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let caller = auth::authenticate(&req, &state.credentials)?;
+    let id = path.into_inner();
+    let status = state
+        .jobs
+        .cancel(&id, &caller)
+        .ok_or_else(|| no_such_job(&id))?;
+    if status.state != jobs::JobState::Cancelled {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "JobFinished",
+            format!("Job {id} already {}", status.state.label()),
+        )
+        .into());
+    }
+    Ok(HttpResponse::Ok().json(status))
+}
+
+/// `GET /api/jobs/{id}/result`, the Parquet file a query job wrote. Whoever can see the
+/// job can read it, whatever the policy of the result bucket.
+pub async fn get_job_result(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let caller = auth::authenticate(&req, &state.credentials)?;
+    let id = path.into_inner();
+    let status = state
+        .jobs
+        .status(&id, &caller)
+        .ok_or_else(|| no_such_job(&id))?;
+    let Some(result) = status.result else {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "JobNotSucceeded",
+            format!("Job {id} is {}", status.state.label()),
+        )
+        .into());
+    };
+    let meta = lookup(
+        &state,
+        &result.bucket,
+        &result.key,
+        Some(&result.version_id),
+    )?;
 
-    Ok(HttpResponse::Ok()
+    let file_stream = read_range(&state, &meta, 0, meta.size).await?;
+    let file_stream = counted(&state, file_stream, storage_read_path(&meta), meta.size);
+    Ok(object_response(HttpResponse::Ok(), &meta)
         .content_type("application/octet-stream")
+        .no_chunking(meta.size)
         .streaming(file_stream))
 }
 
-pub async fn put_parquet_file(
+/// Jobs of other callers are reported as missing too.
+fn no_such_job(id: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "NoSuchJob",
+        format!("Job {id} does not exist"),
+    )
+}
+
+/// An aggregate query, computed here and on every `--query-peer` and merged, or with
+/// `partial` only here and returned unmerged for a coordinator.
+async fn aggregate_bucket(
+    state: &web::Data<AppState>,
+    bucket: &str,
+    request: &query::Query,
+    partial: bool,
+    body: &web::Bytes,
+) -> Result<HttpResponse, Error> {
+    if partial {
+        let (plans, pruning) = query::plan(state, bucket, request).await?;
+        let partial = query::aggregate(
+            state.clone(),
+            bucket,
+            plans,
+            request,
+            pruning,
+            Arc::default(),
+        )
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to run query: {e}")))?;
+        return Ok(HttpResponse::Ok().json(partial));
+    }
+    let (rows, nodes) = aggregate_everywhere(state, bucket, request, body, Arc::default()).await?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "rows": rows,
+        "nodes": nodes,
+    })))
+}
+
+/// The ordered groups of an aggregate query computed here and on every `--query-peer`,
+/// and what each node scanned. `counters` follow the scans of this node.
+///
+/// The nodes list the keys they hold below the prefix first, and every key is placed on
+/// one of its holders, so an object held by several nodes is counted once. Each node
+/// then scans only the keys placed on it.
+pub async fn aggregate_everywhere(
+    state: &web::Data<AppState>,
+    bucket: &str,
+    request: &query::Query,
+    body: &web::Bytes,
+    counters: Arc<query::ScanCounters>,
+) -> Result<
+    (
+        Vec<serde_json::Map<String, serde_json::Value>>,
+        Vec<query::NodeReport>,
+    ),
+    Error,
+> {
+    let config = &state.query;
+    let peer_failed = |peer: &str, e: String| {
+        ApiError::new(
+            StatusCode::BAD_GATEWAY,
+            "PeerFailed",
+            format!("Query on {peer} failed: {e}"),
+        )
+    };
+    let mut local_request = request.clone();
+    let mut peer_bodies = Vec::new();
+    if !config.peers.is_empty() {
+        let listings = futures::future::join_all(config.peers.iter().map(|peer| async move {
+            query::peer_keys(config, peer, bucket, &request.prefix)
+                .await
+                .map_err(|e| peer_failed(peer, e))
+        }))
+        .await;
+        let local_keys = state
+            .metadata
+            .list(bucket, &request.prefix)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let mut holdings = vec![(query::LOCAL_NODE, local_keys)];
+        for (peer, keys) in config.peers.iter().zip(listings) {
+            holdings.push((peer.as_str(), keys?));
+        }
+        if let Some(requested) = &request.keys {
+            let requested: HashSet<&String> = requested.iter().collect();
+            for (_, keys) in &mut holdings {
+                keys.retain(|key| requested.contains(key));
+            }
+        }
+        let mut placed = query::place(&holdings).into_iter();
+        local_request.keys = placed.next();
+        for keys in placed {
+            peer_bodies.push(with_keys(body, keys)?);
+        }
+    }
+
+    let local = async {
+        let (plans, pruning) = query::plan(state, bucket, &local_request).await?;
+        query::aggregate(
+            state.clone(),
+            bucket,
+            plans,
+            &local_request,
+            pruning,
+            counters,
+        )
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to run query: {e}")))
+    };
+    let peers = futures::future::join_all(config.peers.iter().zip(&peer_bodies).map(
+        |(peer, body)| async move {
+            query::peer_partial(config, peer, bucket, body)
+                .await
+                .map_err(|e| peer_failed(peer, e))
+        },
+    ));
+    let (local, peers) = futures::join!(local, peers);
+
+    let mut groups = query::Groups::default();
+    let mut nodes = Vec::new();
+    for partial in std::iter::once(Ok(local?)).chain(peers) {
+        let partial = partial?;
+        groups.merge(partial.groups);
+        nodes.push(partial.report);
+    }
+    let mut rows = groups.rows(request);
+    query::order_groups(&mut rows, request);
+    Ok((rows, nodes))
+}
+
+/// The query in `body` restricted to `keys`.
+fn with_keys(body: &web::Bytes, keys: Vec<String>) -> Result<web::Bytes, ApiError> {
+    let mut query: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(body)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "MalformedQuery", e.to_string()))?;
+    query.insert("keys".to_string(), json!(keys));
+    // Serializing a map of JSON values cannot fail
+    Ok(serde_json::to_vec(&query).unwrap_or_default().into())
+}
+
+pub async fn delete_bucket(
+    req: HttpRequest,
+    state: web::Data<AppState>,
     path: web::Path<String>,
-    mut payload: web::Payload,
 ) -> Result<HttpResponse, Error> {
-    // let file_name = path.into_inner();
+    let bucket = path.into_inner();
+    auth::authorize(&req, &state, &bucket, Permission::Admin)?;
+
+    let deleted = state
+        .metadata
+        .delete_bucket(&bucket)
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to update metadata: {e}")))?;
+    if !deleted {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "BucketNotEmpty",
+            format!("Bucket {bucket} still holds objects"),
+        )
+        .into());
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn get_parquet_file(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<ObjectQuery>,
+) -> Result<HttpResponse, Error> {
+    let (bucket, file_name) = path.into_inner();
+    if query.metadata.is_some() {
+        return parquet_metadata(&req, &state, &bucket, &file_name, &query).await;
+    }
+    auth::authorize(&req, &state, &bucket, Permission::Read)?;
+
+    let meta = lookup(&state, &bucket, &file_name, query.version_id.as_deref())?;
+    if let Some(response) = conditional_read(&req, &file_name, &meta)? {
+        return Ok(response);
+    }
+
+    let range = requested_range(&req, &file_name, meta.size)?;
+    let (start, length) = range.unwrap_or((0, meta.size));
+
+    let mut response = match range {
+        Some((start, length)) => {
+            let mut response = object_response(HttpResponse::PartialContent(), &meta);
+            response.insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((start, start + length - 1)),
+                instance_length: Some(meta.size),
+            }));
+            response
+        }
+        None => object_response(HttpResponse::Ok(), &meta),
+    };
+    response
+        .insert_header((ACCEPT_RANGES, "bytes"))
+        .content_type("application/octet-stream")
+        .no_chunking(length);
+    if let Some(body) = sendfile::body(&req, &state, &meta, start, length).await {
+        return Ok(response.body(body));
+    }
+
+    let (file_stream, read_path) = match range {
+        Some((start, length)) if state.cache.fits(length) => {
+            let object = format!("{bucket}/{file_name}");
+            let (data, read_path) = cached_range(&state, &object, &meta, start, length).await?;
+            (futures::stream::once(async { Ok(data) }).boxed(), read_path)
+        }
+        _ => (
+            read_range(&state, &meta, start, length).await?,
+            storage_read_path(&meta),
+        ),
+    };
+    let file_stream = counted(&state, file_stream, read_path, length);
+    Ok(response.streaming(file_stream))
+}
+
+/// `file_stream`, a download of `length` bytes through `read_path`, counted in the metrics
+/// and for shutdown as it is sent.
+fn counted(
+    state: &AppState,
+    file_stream: BoxStream<'static, io::Result<web::Bytes>>,
+    read_path: ReadPath,
+    length: u64,
+) -> BoxStream<'static, io::Result<web::Bytes>> {
+    let mut download = state.downloads.start(read_path);
+    let mut transfer = state.transfers.download(length);
+    file_stream
+        .inspect(move |chunk| {
+            if let Ok(chunk) = chunk {
+                download.sent(chunk.len());
+                transfer.sent(chunk.len());
+            }
+        })
+        .boxed()
+}
+
+/// Streams `length` plain bytes of `meta` from `start`.
+async fn read_range(
+    state: &AppState,
+    meta: &ObjectMeta,
+    start: u64,
+    length: u64,
+) -> Result<BoxStream<'static, io::Result<web::Bytes>>, Error> {
+    let file = if meta.packed {
+        state.segments.get(&*state.storage, &meta.blob).await
+    } else {
+        state.storage.get(&state.blob_key(&meta.blob)).await
+    }
+    .map_err(ErrorInternalServerError)?;
+    let data_key = meta
+        .encryption
+        .as_ref()
+        .map(|encryption| state.master_keys.data_key(&meta.blob, encryption))
+        .transpose()
+        .map_err(ErrorInternalServerError)?;
+    let source = BlobSource::new(file, data_key, meta.plain_size());
+    Ok(match meta.compressed {
+        Some(compressed) => compression::read_range(source, compressed, start, length)
+            .await
+            .map_err(ErrorInternalServerError)?
+            .boxed(),
+        None => source
+            .stream(start, length)
+            .await
+            .map_err(ErrorInternalServerError)?
+            .boxed(),
+    })
+}
+
+/// How data of `meta` read from storage is served.
+fn storage_read_path(meta: &ObjectMeta) -> ReadPath {
+    if meta.compressed.is_some() || meta.encryption.is_some() {
+        ReadPath::Decoded
+    } else {
+        ReadPath::Plain
+    }
+}
+
+/// Reads `length` plain bytes of `meta` from `start` into memory.
+pub async fn read_bytes(
+    state: &AppState,
+    meta: &ObjectMeta,
+    start: u64,
+    length: u64,
+) -> Result<web::Bytes, Error> {
+    let mut stream = read_range(state, meta, start, length).await?;
+    let mut data = web::BytesMut::with_capacity(length as usize);
+    while let Some(chunk) = stream.next().await {
+        data.extend_from_slice(&chunk.map_err(ErrorInternalServerError)?);
+    }
+    Ok(data.freeze())
+}
+
+/// A range of `meta` through the read cache and the path it was served by. A read that
+/// reaches the end of a Parquet file loads its footer and page indexes into the cache
+/// first.
+pub async fn cached_range(
+    state: &AppState,
+    object: &str,
+    meta: &ObjectMeta,
+    start: u64,
+    length: u64,
+) -> Result<(web::Bytes, ReadPath), Error> {
+    if let Some(data) = state.cache.get(&meta.blob, start, length) {
+        return Ok((data, ReadPath::Cache));
+    }
+    if start + length == meta.size {
+        load_parquet_footer(state, object, meta).await?;
+        if let Some(data) = state.cache.peek(&meta.blob, start, length) {
+            return Ok((data, storage_read_path(meta)));
+        }
+    }
+    let data = read_bytes(state, meta, start, length).await?;
+    state
+        .cache
+        .insert(&meta.blob, object, start, data.clone(), EntryKind::Range);
+    Ok((data, storage_read_path(meta)))
+}
+
+/// Caches the footer and page indexes of `meta` if it is a Parquet file.
+async fn load_parquet_footer(
+    state: &AppState,
+    object: &str,
+    meta: &ObjectMeta,
+) -> Result<(), Error> {
+    let Some(tail_start) = meta.size.checked_sub(PARQUET_TAIL) else {
+        return Ok(());
+    };
+    if state
+        .cache
+        .peek(&meta.blob, tail_start, PARQUET_TAIL)
+        .is_some()
+    {
+        return Ok(());
+    }
+    let Some(footer) = parquet_footer(state, object, meta).await? else {
+        return Ok(());
+    };
+    let footer_start = tail_start - footer.len() as u64;
+    if let Some((offset, length)) = cache::page_index_range(&footer) {
+        if offset + length <= footer_start && state.cache.fits(length) {
+            let data = read_bytes(state, meta, offset, length).await?;
+            state
+                .cache
+                .insert(&meta.blob, object, offset, data, EntryKind::PageIndex);
+        }
+    }
+    Ok(())
+}
+
+/// The footer of `meta` without its tail through the read cache, `None` if it is not a
+/// Parquet file.
+pub async fn parquet_footer(
+    state: &AppState,
+    object: &str,
+    meta: &ObjectMeta,
+) -> Result<Option<web::Bytes>, Error> {
+    let Some(tail_start) = meta.size.checked_sub(PARQUET_TAIL) else {
+        return Ok(None);
+    };
+    let tail = match state.cache.peek(&meta.blob, tail_start, PARQUET_TAIL) {
+        Some(tail) => tail,
+        None => read_bytes(state, meta, tail_start, PARQUET_TAIL).await?,
+    };
+    let Some(footer_len) = cache::parquet_footer_len(&tail) else {
+        return Ok(None);
+    };
+    let Some(footer_start) = tail_start.checked_sub(footer_len) else {
+        return Ok(None);
+    };
+    if let Some(footer) = state.cache.peek(&meta.blob, footer_start, footer_len) {
+        return Ok(Some(footer));
+    }
+    let footer = read_bytes(state, meta, footer_start, footer_len + PARQUET_TAIL).await?;
+    state.cache.insert(
+        &meta.blob,
+        object,
+        footer_start,
+        footer.clone(),
+        EntryKind::Footer,
+    );
+    Ok(Some(footer.slice(..footer_len as usize)))
+}
+
+/// `GET /parquet/{file_name}/metadata`, the original route to `?metadata`.
+pub async fn get_legacy_parquet_metadata(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<String>,
+    query: web::Query<ObjectQuery>,
+) -> Result<HttpResponse, Error> {
+    parquet_metadata(&req, &state, DEFAULT_BUCKET, &path.into_inner(), &query).await
+}
+
+/// Schema, key-value metadata and row groups of a Parquet file as JSON, from the index
+/// built at ingest or else from its footer.
+async fn parquet_metadata(
+    req: &HttpRequest,
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+    query: &ObjectQuery,
+) -> Result<HttpResponse, Error> {
+    auth::authorize(req, state, bucket, Permission::Read)?;
+    let meta = lookup(state, bucket, key, query.version_id.as_deref())?;
+    let body = parquet_description(state, &format!("{bucket}/{key}"), key, &meta)
+        .await?
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "NotParquet",
+                format!("{key} is not a Parquet file"),
+            )
+        })?;
+    Ok(object_response(HttpResponse::Ok(), &meta)
+        .content_type("application/json")
+        .body(body))
+}
+
+/// The JSON description of a Parquet file from `parquet_index::describe`, read from the
+/// index or else decoded from its footer. `None` if it is not a Parquet file.
+pub async fn parquet_description(
+    state: &AppState,
+    object: &str,
+    key: &str,
+    meta: &ObjectMeta,
+) -> Result<Option<Vec<u8>>, Error> {
+    let indexed = match state.storage.stat(&state.index_key(&meta.blob)).await {
+        Ok(Some(index)) => Some(index),
+        Ok(None) => None,
+        Err(e) => {
+            return Err(ErrorInternalServerError(format!(
+                "Failed to look up index: {e}"
+            )))
+        }
+    };
+    if let Some(index) = indexed {
+        let body = async {
+            let mut file = state.storage.get(&index.key).await?;
+            file.read_at(0, index.size as usize).await
+        }
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to read index: {e}")))?;
+        return Ok(Some(body));
+    }
+    let Some(footer) = parquet_footer(state, object, meta).await? else {
+        return Ok(None);
+    };
+    let described = parquet_index::describe(&footer).map_err(|e| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidParquet",
+            format!("The footer of {key} cannot be decoded: {e}"),
+        )
+    })?;
+    Ok(Some(
+        serde_json::to_vec(&described).map_err(ErrorInternalServerError)?,
+    ))
+}
+
+pub async fn head_parquet_file(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<ObjectQuery>,
+) -> Result<HttpResponse, Error> {
+    let (bucket, file_name) = path.into_inner();
+    auth::authorize(&req, &state, &bucket, Permission::Read)?;
 
-    // create_dir_all(PARQUET_FOLDER)
-    //     .await
-    //     .map_err(|e| ErrorInternalServerError(format!("Failed to create directory: {e}")))?;
+    let meta = lookup(&state, &bucket, &file_name, query.version_id.as_deref())?;
+    if let Some(response) = conditional_read(&req, &file_name, &meta)? {
+        return Ok(response);
+    }
 
-    // let full_path = PathBuf::from(PARQUET_FOLDER).join(&file_name);
+    Ok(object_response(HttpResponse::Ok(), &meta)
+        .insert_header((ACCEPT_RANGES, "bytes"))
+        .content_type("application/octet-stream")
+        .no_chunking(meta.size)
+        // Sized so Content-Length is kept, actix never sends the body of a HEAD response
+        .streaming(futures::stream::empty::<Result<web::Bytes, Error>>()))
+}
 
-    // let mut file = File::create(&full_path)
-    //     .await
-    //     .map_err(|e| ErrorInternalServerError(format!("Failed to create file: {e}")))?;
+pub async fn delete_parquet_file(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<ObjectQuery>,
+) -> Result<HttpResponse, Error> {
+    let (bucket, file_name) = path.into_inner();
+    auth::authorize(&req, &state, &bucket, Permission::Write)?;
+    let config = state
+        .metadata
+        .bucket(&bucket)
+        .ok_or_else(|| ApiError::no_such_bucket(&bucket))?;
 
-    let mut buffer = Vec::new();
+    let removed = match (&query.version_id, config.versioning) {
+        (Some(version), _) => {
+            let removed = state
+                .metadata
+                .delete_version(&bucket, &file_name, version)
+                .await
+                .map_err(|e| ErrorInternalServerError(format!("Failed to update metadata: {e}")))?;
+            Some(removed.ok_or_else(|| no_such_version(&file_name, version))?)
+        }
+        (None, Versioning::Disabled) => state
+            .metadata
+            .delete_version(&bucket, &file_name, NULL_VERSION)
+            .await
+            .map_err(|e| ErrorInternalServerError(format!("Failed to update metadata: {e}")))?,
+        (None, versioning) => {
+            // Versioned buckets keep the data and hide it behind a delete marker
+            let marker = ObjectMeta::delete_marker(versioning.next_version());
+            let version = marker.version.clone();
+            let replaced = state
+                .metadata
+                .put(&bucket, &file_name, marker)
+                .await
+                .map_err(|e| ErrorInternalServerError(format!("Failed to update metadata: {e}")))?;
+            if let Some(old) = replaced {
+                state.remove_blob(&old).await;
+            }
+            return Ok(HttpResponse::NoContent()
+                .insert_header((VERSION_ID_HEADER, version))
+                .insert_header((DELETE_MARKER_HEADER, "true"))
+                .finish());
+        }
+    };
+
+    let mut response = HttpResponse::NoContent();
+    if let Some(removed) = removed {
+        if removed.delete_marker {
+            response.insert_header((DELETE_MARKER_HEADER, "true"));
+        }
+        state.remove_blob(&removed).await;
+        response.insert_header((VERSION_ID_HEADER, removed.version));
+    }
+    Ok(response.finish())
+}
+
+/// The requested version of an object, or its current version.
+fn lookup(
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+    version: Option<&str>,
+) -> Result<ObjectMeta, ApiError> {
+    let Some(version) = version else {
+        return state.metadata.get(bucket, key).ok_or_else(|| {
+            ApiError::new(
+                StatusCode::NOT_FOUND,
+                "NoSuchKey",
+                format!("Object {key} does not exist"),
+            )
+        });
+    };
+    let meta = state
+        .metadata
+        .get_version(bucket, key, version)
+        .ok_or_else(|| no_such_version(key, version))?;
+    if meta.delete_marker {
+        return Err(ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "MethodNotAllowed",
+            format!("Version {version} of {key} is a delete marker"),
+        ));
+    }
+    Ok(meta)
+}
+
+/// Headers describing the version an object response is about.
+fn object_response(mut response: HttpResponseBuilder, meta: &ObjectMeta) -> HttpResponseBuilder {
+    response
+        .insert_header((VERSION_ID_HEADER, meta.version.as_str()))
+        .insert_header(ETag(conditional::etag(meta)))
+        .insert_header(LastModified(conditional::last_modified(meta)));
+    if meta.encryption.is_some() {
+        response.insert_header((ENCRYPTION_HEADER, "AES256"));
+    }
+    response
+}
+
+/// The response to a conditional GET or HEAD that does not need the data of `meta`.
+fn conditional_read(
+    req: &HttpRequest,
+    key: &str,
+    meta: &ObjectMeta,
+) -> Result<Option<HttpResponse>, ApiError> {
+    match Preconditions::from_request(req).evaluate_read(meta) {
+        Outcome::Proceed => Ok(None),
+        Outcome::NotModified => Ok(Some(
+            object_response(HttpResponse::NotModified(), meta).finish(),
+        )),
+        Outcome::PreconditionFailed => Err(precondition_failed(key)),
+    }
+}
+
+/// The byte range a GET asks for as start and length, `None` for the whole object.
+/// Requests for several ranges get the whole object, which RFC 9110 allows.
+fn requested_range(
+    req: &HttpRequest,
+    key: &str,
+    size: u64,
+) -> Result<Option<(u64, u64)>, ApiError> {
+    let Ok(Range::Bytes(ranges)) = Range::parse(req) else {
+        return Ok(None);
+    };
+    let [range] = ranges.as_slice() else {
+        return Ok(None);
+    };
+    match range.to_satisfiable_range(size) {
+        Some((start, end)) => Ok(Some((start, end - start + 1))),
+        None => Err(ApiError::new(
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            "InvalidRange",
+            format!("The requested range is outside of {key}, which has {size} bytes"),
+        )),
+    }
+}
+
+fn encryption_unavailable() -> ApiError {
+    ApiError::new(
+        StatusCode::BAD_REQUEST,
+        "ServerSideEncryptionUnavailable",
+        "The server has no master key, see --add-master-key",
+    )
+}
+
+fn precondition_failed(key: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::PRECONDITION_FAILED,
+        "PreconditionFailed",
+        format!("A precondition on {key} does not hold"),
+    )
+}
+
+fn no_such_version(key: &str, version: &str) -> ApiError {
+    ApiError::new(
+        StatusCode::NOT_FOUND,
+        "NoSuchVersion",
+        format!("Object {key} has no version {version}"),
+    )
+}
+
+pub async fn put_parquet_file(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    path: web::Path<(String, String)>,
+    query: web::Query<ObjectQuery>,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let (bucket, file_name) = path.into_inner();
+    let caller = auth::authorize(&req, &state, &bucket, Permission::Write)?;
+    let config = state
+        .metadata
+        .bucket(&bucket)
+        .ok_or_else(|| ApiError::no_such_bucket(&bucket))?;
+    let copy_source = req.headers().get(COPY_SOURCE_HEADER);
+    if query.rename.is_some() || copy_source.is_some() {
+        // Presigned URLs do not sign headers, so anyone holding one could pick the source
+        if let Caller::Presigned { .. } = caller {
+            return Err(
+                ApiError::access_denied("Presigned URLs cannot copy or rename objects").into(),
+            );
+        }
+    }
+    if query.rename.is_some() {
+        return rename_object(&req, &state, &bucket, &file_name).await;
+    }
+    if let Some(source) = copy_source {
+        let source = object_source(COPY_SOURCE_HEADER, source.to_str().ok())?;
+        return copy_object(&req, &state, &config, source, &bucket, &file_name).await;
+    }
+
+    let max_length = caller.max_length();
+    if let (Some(max_length), Some(length)) = (max_length, content_length(&req)) {
+        if length > max_length {
+            return Err(entity_too_large(max_length).into());
+        }
+    }
+
+    let tags = parse_tags(&req)?;
+    let encrypt = match req.headers().get(ENCRYPTION_HEADER) {
+        None => config.encryption == ServerSideEncryption::Aes256,
+        Some(value) if value == "AES256" => true,
+        Some(_) => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "InvalidEncryptionAlgorithm",
+                format!("{ENCRYPTION_HEADER} only supports AES256"),
+            )
+            .into())
+        }
+    };
+    if encrypt && state.master_keys.is_empty() {
+        return Err(encryption_unavailable().into());
+    }
+
+    // Checked before the upload to fail fast, and again when the new version is committed
+    let preconditions = Preconditions::from_request(&req);
+    if !preconditions.holds_for_write(state.metadata.get(&bucket, &file_name).as_ref()) {
+        return Err(precondition_failed(&file_name).into());
+    }
+
+    // Anonymous uploaders are told apart by address
+    let client = match caller.access_key() {
+        Some(access_key) => access_key.to_string(),
+        None => req
+            .peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default(),
+    };
+    let mut permit = state
+        .admission
+        .admit(&client, content_length(&req).unwrap_or(0))
+        .await
+        .ok_or_else(|| ApiError::slow_down(state.admission.retry_after()))?;
+
+    // Files to get bloom filters are rewritten as a whole, so they are collected first and
+    // stored with the length of the rewritten file
+    let (payload, length, max_length) = match bloom_filter_columns(&req)? {
+        Some(columns) => {
+            let max_rewrite = state.bloom.max_rewrite;
+            if content_length(&req).is_some_and(|length| length > max_rewrite) {
+                return Err(rewrite_too_large(max_rewrite).into());
+            }
+            let data = collect_payload(payload, max_length, max_rewrite, &mut permit).await?;
+            let data =
+                tokio::task::spawn_blocking(move || bloom::add_bloom_filters(data, &columns))
+                    .await
+                    .map_err(|e| {
+                        ErrorInternalServerError(format!("Failed to add bloom filters: {e}"))
+                    })?
+                    .map_err(|e| {
+                        ApiError::new(
+                            StatusCode::BAD_REQUEST,
+                            "InvalidArgument",
+                            format!("Failed to add bloom filters: {e}"),
+                        )
+                    })?;
+            let length = data.len() as u64;
+            // The limits held for the body as it was sent, the filters add to it
+            let rewritten = stream::once(futures::future::ready(Ok(data))).boxed_local();
+            (rewritten, Some(length), None)
+        }
+        None => (payload.boxed_local(), content_length(&req), max_length),
+    };
+
+    let mut transfer = state.transfers.upload();
+    let blob = new_id();
+    let tmp_key = state.tmp_key(&bucket, &blob);
+    // Small uploads of known size are collected in memory and packed into a segment
+    let pack_buffer = length
+        .filter(|length| state.segments.packs(*length))
+        .map(|_| PackBuffer::default());
+    let file: Box<dyn BlobWrite> = match &pack_buffer {
+        Some(buffer) => Box::new(buffer.clone()),
+        None => state
+            .storage
+            .put(&tmp_key)
+            .await
+            .map_err(|e| ErrorInternalServerError(format!("Failed to create file: {e}")))?,
+    };
+
+    let (data_key, encryption) = if encrypt {
+        let (data_key, encryption) = state
+            .master_keys
+            .new_data_key(&blob)
+            .map_err(|e| ErrorInternalServerError(format!("Failed to create data key: {e}")))?;
+        (Some(data_key), Some(encryption))
+    } else {
+        (None, None)
+    };
+
+    // Parquet pages are compressed already, other Parquet files are recognized by their magic
+    let compression = if file_name.ends_with(".parquet") {
+        Compression::None
+    } else {
+        config.compression
+    };
+    let writer = BlobWriter::new(BlobSink::new(file, data_key), compression);
+    // The index would reveal the schema and statistics of encrypted files, packed files
+    // are small enough to decode on request
+    let mut tail = (encryption.is_none() && pack_buffer.is_none()).then(Tail::default);
+    let written = write_payload(writer, payload, max_length, &mut permit, tail.as_mut()).await;
+    let (size, compressed) = match written {
+        Ok(written) => written,
+        Err(e) => {
+            // Whatever is left behind after a crash here is picked up by GC
+            if pack_buffer.is_none() {
+                let _ = state.storage.delete(&tmp_key).await;
+            }
+            return Err(e);
+        }
+    };
+
+    let blob_key = match &pack_buffer {
+        Some(buffer) => state
+            .segments
+            .append(&*state.storage, &blob, &buffer.take())
+            .await
+            .map_err(|e| ErrorInternalServerError(format!("Failed to pack object: {e}")))?,
+        None => {
+            let blob_key = state.blob_key(&blob);
+            state
+                .storage
+                .rename(&tmp_key, &blob_key)
+                .await
+                .map_err(|e| ErrorInternalServerError(format!("Failed to move file: {e}")))?;
+            blob_key
+        }
+    };
+    let mut written_keys = vec![blob_key];
+    if let Some(footer) = tail.as_ref().and_then(Tail::footer) {
+        // Not being able to decode the footer only means there is no index
+        if let Ok(described) = parquet_index::describe(footer) {
+            let index_key = state.index_key(&blob);
+            write_index(&state, &index_key, &described)
+                .await
+                .map_err(|e| ErrorInternalServerError(format!("Failed to write index: {e}")))?;
+            written_keys.push(index_key);
+        }
+    }
+    // The data has to be durable before the metadata pointing to it is
+    futures::future::try_join_all(written_keys.into_iter().map(|key| state.sync_blob(key)))
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to sync object: {e}")))?;
+
+    let meta = ObjectMeta {
+        blob,
+        size,
+        created: unix_now(),
+        version: config.versioning.next_version(),
+        delete_marker: false,
+        tags,
+        compressed,
+        encryption,
+        packed: pack_buffer.is_some(),
+    };
+    let outcome = state
+        .metadata
+        .put_if(&bucket, &file_name, meta.clone(), |current| {
+            preconditions.holds_for_write(current)
+        })
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to update metadata: {e}")))?;
+
+    match outcome {
+        PutOutcome::Stored(Some(old)) => state.remove_blob(&old).await,
+        PutOutcome::Stored(None) => {}
+        PutOutcome::PreconditionFailed => {
+            // Another writer got there first
+            state.remove_blob(&meta).await;
+            return Err(precondition_failed(&file_name).into());
+        }
+    }
+
+    transfer.complete();
+    Ok(object_response(HttpResponse::Ok(), &meta).finish())
+}
+
+/// Bucket, key and version ID named by a copy or rename source header, `{bucket}/{key}`
+/// with an optional leading `/` and `?versionId=` suffix.
+fn object_source(
+    header: &str,
+    value: Option<&str>,
+) -> Result<(String, String, Option<String>), ApiError> {
+    let invalid = || {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidArgument",
+            format!("{header} has to name an object as {{bucket}}/{{key}}"),
+        )
+    };
+    let value = value.ok_or_else(invalid)?;
+    let value = value.strip_prefix('/').unwrap_or(value);
+    let (object, version) = match value.split_once("?versionId=") {
+        Some((object, version)) => (object, Some(version.to_string())),
+        None => (value, None),
+    };
+    match object.split_once('/') {
+        Some((bucket, key)) if !bucket.is_empty() && !key.is_empty() => {
+            Ok((bucket.to_string(), key.to_string(), version))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// Stores a copy of another object as a new version of `bucket`/`key` without sending its
+/// data through the request. The copy keeps the compression and encryption of its source
+/// and gets its tags too, unless the metadata directive is `REPLACE`.
+async fn copy_object(
+    req: &HttpRequest,
+    state: &AppState,
+    config: &BucketConfig,
+    (source_bucket, source_key, source_version): (String, String, Option<String>),
+    bucket: &str,
+    key: &str,
+) -> Result<HttpResponse, Error> {
+    auth::authorize(req, state, &source_bucket, Permission::Read)?;
+    let source = lookup(
+        state,
+        &source_bucket,
+        &source_key,
+        source_version.as_deref(),
+    )?;
+
+    let tags = match req.headers().get(METADATA_DIRECTIVE_HEADER) {
+        None => source.tags.clone(),
+        Some(value) if value == "COPY" => source.tags.clone(),
+        Some(value) if value == "REPLACE" => parse_tags(req)?,
+        Some(_) => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "InvalidArgument",
+                format!("{METADATA_DIRECTIVE_HEADER} has to be COPY or REPLACE"),
+            )
+            .into())
+        }
+    };
+    // Re-encoding would mean reading and writing all of the data
+    if let Some(value) = req.headers().get(ENCRYPTION_HEADER) {
+        if value != "AES256" || source.encryption.is_none() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "InvalidRequest",
+                "A copy keeps the encryption of its source",
+            )
+            .into());
+        }
+    }
+
+    let preconditions = Preconditions::from_request(req);
+    if !preconditions.holds_for_write(state.metadata.get(bucket, key).as_ref()) {
+        return Err(precondition_failed(key).into());
+    }
+
+    let blob = new_id();
+    let encryption = source
+        .encryption
+        .as_ref()
+        .map(|encryption| state.master_keys.rebind(&source.blob, &blob, encryption))
+        .transpose()
+        .map_err(|e| ErrorInternalServerError(format!("Failed to rebind data key: {e}")))?;
+    let written_keys = match copy_blob(state, &source, &blob).await {
+        Ok(keys) => keys,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "NoSuchKey",
+                format!("Object {source_key} was deleted while it was copied"),
+            )
+            .into())
+        }
+        Err(e) => {
+            return Err(ErrorInternalServerError(format!(
+                "Failed to copy object: {e}"
+            )))
+        }
+    };
+    futures::future::try_join_all(written_keys.into_iter().map(|key| state.sync_blob(key)))
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to sync object: {e}")))?;
+
+    let meta = ObjectMeta {
+        blob,
+        size: source.size,
+        created: unix_now(),
+        version: config.versioning.next_version(),
+        delete_marker: false,
+        tags,
+        compressed: source.compressed,
+        encryption,
+        packed: source.packed,
+    };
+    let outcome = state
+        .metadata
+        .put_if(bucket, key, meta.clone(), |current| {
+            preconditions.holds_for_write(current)
+        })
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to update metadata: {e}")))?;
+    match outcome {
+        PutOutcome::Stored(Some(old)) => state.remove_blob(&old).await,
+        PutOutcome::Stored(None) => {}
+        PutOutcome::PreconditionFailed => {
+            state.remove_blob(&meta).await;
+            return Err(precondition_failed(key).into());
+        }
+    }
+
+    Ok(object_response(HttpResponse::Ok(), &meta)
+        .insert_header((COPY_SOURCE_VERSION_HEADER, source.version))
+        .finish())
+}
+
+/// Makes the stored data of `source`, and its footer index, available as `blob`. Returns
+/// the keys to sync. Blobs of their own are linked, packed ones are small and copied into
+/// a new needle.
+async fn copy_blob(state: &AppState, source: &ObjectMeta, blob: &str) -> io::Result<Vec<String>> {
+    if source.packed {
+        let mut file = state.segments.get(&*state.storage, &source.blob).await?;
+        let data = file.read_at(0, source.stored_size() as usize).await?;
+        return Ok(vec![
+            state.segments.append(&*state.storage, blob, &data).await?,
+        ]);
+    }
+    let blob_key = state.blob_key(blob);
+    state
+        .storage
+        .link(&state.blob_key(&source.blob), &blob_key)
+        .await?;
+    let mut keys = vec![blob_key];
+    let index_key = state.index_key(blob);
+    match state
+        .storage
+        .link(&state.index_key(&source.blob), &index_key)
+        .await
+    {
+        Ok(()) => keys.push(index_key),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    Ok(keys)
+}
+
+/// Moves the current version of the object named by the rename source header to
+/// `bucket`/`key` in a single metadata record, leaving its data in place.
+async fn rename_object(
+    req: &HttpRequest,
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+) -> Result<HttpResponse, Error> {
+    let header = req.headers().get(RENAME_SOURCE_HEADER);
+    let (source_bucket, source_key, version) = object_source(
+        RENAME_SOURCE_HEADER,
+        header.and_then(|value| value.to_str().ok()),
+    )?;
+    if version.is_some() || (source_bucket.as_str(), source_key.as_str()) == (bucket, key) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidRequest",
+            "Only the current version of another object can be renamed",
+        )
+        .into());
+    }
+    // Renaming reveals the source under the new key, like a copy would
+    auth::authorize(req, state, &source_bucket, Permission::Read)?;
+    auth::authorize(req, state, &source_bucket, Permission::Write)?;
+
+    let preconditions = Preconditions::from_request(req);
+    let outcome = state
+        .metadata
+        .rename(&source_bucket, &source_key, bucket, key, |current| {
+            preconditions.holds_for_write(current)
+        })
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to update metadata: {e}")))?;
+    match outcome {
+        RenameOutcome::Renamed { meta, replaced } => {
+            for old in &replaced {
+                state.remove_blob(old).await;
+            }
+            Ok(object_response(HttpResponse::Ok(), &meta).finish())
+        }
+        RenameOutcome::NoSuchKey => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "NoSuchKey",
+            format!("Object {source_key} does not exist"),
+        )
+        .into()),
+        RenameOutcome::PreconditionFailed => Err(precondition_failed(key).into()),
+    }
+}
+
+/// Stores `data`, a Parquet file written by the server itself such as the result of a
+/// query job, as a new version of `bucket`/`key`, encrypted if the bucket asks for it.
+pub async fn store_object(
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+    data: &[u8],
+) -> Result<ObjectMeta, Error> {
+    let config = state
+        .metadata
+        .bucket(bucket)
+        .ok_or_else(|| ApiError::no_such_bucket(bucket))?;
+    let encrypt = config.encryption == ServerSideEncryption::Aes256;
+    if encrypt && state.master_keys.is_empty() {
+        return Err(encryption_unavailable().into());
+    }
+
+    let blob = new_id();
+    let tmp_key = state.tmp_key(bucket, &blob);
+    let file = state
+        .storage
+        .put(&tmp_key)
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to create file: {e}")))?;
+    let (data_key, encryption) = if encrypt {
+        let (data_key, encryption) = state
+            .master_keys
+            .new_data_key(&blob)
+            .map_err(|e| ErrorInternalServerError(format!("Failed to create data key: {e}")))?;
+        (Some(data_key), Some(encryption))
+    } else {
+        (None, None)
+    };
+    // Results are Parquet files, whose pages are compressed already
+    let mut writer = BlobWriter::new(BlobSink::new(file, data_key), Compression::None);
+    let written = match writer.write(data).await {
+        Ok(()) => writer.finish().await,
+        Err(e) => Err(e),
+    };
+    let (size, compressed) = match written {
+        Ok(written) => written,
+        Err(e) => {
+            let _ = state.storage.delete(&tmp_key).await;
+            return Err(ErrorInternalServerError(format!(
+                "Failed to write object: {e}"
+            )));
+        }
+    };
+
+    let blob_key = state.blob_key(&blob);
+    state
+        .storage
+        .rename(&tmp_key, &blob_key)
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to move file: {e}")))?;
+    let mut written_keys = vec![blob_key];
+    let mut tail = Tail::default();
+    tail.push(data);
+    let described = tail
+        .footer()
+        .and_then(|footer| parquet_index::describe(footer).ok());
+    if let Some(described) = described.filter(|_| encryption.is_none()) {
+        let index_key = state.index_key(&blob);
+        write_index(state, &index_key, &described)
+            .await
+            .map_err(|e| ErrorInternalServerError(format!("Failed to write index: {e}")))?;
+        written_keys.push(index_key);
+    }
+    futures::future::try_join_all(written_keys.into_iter().map(|key| state.sync_blob(key)))
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to sync object: {e}")))?;
+
+    let meta = ObjectMeta {
+        blob,
+        size,
+        created: unix_now(),
+        version: config.versioning.next_version(),
+        delete_marker: false,
+        tags: BTreeMap::new(),
+        compressed,
+        encryption,
+        packed: false,
+    };
+    let replaced = state
+        .metadata
+        .put(bucket, key, meta.clone())
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to update metadata: {e}")))?;
+    if let Some(old) = replaced {
+        state.remove_blob(&old).await;
+    }
+    Ok(meta)
+}
+
+async fn write_index(state: &AppState, key: &str, described: &serde_json::Value) -> io::Result<()> {
+    let mut file = state.storage.put(key).await?;
+    file.write_all(&serde_json::to_vec(described)?).await?;
+    file.finish().await
+}
+
+/// The columns of `x-mvp-bloom-filter-columns`, comma-separated.
+fn bloom_filter_columns(req: &HttpRequest) -> Result<Option<Vec<String>>, ApiError> {
+    let Some(value) = req.headers().get(BLOOM_FILTER_COLUMNS_HEADER) else {
+        return Ok(None);
+    };
+    let columns: Vec<String> = value
+        .to_str()
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|column| !column.is_empty())
+        .map(String::from)
+        .collect();
+    if columns.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidArgument",
+            format!("{BLOOM_FILTER_COLUMNS_HEADER} has to name columns"),
+        ));
+    }
+    Ok(Some(columns))
+}
+
+/// The whole body of an upload to rewrite, within `max_length` and `max_rewrite`.
+async fn collect_payload(
+    mut payload: web::Payload,
+    max_length: Option<u64>,
+    max_rewrite: u64,
+    permit: &mut Permit,
+) -> Result<web::Bytes, Error> {
+    let mut data = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(read_failed)?;
+        data.extend_from_slice(&chunk);
+        if let Some(max_length) = max_length.filter(|max_length| data.len() as u64 > *max_length) {
+            return Err(entity_too_large(max_length).into());
+        }
+        if data.len() as u64 > max_rewrite {
+            return Err(rewrite_too_large(max_rewrite).into());
+        }
+        permit.received(data.len() as u64);
+    }
+    Ok(data.freeze())
+}
+
+async fn write_payload(
+    mut writer: BlobWriter,
+    mut payload: LocalBoxStream<'_, Result<web::Bytes, PayloadError>>,
+    max_length: Option<u64>,
+    permit: &mut Permit,
+    mut tail: Option<&mut Tail>,
+) -> Result<(u64, Option<Compressed>), Error> {
+    let mut size = 0;
 
     while let Some(chunk) = payload.next().await {
-        let data =
-            chunk.map_err(|e| ErrorInternalServerError(format!("Failed to read chunk: {e}")))?;
+        let data = chunk.map_err(read_failed)?;
 
-        buffer.extend_from_slice(&data);
+        size += data.len() as u64;
+        // Content-Length is not required, so the limit is enforced on the stream as well
+        if let Some(max_length) = max_length.filter(|max_length| size > *max_length) {
+            return Err(entity_too_large(max_length).into());
+        }
+        permit.received(size);
+        if let Some(tail) = tail.as_deref_mut() {
+            tail.push(&data);
+        }
+
+        writer
+            .write(&data)
+            .await
+            .map_err(|e| ErrorInternalServerError(format!("Failed to write chunk: {e}")))?;
+    }
 
-        while buffer.len() >= MAX_CHUNK_SIZE {
-            let chunk_to_write = buffer.drain(..MAX_CHUNK_SIZE).collect::<Vec<_>>();
+    writer
+        .finish()
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to finish file: {e}")))
+}
 
-            if let Some(x) = chunk_to_write.first() {
-                println!("{},{}", x, path);
+/// Admission control state in the Prometheus text format, open so scrapers need no keys.
+pub async fn metrics(state: web::Data<AppState>) -> HttpResponse {
+    let mut metrics = state.admission.metrics();
+    if let Some(commit) = &state.commit {
+        metrics += &commit.metrics();
+    }
+    metrics += &state.cache.metrics();
+    metrics += &state.downloads.metrics();
+    metrics += &state.jobs.metrics();
+    metrics += &state.queries.metrics();
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics)
+}
+
+pub async fn run_gc(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<DryRunQuery>,
+) -> Result<HttpResponse, Error> {
+    auth::authorize_root(&req, &state)?;
+    let report = gc::run(&state, query.dry_run)
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("GC failed: {e}")))?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Re-wraps the data keys of all encrypted versions with the newest master key of the key
+/// file. Object data stays untouched, older master keys can be removed afterwards.
+pub async fn rotate_keys(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<DryRunQuery>,
+) -> Result<HttpResponse, Error> {
+    auth::authorize_root(&req, &state)?;
+    state
+        .master_keys
+        .reload()
+        .map_err(|e| ErrorInternalServerError(format!("Failed to read master keys: {e}")))?;
+    let Some(active) = state.master_keys.active_id() else {
+        return Err(encryption_unavailable().into());
+    };
+
+    let mut rewrapped = 0;
+    let mut current = 0;
+    let mut failed = Vec::new();
+    for (bucket, key, meta) in state.metadata.entries() {
+        let Some(encryption) = &meta.encryption else {
+            continue;
+        };
+        if encryption.key_id == active {
+            current += 1;
+            continue;
+        }
+        let rewrapped_key = match state.master_keys.rewrap(&meta.blob, encryption) {
+            Ok(rewrapped_key) => rewrapped_key,
+            Err(e) => {
+                failed.push(json!({
+                    "key": format!("{bucket}/{key}"),
+                    "version_id": meta.version,
+                    "error": e.to_string(),
+                }));
+                continue;
             }
-            // file.write_all(&chunk_to_write)
-            //     .await
-            //     .map_err(|e| ErrorInternalServerError(format!("Failed to write chunk: {e}")))?;
+        };
+        if !query.dry_run {
+            state
+                .metadata
+                .update_version(&bucket, &key, &meta.version, &meta.blob, |meta| {
+                    meta.encryption = Some(rewrapped_key)
+                })
+                .await
+                .map_err(|e| ErrorInternalServerError(format!("Failed to update metadata: {e}")))?;
         }
+        rewrapped += 1;
     }
-    if !buffer.is_empty() {
-        // file.write_all(&buffer)
-        //     .await
-        //     .map_err(|e| ErrorInternalServerError(format!("Failed to write final chunk: {e}")))?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "dry_run": query.dry_run,
+        "active_key": active,
+        "rewrapped": rewrapped,
+        "already_current": current,
+        "failed": failed,
+    })))
+}
+
+pub async fn run_lifecycle(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<DryRunQuery>,
+) -> Result<HttpResponse, Error> {
+    auth::authorize_root(&req, &state)?;
+    let report = lifecycle::run(&state, query.dry_run)
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Lifecycle run failed: {e}")))?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+pub async fn run_compaction(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    query: web::Query<DryRunQuery>,
+) -> Result<HttpResponse, Error> {
+    auth::authorize_root(&req, &state)?;
+    let report = state
+        .segments
+        .compact(&*state.storage, query.dry_run)
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Compaction failed: {e}")))?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// Cache usage and the most recently used entries.
+pub async fn cache_report(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    auth::authorize_root(&req, &state)?;
+    Ok(HttpResponse::Ok().json(state.cache.report()))
+}
+
+pub async fn clear_cache(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    auth::authorize_root(&req, &state)?;
+    let (entries, bytes) = state.cache.clear();
+    Ok(HttpResponse::Ok().json(json!({
+        "entries_dropped": entries,
+        "bytes_dropped": bytes,
+    })))
+}
+
+pub async fn presign(
+    req: HttpRequest,
+    state: web::Data<AppState>,
+    body: web::Json<PresignRequest>,
+) -> Result<HttpResponse, Error> {
+    let permission = match body.method.as_str() {
+        "GET" => Permission::Read,
+        "PUT" => Permission::Write,
+        method => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "InvalidRequest",
+                format!("Cannot presign {method} requests"),
+            )
+            .into())
+        }
+    };
+    if body.expires_in > signing::MAX_PRESIGN_EXPIRY_SECS {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "InvalidRequest",
+            format!(
+                "Presigned URLs expire after at most {} seconds",
+                signing::MAX_PRESIGN_EXPIRY_SECS
+            ),
+        )
+        .into());
     }
 
-    // file.flush()
-    //     .await
-    //     .map_err(|e| ErrorInternalServerError(format!("Failed to flush file: {e}")))?;
+    // Handing out a URL must not grant more than the caller could do itself
+    let caller = auth::authorize(&req, &state, &body.bucket, permission)?;
+    let Caller::Key { access_key, .. } = &caller else {
+        return Err(ApiError::access_denied("Only signed requests can presign URLs").into());
+    };
+    let secret = state
+        .credentials
+        .secret(access_key)
+        .ok_or_else(|| ApiError::access_denied("Unknown access key"))?;
+
+    let info = req.connection_info();
+    let base = format!("{}://{}", info.scheme(), info.host());
+    let url =
+        signing::object_url(&base, &body.bucket, &body.key).map_err(ErrorInternalServerError)?;
+    let expires = unix_now() + body.expires_in;
+    let url = signing::presign(
+        url,
+        &body.method,
+        access_key,
+        secret,
+        expires,
+        body.max_length,
+    );
 
+    Ok(HttpResponse::Ok().json(json!({
+        "url": url.as_str(),
+        "expires": expires,
+    })))
+}
+
+async fn update_bucket(
+    state: &AppState,
+    bucket: &str,
+    update: impl FnOnce(&mut BucketConfig),
+) -> Result<HttpResponse, Error> {
+    let updated = state
+        .metadata
+        .update_bucket(bucket, update)
+        .await
+        .map_err(|e| ErrorInternalServerError(format!("Failed to update metadata: {e}")))?;
+    if !updated {
+        return Err(ApiError::no_such_bucket(bucket).into());
+    }
     Ok(HttpResponse::Ok().finish())
 }
+
+/// Object tags from the `x-mvp-tagging` header, encoded like a query string.
+fn parse_tags(req: &HttpRequest) -> Result<BTreeMap<String, String>, ApiError> {
+    let Some(tagging) = req.headers().get(TAGGING_HEADER) else {
+        return Ok(BTreeMap::new());
+    };
+    let tagging = tagging.to_str().unwrap_or_default();
+    web::Query::<BTreeMap<String, String>>::from_query(tagging)
+        .map(web::Query::into_inner)
+        .map_err(|e| ApiError::new(StatusCode::BAD_REQUEST, "InvalidTag", e.to_string()))
+}
+
+fn content_length(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(actix_web::http::header::CONTENT_LENGTH)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+fn read_failed(error: PayloadError) -> Error {
+    if auth::is_content_sha256_mismatch(&error) {
+        return ApiError::new(StatusCode::BAD_REQUEST, "BadDigest", error.to_string()).into();
+    }
+    ErrorInternalServerError(format!("Failed to read chunk: {error}"))
+}
+
+fn entity_too_large(max_length: u64) -> ApiError {
+    ApiError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "EntityTooLarge",
+        format!("Uploads through this URL are limited to {max_length} bytes"),
+    )
+}
+
+fn rewrite_too_large(max_rewrite: u64) -> ApiError {
+    ApiError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "EntityTooLarge",
+        format!("Uploads with {BLOOM_FILTER_COLUMNS_HEADER} are limited to {max_rewrite} bytes"),
+    )
+}
+
+fn is_valid_bucket_name(bucket: &str) -> bool {
+    (3..=63).contains(&bucket.len())
+        && bucket
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.')
+        && !RESERVED_BUCKETS.contains(&bucket)
+}
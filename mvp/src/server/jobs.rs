@@ -0,0 +1,674 @@
+//! Asynchronous query jobs.
+//!
+//! Aggregations over hundreds of files can run longer than clients and proxies keep a
+//! request open. `POST /{bucket}?query&job` takes the same query, answers `202` with a job
+//! ID right away and runs it in the background, `--max-query-jobs` at a time while later
+//! ones wait. `GET /api/jobs/{id}` reports the state of a job and the files and bytes it
+//! processed so far, `DELETE /api/jobs/{id}` cancels it. The rows of a job that succeeded
+//! are written as a Parquet file to the result bucket, where a lifecycle rule expires them
+//! after the retention period, and `GET /api/jobs/{id}/result` serves it to whoever can see
+//! the job. Jobs are kept in memory: a restart forgets them, while their results stay until
+//! they expire.
+
+use actix_web::web::{self, Bytes};
+use arrow_array::{
+    ArrayRef, BooleanArray, Float64Array, Int64Array, RecordBatch, RecordBatchOptions, StringArray,
+};
+use arrow_schema::{DataType, Field, Schema};
+use futures::StreamExt;
+use parquet::arrow::ArrowWriter;
+use parquet::errors::Result;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::io;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::AbortHandle;
+
+use crate::auth::Caller;
+use crate::lifecycle::{self, LifecycleRule};
+use crate::metadata::{new_id, unix_now, BucketConfig, MetadataStore};
+use crate::query::{self, Query, ScanCounters};
+use crate::{handlers, AppState};
+
+/// ID of the lifecycle rule that expires job results in the result bucket
+const RESULT_RULE: &str = "query-job-results";
+/// Rows per record batch of a result file
+const RESULT_BATCH_ROWS: usize = 64 * 1024;
+/// Batches of a scan waiting for the result writer
+const QUEUED_BATCHES: usize = 4;
+
+pub struct JobConfig {
+    /// Bucket the results are written to
+    pub result_bucket: String,
+    /// Days after which results expire, 0 keeps them
+    pub retention_days: u64,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    const ALL: [JobState; 5] = [
+        JobState::Queued,
+        JobState::Running,
+        JobState::Succeeded,
+        JobState::Failed,
+        JobState::Cancelled,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            JobState::Queued => "queued",
+            JobState::Running => "running",
+            JobState::Succeeded => "succeeded",
+            JobState::Failed => "failed",
+            JobState::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn finished(self) -> bool {
+        matches!(
+            self,
+            JobState::Succeeded | JobState::Failed | JobState::Cancelled
+        )
+    }
+}
+
+/// What polling a job reports.
+#[derive(Serialize, Clone, Debug)]
+pub struct JobStatus {
+    pub id: String,
+    pub bucket: String,
+    pub prefix: String,
+    pub state: JobState,
+    pub submitted: u64,
+    pub started: Option<u64>,
+    pub finished: Option<u64>,
+    pub progress: Progress,
+    pub result: Option<JobResult>,
+    pub error: Option<String>,
+}
+
+/// Files and bytes of this node a job processed so far. Peers of aggregate jobs only
+/// report once they are done.
+#[derive(Serialize, Clone, Default, Debug)]
+pub struct Progress {
+    pub files_total: u64,
+    pub files_done: u64,
+    pub rows_scanned: u64,
+    pub bytes_scanned: u64,
+}
+
+/// The Parquet file a job wrote its rows to.
+#[derive(Serialize, Clone, Debug)]
+pub struct JobResult {
+    pub bucket: String,
+    pub key: String,
+    pub version_id: String,
+    pub rows: u64,
+    pub size: u64,
+}
+
+struct Job {
+    /// Access key of the caller that submitted the job
+    owner: String,
+    status: Mutex<JobStatus>,
+    counters: Arc<ScanCounters>,
+    task: Mutex<Option<AbortHandle>>,
+}
+
+impl Job {
+    fn status(&self) -> JobStatus {
+        let mut status = self.status.lock().unwrap().clone();
+        let counters = &self.counters;
+        status.progress = Progress {
+            files_total: counters.planned.load(Ordering::Relaxed),
+            files_done: counters.files.load(Ordering::Relaxed),
+            rows_scanned: counters.rows.load(Ordering::Relaxed),
+            bytes_scanned: counters.bytes.load(Ordering::Relaxed),
+        };
+        status
+    }
+
+    /// Applies `update` unless the job was cancelled in the meantime.
+    fn update(&self, update: impl FnOnce(&mut JobStatus)) {
+        let mut status = self.status.lock().unwrap();
+        if status.state != JobState::Cancelled {
+            update(&mut status);
+        }
+    }
+
+    fn visible_to(&self, caller: &Caller) -> bool {
+        matches!(caller, Caller::Key { root: true, .. })
+            || caller.access_key() == Some(self.owner.as_str())
+    }
+}
+
+pub struct Jobs {
+    pub config: JobConfig,
+    /// Jobs running at the same time
+    slots: Arc<Semaphore>,
+    jobs: Mutex<HashMap<String, Arc<Job>>>,
+}
+
+impl Jobs {
+    pub fn new(config: JobConfig, max_jobs: usize) -> Self {
+        Self {
+            config,
+            slots: Arc::new(Semaphore::new(max_jobs.max(1))),
+            jobs: Mutex::default(),
+        }
+    }
+
+    /// The status of job `id`, if it exists and `caller` submitted it or is root.
+    pub fn status(&self, id: &str, caller: &Caller) -> Option<JobStatus> {
+        self.job(id, caller).map(|job| job.status())
+    }
+
+    /// The jobs `caller` may see, oldest first.
+    pub fn list(&self, caller: &Caller) -> Vec<JobStatus> {
+        self.forget_expired();
+        let mut jobs: Vec<JobStatus> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| job.visible_to(caller))
+            .map(|job| job.status())
+            .collect();
+        jobs.sort_by(|a, b| (a.submitted, &a.id).cmp(&(b.submitted, &b.id)));
+        jobs
+    }
+
+    /// Cancels job `id` unless it finished already. Returns its status afterwards, or
+    /// `None` if `caller` cannot see it.
+    pub fn cancel(&self, id: &str, caller: &Caller) -> Option<JobStatus> {
+        let job = self.job(id, caller)?;
+        {
+            let mut status = job.status.lock().unwrap();
+            if status.state.finished() {
+                drop(status);
+                return Some(job.status());
+            }
+            status.state = JobState::Cancelled;
+            status.finished = Some(unix_now());
+        }
+        // Stops the scans at their next await, a result written by then stays until it expires
+        if let Some(task) = job.task.lock().unwrap().take() {
+            task.abort();
+        }
+        println!("Query job {id} cancelled");
+        Some(job.status())
+    }
+
+    fn job(&self, id: &str, caller: &Caller) -> Option<Arc<Job>> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|job| job.visible_to(caller))
+            .cloned()
+    }
+
+    /// Forgets finished jobs once their results expired, or after a day if results are kept.
+    fn forget_expired(&self) {
+        let keep = self.config.retention_days.max(1);
+        let now = unix_now();
+        self.jobs.lock().unwrap().retain(|_, job| {
+            let status = job.status.lock().unwrap();
+            status
+                .finished
+                .is_none_or(|finished| now < lifecycle::days_after(finished, keep))
+        });
+    }
+
+    /// Jobs by state in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let mut counts = [0; JobState::ALL.len()];
+        for job in self.jobs.lock().unwrap().values() {
+            counts[job.status.lock().unwrap().state as usize] += 1;
+        }
+        let mut out = String::new();
+        let name = "mvp_query_jobs";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Query jobs known to this node by state\n# TYPE {name} gauge"
+        );
+        for state in JobState::ALL {
+            let _ = writeln!(
+                out,
+                "{name}{{state=\"{}\"}} {}",
+                state.label(),
+                counts[state as usize]
+            );
+        }
+        out
+    }
+}
+
+/// Creates the result bucket unless it exists, and sets the lifecycle rule that expires
+/// job results in it to the configured retention.
+pub async fn prepare_result_bucket(metadata: &MetadataStore, config: &JobConfig) -> io::Result<()> {
+    let bucket = &config.result_bucket;
+    // Only root credentials can read it until its policy grants more
+    metadata
+        .create_bucket(
+            bucket,
+            BucketConfig {
+                created: unix_now(),
+                ..Default::default()
+            },
+        )
+        .await?;
+    let days = config.retention_days;
+    metadata
+        .update_bucket(bucket, |bucket| {
+            let rules = &mut bucket.lifecycle.rules;
+            rules.retain(|rule| rule.id != RESULT_RULE);
+            if days > 0 {
+                rules.push(LifecycleRule {
+                    id: RESULT_RULE.to_string(),
+                    enabled: true,
+                    prefix: String::new(),
+                    tags: BTreeMap::new(),
+                    expiration_days: Some(days),
+                    noncurrent_expiration_days: Some(days),
+                    abort_incomplete_upload_days: None,
+                });
+            }
+        })
+        .await?;
+    Ok(())
+}
+
+/// Starts a job running `query` over `bucket` for `owner`. `body` is the query as it was
+/// sent, for the peers of aggregate queries.
+pub fn submit(
+    state: &web::Data<AppState>,
+    owner: String,
+    bucket: String,
+    query: Query,
+    body: Bytes,
+) -> JobStatus {
+    let jobs = &state.jobs;
+    jobs.forget_expired();
+    let id = new_id();
+    let job = Arc::new(Job {
+        owner,
+        status: Mutex::new(JobStatus {
+            id: id.clone(),
+            bucket: bucket.clone(),
+            prefix: query.prefix.clone(),
+            state: JobState::Queued,
+            submitted: unix_now(),
+            started: None,
+            finished: None,
+            progress: Progress::default(),
+            result: None,
+            error: None,
+        }),
+        counters: Arc::default(),
+        task: Mutex::default(),
+    });
+    jobs.jobs
+        .lock()
+        .unwrap()
+        .insert(id.clone(), Arc::clone(&job));
+
+    // On the worker that took the request, as query errors cannot move between threads
+    let task = actix_web::rt::spawn(run(
+        state.clone(),
+        Arc::clone(&job),
+        id.clone(),
+        bucket,
+        query,
+        body,
+    ));
+    *job.task.lock().unwrap() = Some(task.abort_handle());
+    println!("Query job {id} submitted");
+    job.status()
+}
+
+async fn run(
+    state: web::Data<AppState>,
+    job: Arc<Job>,
+    id: String,
+    bucket: String,
+    query: Query,
+    body: Bytes,
+) {
+    // The semaphore is never closed
+    let Ok(_slot) = Arc::clone(&state.jobs.slots).acquire_owned().await else {
+        return;
+    };
+    job.update(|status| {
+        status.state = JobState::Running;
+        status.started = Some(unix_now());
+    });
+
+    let query = Arc::new(query);
+    let outcome = execute(&state, &job, &id, &bucket, &query, &body).await;
+    job.update(|status| {
+        status.finished = Some(unix_now());
+        match outcome {
+            Ok(result) => {
+                println!(
+                    "Query job {id} wrote {} rows to {}/{}",
+                    result.rows, result.bucket, result.key
+                );
+                status.state = JobState::Succeeded;
+                status.result = Some(result);
+            }
+            Err(e) => {
+                eprintln!("Query job {id} failed: {e}");
+                status.state = JobState::Failed;
+                status.error = Some(e);
+            }
+        }
+    });
+}
+
+/// Runs the query of a job and writes its rows to `{bucket}/{id}.parquet` in the result
+/// bucket.
+async fn execute(
+    state: &web::Data<AppState>,
+    job: &Job,
+    id: &str,
+    bucket: &str,
+    query: &Arc<Query>,
+    body: &Bytes,
+) -> std::result::Result<JobResult, String> {
+    let counters = Arc::clone(&job.counters);
+    let (count, data) = if query.aggregated() {
+        let (rows, _) = handlers::aggregate_everywhere(state, bucket, query, body, counters)
+            .await
+            .map_err(|e| e.to_string())?;
+        write_rows(rows, query).await?
+    } else {
+        let (plans, _) = query::plan(state, bucket, query)
+            .await
+            .map_err(|e| e.to_string())?;
+        match query.limit {
+            Some(limit) => {
+                let (rows, _) = query::top_k(state.clone(), bucket, plans, query, limit, counters)
+                    .await
+                    .map_err(|e| format!("Failed to run query: {e}"))?;
+                write_rows(rows, query).await?
+            }
+            None => write_scan(state, bucket, plans, query, counters).await?,
+        }
+    };
+
+    let result_bucket = &state.jobs.config.result_bucket;
+    let key = format!("{bucket}/{id}.parquet");
+    let meta = handlers::store_object(state, result_bucket, &key, &data)
+        .await
+        .map_err(|e| format!("Failed to store the result: {e}"))?;
+    Ok(JobResult {
+        bucket: result_bucket.clone(),
+        key,
+        version_id: meta.version,
+        rows: count,
+        size: meta.size,
+    })
+}
+
+/// `rows`, at most a limit of them or one per group, as a Parquet file and how many there
+/// were.
+async fn write_rows(
+    rows: Vec<Map<String, Value>>,
+    query: &Query,
+) -> std::result::Result<(u64, Vec<u8>), String> {
+    let columns = result_columns(query);
+    let count = rows.len() as u64;
+    let data = tokio::task::spawn_blocking(move || result_file(&rows, columns))
+        .await
+        .map_err(|e| format!("Failed to write the result: {e}"))?
+        .map_err(|e| format!("Failed to write the result: {e}"))?;
+    Ok((count, data))
+}
+
+/// The matching rows of a scan without a limit as a Parquet file and how many there were.
+/// Batches are written as they are scanned, the column types are taken from the footers
+/// of the files beforehand.
+async fn write_scan(
+    state: &web::Data<AppState>,
+    bucket: &str,
+    plans: Vec<query::FilePlan>,
+    query: &Arc<Query>,
+    counters: Arc<ScanCounters>,
+) -> std::result::Result<(u64, Vec<u8>), String> {
+    let types = query::output_types(state, bucket, &plans, query)
+        .await
+        .map_err(|e| format!("Failed to run query: {e}"))?;
+    let mut columns = result_columns(query);
+    for (name, _) in &types {
+        if !columns.contains(name) {
+            columns.push(name.clone());
+        }
+    }
+    let kinds: Vec<Kind> = columns
+        .iter()
+        .map(|name| {
+            let types = types.iter().find(|(column, _)| column == name);
+            types
+                .map_or(&[][..], |(_, types)| types.as_slice())
+                .iter()
+                .fold(Kind::Null, |kind, data_type| {
+                    kind.merge(Kind::of_type(data_type))
+                })
+        })
+        .collect();
+
+    let (sender, mut receiver) = mpsc::channel::<query::ValueBatch>(QUEUED_BATCHES);
+    let writer_columns = columns.clone();
+    let writer = tokio::task::spawn_blocking(move || {
+        let mut writer = ResultWriter::new(writer_columns, kinds)?;
+        while let Some(batch) = receiver.blocking_recv() {
+            writer.write(batch.rows, &batch.columns)?;
+        }
+        writer.finish()
+    });
+
+    let mut batches = query::value_batches(
+        state.clone(),
+        bucket.to_string(),
+        plans,
+        Arc::clone(query),
+        columns.into(),
+        counters,
+    );
+    let mut count = 0;
+    let mut scanned = Ok(());
+    while let Some(batch) = batches.next().await {
+        let batch = match batch {
+            Ok(batch) => batch,
+            Err(e) => {
+                scanned = Err(format!("Failed to run query: {e}"));
+                break;
+            }
+        };
+        count += batch.rows as u64;
+        // The writer only goes away when it failed, which it reports below
+        if sender.send(batch).await.is_err() {
+            break;
+        }
+    }
+    drop(sender);
+    let written = writer
+        .await
+        .map_err(|e| format!("Failed to write the result: {e}"))?
+        .map_err(|e| format!("Failed to write the result: {e}"));
+    scanned?;
+    Ok((count, written?))
+}
+
+/// Columns of the result known from the query alone, in the order it names them.
+fn result_columns(query: &Query) -> Vec<String> {
+    if query.aggregated() {
+        return query
+            .group_by
+            .iter()
+            .cloned()
+            .chain(query.aggregates.iter().map(|aggregate| aggregate.name()))
+            .collect();
+    }
+    query.columns.clone().unwrap_or_default()
+}
+
+/// The kind of Parquet column the JSON values of a result column fit into.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Null,
+    Boolean,
+    Integer,
+    Float,
+    Text,
+}
+
+impl Kind {
+    fn widen(self, value: &Value) -> Kind {
+        let kind = match value {
+            Value::Null => return self,
+            Value::Bool(_) => Kind::Boolean,
+            Value::Number(number) if number.is_i64() => Kind::Integer,
+            Value::Number(_) => Kind::Float,
+            _ => Kind::Text,
+        };
+        self.merge(kind)
+    }
+
+    fn merge(self, kind: Kind) -> Kind {
+        match (self, kind) {
+            (Kind::Null, kind) | (kind, Kind::Null) => kind,
+            (a, b) if a == b => a,
+            (Kind::Integer, Kind::Float) | (Kind::Float, Kind::Integer) => Kind::Float,
+            _ => Kind::Text,
+        }
+    }
+
+    /// The kind of the values a scan makes of an Arrow array of `data_type`. Unsigned
+    /// 64-bit integers may not fit a signed one and become doubles.
+    fn of_type(data_type: &DataType) -> Kind {
+        match data_type {
+            DataType::Null => Kind::Null,
+            DataType::Boolean => Kind::Boolean,
+            DataType::Int8
+            | DataType::Int16
+            | DataType::Int32
+            | DataType::Int64
+            | DataType::UInt8
+            | DataType::UInt16
+            | DataType::UInt32 => Kind::Integer,
+            DataType::UInt64 | DataType::Float32 | DataType::Float64 => Kind::Float,
+            _ => Kind::Text,
+        }
+    }
+}
+
+/// `rows` as a Parquet file with `columns` first and then any others the rows have. Each
+/// column gets the narrowest type all its values fit: booleans, 64-bit integers, doubles,
+/// or strings, as which nested and mixed values are written in JSON.
+fn result_file(rows: &[Map<String, Value>], mut columns: Vec<String>) -> Result<Vec<u8>> {
+    let mut known: HashSet<String> = columns.iter().cloned().collect();
+    for row in rows {
+        for name in row.keys() {
+            if known.insert(name.clone()) {
+                columns.push(name.clone());
+            }
+        }
+    }
+    let kinds: Vec<Kind> = columns
+        .iter()
+        .map(|name| {
+            rows.iter().fold(Kind::Null, |kind, row| {
+                kind.widen(row.get(name).unwrap_or(&Value::Null))
+            })
+        })
+        .collect();
+
+    let mut writer = ResultWriter::new(columns.clone(), kinds)?;
+    for chunk in rows.chunks(RESULT_BATCH_ROWS) {
+        let values: Vec<Vec<Value>> = columns
+            .iter()
+            .map(|name| {
+                chunk
+                    .iter()
+                    .map(|row| row.get(name).cloned().unwrap_or(Value::Null))
+                    .collect()
+            })
+            .collect();
+        writer.write(chunk.len(), &values)?;
+    }
+    writer.finish()
+}
+
+/// Writes values of a kind per column as a Parquet file.
+struct ResultWriter {
+    kinds: Vec<Kind>,
+    schema: Arc<Schema>,
+    writer: ArrowWriter<Vec<u8>>,
+}
+
+impl ResultWriter {
+    fn new(columns: Vec<String>, kinds: Vec<Kind>) -> Result<Self> {
+        let schema = Arc::new(Schema::new(
+            columns
+                .iter()
+                .zip(&kinds)
+                .map(|(name, kind)| Field::new(name, array(*kind, &[]).data_type().clone(), true))
+                .collect::<Vec<_>>(),
+        ));
+        let writer = ArrowWriter::try_new(Vec::new(), Arc::clone(&schema), None)?;
+        Ok(Self {
+            kinds,
+            schema,
+            writer,
+        })
+    }
+
+    /// Writes `rows` rows given as the values of each column.
+    fn write(&mut self, rows: usize, columns: &[Vec<Value>]) -> Result<()> {
+        let arrays = self
+            .kinds
+            .iter()
+            .zip(columns)
+            .map(|(kind, values)| array(*kind, values))
+            .collect();
+        let options = RecordBatchOptions::new().with_row_count(Some(rows));
+        let batch = RecordBatch::try_new_with_options(Arc::clone(&self.schema), arrays, &options)?;
+        self.writer.write(&batch)
+    }
+
+    fn finish(self) -> Result<Vec<u8>> {
+        self.writer.into_inner()
+    }
+}
+
+/// `values` as an array of `kind`, values that do not fit it are null.
+fn array(kind: Kind, values: &[Value]) -> ArrayRef {
+    let values = values.iter();
+    match kind {
+        Kind::Boolean => Arc::new(BooleanArray::from_iter(values.map(Value::as_bool))),
+        Kind::Integer => Arc::new(Int64Array::from_iter(values.map(Value::as_i64))),
+        Kind::Float => Arc::new(Float64Array::from_iter(values.map(Value::as_f64))),
+        Kind::Null | Kind::Text => {
+            Arc::new(StringArray::from_iter(values.map(|value| match value {
+                Value::Null => None,
+                Value::String(text) => Some(text.clone()),
+                value => Some(value.to_string()),
+            })))
+        }
+    }
+}
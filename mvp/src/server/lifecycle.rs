@@ -0,0 +1,228 @@
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::time::{Duration, SystemTime};
+
+use crate::metadata::{unix_now, ObjectMeta, PutOutcome, Versioning};
+use crate::{AppState, TMP_PREFIX};
+
+pub const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// The unix time `days` days after `timestamp`, saturating so absurd day counts never come due.
+pub fn days_after(timestamp: u64, days: u64) -> u64 {
+    timestamp.saturating_add(days.saturating_mul(SECS_PER_DAY))
+}
+
+/// Lifecycle rules of a bucket, set with `PUT /{bucket}?lifecycle`.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct LifecycleConfig {
+    pub rules: Vec<LifecycleRule>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LifecycleRule {
+    pub id: String,
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+    /// Only objects whose key starts with this prefix are affected
+    #[serde(default)]
+    pub prefix: String,
+    /// Only objects carrying all of these tags are affected
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// Expire current versions this many days after they were written
+    pub expiration_days: Option<u64>,
+    /// Delete versions this many days after a newer version replaced them
+    pub noncurrent_expiration_days: Option<u64>,
+    /// Delete upload staging files of the bucket that are this many days old. Since
+    /// the key of an unfinished upload is unknown, prefix and tags do not apply here.
+    pub abort_incomplete_upload_days: Option<u64>,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+impl LifecycleRule {
+    fn matches(&self, key: &str, meta: &ObjectMeta) -> bool {
+        self.enabled
+            && key.starts_with(&self.prefix)
+            && self
+                .tags
+                .iter()
+                .all(|(tag, value)| meta.tags.get(tag) == Some(value))
+    }
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct LifecycleReport {
+    pub dry_run: bool,
+    /// Current versions that were deleted or hidden behind a delete marker
+    pub expired: Vec<String>,
+    pub noncurrent_deleted: Vec<String>,
+    pub uploads_aborted: usize,
+    pub bytes_reclaimed: u64,
+}
+
+/// Evaluates the lifecycle rules of every bucket once.
+pub async fn run(state: &AppState, dry_run: bool) -> io::Result<LifecycleReport> {
+    let mut report = LifecycleReport {
+        dry_run,
+        ..Default::default()
+    };
+    let now = unix_now();
+
+    for (bucket, config) in state.metadata.buckets() {
+        let rules = &config.lifecycle.rules;
+        if rules.is_empty() {
+            continue;
+        }
+
+        for (key, versions) in state.metadata.list_versions(&bucket, "") {
+            let Some((current, noncurrent)) = versions.split_last() else {
+                continue;
+            };
+
+            let expire = !current.delete_marker
+                && rules.iter().any(|rule| {
+                    rule.matches(&key, current)
+                        && rule
+                            .expiration_days
+                            .is_some_and(|days| now >= days_after(current.created, days))
+                });
+            if expire
+                && (dry_run
+                    || expire_current(state, &bucket, &key, config.versioning, current).await?)
+            {
+                report.expired.push(format!("{bucket}/{key}"));
+                if config.versioning == Versioning::Disabled {
+                    report.bytes_reclaimed += current.size;
+                }
+            }
+
+            // A version became noncurrent when its successor was written
+            for (meta, successor) in noncurrent.iter().zip(&versions[1..]) {
+                let delete = rules.iter().any(|rule| {
+                    rule.matches(&key, meta)
+                        && rule
+                            .noncurrent_expiration_days
+                            .is_some_and(|days| now >= days_after(successor.created, days))
+                });
+                if !delete {
+                    continue;
+                }
+                let deleted = dry_run
+                    || state
+                        .metadata
+                        .remove_if_blob(&bucket, &key, &meta.version, &meta.blob)
+                        .await?;
+                if deleted {
+                    if !dry_run {
+                        state.remove_blob(meta).await;
+                    }
+                    report
+                        .noncurrent_deleted
+                        .push(format!("{bucket}/{key}?versionId={}", meta.version));
+                    report.bytes_reclaimed += meta.size;
+                }
+            }
+        }
+
+        let abort_after = rules
+            .iter()
+            .filter(|rule| rule.enabled)
+            .filter_map(|rule| rule.abort_incomplete_upload_days)
+            .min();
+        if let Some(days) = abort_after {
+            abort_uploads(state, &bucket, days, dry_run, &mut report).await?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// Runs the lifecycle rules every `interval` until the process exits.
+pub fn spawn(state: web::Data<AppState>, interval: Duration, dry_run: bool) {
+    if interval.is_zero() {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match run(&state, dry_run).await {
+                Ok(report) => println!("Lifecycle: {:?}", report),
+                Err(e) => eprintln!("Lifecycle run failed: {e}"),
+            }
+        }
+    });
+}
+
+/// Deletes the current version, or hides it behind a delete marker in versioned buckets.
+/// Returns false if the object changed since it was evaluated.
+async fn expire_current(
+    state: &AppState,
+    bucket: &str,
+    key: &str,
+    versioning: Versioning,
+    current: &ObjectMeta,
+) -> io::Result<bool> {
+    if versioning == Versioning::Disabled {
+        let removed = state
+            .metadata
+            .remove_if_blob(bucket, key, &current.version, &current.blob)
+            .await?;
+        if removed {
+            state.remove_blob(current).await;
+        }
+        return Ok(removed);
+    }
+
+    let marker = ObjectMeta::delete_marker(versioning.next_version());
+    let outcome = state
+        .metadata
+        .put_if(bucket, key, marker, |latest| {
+            latest.is_some_and(|latest| latest.version == current.version)
+        })
+        .await?;
+    match outcome {
+        PutOutcome::Stored(replaced) => {
+            // Suspended buckets replace a null current version with the marker
+            if let Some(replaced) = replaced {
+                state.remove_blob(&replaced).await;
+            }
+            Ok(true)
+        }
+        PutOutcome::PreconditionFailed => Ok(false),
+    }
+}
+
+async fn abort_uploads(
+    state: &AppState,
+    bucket: &str,
+    days: u64,
+    dry_run: bool,
+    report: &mut LifecycleReport,
+) -> io::Result<()> {
+    let cutoff = SystemTime::now()
+        .checked_sub(Duration::from_secs(days.saturating_mul(SECS_PER_DAY)))
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let prefix = format!("{TMP_PREFIX}{bucket}/");
+    for upload in state.storage.list(&prefix).await? {
+        if upload.modified >= cutoff {
+            continue;
+        }
+        if !dry_run {
+            match state.storage.delete(&upload.key).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        report.uploads_aborted += 1;
+        report.bytes_reclaimed += upload.size;
+    }
+    Ok(())
+}
@@ -0,0 +1,409 @@
+//! Blobs as files below the data folder.
+//!
+//! Without a ring, files go through the page cache with tokio's blocking file I/O. With
+//! one, blobs are opened with O_DIRECT and reads and writes are submitted to io_uring, so
+//! large transfers neither pollute the page cache nor occupy blocking threads. O_DIRECT
+//! needs aligned offsets and lengths: writes go out in `uring::BUFFER_SIZE` blocks with
+//! the last one zero-padded and the file truncated to its length afterwards, reads are
+//! widened to aligned boundaries. On file systems without O_DIRECT support the same I/O
+//! runs buffered through the ring.
+//!
+//! `sync` also syncs the directories that name the blobs: the ones they are in and the
+//! ones they were renamed out of. Directories are synced into their parents when created.
+
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::FileTimes;
+use std::io::{self, SeekFrom};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::storage::{BlobRead, BlobStat, BlobWrite, Store};
+use crate::uring::{Ring, ALIGNMENT, BUFFER_SIZE};
+
+/// Chunks plain files are streamed in, larger than tokio's default of 4 KiB so a download
+/// takes fewer reads, wakeups and chunk encodings
+const STREAM_CHUNK_SIZE: usize = 256 * 1024;
+
+pub struct LocalStore {
+    root: PathBuf,
+    ring: Option<Arc<Ring>>,
+    /// Directories blobs were renamed out of, by the key they were renamed to, synced
+    /// along with that key
+    renamed_from: Mutex<HashMap<String, PathBuf>>,
+}
+
+impl LocalStore {
+    pub fn new(root: &Path, ring: Option<Arc<Ring>>) -> Self {
+        Self {
+            root: root.to_path_buf(),
+            ring,
+            renamed_from: Mutex::default(),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    /// Creates the missing directories above `path` and syncs each into its parent.
+    async fn create_parent(path: &Path) -> io::Result<()> {
+        let Some(parent) = path.parent() else {
+            return Ok(());
+        };
+        if fs::try_exists(parent).await? {
+            return Ok(());
+        }
+        let mut missing = vec![parent.to_path_buf()];
+        while let Some(above) = missing.last().and_then(|dir| dir.parent()) {
+            if fs::try_exists(above).await? {
+                break;
+            }
+            missing.push(above.to_path_buf());
+        }
+        fs::create_dir_all(parent).await?;
+        for dir in missing.iter().rev() {
+            if let Some(above) = dir.parent() {
+                sync_dir(above.to_path_buf()).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Store for LocalStore {
+    async fn put(&self, key: &str) -> io::Result<Box<dyn BlobWrite>> {
+        let path = self.path(key);
+        Self::create_parent(&path).await?;
+        let Some(ring) = &self.ring else {
+            return Ok(Box::new(File::create(path).await?));
+        };
+        let fd = open_direct(&path, true).await?;
+        Ok(Box::new(UringFile {
+            ring: Arc::clone(ring),
+            fd: Arc::new(fd),
+            pending: Vec::with_capacity(BUFFER_SIZE),
+            written: 0,
+        }))
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Box<dyn BlobRead>> {
+        let path = self.path(key);
+        let Some(ring) = &self.ring else {
+            return Ok(Box::new(File::open(path).await?));
+        };
+        let fd = open_direct(&path, false).await?;
+        Ok(Box::new(UringFile {
+            ring: Arc::clone(ring),
+            fd: Arc::new(fd),
+            pending: Vec::new(),
+            written: 0,
+        }))
+    }
+
+    async fn append(&self, key: &str, data: &[u8]) -> io::Result<u64> {
+        let path = self.path(key);
+        Self::create_parent(&path).await?;
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        let offset = file.metadata().await?.len();
+        AsyncWriteExt::write_all(&mut file, data).await?;
+        file.flush().await?;
+        Ok(offset)
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to_key) = (self.path(from), to);
+        let to = self.path(to_key);
+        Self::create_parent(&to).await?;
+        fs::rename(&from, to).await?;
+        if let Some(dir) = from.parent() {
+            self.renamed_from
+                .lock()
+                .unwrap()
+                .insert(to_key.to_string(), dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    async fn link(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to_key) = (self.path(from), to);
+        let to = self.path(to_key);
+        Self::create_parent(&to).await?;
+        match fs::remove_file(&to).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        // Blobs are never rewritten, so both keys can share one file. File systems
+        // without hard links get a copy
+        if let Err(e) = fs::hard_link(&from, &to).await {
+            if e.kind() == io::ErrorKind::NotFound {
+                return Err(e);
+            }
+            fs::copy(&from, &to).await?;
+        }
+        self.set_modified(to_key, SystemTime::now()).await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        fs::remove_file(self.path(key)).await
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<BlobStat>> {
+        let mut blobs = Vec::new();
+        let mut pending = vec![self.path(prefix)];
+        while let Some(dir) = pending.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let metadata = entry.metadata().await?;
+                let path = entry.path();
+                if metadata.is_dir() {
+                    pending.push(path);
+                    continue;
+                }
+                let key = path.strip_prefix(&self.root).ok().and_then(Path::to_str);
+                if let (true, Some(key)) = (metadata.is_file(), key) {
+                    blobs.push(BlobStat {
+                        key: key.to_string(),
+                        size: metadata.len(),
+                        modified: metadata.modified()?,
+                    });
+                }
+            }
+        }
+        Ok(blobs)
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<Option<BlobStat>> {
+        match fs::metadata(self.path(key)).await {
+            Ok(metadata) => Ok(Some(BlobStat {
+                key: key.to_string(),
+                size: metadata.len(),
+                modified: metadata.modified()?,
+            })),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn set_modified(&self, key: &str, modified: SystemTime) -> io::Result<()> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(self.path(key))
+            .await?
+            .into_std()
+            .await;
+        tokio::task::spawn_blocking(move || file.set_times(FileTimes::new().set_modified(modified)))
+            .await?
+    }
+
+    async fn sync(&self, keys: &[String]) -> io::Result<()> {
+        futures::future::try_join_all(keys.iter().map(|key| async move {
+            let path = self.path(key);
+            match &self.ring {
+                Some(ring) => {
+                    ring.fsync(&Arc::new(open_direct(&path, false).await?))
+                        .await
+                }
+                None => File::open(path).await?.sync_all().await,
+            }
+        }))
+        .await?;
+
+        let mut dirs: BTreeSet<PathBuf> = keys
+            .iter()
+            .filter_map(|key| self.path(key).parent().map(Path::to_path_buf))
+            .collect();
+        {
+            let mut renamed_from = self.renamed_from.lock().unwrap();
+            dirs.extend(keys.iter().filter_map(|key| renamed_from.remove(key)));
+        }
+        futures::future::try_join_all(dirs.into_iter().map(sync_dir)).await?;
+        Ok(())
+    }
+
+    fn file_path(&self, key: &str) -> Option<PathBuf> {
+        Some(self.path(key))
+    }
+}
+
+#[async_trait]
+impl BlobWrite for File {
+    async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        AsyncWriteExt::write_all(self, data).await
+    }
+
+    async fn finish(&mut self) -> io::Result<()> {
+        self.flush().await
+    }
+}
+
+#[async_trait]
+impl BlobRead for File {
+    async fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; len];
+        self.seek(SeekFrom::Start(offset)).await?;
+        self.read_exact(&mut data).await?;
+        Ok(data)
+    }
+
+    async fn stream(
+        mut self: Box<Self>,
+        start: u64,
+        length: u64,
+    ) -> io::Result<BoxStream<'static, io::Result<Bytes>>> {
+        self.seek(SeekFrom::Start(start)).await?;
+        Ok(
+            tokio_util::io::ReaderStream::with_capacity(self.take(length), STREAM_CHUNK_SIZE)
+                .boxed(),
+        )
+    }
+}
+
+async fn sync_dir(dir: PathBuf) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || std::fs::File::open(dir)?.sync_all()).await?
+}
+
+/// Opens `path` with O_DIRECT, without it if the file system refuses it.
+async fn open_direct(path: &Path, create: bool) -> io::Result<OwnedFd> {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let open = |flags: i32| {
+            std::fs::OpenOptions::new()
+                .read(!create)
+                .write(create)
+                .create(create)
+                .truncate(create)
+                .custom_flags(flags)
+                .open(&path)
+        };
+        let file = match open(libc::O_DIRECT) {
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) => open(0),
+            result => result,
+        }?;
+        Ok(OwnedFd::from(file))
+    })
+    .await?
+}
+
+struct UringFile {
+    ring: Arc<Ring>,
+    fd: Arc<OwnedFd>,
+    /// Data not written yet, less than a buffer
+    pending: Vec<u8>,
+    /// Bytes written to the file so far, a multiple of `BUFFER_SIZE` until `finish` sets it
+    /// to the length of the blob
+    written: u64,
+}
+
+#[async_trait]
+impl BlobWrite for UringFile {
+    async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(data);
+        while self.pending.len() >= BUFFER_SIZE {
+            let mut buffer = self.ring.buffer().await;
+            buffer.fill(&self.pending[..BUFFER_SIZE]);
+            self.pending.drain(..BUFFER_SIZE);
+            self.ring.write(&self.fd, self.written, buffer).await?;
+            self.written += BUFFER_SIZE as u64;
+        }
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            let len = self.written + self.pending.len() as u64;
+            let mut buffer = self.ring.buffer().await;
+            buffer.fill(&self.pending);
+            buffer.pad();
+            self.pending.clear();
+            self.ring.write(&self.fd, self.written, buffer).await?;
+            // Drop the padding of the last block
+            let fd = Arc::clone(&self.fd);
+            tokio::task::spawn_blocking(move || {
+                match unsafe { libc::ftruncate(fd.as_raw_fd(), len as libc::off_t) } {
+                    0 => Ok(()),
+                    _ => Err(io::Error::last_os_error()),
+                }
+            })
+            .await??;
+            self.written = len;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlobRead for UringFile {
+    async fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let end = offset + len as u64;
+        let mut data = Vec::with_capacity(len);
+        let mut position = offset - offset % ALIGNMENT as u64;
+        while position < end {
+            let want = ((end - position) as usize)
+                .next_multiple_of(ALIGNMENT)
+                .min(BUFFER_SIZE);
+            let buffer = self.ring.buffer().await;
+            let buffer = self.ring.read(&self.fd, position, want, buffer).await?;
+            let read = buffer.as_slice();
+            if read.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "read past the end of the blob",
+                ));
+            }
+            let from = offset.saturating_sub(position) as usize;
+            let to = read.len().min((end - position) as usize);
+            if from < to {
+                data.extend_from_slice(&read[from..to]);
+            }
+            position += read.len() as u64;
+            // Only the end of the file is unaligned, and O_DIRECT cannot go on from there.
+            // Aligned short reads just continue at the next block
+            if read.len() % ALIGNMENT != 0 && position < end {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "short read before the end of the blob",
+                ));
+            }
+        }
+        Ok(data)
+    }
+
+    async fn stream(
+        self: Box<Self>,
+        start: u64,
+        length: u64,
+    ) -> io::Result<BoxStream<'static, io::Result<Bytes>>> {
+        let end = start + length;
+        Ok(
+            futures::stream::try_unfold((self, start), move |(mut file, position)| async move {
+                if position >= end {
+                    return Ok(None);
+                }
+                // Up to the next buffer boundary, so later reads stay aligned
+                let block_end = (position / BUFFER_SIZE as u64 + 1) * BUFFER_SIZE as u64;
+                let len = (block_end.min(end) - position) as usize;
+                let data = file.read_at(position, len).await?;
+                Ok(Some((Bytes::from(data), (file, position + len as u64))))
+            })
+            .boxed(),
+        )
+    }
+}
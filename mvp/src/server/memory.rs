@@ -0,0 +1,242 @@
+//! Blobs kept in memory, for tests and throwaway deployments.
+
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use crate::storage::{BlobRead, BlobStat, BlobWrite, Store};
+
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Data as the chunks it was appended in, each with its offset. Chunks never change once
+/// appended, so readers and linked keys share them without copying and only look at the
+/// length the blob had when they got it.
+#[derive(Default)]
+struct Chunks(RwLock<Vec<(u64, Bytes)>>);
+
+impl Chunks {
+    /// The parts of the chunks that hold `start..end`, without copying.
+    fn slices(&self, start: u64, end: u64) -> Vec<Bytes> {
+        let chunks = self.0.read().unwrap();
+        let first = chunks.partition_point(|(offset, data)| offset + data.len() as u64 <= start);
+        chunks[first..]
+            .iter()
+            .take_while(|(offset, _)| *offset < end)
+            .map(|(offset, data)| {
+                let from = start.saturating_sub(*offset) as usize;
+                let to = ((end - offset) as usize).min(data.len());
+                data.slice(from..to)
+            })
+            .collect()
+    }
+}
+
+struct Blob {
+    chunks: Arc<Chunks>,
+    len: u64,
+    modified: SystemTime,
+}
+
+impl Blob {
+    fn new() -> Self {
+        Self {
+            chunks: Arc::default(),
+            len: 0,
+            modified: SystemTime::now(),
+        }
+    }
+
+    fn append(&mut self, data: &[u8]) {
+        self.modified = SystemTime::now();
+        if data.is_empty() {
+            return;
+        }
+        let mut chunks = self.chunks.0.write().unwrap();
+        let end = chunks
+            .last()
+            .map_or(0, |(offset, data)| offset + data.len() as u64);
+        if end != self.len {
+            // A key linked to the same chunks appended to them, go on with a list of our
+            // own. Lengths always end on a chunk boundary
+            let own: Vec<_> = chunks
+                .iter()
+                .take_while(|(offset, _)| *offset < self.len)
+                .cloned()
+                .collect();
+            drop(chunks);
+            self.chunks = Arc::new(Chunks(RwLock::new(own)));
+            chunks = self.chunks.0.write().unwrap();
+        }
+        chunks.push((self.len, Bytes::copy_from_slice(data)));
+        self.len += data.len() as u64;
+    }
+}
+
+type Blobs = Arc<Mutex<HashMap<String, Blob>>>;
+
+#[derive(Default)]
+pub struct MemoryStore {
+    blobs: Blobs,
+}
+
+fn not_found(key: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("no blob {key}"))
+}
+
+#[async_trait]
+impl Store for MemoryStore {
+    async fn put(&self, key: &str) -> io::Result<Box<dyn BlobWrite>> {
+        self.blobs
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), Blob::new());
+        Ok(Box::new(MemoryWriter {
+            blobs: Arc::clone(&self.blobs),
+            key: key.to_string(),
+        }))
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Box<dyn BlobRead>> {
+        let blobs = self.blobs.lock().unwrap();
+        let blob = blobs.get(key).ok_or_else(|| not_found(key))?;
+        Ok(Box::new(MemoryReader {
+            chunks: Arc::clone(&blob.chunks),
+            len: blob.len,
+        }))
+    }
+
+    async fn append(&self, key: &str, data: &[u8]) -> io::Result<u64> {
+        let mut blobs = self.blobs.lock().unwrap();
+        let blob = blobs.entry(key.to_string()).or_insert_with(Blob::new);
+        let offset = blob.len;
+        blob.append(data);
+        Ok(offset)
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut blobs = self.blobs.lock().unwrap();
+        let blob = blobs.remove(from).ok_or_else(|| not_found(from))?;
+        blobs.insert(to.to_string(), blob);
+        Ok(())
+    }
+
+    async fn link(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut blobs = self.blobs.lock().unwrap();
+        let blob = blobs.get(from).ok_or_else(|| not_found(from))?;
+        let linked = Blob {
+            chunks: Arc::clone(&blob.chunks),
+            len: blob.len,
+            modified: SystemTime::now(),
+        };
+        blobs.insert(to.to_string(), linked);
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match self.blobs.lock().unwrap().remove(key) {
+            Some(_) => Ok(()),
+            None => Err(not_found(key)),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<BlobStat>> {
+        Ok(self
+            .blobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, blob)| BlobStat {
+                key: key.clone(),
+                size: blob.len,
+                modified: blob.modified,
+            })
+            .collect())
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<Option<BlobStat>> {
+        Ok(self.blobs.lock().unwrap().get(key).map(|blob| BlobStat {
+            key: key.to_string(),
+            size: blob.len,
+            modified: blob.modified,
+        }))
+    }
+
+    async fn set_modified(&self, key: &str, modified: SystemTime) -> io::Result<()> {
+        let mut blobs = self.blobs.lock().unwrap();
+        let blob = blobs.get_mut(key).ok_or_else(|| not_found(key))?;
+        blob.modified = modified;
+        Ok(())
+    }
+
+    async fn sync(&self, _keys: &[String]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Appends to the blob in the store, so it can be listed and reclaimed like a partially
+/// written file while the upload is in flight.
+struct MemoryWriter {
+    blobs: Blobs,
+    key: String,
+}
+
+#[async_trait]
+impl BlobWrite for MemoryWriter {
+    async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut blobs = self.blobs.lock().unwrap();
+        let blob = blobs
+            .get_mut(&self.key)
+            .ok_or_else(|| not_found(&self.key))?;
+        blob.append(data);
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct MemoryReader {
+    chunks: Arc<Chunks>,
+    /// Length of the blob when it was opened
+    len: u64,
+}
+
+#[async_trait]
+impl BlobRead for MemoryReader {
+    async fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let end = offset + len as u64;
+        if end > self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of the blob",
+            ));
+        }
+        Ok(self.chunks.slices(offset, end).concat())
+    }
+
+    async fn stream(
+        self: Box<Self>,
+        start: u64,
+        length: u64,
+    ) -> io::Result<BoxStream<'static, io::Result<Bytes>>> {
+        let end = (start + length).min(self.len);
+        let pieces = self
+            .chunks
+            .slices(start.min(end), end)
+            .into_iter()
+            .flat_map(|slice| {
+                (0..slice.len())
+                    .step_by(STREAM_CHUNK_SIZE)
+                    .map(|from| Ok(slice.slice(from..(from + STREAM_CHUNK_SIZE).min(slice.len()))))
+                    .collect::<Vec<_>>()
+            });
+        Ok(futures::stream::iter(pieces).boxed())
+    }
+}
@@ -0,0 +1,945 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{self as sync, Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::auth::BucketPolicy;
+use crate::commit::Batcher;
+use crate::compression::{Compressed, Compression};
+use crate::encryption::{encrypted_len, Encryption, ServerSideEncryption};
+use crate::lifecycle::LifecycleConfig;
+
+const LOG_FILE: &str = "index.log";
+pub const DEFAULT_BUCKET: &str = "parquet";
+/// Version ID of objects written while versioning is not enabled
+pub const NULL_VERSION: &str = "null";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ObjectMeta {
+    /// Empty for delete markers, which have no data
+    pub blob: String,
+    pub size: u64,
+    pub created: u64,
+    #[serde(default = "null_version")]
+    pub version: String,
+    #[serde(default)]
+    pub delete_marker: bool,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tags: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compressed: Option<Compressed>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<Encryption>,
+    /// Stored in a segment instead of a blob of its own
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub packed: bool,
+}
+
+impl ObjectMeta {
+    pub fn delete_marker(version: String) -> Self {
+        Self {
+            blob: String::new(),
+            size: 0,
+            created: unix_now(),
+            version,
+            delete_marker: true,
+            tags: BTreeMap::new(),
+            compressed: None,
+            encryption: None,
+            packed: false,
+        }
+    }
+
+    /// Length of the blob before encryption.
+    pub fn plain_size(&self) -> u64 {
+        self.compressed
+            .map_or(self.size, |compressed| compressed.stored_size)
+    }
+
+    /// Bytes the version takes on disk.
+    pub fn stored_size(&self) -> u64 {
+        match self.encryption {
+            Some(_) => encrypted_len(self.plain_size()),
+            None => self.plain_size(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Versioning {
+    #[default]
+    Disabled,
+    Enabled,
+    /// Versioning was enabled once, new writes replace the null version again
+    Suspended,
+}
+
+impl Versioning {
+    /// Version ID for the next write to a bucket in this state.
+    pub fn next_version(self) -> String {
+        match self {
+            Versioning::Enabled => new_id(),
+            Versioning::Disabled | Versioning::Suspended => NULL_VERSION.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct BucketConfig {
+    pub created: u64,
+    pub policy: BucketPolicy,
+    #[serde(default)]
+    pub versioning: Versioning,
+    #[serde(default)]
+    pub lifecycle: LifecycleConfig,
+    /// Applied to new uploads, existing objects keep their encoding
+    #[serde(default)]
+    pub compression: Compression,
+    /// Encryption of new uploads that do not ask for it themselves
+    #[serde(default)]
+    pub encryption: ServerSideEncryption,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+enum LogRecord {
+    Bucket {
+        bucket: String,
+        config: BucketConfig,
+    },
+    DeleteBucket {
+        bucket: String,
+    },
+    Put {
+        #[serde(default = "default_bucket")]
+        bucket: String,
+        key: String,
+        meta: ObjectMeta,
+    },
+    /// Replaces a version in place, keeping its position in the history
+    Update {
+        bucket: String,
+        key: String,
+        meta: ObjectMeta,
+    },
+    /// Removes one version, or the whole key if `version` is missing
+    Delete {
+        #[serde(default = "default_bucket")]
+        bucket: String,
+        key: String,
+        #[serde(default)]
+        version: Option<String>,
+    },
+    /// Moves `version` to `to_key` in `to_bucket` as `meta`, leaving `marker` behind
+    Rename {
+        bucket: String,
+        key: String,
+        version: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        marker: Option<Box<ObjectMeta>>,
+        to_bucket: String,
+        to_key: String,
+        meta: ObjectMeta,
+    },
+}
+
+fn default_bucket() -> String {
+    DEFAULT_BUCKET.to_string()
+}
+
+fn null_version() -> String {
+    NULL_VERSION.to_string()
+}
+
+type ObjectId = (String, String);
+
+#[derive(Default, Clone)]
+struct Index {
+    buckets: BTreeMap<String, BucketConfig>,
+    /// All versions of an object, oldest first
+    objects: BTreeMap<ObjectId, Vec<ObjectMeta>>,
+}
+
+impl Index {
+    fn apply(&mut self, record: LogRecord) {
+        match record {
+            LogRecord::Bucket { bucket, config } => {
+                self.buckets.insert(bucket, config);
+            }
+            LogRecord::DeleteBucket { bucket } => {
+                self.buckets.remove(&bucket);
+            }
+            LogRecord::Put { bucket, key, meta } => {
+                let versions = self.objects.entry((bucket, key)).or_default();
+                versions.retain(|v| v.version != meta.version);
+                versions.push(meta);
+            }
+            LogRecord::Update { bucket, key, meta } => {
+                let version = self
+                    .objects
+                    .get_mut(&(bucket, key))
+                    .and_then(|versions| versions.iter_mut().find(|v| v.version == meta.version));
+                if let Some(version) = version {
+                    *version = meta;
+                }
+            }
+            LogRecord::Delete {
+                bucket,
+                key,
+                version: None,
+            } => {
+                self.objects.remove(&(bucket, key));
+            }
+            LogRecord::Delete {
+                bucket,
+                key,
+                version: Some(version),
+            } => {
+                let id = (bucket, key);
+                if let Some(versions) = self.objects.get_mut(&id) {
+                    versions.retain(|v| v.version != version);
+                    if versions.is_empty() {
+                        self.objects.remove(&id);
+                    }
+                }
+            }
+            LogRecord::Rename {
+                bucket,
+                key,
+                version,
+                marker,
+                to_bucket,
+                to_key,
+                meta,
+            } => {
+                self.apply(LogRecord::Delete {
+                    bucket: bucket.clone(),
+                    key: key.clone(),
+                    version: Some(version),
+                });
+                if let Some(marker) = marker {
+                    self.apply(LogRecord::Put {
+                        bucket,
+                        key,
+                        meta: *marker,
+                    });
+                }
+                self.apply(LogRecord::Put {
+                    bucket: to_bucket,
+                    key: to_key,
+                    meta,
+                });
+            }
+        }
+    }
+
+    /// The current version of an object, unless that is a delete marker.
+    fn get(&self, bucket: &str, key: &str) -> Option<&ObjectMeta> {
+        self.objects
+            .get(&(bucket.to_string(), key.to_string()))?
+            .last()
+            .filter(|meta| !meta.delete_marker)
+    }
+
+    fn version(&self, bucket: &str, key: &str, version: &str) -> Option<&ObjectMeta> {
+        self.objects
+            .get(&(bucket.to_string(), key.to_string()))?
+            .iter()
+            .find(|v| v.version == version)
+    }
+
+    fn records(&self) -> impl Iterator<Item = LogRecord> + '_ {
+        let buckets = self
+            .buckets
+            .iter()
+            .map(|(bucket, config)| LogRecord::Bucket {
+                bucket: bucket.clone(),
+                config: config.clone(),
+            });
+        let objects = self.objects.iter().flat_map(|((bucket, key), versions)| {
+            versions.iter().map(|meta| LogRecord::Put {
+                bucket: bucket.clone(),
+                key: key.clone(),
+                meta: meta.clone(),
+            })
+        });
+        buckets.chain(objects)
+    }
+
+    fn bucket_is_empty(&self, bucket: &str) -> bool {
+        self.objects
+            .range((bucket.to_string(), String::new())..)
+            .next()
+            .is_none_or(|((b, _), _)| b != bucket)
+    }
+}
+
+pub enum PutOutcome {
+    /// Carries the entry that was replaced, see `MetadataStore::put`
+    Stored(Option<ObjectMeta>),
+    PreconditionFailed,
+}
+
+pub enum RenameOutcome {
+    /// Carries the version at its new key and the entries that were replaced
+    Renamed {
+        meta: ObjectMeta,
+        replaced: Vec<ObjectMeta>,
+    },
+    NoSuchKey,
+    PreconditionFailed,
+}
+
+/// In-memory bucket and object index, persisted as an append-only log of JSON lines.
+///
+/// Readers only see durable records. Writers check their preconditions against a second
+/// copy of the index held with the log, which already has the records that are written
+/// but still wait for their batch to be synced.
+pub struct MetadataStore {
+    durable: Arc<Durable>,
+    log: Mutex<Log>,
+    /// Handle of the log to sync batches through without taking the log lock
+    sync_file: Option<Arc<fs::File>>,
+    /// Batches syncs of records by their sequence numbers
+    commit: Option<Arc<Batcher<u64>>>,
+    /// Fails syncs of the log that are not batched, for tests
+    #[cfg(test)]
+    fail_syncs: sync::atomic::AtomicBool,
+}
+
+/// The index readers see and the records that still have to become durable to show up.
+#[derive(Default)]
+struct Durable {
+    index: RwLock<Index>,
+    /// Records written to the log but not synced yet, with their sequence numbers in
+    /// log order
+    unsynced: sync::Mutex<VecDeque<(u64, LogRecord)>>,
+}
+
+impl Durable {
+    /// Applies the records up to `sequence` to the index. They were written before it, so
+    /// the sync that made it durable covered them as well.
+    fn apply_synced(&self, sequence: u64) {
+        let mut unsynced = self.unsynced.lock().unwrap();
+        let mut index = self.index.write().unwrap();
+        while unsynced
+            .front()
+            .is_some_and(|(written, _)| *written <= sequence)
+        {
+            let (_, record) = unsynced.pop_front().unwrap();
+            index.apply(record);
+        }
+    }
+}
+
+struct Log {
+    /// None if nothing is persisted
+    file: Option<File>,
+    /// The index with every record written
+    index: Index,
+    /// Sequence number of the last record written
+    written: u64,
+    /// Bytes of the records written, where a failed append cuts the log back to
+    len: u64,
+}
+
+impl MetadataStore {
+    /// Replays the log in `dir` and rewrites it compacted before accepting new records.
+    /// With `commit`, records are synced in batches instead of one by one.
+    pub fn open(dir: &Path, commit: Option<Arc<Batcher<u64>>>) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let log_path = dir.join(LOG_FILE);
+
+        let mut index = Index::default();
+        if log_path.exists() {
+            let reader = BufReader::new(fs::File::open(&log_path)?);
+            for line in reader.lines() {
+                let line = line?;
+                // A crash during append can leave a torn last line behind
+                match serde_json::from_str::<LogRecord>(&line) {
+                    Ok(record) => index.apply(record),
+                    Err(e) => eprintln!("Skipping corrupt metadata record: {e}"),
+                }
+            }
+        }
+
+        let compacted_path = dir.join(format!("{LOG_FILE}.tmp"));
+        {
+            let mut compacted = fs::File::create(&compacted_path)?;
+            for record in index.records() {
+                writeln!(compacted, "{}", serde_json::to_string(&record)?)?;
+            }
+            compacted.sync_all()?;
+        }
+        fs::rename(&compacted_path, &log_path)?;
+        fs::File::open(dir)?.sync_all()?;
+
+        let log = OpenOptions::new().append(true).open(&log_path)?;
+        let len = log.metadata()?.len();
+        let sync_file = Arc::new(log.try_clone()?);
+
+        Ok(Self {
+            durable: Arc::new(Durable {
+                index: RwLock::new(index.clone()),
+                unsynced: sync::Mutex::default(),
+            }),
+            log: Mutex::new(Log {
+                file: Some(File::from_std(log)),
+                index,
+                written: 0,
+                len,
+            }),
+            sync_file: Some(sync_file),
+            commit,
+            #[cfg(test)]
+            fail_syncs: Default::default(),
+        })
+    }
+
+    /// An empty store without a log, for tests.
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        Self {
+            durable: Arc::default(),
+            log: Mutex::new(Log {
+                file: None,
+                index: Index::default(),
+                written: 0,
+                len: 0,
+            }),
+            sync_file: None,
+            commit: None,
+            fail_syncs: Default::default(),
+        }
+    }
+
+    pub fn bucket(&self, bucket: &str) -> Option<BucketConfig> {
+        self.durable
+            .index
+            .read()
+            .unwrap()
+            .buckets
+            .get(bucket)
+            .cloned()
+    }
+
+    pub fn buckets(&self) -> Vec<(String, BucketConfig)> {
+        self.durable
+            .index
+            .read()
+            .unwrap()
+            .buckets
+            .iter()
+            .map(|(bucket, config)| (bucket.clone(), config.clone()))
+            .collect()
+    }
+
+    /// The current version of an object, unless that is a delete marker.
+    pub fn get(&self, bucket: &str, key: &str) -> Option<ObjectMeta> {
+        self.durable.index.read().unwrap().get(bucket, key).cloned()
+    }
+
+    pub fn get_version(&self, bucket: &str, key: &str, version: &str) -> Option<ObjectMeta> {
+        self.durable
+            .index
+            .read()
+            .unwrap()
+            .version(bucket, key, version)
+            .cloned()
+    }
+
+    /// Current objects in `bucket` whose key starts with `prefix`, in key order.
+    pub fn list(&self, bucket: &str, prefix: &str) -> Vec<(String, ObjectMeta)> {
+        self.list_versions(bucket, prefix)
+            .into_iter()
+            .filter_map(|(key, mut versions)| {
+                let current = versions.pop()?;
+                (!current.delete_marker).then_some((key, current))
+            })
+            .collect()
+    }
+
+    /// All versions of the objects in `bucket` whose key starts with `prefix`, oldest first.
+    pub fn list_versions(&self, bucket: &str, prefix: &str) -> Vec<(String, Vec<ObjectMeta>)> {
+        self.durable
+            .index
+            .read()
+            .unwrap()
+            .objects
+            .range((bucket.to_string(), prefix.to_string())..)
+            .take_while(|((b, key), _)| b == bucket && key.starts_with(prefix))
+            .map(|((_, key), versions)| (key.clone(), versions.clone()))
+            .collect()
+    }
+
+    /// Every stored version that has data, across all buckets.
+    pub fn entries(&self) -> Vec<(String, String, ObjectMeta)> {
+        self.durable
+            .index
+            .read()
+            .unwrap()
+            .objects
+            .iter()
+            .flat_map(|((bucket, key), versions)| {
+                versions
+                    .iter()
+                    .filter(|meta| !meta.delete_marker)
+                    .map(|meta| (bucket.clone(), key.clone(), meta.clone()))
+            })
+            .collect()
+    }
+
+    pub fn referenced_blobs(&self) -> HashSet<String> {
+        self.entries()
+            .into_iter()
+            .map(|(_, _, meta)| meta.blob)
+            .collect()
+    }
+
+    /// Applies `update` to the configuration of `bucket`, returns false if it does not exist.
+    pub async fn update_bucket(
+        &self,
+        bucket: &str,
+        update: impl FnOnce(&mut BucketConfig),
+    ) -> io::Result<bool> {
+        let log = self.log.lock().await;
+        let Some(mut config) = log.index.buckets.get(bucket).cloned() else {
+            return Ok(false);
+        };
+        update(&mut config);
+        self.append(
+            log,
+            LogRecord::Bucket {
+                bucket: bucket.to_string(),
+                config,
+            },
+        )
+        .await?;
+        Ok(true)
+    }
+
+    /// Creates `bucket` unless it exists, returns whether it was created.
+    pub async fn create_bucket(&self, bucket: &str, config: BucketConfig) -> io::Result<bool> {
+        let log = self.log.lock().await;
+        if log.index.buckets.contains_key(bucket) {
+            return Ok(false);
+        }
+        self.append(
+            log,
+            LogRecord::Bucket {
+                bucket: bucket.to_string(),
+                config,
+            },
+        )
+        .await?;
+        Ok(true)
+    }
+
+    /// Deletes `bucket` if it holds no objects, returns whether it was deleted.
+    pub async fn delete_bucket(&self, bucket: &str) -> io::Result<bool> {
+        let log = self.log.lock().await;
+        if !log.index.bucket_is_empty(bucket) {
+            return Ok(false);
+        }
+        self.append(
+            log,
+            LogRecord::DeleteBucket {
+                bucket: bucket.to_string(),
+            },
+        )
+        .await?;
+        Ok(true)
+    }
+
+    /// Durably records `meta` as the current version of `bucket`/`key` and returns the
+    /// entry it replaced, which only exists when both have the same (null) version ID.
+    pub async fn put(
+        &self,
+        bucket: &str,
+        key: &str,
+        meta: ObjectMeta,
+    ) -> io::Result<Option<ObjectMeta>> {
+        match self.put_if(bucket, key, meta, |_| true).await? {
+            PutOutcome::Stored(previous) => Ok(previous),
+            PutOutcome::PreconditionFailed => unreachable!("unconditional put"),
+        }
+    }
+
+    /// Like `put`, but only if `precondition` holds for the current version at commit time.
+    pub async fn put_if(
+        &self,
+        bucket: &str,
+        key: &str,
+        meta: ObjectMeta,
+        precondition: impl FnOnce(Option<&ObjectMeta>) -> bool,
+    ) -> io::Result<PutOutcome> {
+        let log = self.log.lock().await;
+        if !precondition(log.index.get(bucket, key)) {
+            return Ok(PutOutcome::PreconditionFailed);
+        }
+        let previous = log.index.version(bucket, key, &meta.version).cloned();
+        self.append(
+            log,
+            LogRecord::Put {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                meta,
+            },
+        )
+        .await?;
+        Ok(PutOutcome::Stored(previous))
+    }
+
+    /// Moves the current version of `bucket`/`key` to `to_bucket`/`to_key` in one record,
+    /// if `precondition` holds for the current version there. The data stays where it is,
+    /// the version gets an ID of the target bucket and keeps its creation time. Versioned
+    /// source buckets keep their older versions behind a delete marker.
+    pub async fn rename(
+        &self,
+        bucket: &str,
+        key: &str,
+        to_bucket: &str,
+        to_key: &str,
+        precondition: impl FnOnce(Option<&ObjectMeta>) -> bool,
+    ) -> io::Result<RenameOutcome> {
+        let log = self.log.lock().await;
+        let Some(source) = log.index.get(bucket, key).cloned() else {
+            return Ok(RenameOutcome::NoSuchKey);
+        };
+        if !precondition(log.index.get(to_bucket, to_key)) {
+            return Ok(RenameOutcome::PreconditionFailed);
+        }
+        let versioning = |bucket| {
+            log.index
+                .buckets
+                .get(bucket)
+                .map(|config| config.versioning)
+                .unwrap_or_default()
+        };
+        let marker = match versioning(bucket) {
+            Versioning::Disabled => None,
+            versioning => Some(ObjectMeta::delete_marker(versioning.next_version())),
+        };
+        let meta = ObjectMeta {
+            version: versioning(to_bucket).next_version(),
+            ..source.clone()
+        };
+
+        let mut replaced: Vec<ObjectMeta> = marker
+            .iter()
+            .filter_map(|marker| log.index.version(bucket, key, &marker.version).cloned())
+            .filter(|previous| previous.version != source.version)
+            .collect();
+        replaced.extend(log.index.version(to_bucket, to_key, &meta.version).cloned());
+        self.append(
+            log,
+            LogRecord::Rename {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                version: source.version,
+                marker: marker.map(Box::new),
+                to_bucket: to_bucket.to_string(),
+                to_key: to_key.to_string(),
+                meta: meta.clone(),
+            },
+        )
+        .await?;
+        Ok(RenameOutcome::Renamed { meta, replaced })
+    }
+
+    /// Permanently removes one version and returns it.
+    pub async fn delete_version(
+        &self,
+        bucket: &str,
+        key: &str,
+        version: &str,
+    ) -> io::Result<Option<ObjectMeta>> {
+        let log = self.log.lock().await;
+        let Some(removed) = log.index.version(bucket, key, version).cloned() else {
+            return Ok(None);
+        };
+        self.append(
+            log,
+            LogRecord::Delete {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                version: Some(version.to_string()),
+            },
+        )
+        .await?;
+        Ok(Some(removed))
+    }
+
+    /// Applies `update` to a version if it still has `blob`, returns false otherwise.
+    pub async fn update_version(
+        &self,
+        bucket: &str,
+        key: &str,
+        version: &str,
+        blob: &str,
+        update: impl FnOnce(&mut ObjectMeta),
+    ) -> io::Result<bool> {
+        let log = self.log.lock().await;
+        let Some(mut meta) = log
+            .index
+            .version(bucket, key, version)
+            .filter(|meta| meta.blob == blob)
+            .cloned()
+        else {
+            return Ok(false);
+        };
+        update(&mut meta);
+        self.append(
+            log,
+            LogRecord::Update {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                meta,
+            },
+        )
+        .await?;
+        Ok(true)
+    }
+
+    /// Removes the version only if it still points at `blob`, so a concurrent overwrite is kept.
+    pub async fn remove_if_blob(
+        &self,
+        bucket: &str,
+        key: &str,
+        version: &str,
+        blob: &str,
+    ) -> io::Result<bool> {
+        let log = self.log.lock().await;
+        if log
+            .index
+            .version(bucket, key, version)
+            .is_none_or(|meta| meta.blob != blob)
+        {
+            return Ok(false);
+        }
+        self.append(
+            log,
+            LogRecord::Delete {
+                bucket: bucket.to_string(),
+                key: key.to_string(),
+                version: Some(version.to_string()),
+            },
+        )
+        .await?;
+        Ok(true)
+    }
+
+    /// Flushes and syncs the log, waiting for a write in progress.
+    pub async fn close(&self) -> io::Result<()> {
+        let mut log = self.log.lock().await;
+        let Some(file) = log.file.as_mut() else {
+            return Ok(());
+        };
+        file.flush().await?;
+        file.sync_all().await
+    }
+
+    /// Writes `record` to the log, then releases the log lock and returns once the record
+    /// is durable and applied to the index readers see. With group commit the records of
+    /// concurrent writers are synced together and show up to readers together.
+    ///
+    /// A record that fails to be written or, without group commit, synced is cut from the
+    /// log again. Records of a batch that fails to sync stay, and show up with the next
+    /// batch that does.
+    async fn append(&self, mut log: MutexGuard<'_, Log>, record: LogRecord) -> io::Result<()> {
+        let len = log.len;
+        let (Some(file), Some(sync_file)) = (log.file.as_mut(), &self.sync_file) else {
+            log.index.apply(record.clone());
+            self.durable.index.write().unwrap().apply(record);
+            return Ok(());
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        let written = async {
+            file.write_all(&line).await?;
+            // The write has to reach the file before it is synced, on its own or in a batch
+            file.flush().await?;
+            match &self.commit {
+                Some(_) => Ok(()),
+                None => self.sync(file).await,
+            }
+        }
+        .await;
+        if let Err(e) = written {
+            // Left behind, the record would show up on replay although it failed, and a
+            // torn line would swallow the record written after it
+            if let Err(e) = file.set_len(len).await {
+                eprintln!("Failed to cut a failed record from the metadata log: {e}");
+            }
+            return Err(e);
+        }
+        log.len += line.len() as u64;
+        let Some(commit) = &self.commit else {
+            log.index.apply(record.clone());
+            self.durable.index.write().unwrap().apply(record);
+            return Ok(());
+        };
+        log.index.apply(record.clone());
+        log.written += 1;
+        let sequence = log.written;
+        self.durable
+            .unsynced
+            .lock()
+            .unwrap()
+            .push_back((sequence, record));
+        drop(log);
+
+        // The leader applies the batch, which a request that goes away cannot prevent
+        let (file, durable) = (Arc::clone(sync_file), Arc::clone(&self.durable));
+        commit
+            .commit(sequence, move |sequences| async move {
+                tokio::task::spawn_blocking(move || file.sync_data()).await??;
+                durable.apply_synced(sequences.into_iter().max().unwrap_or_default());
+                Ok(())
+            })
+            .await
+    }
+
+    async fn sync(&self, file: &File) -> io::Result<()> {
+        #[cfg(test)]
+        if self.fail_syncs.load(sync::atomic::Ordering::Relaxed) {
+            return Err(io::Error::other("Sync failed for the test"));
+        }
+        file.sync_data().await
+    }
+}
+
+pub fn new_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commit::CommitConfig;
+    use crate::tests::TempDir;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    fn meta(blob: &str) -> ObjectMeta {
+        ObjectMeta {
+            blob: blob.to_string(),
+            size: 1,
+            created: unix_now(),
+            version: NULL_VERSION.to_string(),
+            delete_marker: false,
+            tags: BTreeMap::new(),
+            compressed: None,
+            encryption: None,
+            packed: false,
+        }
+    }
+
+    fn blob(store: &MetadataStore, key: &str) -> Option<String> {
+        store.get("tests", key).map(|meta| meta.blob)
+    }
+
+    #[tokio::test]
+    async fn failed_syncs_leave_no_record_behind() {
+        let dir = TempDir::new();
+        let store = MetadataStore::open(dir.path(), None).expect("opened store");
+        store.put("tests", "a", meta("a1")).await.expect("put a");
+        store.fail_syncs.store(true, Ordering::Relaxed);
+        assert!(store.put("tests", "b", meta("b1")).await.is_err());
+        assert!(store.put("tests", "a", meta("a2")).await.is_err());
+        store.fail_syncs.store(false, Ordering::Relaxed);
+        assert_eq!(blob(&store, "a").as_deref(), Some("a1"));
+        assert_eq!(blob(&store, "b"), None);
+
+        store.put("tests", "c", meta("c1")).await.expect("put c");
+        store.close().await.expect("closed store");
+        drop(store);
+        let log = fs::read_to_string(dir.path().join(LOG_FILE)).expect("log");
+        assert_eq!(log.lines().count(), 2);
+
+        let store = MetadataStore::open(dir.path(), None).expect("reopened store");
+        assert_eq!(blob(&store, "a").as_deref(), Some("a1"));
+        assert_eq!(blob(&store, "b"), None);
+        assert_eq!(blob(&store, "c").as_deref(), Some("c1"));
+    }
+
+    #[tokio::test]
+    async fn torn_last_lines_are_skipped_on_replay() {
+        let dir = TempDir::new();
+        let store = MetadataStore::open(dir.path(), None).expect("opened store");
+        store.put("tests", "a", meta("a1")).await.expect("put a");
+        store.put("tests", "b", meta("b1")).await.expect("put b");
+        store.close().await.expect("closed store");
+        drop(store);
+
+        // A crash in the middle of appending c
+        let path = dir.path().join(LOG_FILE);
+        let line = serde_json::to_string(&LogRecord::Put {
+            bucket: "tests".to_string(),
+            key: "c".to_string(),
+            meta: meta("c1"),
+        })
+        .expect("record");
+        let mut log = OpenOptions::new().append(true).open(&path).expect("log");
+        log.write_all(&line.as_bytes()[..line.len() / 2])
+            .expect("torn line");
+        drop(log);
+
+        let store = MetadataStore::open(dir.path(), None).expect("reopened store");
+        assert_eq!(blob(&store, "a").as_deref(), Some("a1"));
+        assert_eq!(blob(&store, "b").as_deref(), Some("b1"));
+        assert_eq!(blob(&store, "c"), None);
+        // The torn line is gone with the compaction, so it cannot swallow the next record
+        store.put("tests", "d", meta("d1")).await.expect("put d");
+        store.close().await.expect("closed store");
+        drop(store);
+        let store = MetadataStore::open(dir.path(), None).expect("reopened store");
+        assert_eq!(blob(&store, "d").as_deref(), Some("d1"));
+    }
+
+    #[tokio::test]
+    async fn group_commits_are_durable_and_visible_together() {
+        let dir = TempDir::new();
+        let commit = Batcher::new(CommitConfig {
+            window: Duration::from_millis(20),
+            max_batch: 64,
+        });
+        let store = Arc::new(MetadataStore::open(dir.path(), Some(commit)).expect("opened store"));
+        let puts: Vec<_> = (0..32)
+            .map(|i| {
+                let store = Arc::clone(&store);
+                tokio::spawn(async move {
+                    let key = format!("key-{i}");
+                    store.put("tests", &key, meta(&key)).await
+                })
+            })
+            .collect();
+        for put in puts {
+            put.await.expect("put task").expect("put");
+        }
+        assert_eq!(store.list("tests", "").len(), 32);
+        store.close().await.expect("closed store");
+        drop(store);
+
+        let store = MetadataStore::open(dir.path(), None).expect("reopened store");
+        let keys: HashSet<String> = store
+            .list("tests", "")
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, (0..32).map(|i| format!("key-{i}")).collect());
+    }
+}
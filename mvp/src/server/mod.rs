@@ -1,19 +1,540 @@
-use actix_web::{App, HttpServer};
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{web, App, HttpServer};
+use clap::Parser;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-mod routes;
+mod admission;
+mod auth;
+mod bloom;
+mod cache;
+mod commit;
+mod compression;
+mod conditional;
+mod downloads;
+mod encryption;
+mod error;
+mod explain;
+mod gc;
 mod handlers;
+mod jobs;
+mod lifecycle;
+mod local;
+mod memory;
+mod metadata;
+mod parquet_index;
+mod query;
+mod routes;
+mod segments;
+mod sendfile;
+mod shutdown;
+#[path = "../signing.rs"]
+mod signing;
+mod storage;
+#[cfg(test)]
+mod tests;
+mod tls;
+mod uring;
 
 const MAX_CHUNK_SIZE: usize = 8192;
 const PARQUET_FOLDER: &str = "/mnt/raid0/";
+const BLOB_PREFIX: &str = "blobs/";
+const TMP_PREFIX: &str = "tmp/";
+const INDEX_PREFIX: &str = "index/";
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[arg(short, long, default_value_t = String::from(PARQUET_FOLDER))]
+    folder: String,
+
+    /// Credentials file, defaults to `credentials` in the data folder
+    #[arg(short, long)]
+    credentials: Option<PathBuf>,
+
+    /// Append a new key pair to the credentials file, print it and exit
+    #[arg(long, default_value_t = false)]
+    add_credential: bool,
+
+    /// Make the key pair created by --add-credential a root credential
+    #[arg(long, default_value_t = false, requires = "add_credential")]
+    root: bool,
+
+    /// Master key file for server-side encryption, defaults to `master.key` in the data folder
+    #[arg(long)]
+    master_key_file: Option<PathBuf>,
+
+    /// Append a new master key to the key file, print its ID and exit. Running
+    /// POST /admin/rotate-keys afterwards re-wraps existing data keys with it
+    #[arg(long, default_value_t = false)]
+    add_master_key: bool,
+
+    /// PEM certificate chain, HTTPS is served on --tls-port instead of HTTP on port 80.
+    /// Certificate and key are read again on SIGHUP
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    #[arg(long, default_value_t = 443)]
+    tls_port: u16,
+
+    /// Stream plain objects through user space instead of sending them with sendfile(2)
+    /// over plain HTTP
+    #[arg(long, default_value_t = false)]
+    no_sendfile: bool,
+
+    /// Create a test CA and a certificate signed by it in `tls/` of the data folder, print
+    /// their paths and exit
+    #[arg(long, default_value_t = false)]
+    generate_certificate: bool,
+
+    /// Host names and addresses the certificate of --generate-certificate is valid for
+    #[arg(long, value_delimiter = ',', default_value = "localhost,127.0.0.1")]
+    certificate_hosts: Vec<String>,
+
+    /// Seconds an unreferenced file has to be untouched before GC deletes it
+    #[arg(long, default_value_t = 3600)]
+    gc_grace_period: u64,
+
+    /// Seconds between background GC runs, 0 disables them
+    #[arg(long, default_value_t = 900)]
+    gc_interval: u64,
+
+    /// Only report what background GC would reclaim
+    #[arg(long, default_value_t = false)]
+    gc_dry_run: bool,
+
+    /// Seconds between lifecycle rule evaluations, 0 disables them
+    #[arg(long, default_value_t = 3600)]
+    lifecycle_interval: u64,
+
+    /// Only report what the lifecycle rules would delete
+    #[arg(long, default_value_t = false)]
+    lifecycle_dry_run: bool,
+
+    /// Uploads received at the same time, 0 is unlimited
+    #[arg(long, default_value_t = 256)]
+    max_uploads: usize,
+
+    /// Bytes of all uploads received at the same time, 0 is unlimited
+    #[arg(long, default_value_t = 4 << 30)]
+    max_upload_bytes: u64,
+
+    /// Uploads a single client sends at the same time, 0 is unlimited
+    #[arg(long, default_value_t = 64)]
+    max_uploads_per_client: usize,
+
+    /// Bytes of the uploads a single client sends at the same time, 0 is unlimited
+    #[arg(long, default_value_t = 1 << 30)]
+    max_upload_bytes_per_client: u64,
+
+    /// Milliseconds an upload waits for admission before it is rejected with 503 SlowDown
+    #[arg(long, default_value_t = 5000)]
+    admission_queue_timeout: u64,
+
+    /// Seconds in-flight transfers get to finish after SIGTERM or SIGINT
+    #[arg(long, default_value_t = 30)]
+    shutdown_timeout: u64,
+
+    /// Seconds clients are told to wait before retrying a rejected upload
+    #[arg(long, default_value_t = 1)]
+    retry_after: u64,
+
+    /// Where blob data is kept: files through the page cache (`tokio`), files with io_uring
+    /// and O_DIRECT (`uring`) or in memory (`memory`, lost on restart)
+    #[arg(long, value_enum, default_value_t = storage::Backend::Tokio)]
+    storage_backend: storage::Backend,
+
+    /// Objects up to this many bytes are packed into segment files, 0 disables packing
+    #[arg(long, default_value_t = 0)]
+    pack_threshold: u64,
+
+    /// Bytes after which a new segment file is started
+    #[arg(long, default_value_t = 256 << 20)]
+    pack_segment_size: u64,
+
+    /// Segments with a smaller share of live data are compacted
+    #[arg(long, default_value_t = 0.5)]
+    pack_min_live_ratio: f64,
+
+    /// Seconds between segment compactions, 0 disables them
+    #[arg(long, default_value_t = 600)]
+    compaction_interval: u64,
+
+    /// Batch the fsyncs of data files and the metadata log across concurrent requests
+    #[arg(long, default_value_t = false)]
+    group_commit: bool,
+
+    /// Microseconds a group commit batch waits for more requests
+    #[arg(long, default_value_t = 2000)]
+    group_commit_window: u64,
+
+    /// Entries after which a group commit batch is synced without waiting out the window
+    #[arg(long, default_value_t = 128)]
+    group_commit_max_batch: usize,
+
+    /// Bytes of object data cached in memory for repeated reads, 0 disables the cache
+    #[arg(long, default_value_t = 256 << 20)]
+    cache_size: u64,
+
+    /// Reads of more bytes bypass the cache
+    #[arg(long, default_value_t = 16 << 20)]
+    cache_max_entry: u64,
+
+    /// Bytes of an upload with x-mvp-bloom-filter-columns, which is rewritten in memory.
+    /// Larger ones are rejected with 413
+    #[arg(long, default_value_t = 256 << 20)]
+    max_bloom_rewrite: u64,
+
+    /// Files a query scans at the same time
+    #[arg(long, default_value_t = 8)]
+    query_concurrency: usize,
+
+    /// Base URL of another storage node holding part of the data, aggregate queries run
+    /// on every peer and are merged here. Can be repeated
+    #[arg(long = "query-peer")]
+    query_peers: Vec<String>,
+
+    /// Access key this node signs peer requests with
+    #[arg(long, requires = "peer_secret_key")]
+    peer_access_key: Option<String>,
+
+    /// Secret key this node signs peer requests with
+    #[arg(long, requires = "peer_access_key")]
+    peer_secret_key: Option<String>,
+
+    /// Query jobs run at the same time, later ones wait until one finished
+    #[arg(long, default_value_t = 2)]
+    max_query_jobs: usize,
+
+    /// Bucket query jobs write their results to, created at startup
+    #[arg(long, default_value_t = String::from("query-results"))]
+    job_result_bucket: String,
+
+    /// Days after which a lifecycle rule on the result bucket expires job results, 0 keeps
+    /// them
+    #[arg(long, default_value_t = 7)]
+    job_result_retention_days: u64,
+
+    /// Entries of the io_uring ring, also the number of 1 MiB I/O buffers in its pool
+    #[arg(long, default_value_t = 64)]
+    uring_queue_depth: u32,
+}
+
+pub struct AppState {
+    pub folder: PathBuf,
+    pub metadata: metadata::MetadataStore,
+    pub credentials: auth::Credentials,
+    pub master_keys: encryption::MasterKeys,
+    pub gc: gc::GcConfig,
+    pub admission: Arc<admission::Admission>,
+    pub transfers: Arc<shutdown::Transfers>,
+    pub storage: Arc<dyn storage::Store>,
+    /// Batches fsyncs of concurrent requests, fsyncs are per request without it
+    pub commit: Option<Arc<commit::GroupCommit>>,
+    pub segments: segments::Segments,
+    pub cache: cache::ReadCache,
+    pub downloads: Arc<downloads::Downloads>,
+    pub bloom: bloom::BloomConfig,
+    pub query: query::QueryConfig,
+    pub queries: query::QueryMetrics,
+    pub jobs: jobs::Jobs,
+}
+
+impl AppState {
+    pub fn blob_key(&self, blob: &str) -> String {
+        format!("{BLOB_PREFIX}{}/{blob}", &blob[..2])
+    }
+
+    /// Footer index of a Parquet blob, see `parquet_index`
+    pub fn index_key(&self, blob: &str) -> String {
+        format!("{INDEX_PREFIX}{}/{blob}.json", &blob[..2])
+    }
+
+    /// Staging blob of an upload into `bucket`, grouped per bucket for lifecycle rules
+    pub fn tmp_key(&self, bucket: &str, blob: &str) -> String {
+        format!("{TMP_PREFIX}{bucket}/{blob}")
+    }
+
+    /// Makes a data file durable, batched with other requests under group commit.
+    pub async fn sync_blob(&self, key: String) -> std::io::Result<()> {
+        let Some(commit) = &self.commit else {
+            return self.storage.sync(&[key]).await;
+        };
+        let storage = Arc::clone(&self.storage);
+        commit
+            .blobs
+            .commit(key, move |mut keys| async move {
+                // Packed objects share segments
+                keys.sort_unstable();
+                keys.dedup();
+                storage.sync(&keys).await
+            })
+            .await
+    }
+
+    /// The state `args` configure around stores opened by the caller. Creates the buckets
+    /// the server relies on.
+    async fn new(
+        args: &Args,
+        folder: PathBuf,
+        credentials: auth::Credentials,
+        master_keys: encryption::MasterKeys,
+        storage: Arc<dyn storage::Store>,
+        commit: Option<Arc<commit::GroupCommit>>,
+        metadata: metadata::MetadataStore,
+    ) -> std::io::Result<Self> {
+        let segments = segments::Segments::open(
+            &*storage,
+            segments::PackConfig {
+                threshold: args.pack_threshold,
+                segment_size: args.pack_segment_size,
+                min_live_ratio: args.pack_min_live_ratio,
+                compaction_interval: Duration::from_secs(args.compaction_interval),
+            },
+        )
+        .await?;
+
+        // The legacy /parquet/{file_name} routes live on in this bucket
+        metadata
+            .create_bucket(
+                metadata::DEFAULT_BUCKET,
+                metadata::BucketConfig {
+                    created: metadata::unix_now(),
+                    ..Default::default()
+                },
+            )
+            .await?;
+        let job_config = jobs::JobConfig {
+            result_bucket: args.job_result_bucket.clone(),
+            retention_days: args.job_result_retention_days,
+        };
+        jobs::prepare_result_bucket(&metadata, &job_config).await?;
+
+        Ok(AppState {
+            metadata,
+            credentials,
+            master_keys,
+            gc: gc::GcConfig {
+                grace_period: Duration::from_secs(args.gc_grace_period),
+                interval: Duration::from_secs(args.gc_interval),
+                dry_run: args.gc_dry_run,
+            },
+            admission: admission::Admission::new(admission::AdmissionConfig {
+                max_uploads: args.max_uploads,
+                max_upload_bytes: args.max_upload_bytes,
+                max_uploads_per_client: args.max_uploads_per_client,
+                max_upload_bytes_per_client: args.max_upload_bytes_per_client,
+                queue_timeout: Duration::from_millis(args.admission_queue_timeout),
+                retry_after: Duration::from_secs(args.retry_after),
+            }),
+            transfers: Arc::default(),
+            storage,
+            commit,
+            segments,
+            cache: cache::ReadCache::new(cache::CacheConfig {
+                budget: args.cache_size,
+                max_entry: args.cache_max_entry,
+            }),
+            downloads: Arc::default(),
+            bloom: bloom::BloomConfig {
+                max_rewrite: args.max_bloom_rewrite,
+            },
+            queries: query::QueryMetrics::default(),
+            query: query::QueryConfig {
+                concurrency: args.query_concurrency.max(1),
+                peers: args.query_peers.clone(),
+                peer_credential: args
+                    .peer_access_key
+                    .clone()
+                    .zip(args.peer_secret_key.clone()),
+                client: reqwest::Client::new(),
+            },
+            jobs: jobs::Jobs::new(job_config, args.max_query_jobs),
+            folder,
+        })
+    }
+
+    /// State with the default configuration that keeps blobs and metadata in memory, for
+    /// running the HTTP layer in tests.
+    #[cfg(test)]
+    pub async fn in_memory(credentials: auth::Credentials) -> std::io::Result<Self> {
+        let args = Args::parse_from(["server", "--storage-backend", "memory"]);
+        let storage = storage::open(storage::Backend::Memory, std::path::Path::new(""), 0)?;
+        Self::new(
+            &args,
+            PathBuf::new(),
+            credentials,
+            encryption::MasterKeys::default(),
+            storage,
+            None,
+            metadata::MetadataStore::in_memory(),
+        )
+        .await
+    }
+
+    /// Deletes the data of a version that is no longer referenced by the index.
+    pub async fn remove_blob(&self, meta: &metadata::ObjectMeta) {
+        if meta.delete_marker {
+            return;
+        }
+        self.cache.invalidate(&meta.blob);
+        // A leftover is not fatal, GC removes it later
+        let _ = self.storage.delete(&self.index_key(&meta.blob)).await;
+        let _ = if meta.packed {
+            self.segments.delete(&*self.storage, &meta.blob).await
+        } else {
+            self.storage.delete(&self.blob_key(&meta.blob)).await
+        };
+    }
+}
+
+/// The routes and middleware the server answers requests with.
+fn app(
+    state: web::Data<AppState>,
+) -> App<
+    impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<impl MessageBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >,
+> {
+    App::new()
+        .app_data(state)
+        .wrap_fn(|mut req, srv| {
+            auth::check_content_sha256(&mut req);
+            srv.call(req)
+        })
+        .configure(routes::init_routes)
+}
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    HttpServer::new(|| {
-        App::new()
-            .configure(routes::init_routes) 
-    })
-    .bind("0.0.0.0:80")?
-    .run()
-    .await
-}
+    // Errors logged through `log` are shown unless RUST_LOG filters them out
+    env_logger::init();
+    let args = Args::parse();
+    let folder = PathBuf::from(&args.folder);
+    let credentials_path = args
+        .credentials
+        .clone()
+        .unwrap_or_else(|| folder.join("credentials"));
+
+    let master_key_path = args
+        .master_key_file
+        .clone()
+        .unwrap_or_else(|| folder.join("master.key"));
+
+    if args.add_master_key {
+        std::fs::create_dir_all(&folder)?;
+        let key_id = encryption::MasterKeys::generate(&master_key_path)?;
+        println!("Master key: {key_id}");
+        return Ok(());
+    }
 
+    if args.generate_certificate {
+        let generated = tls::generate(
+            &folder.join(tls::CERTIFICATE_FOLDER),
+            &args.certificate_hosts,
+        )?;
+        println!("CA certificate: {:?}", generated.ca);
+        println!("Certificate: {:?}", generated.cert);
+        println!("Key: {:?}", generated.key);
+        return Ok(());
+    }
+
+    if args.add_credential {
+        std::fs::create_dir_all(&folder)?;
+        let (access_key, secret) = auth::Credentials::generate(&credentials_path, args.root)?;
+        println!("Access key: {access_key}");
+        println!("Secret key: {secret}");
+        return Ok(());
+    }
+
+    let credentials = auth::Credentials::load(&credentials_path)?;
+    if credentials.is_empty() {
+        eprintln!(
+            "No credentials in {:?}, only anonymous reads are possible. Create some with --add-credential",
+            credentials_path
+        );
+    }
+
+    let master_keys = encryption::MasterKeys::load(&master_key_path)?;
+    if master_keys.is_empty() {
+        println!(
+            "No master key in {:?}, server-side encryption is unavailable. Create one with --add-master-key",
+            master_key_path
+        );
+    }
+
+    let storage =
+        storage::open(args.storage_backend, &folder, args.uring_queue_depth).map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!(
+                    "Failed to set up the {:?} storage backend: {e}",
+                    args.storage_backend
+                ),
+            )
+        })?;
+
+    let commit = args.group_commit.then(|| {
+        commit::GroupCommit::new(commit::CommitConfig {
+            window: Duration::from_micros(args.group_commit_window),
+            max_batch: args.group_commit_max_batch.max(1),
+        })
+    });
+    let metadata = metadata::MetadataStore::open(
+        &folder.join("meta"),
+        commit.as_ref().map(|commit| Arc::clone(&commit.log)),
+    )?;
+    let state = web::Data::new(
+        AppState::new(
+            &args,
+            folder,
+            credentials,
+            master_keys,
+            storage,
+            commit,
+            metadata,
+        )
+        .await?,
+    );
+    gc::spawn(state.clone());
+    segments::spawn(state.clone());
+    lifecycle::spawn(
+        state.clone(),
+        Duration::from_secs(args.lifecycle_interval),
+        args.lifecycle_dry_run,
+    );
+
+    let app_state = state.clone();
+    let server = HttpServer::new(move || app(app_state.clone()))
+        .shutdown_signal(shutdown::signal(
+            state.transfers.clone(),
+            Duration::from_secs(args.shutdown_timeout),
+        ))
+        .shutdown_timeout(args.shutdown_timeout);
+    let server = match args.tls_cert.zip(args.tls_key) {
+        Some((cert, key)) => {
+            let resolver = tls::CertificateResolver::load(&cert, &key)?;
+            tls::spawn_reload(Arc::clone(&resolver));
+            server.bind_rustls_0_23(("0.0.0.0", args.tls_port), tls::server_config(resolver)?)?
+        }
+        None if args.no_sendfile => server.bind("0.0.0.0:80")?,
+        // Sendfile bodies write to the socket themselves, see `sendfile`
+        None => server
+            .on_connect(sendfile::on_connect)
+            .h1_write_buffer_size(1)
+            .bind("0.0.0.0:80")?,
+    };
+    server.run().await?;
+
+    shutdown::finish(&state).await
+}
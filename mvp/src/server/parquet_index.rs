@@ -0,0 +1,166 @@
+//! Ingest-time index of Parquet footers.
+//!
+//! Clients that only want the schema or statistics of a file should not have to download
+//! it. While a Parquet file is uploaded its last bytes are kept, and once it is complete
+//! its footer is decoded and stored as JSON next to the blob, which is what
+//! `GET /{bucket}/{key}?metadata` serves. Files without an index entry, such as encrypted
+//! or packed ones and those uploaded before it existed, have their footer read and decoded
+//! on request instead.
+
+use parquet::data_type::ByteArray;
+use parquet::errors::Result;
+use parquet::file::metadata::{ColumnChunkMetaData, ParquetMetaDataReader};
+use parquet::file::statistics::Statistics;
+use serde_json::{json, Value};
+
+use crate::cache::{parquet_footer_len, PARQUET_TAIL};
+
+const PARQUET_MAGIC: &[u8; 4] = b"PAR1";
+/// Footers up to this size are indexed at ingest, larger ones are decoded on request
+pub const MAX_INDEXED_FOOTER: usize = 1 << 20;
+
+/// The last bytes of an upload, enough to hold a footer of up to `MAX_INDEXED_FOOTER`.
+#[derive(Default)]
+pub struct Tail {
+    data: Vec<u8>,
+    /// Whether the upload starts like a Parquet file, known after its first bytes
+    parquet: Option<bool>,
+}
+
+impl Tail {
+    pub fn push(&mut self, chunk: &[u8]) {
+        if self.parquet == Some(false) {
+            return;
+        }
+        self.data.extend_from_slice(chunk);
+        if self.parquet.is_none() && self.data.len() >= PARQUET_MAGIC.len() {
+            let parquet = self.data.starts_with(PARQUET_MAGIC);
+            self.parquet = Some(parquet);
+            if !parquet {
+                self.data = Vec::new();
+                return;
+            }
+        }
+        // Trimmed in bulk so every byte is moved at most once more
+        let keep = MAX_INDEXED_FOOTER + PARQUET_TAIL as usize;
+        if self.data.len() > 2 * keep {
+            self.data.drain(..self.data.len() - keep);
+        }
+    }
+
+    /// The footer of the upload without its tail, if it is a Parquet file whose footer
+    /// was kept.
+    pub fn footer(&self) -> Option<&[u8]> {
+        if self.parquet != Some(true) {
+            return None;
+        }
+        let tail_start = self.data.len().checked_sub(PARQUET_TAIL as usize)?;
+        let footer_len = parquet_footer_len(&self.data[tail_start..])? as usize;
+        self.data
+            .get(tail_start.checked_sub(footer_len)?..tail_start)
+    }
+}
+
+/// The schema, key-value metadata and row groups described by `footer`, a Parquet footer
+/// without its tail.
+pub fn describe(footer: &[u8]) -> Result<Value> {
+    let metadata = ParquetMetaDataReader::decode_metadata(footer)?;
+    let file = metadata.file_metadata();
+    let schema = file.schema_descr();
+
+    let mut message = Vec::new();
+    parquet::schema::printer::print_schema(&mut message, schema.root_schema());
+    let columns: Vec<Value> = schema
+        .columns()
+        .iter()
+        .map(|column| {
+            json!({
+                "path": column.path().string(),
+                "physical_type": column.physical_type().to_string(),
+                "logical_type": column.logical_type().map(|logical| format!("{logical:?}")),
+                "converted_type": column.converted_type().to_string(),
+                "max_definition_level": column.max_def_level(),
+                "max_repetition_level": column.max_rep_level(),
+            })
+        })
+        .collect();
+    let key_value_metadata: serde_json::Map<String, Value> = file
+        .key_value_metadata()
+        .into_iter()
+        .flatten()
+        .map(|entry| (entry.key.clone(), json!(entry.value)))
+        .collect();
+
+    let row_groups: Vec<Value> = metadata
+        .row_groups()
+        .iter()
+        .map(|group| {
+            json!({
+                "num_rows": group.num_rows(),
+                "file_offset": group.file_offset(),
+                "total_byte_size": group.total_byte_size(),
+                "compressed_size": group.compressed_size(),
+                "columns": group.columns().iter().map(describe_column).collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+
+    Ok(json!({
+        "version": file.version(),
+        "created_by": file.created_by(),
+        "num_rows": file.num_rows(),
+        "footer_length": footer.len(),
+        "schema": String::from_utf8_lossy(&message),
+        "columns": columns,
+        "key_value_metadata": key_value_metadata,
+        "row_groups": row_groups,
+    }))
+}
+
+fn describe_column(column: &ColumnChunkMetaData) -> Value {
+    json!({
+        "path": column.column_path().string(),
+        "physical_type": column.column_type().to_string(),
+        "codec": column.compression().to_string(),
+        "encodings": column.encodings().iter().map(ToString::to_string).collect::<Vec<_>>(),
+        "num_values": column.num_values(),
+        "file_offset": column.file_offset(),
+        "data_page_offset": column.data_page_offset(),
+        "dictionary_page_offset": column.dictionary_page_offset(),
+        "compressed_size": column.compressed_size(),
+        "uncompressed_size": column.uncompressed_size(),
+        "statistics": column.statistics().map(describe_statistics),
+        "bloom_filter_offset": column.bloom_filter_offset(),
+        "bloom_filter_length": column.bloom_filter_length(),
+    })
+}
+
+fn describe_statistics(statistics: &Statistics) -> Value {
+    fn bytes(value: &ByteArray) -> Value {
+        match value.as_utf8() {
+            Ok(text) => json!(text),
+            Err(_) => json!(hex::encode(value.data())),
+        }
+    }
+    let (min, max) = match statistics {
+        Statistics::Boolean(s) => (s.min_opt().map(|v| json!(v)), s.max_opt().map(|v| json!(v))),
+        Statistics::Int32(s) => (s.min_opt().map(|v| json!(v)), s.max_opt().map(|v| json!(v))),
+        Statistics::Int64(s) => (s.min_opt().map(|v| json!(v)), s.max_opt().map(|v| json!(v))),
+        Statistics::Int96(s) => (
+            s.min_opt().map(|v| json!(v.to_string())),
+            s.max_opt().map(|v| json!(v.to_string())),
+        ),
+        Statistics::Float(s) => (s.min_opt().map(|v| json!(v)), s.max_opt().map(|v| json!(v))),
+        Statistics::Double(s) => (s.min_opt().map(|v| json!(v)), s.max_opt().map(|v| json!(v))),
+        Statistics::ByteArray(s) => (s.min_opt().map(bytes), s.max_opt().map(bytes)),
+        Statistics::FixedLenByteArray(s) => {
+            (s.min_opt().map(|v| bytes(v)), s.max_opt().map(|v| bytes(v)))
+        }
+    };
+    json!({
+        "min": min,
+        "max": max,
+        "null_count": statistics.null_count_opt(),
+        "distinct_count": statistics.distinct_count_opt(),
+    })
+}
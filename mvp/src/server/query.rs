@@ -0,0 +1,2319 @@
+//! Near-storage queries over the Parquet files below a prefix.
+//!
+//! Datasets are laid out as many files under Hive-style prefixes such as
+//! `table/date=2024-01-01/part-3.parquet`. A query names a prefix, the columns to return
+//! and predicates that all returned rows match. Files are pruned by the partition columns
+//! in their key first, then files and row groups by the min/max statistics of their footer
+//! index. The remaining files are scanned concurrently, reading only the column chunks of
+//! the projection and the filter, and their rows are merged into one stream of JSON lines
+//! in no particular order.
+//!
+//! Aggregate queries keep only mergeable per-group state instead of rows, one set of groups
+//! per file merged on the node. A node with peers places every key below the prefix on one
+//! of the nodes holding it, sends the peers the query for the partial groups of their keys
+//! and merges those with its own, so only groups cross the network.
+//!
+//! Queries with a limit keep only the best rows of each file in a bounded heap and skip
+//! row groups whose statistics rule out a place among them.
+
+use actix_web::error::ErrorInternalServerError;
+use actix_web::web::{self, Bytes};
+use arrow_array::cast::AsArray;
+use arrow_array::types::{
+    Float32Type, Float64Type, Int16Type, Int32Type, Int64Type, Int8Type, UInt16Type, UInt32Type,
+    UInt64Type, UInt8Type,
+};
+use arrow_array::{Array, BooleanArray, RecordBatch};
+use arrow_cast::display::{ArrayFormatter, FormatOptions};
+use arrow_schema::DataType;
+use futures::future::BoxFuture;
+use futures::stream::{self, BoxStream};
+use futures::{FutureExt, StreamExt, TryStreamExt};
+use parquet::arrow::arrow_reader::{ArrowReaderMetadata, ArrowReaderOptions};
+use parquet::arrow::async_reader::AsyncFileReader;
+use parquet::arrow::{ParquetRecordBatchStreamBuilder, ProjectionMask};
+use parquet::errors::{ParquetError, Result};
+use parquet::file::metadata::{ParquetMetaData, ParquetMetaDataReader};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fmt::Write;
+use std::io;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+
+use crate::metadata::ObjectMeta;
+use crate::{bloom, handlers, signing, AppState};
+
+/// Query string of the requests a coordinator sends to its peers
+pub const PARTIAL_QUERY: &str = "partial&query";
+/// Name of this node in reports and placement
+pub const LOCAL_NODE: &str = "local";
+/// Rows per record batch read from a file
+const BATCH_ROWS: usize = 8192;
+/// Converted types whose statistics order like the values returned for them
+const COMPARABLE_STATISTICS: [&str; 6] = ["NONE", "UTF8", "INT_8", "INT_16", "INT_32", "INT_64"];
+
+pub struct QueryConfig {
+    /// Files a query scans at the same time
+    pub concurrency: usize,
+    /// Base URLs of the other storage nodes aggregate queries are shipped to
+    pub peers: Vec<String>,
+    /// Access and secret key of this node on its peers
+    pub peer_credential: Option<(String, String)>,
+    pub client: reqwest::Client,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Query {
+    /// Files below this prefix are queried
+    #[serde(default)]
+    pub prefix: String,
+    /// Only these keys below the prefix are queried, set by a coordinator to the files it
+    /// placed on a node
+    pub keys: Option<Vec<String>>,
+    /// Columns to return, all of them if missing
+    pub columns: Option<Vec<String>>,
+    /// Predicates every returned row matches
+    #[serde(default)]
+    pub filter: Vec<Predicate>,
+    /// Columns whose values form the groups of `aggregates`
+    #[serde(default)]
+    pub group_by: Vec<String>,
+    /// Returns one row per group with these instead of the matching rows
+    #[serde(default)]
+    pub aggregates: Vec<Aggregate>,
+    /// Columns the rows are sorted by, nulls last. Rows need a `limit` as well, groups are
+    /// sorted by their output columns
+    #[serde(default)]
+    pub order_by: Vec<OrderBy>,
+    /// Rows or groups returned at most
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    /// Whether the query returns groups rather than rows.
+    pub fn aggregated(&self) -> bool {
+        !self.group_by.is_empty() || !self.aggregates.is_empty()
+    }
+
+    /// Whether the scan reads `column`.
+    pub fn needs(&self, column: &str) -> bool {
+        let output = match !self.aggregated() {
+            true => {
+                self.columns
+                    .as_ref()
+                    .is_none_or(|columns| columns.iter().any(|c| c == column))
+                    || self.order_by.iter().any(|order| order.column == column)
+            }
+            false => {
+                self.group_by.iter().any(|c| c == column)
+                    || self
+                        .aggregates
+                        .iter()
+                        .any(|aggregate| aggregate.column.as_deref() == Some(column))
+            }
+        };
+        output
+            || self
+                .filter
+                .iter()
+                .any(|predicate| predicate.column == column)
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Predicate {
+    pub column: String,
+    pub op: Op,
+    pub value: Value,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct OrderBy {
+    pub column: String,
+    #[serde(default)]
+    pub descending: bool,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Aggregate {
+    pub function: Function,
+    /// The column aggregated, `count` without one counts rows
+    pub column: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Function {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+impl Aggregate {
+    /// The column of the result, such as `sum(amount)` or `count(*)`.
+    pub fn name(&self) -> String {
+        let function = match self.function {
+            Function::Count => "count",
+            Function::Sum => "sum",
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::Avg => "avg",
+        };
+        format!("{function}({})", self.column.as_deref().unwrap_or("*"))
+    }
+}
+
+impl Predicate {
+    /// Whether `value` matches, never the case for nulls and values of another type.
+    pub fn matches(&self, value: &Value) -> bool {
+        compare(value, &self.value).is_some_and(|ordering| match self.op {
+            Op::Eq => ordering.is_eq(),
+            Op::Ne => ordering.is_ne(),
+            Op::Lt => ordering.is_lt(),
+            Op::Le => ordering.is_le(),
+            Op::Gt => ordering.is_gt(),
+            Op::Ge => ordering.is_ge(),
+        })
+    }
+
+    /// Whether a column whose values lie between `min` and `max` may hold a match.
+    fn may_match(&self, min: &Value, max: &Value) -> bool {
+        let (Some(min), Some(max)) = (compare(min, &self.value), compare(max, &self.value)) else {
+            return true;
+        };
+        match self.op {
+            Op::Eq => min.is_le() && max.is_ge(),
+            Op::Ne => !(min.is_eq() && max.is_eq()),
+            Op::Lt => min.is_lt(),
+            Op::Le => min.is_le(),
+            Op::Gt => max.is_gt(),
+            Op::Ge => max.is_ge(),
+        }
+    }
+}
+
+/// Orders JSON scalars of the same kind, numbers by value.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => match (a.as_i64(), b.as_i64()) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        },
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/// Orders values by `compare` with nulls last, values of different kinds are equal.
+fn sort_order(a: &Value, b: &Value) -> Ordering {
+    compare(a, b).unwrap_or_else(|| a.is_null().cmp(&b.is_null()))
+}
+
+/// Orders rows by their sort keys, the values of the `order_by` columns.
+fn key_order(a: &[Value], b: &[Value], order_by: &[OrderBy]) -> Ordering {
+    a.iter()
+        .zip(b)
+        .zip(order_by)
+        .map(
+            |((a, b), order)| match (order.descending, a.is_null() || b.is_null()) {
+                (true, false) => sort_order(b, a),
+                _ => sort_order(a, b),
+            },
+        )
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
+
+/// The Hive-style `name=value` directories in `key`.
+pub fn partitions(key: &str) -> Vec<(String, String)> {
+    let directories = key
+        .rsplit_once('/')
+        .map_or("", |(directories, _)| directories);
+    directories
+        .split('/')
+        .filter_map(|directory| directory.split_once('='))
+        .filter(|(name, _)| !name.is_empty())
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect()
+}
+
+/// A partition value typed like `like`, the value it is compared with.
+fn partition_value(raw: &str, like: &Value) -> Value {
+    let parsed = match like {
+        Value::Number(_) => raw
+            .parse::<i64>()
+            .map(Value::from)
+            .or_else(|_| raw.parse::<f64>().map(Value::from))
+            .ok(),
+        Value::Bool(_) => raw.parse::<bool>().ok().map(Value::from),
+        _ => None,
+    };
+    parsed.unwrap_or_else(|| Value::from(raw))
+}
+
+/// What pruning left of the files below the prefix.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Pruning {
+    pub files: usize,
+    pub not_parquet: usize,
+    pub pruned_by_partition: usize,
+    pub pruned_by_statistics: usize,
+    #[serde(default)]
+    pub pruned_by_bloom_filter: usize,
+    /// Row groups left to scan
+    pub row_groups: usize,
+    /// Row groups pruned by statistics
+    pub row_groups_pruned: usize,
+    #[serde(default)]
+    pub row_groups_pruned_by_bloom_filter: usize,
+}
+
+/// Row groups of all queries by what became of them.
+#[derive(Default)]
+pub struct QueryMetrics {
+    queries: AtomicU64,
+    scanned: AtomicU64,
+    pruned_by_statistics: AtomicU64,
+    pruned_by_bloom_filter: AtomicU64,
+    skipped_by_top_k: AtomicU64,
+}
+
+impl QueryMetrics {
+    fn record(&self, pruning: &Pruning) {
+        self.queries.fetch_add(1, AtomicOrdering::Relaxed);
+        for (counter, row_groups) in [
+            (&self.scanned, pruning.row_groups),
+            (&self.pruned_by_statistics, pruning.row_groups_pruned),
+            (
+                &self.pruned_by_bloom_filter,
+                pruning.row_groups_pruned_by_bloom_filter,
+            ),
+        ] {
+            counter.fetch_add(row_groups as u64, AtomicOrdering::Relaxed);
+        }
+    }
+
+    /// Queries and their row groups in the Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let mut out = String::new();
+        let queries = self.queries.load(AtomicOrdering::Relaxed);
+        let _ = writeln!(
+            out,
+            "# HELP mvp_queries_total Queries planned\n# TYPE mvp_queries_total counter\nmvp_queries_total {queries}"
+        );
+        let name = "mvp_query_row_groups_total";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Row groups of queries by outcome\n# TYPE {name} counter"
+        );
+        for (outcome, counter) in [
+            ("scanned", &self.scanned),
+            ("pruned_by_statistics", &self.pruned_by_statistics),
+            ("pruned_by_bloom_filter", &self.pruned_by_bloom_filter),
+            ("skipped_by_top_k", &self.skipped_by_top_k),
+        ] {
+            let value = counter.load(AtomicOrdering::Relaxed);
+            let _ = writeln!(out, "{name}{{outcome=\"{outcome}\"}} {value}");
+        }
+        out
+    }
+}
+
+/// A file to scan and the row groups of it that may hold matches.
+pub struct FilePlan {
+    pub key: String,
+    pub meta: ObjectMeta,
+    pub partitions: Vec<(String, String)>,
+    pub row_groups: Vec<usize>,
+    /// Min and max of the first `order_by` column in each of `row_groups`, if known
+    pub sort_ranges: Vec<Option<(Value, Value)>>,
+}
+
+impl FilePlan {
+    fn partition(&self, column: &str) -> Option<&str> {
+        self.partitions
+            .iter()
+            .find(|(name, _)| name == column)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// The step of planning that ruled a file or row group out.
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PrunedBy {
+    Partition,
+    NotParquet,
+    Statistics,
+    BloomFilter,
+}
+
+/// What planning decided for a file below the prefix, see `explain`.
+pub struct Considered {
+    pub key: String,
+    pub partitions: Vec<(String, String)>,
+    pub size: u64,
+    pub pruned_by: Option<PrunedBy>,
+    /// Description of the file by `parquet_index::describe`, if it is a Parquet file
+    pub description: Option<Value>,
+    /// Row groups statistics left
+    pub after_statistics: Vec<usize>,
+    /// Row groups left to scan
+    pub row_groups: Vec<usize>,
+    /// Bytes of bloom filters read to plan the file
+    pub bloom_filter_bytes: u64,
+}
+
+/// The files below the prefix of `query` in `bucket` that may hold matches.
+pub async fn plan(
+    state: &web::Data<AppState>,
+    bucket: &str,
+    query: &Query,
+) -> std::result::Result<(Vec<FilePlan>, Pruning), actix_web::Error> {
+    let planned = plan_considering(state, bucket, query, None).await?;
+    state.queries.record(&planned.1);
+    Ok(planned)
+}
+
+/// Like `plan`, also recording the decision for every file in `considered` if given.
+pub async fn plan_considering(
+    state: &web::Data<AppState>,
+    bucket: &str,
+    query: &Query,
+    mut considered: Option<&mut Vec<Considered>>,
+) -> std::result::Result<(Vec<FilePlan>, Pruning), actix_web::Error> {
+    let mut consider = |file: &FilePlan,
+                        pruned_by: Option<PrunedBy>,
+                        description: Option<Value>,
+                        after_statistics: Vec<usize>,
+                        bloom_filter_bytes: u64| {
+        if let Some(considered) = considered.as_deref_mut() {
+            considered.push(Considered {
+                key: file.key.clone(),
+                partitions: file.partitions.clone(),
+                size: file.meta.size,
+                pruned_by,
+                description,
+                after_statistics,
+                row_groups: file.row_groups.clone(),
+                bloom_filter_bytes,
+            });
+        }
+    };
+    let mut pruning = Pruning::default();
+    let mut candidates = Vec::new();
+    let placed: Option<HashSet<&str>> = query
+        .keys
+        .as_ref()
+        .map(|keys| keys.iter().map(String::as_str).collect());
+    for (key, meta) in state.metadata.list(bucket, &query.prefix) {
+        if placed
+            .as_ref()
+            .is_some_and(|placed| !placed.contains(key.as_str()))
+        {
+            continue;
+        }
+        pruning.files += 1;
+        let partitions = partitions(&key);
+        let matches = query.filter.iter().all(|predicate| {
+            partitions
+                .iter()
+                .find(|(name, _)| *name == predicate.column)
+                .is_none_or(|(_, raw)| predicate.matches(&partition_value(raw, &predicate.value)))
+        });
+        let file = FilePlan {
+            key,
+            meta,
+            partitions,
+            row_groups: Vec::new(),
+            sort_ranges: Vec::new(),
+        };
+        if !matches {
+            pruning.pruned_by_partition += 1;
+            consider(&file, Some(PrunedBy::Partition), None, Vec::new(), 0);
+            continue;
+        }
+        candidates.push(file);
+    }
+
+    let descriptions: Vec<_> = stream::iter(candidates)
+        .map(|file| async move {
+            let object = format!("{bucket}/{}", file.key);
+            let description = handlers::parquet_description(state, &object, &file.key, &file.meta)
+                .await?
+                .map(|description| serde_json::from_slice::<Value>(&description))
+                .transpose()
+                .map_err(actix_web::error::ErrorInternalServerError)?;
+            Ok::<_, actix_web::Error>((file, description))
+        })
+        .buffered(state.query.concurrency)
+        .try_collect()
+        .await?;
+
+    let mut candidates = Vec::new();
+    for (mut file, description) in descriptions {
+        let Some(description) = description else {
+            pruning.not_parquet += 1;
+            consider(&file, Some(PrunedBy::NotParquet), None, Vec::new(), 0);
+            continue;
+        };
+        let filter: Vec<&Predicate> = query
+            .filter
+            .iter()
+            .filter(|predicate| file.partition(&predicate.column).is_none())
+            .collect();
+        let total = description["row_groups"].as_array().map_or(0, Vec::len);
+        file.row_groups = row_groups(&description, &filter);
+        pruning.row_groups_pruned += total - file.row_groups.len();
+        if file.row_groups.is_empty() {
+            pruning.pruned_by_statistics += 1;
+            consider(
+                &file,
+                Some(PrunedBy::Statistics),
+                Some(description),
+                Vec::new(),
+                0,
+            );
+            continue;
+        }
+        candidates.push((file, description));
+    }
+
+    let lookups: Vec<&Predicate> = query
+        .filter
+        .iter()
+        .filter(|predicate| matches!(predicate.op, Op::Eq))
+        .collect();
+    let candidates: Vec<_> = stream::iter(candidates)
+        .map(|(mut file, description)| {
+            let lookups: Vec<&Predicate> = lookups
+                .iter()
+                .filter(|predicate| file.partition(&predicate.column).is_none())
+                .copied()
+                .collect();
+            async move {
+                let after_statistics = file.row_groups.clone();
+                let mut bloom_filter_bytes = 0;
+                if may_have_bloom_filters(&description, &file, &lookups) {
+                    (file.row_groups, bloom_filter_bytes) =
+                        bloom_filter_row_groups(state, bucket, &file, &lookups)
+                            .await
+                            .map_err(|e| {
+                                ErrorInternalServerError(format!(
+                                    "Failed to read bloom filters: {e}"
+                                ))
+                            })?;
+                }
+                Ok::<_, actix_web::Error>((file, description, after_statistics, bloom_filter_bytes))
+            }
+        })
+        .buffered(state.query.concurrency)
+        .try_collect()
+        .await?;
+
+    let mut plans = Vec::new();
+    for (mut file, description, after_statistics, bloom_filter_bytes) in candidates {
+        pruning.row_groups_pruned_by_bloom_filter += after_statistics.len() - file.row_groups.len();
+        if file.row_groups.is_empty() {
+            pruning.pruned_by_bloom_filter += 1;
+            consider(
+                &file,
+                Some(PrunedBy::BloomFilter),
+                Some(description),
+                after_statistics,
+                bloom_filter_bytes,
+            );
+            continue;
+        }
+        pruning.row_groups += file.row_groups.len();
+        file.sort_ranges = match query.order_by.first().filter(|_| !query.aggregated()) {
+            Some(order) => sort_ranges(&description, &file, &order.column),
+            None => vec![None; file.row_groups.len()],
+        };
+        consider(
+            &file,
+            None,
+            Some(description),
+            after_statistics,
+            bloom_filter_bytes,
+        );
+        plans.push(file);
+    }
+    Ok((plans, pruning))
+}
+
+/// Whether a chunk of the `lookups` columns in the row groups of `file` to scan has a bloom
+/// filter or its description, written before they were indexed, does not tell.
+fn may_have_bloom_filters(description: &Value, file: &FilePlan, lookups: &[&Predicate]) -> bool {
+    file.row_groups.iter().any(|&index| {
+        description["row_groups"][index]["columns"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|chunk| {
+                lookups
+                    .iter()
+                    .any(|predicate| chunk["path"] == predicate.column.as_str())
+            })
+            .any(|chunk| chunk.get("bloom_filter_offset") != Some(&Value::Null))
+    })
+}
+
+/// The row groups of `file` to scan whose bloom filters may hold the values `lookups` ask
+/// for, all of them for columns without bloom filters, and the bytes of filters read.
+async fn bloom_filter_row_groups(
+    state: &web::Data<AppState>,
+    bucket: &str,
+    file: &FilePlan,
+    lookups: &[&Predicate],
+) -> Result<(Vec<usize>, u64)> {
+    let object = format!("{bucket}/{}", file.key);
+    let metadata = reader_metadata(state, &object, &file.meta).await?;
+    let parquet = Arc::clone(metadata.metadata());
+    let counters = Arc::new(ScanCounters::default());
+    let reader = ObjectReader {
+        state: state.clone(),
+        object,
+        meta: file.meta.clone(),
+        metadata: Arc::clone(&parquet),
+        counters: Arc::clone(&counters),
+    };
+    let mut builder = ParquetRecordBatchStreamBuilder::new_with_metadata(reader, metadata);
+    let lookups: Vec<(&Predicate, usize)> = lookups
+        .iter()
+        .filter_map(|predicate| {
+            Some((*predicate, bloom::leaf_column(&parquet, &predicate.column)?))
+        })
+        .collect();
+
+    let mut row_groups = Vec::new();
+    'groups: for &group in &file.row_groups {
+        for &(predicate, leaf) in &lookups {
+            let Some(filter) = builder
+                .get_row_group_column_bloom_filter(group, leaf)
+                .await?
+            else {
+                continue;
+            };
+            let column = parquet.file_metadata().schema_descr().column(leaf);
+            if !bloom::may_contain(&filter, &column, &predicate.value) {
+                continue 'groups;
+            }
+        }
+        row_groups.push(group);
+    }
+    Ok((row_groups, counters.bytes.load(AtomicOrdering::Relaxed)))
+}
+
+/// The row groups of a file described by `parquet_index::describe` whose statistics do
+/// not rule out matches of `filter`. A column the file lacks is null in all of its rows.
+fn row_groups(description: &Value, filter: &[&Predicate]) -> Vec<usize> {
+    let leaves: Vec<&Value> = description["columns"]
+        .as_array()
+        .map(|columns| columns.iter().collect())
+        .unwrap_or_default();
+    let present = |column: &str| {
+        leaves.iter().any(|leaf| {
+            leaf["path"]
+                .as_str()
+                .is_some_and(|path| path == column || path.starts_with(&format!("{column}.")))
+        })
+    };
+    if !filter.iter().all(|predicate| present(&predicate.column)) {
+        return Vec::new();
+    }
+    let comparable = |column: &str| {
+        leaves.iter().any(|leaf| {
+            leaf["path"] == column
+                && leaf["converted_type"]
+                    .as_str()
+                    .is_some_and(|converted| COMPARABLE_STATISTICS.contains(&converted))
+        })
+    };
+
+    let groups = description["row_groups"]
+        .as_array()
+        .map_or(&[][..], Vec::as_slice);
+    (0..groups.len())
+        .filter(|&index| {
+            let group = &groups[index];
+            if group["num_rows"].as_u64() == Some(0) {
+                return false;
+            }
+            filter.iter().all(|predicate| {
+                let Some(chunk) = group["columns"].as_array().and_then(|chunks| {
+                    chunks
+                        .iter()
+                        .find(|chunk| chunk["path"] == predicate.column.as_str())
+                }) else {
+                    return true;
+                };
+                let statistics = &chunk["statistics"];
+                if statistics["null_count"].as_u64().is_some()
+                    && statistics["null_count"] == chunk["num_values"]
+                {
+                    return false;
+                }
+                !comparable(&predicate.column)
+                    || predicate.may_match(&statistics["min"], &statistics["max"])
+            })
+        })
+        .collect()
+}
+
+/// The min and max of `column` in each row group of `file` to scan, from the statistics in
+/// its description or its partition value.
+fn sort_ranges(description: &Value, file: &FilePlan, column: &str) -> Vec<Option<(Value, Value)>> {
+    if let Some(value) = file.partition(column) {
+        return vec![Some((Value::from(value), Value::from(value))); file.row_groups.len()];
+    }
+    let comparable = description["columns"].as_array().is_some_and(|columns| {
+        columns.iter().any(|leaf| {
+            leaf["path"] == column
+                && leaf["converted_type"]
+                    .as_str()
+                    .is_some_and(|converted| COMPARABLE_STATISTICS.contains(&converted))
+        })
+    });
+    file.row_groups
+        .iter()
+        .map(|&index| {
+            let chunk = description["row_groups"][index]["columns"]
+                .as_array()?
+                .iter()
+                .find(|chunk| chunk["path"] == column)
+                .filter(|_| comparable)?;
+            let (min, max) = (&chunk["statistics"]["min"], &chunk["statistics"]["max"]);
+            (!min.is_null() && !max.is_null()).then(|| (min.clone(), max.clone()))
+        })
+        .collect()
+}
+
+/// The columns a query returns from `file`, all of them and its partition columns by
+/// default.
+fn output_columns(query: &Query, fields: Vec<String>, file: &FilePlan) -> Vec<String> {
+    match &query.columns {
+        Some(columns) => columns.clone(),
+        None => fields
+            .into_iter()
+            .chain(file.partitions.iter().map(|(name, _)| name.clone()))
+            .collect(),
+    }
+}
+
+/// Scans the files of `plans` in `bucket`, `concurrency` at a time, into one stream of
+/// JSON lines.
+pub fn scan(
+    state: web::Data<AppState>,
+    bucket: String,
+    plans: Vec<FilePlan>,
+    query: Arc<Query>,
+) -> BoxStream<'static, io::Result<Bytes>> {
+    let concurrency = state.query.concurrency;
+    let counters = Arc::new(ScanCounters::default());
+    stream::iter(plans)
+        .map(move |file| {
+            let (state, bucket, query) = (state.clone(), bucket.clone(), Arc::clone(&query));
+            let counters = Arc::clone(&counters);
+            let file = Arc::new(file);
+            stream::once(async move {
+                let (batches, fields) =
+                    matching_batches(state, &bucket, Arc::clone(&file), &query, counters).await?;
+                let output = output_columns(&query, fields, &file);
+                Ok::<_, ParquetError>(
+                    batches.map_ok(move |batch| json_lines(&batch, &output, &file)),
+                )
+            })
+            .try_flatten()
+            .map_err(io::Error::other)
+            .boxed()
+        })
+        .flatten_unordered(concurrency)
+        .boxed()
+}
+
+/// The columns the rows of `query` over the files of `plans` in `bucket` have, in the order
+/// files name them, with the Arrow types they have in the files that hold them. Partition
+/// columns are strings. Only footers are read.
+pub async fn output_types(
+    state: &web::Data<AppState>,
+    bucket: &str,
+    plans: &[FilePlan],
+    query: &Query,
+) -> Result<Vec<(String, Vec<DataType>)>> {
+    let schemas: Vec<_> = stream::iter(plans)
+        .map(|file| async move {
+            let object = format!("{bucket}/{}", file.key);
+            let metadata = reader_metadata(state, &object, &file.meta).await?;
+            Ok::<_, ParquetError>((file, Arc::clone(metadata.schema())))
+        })
+        .buffered(state.query.concurrency)
+        .try_collect()
+        .await?;
+
+    let mut columns: Vec<(String, Vec<DataType>)> = Vec::new();
+    for (file, schema) in schemas {
+        let fields = schema.fields().iter().map(|field| field.name().clone());
+        for name in output_columns(query, fields.collect(), file) {
+            let data_type = match schema.field_with_name(&name) {
+                Ok(field) => Some(field.data_type().clone()),
+                Err(_) => file.partition(&name).map(|_| DataType::Utf8),
+            };
+            let index = match columns.iter().position(|(column, _)| *column == name) {
+                Some(index) => index,
+                None => {
+                    columns.push((name, Vec::new()));
+                    columns.len() - 1
+                }
+            };
+            columns[index].1.extend(data_type);
+        }
+    }
+    Ok(columns)
+}
+
+/// The matching rows of `query` over the files of `plans` in `bucket`, `concurrency` files
+/// at a time, as batches of the values of `columns`, for results that are written as they
+/// are scanned. Partition columns are strings, and columns a file lacks are null.
+pub fn value_batches(
+    state: web::Data<AppState>,
+    bucket: String,
+    plans: Vec<FilePlan>,
+    query: Arc<Query>,
+    columns: Arc<[String]>,
+    counters: Arc<ScanCounters>,
+) -> BoxStream<'static, Result<ValueBatch>> {
+    counters
+        .planned
+        .store(plans.len() as u64, AtomicOrdering::Relaxed);
+    let concurrency = state.query.concurrency;
+    stream::iter(plans)
+        .map(move |file| {
+            let (state, bucket, query) = (state.clone(), bucket.clone(), Arc::clone(&query));
+            let (columns, counters) = (Arc::clone(&columns), Arc::clone(&counters));
+            let file = Arc::new(file);
+            stream::once(async move {
+                let (batches, _) = matching_batches(
+                    state,
+                    &bucket,
+                    Arc::clone(&file),
+                    &query,
+                    Arc::clone(&counters),
+                )
+                .await?;
+                let values = batches.map_ok(move |batch| ValueBatch {
+                    rows: batch.num_rows(),
+                    columns: columns
+                        .iter()
+                        .map(|name| column_values(&batch, name, &file))
+                        .collect(),
+                });
+                let done = stream::once(async move {
+                    counters.files.fetch_add(1, AtomicOrdering::Relaxed);
+                    Ok(None)
+                });
+                Ok::<_, ParquetError>(
+                    values
+                        .map_ok(Some)
+                        .chain(done)
+                        .try_filter_map(|batch| futures::future::ready(Ok(batch))),
+                )
+            })
+            .try_flatten()
+            .boxed()
+        })
+        .flatten_unordered(concurrency)
+        .boxed()
+}
+
+/// Rows of a scan as the values of each column.
+pub struct ValueBatch {
+    pub rows: usize,
+    pub columns: Vec<Vec<Value>>,
+}
+
+/// Files planned and finished, rows and bytes read by the scans of a query.
+#[derive(Default)]
+pub struct ScanCounters {
+    pub planned: AtomicU64,
+    pub files: AtomicU64,
+    pub rows: AtomicU64,
+    pub bytes: AtomicU64,
+}
+
+/// The batches of `file` with the columns `query` needs and the rows that match its
+/// filter, along with the names of all columns of the file.
+async fn matching_batches(
+    state: web::Data<AppState>,
+    bucket: &str,
+    file: Arc<FilePlan>,
+    query: &Query,
+    counters: Arc<ScanCounters>,
+) -> Result<(BoxStream<'static, Result<RecordBatch>>, Vec<String>)> {
+    let row_groups = file.row_groups.clone();
+    let open = OpenFile::open(state, bucket, file, query, counters).await?;
+    Ok((open.batches(row_groups)?, open.fields))
+}
+
+/// The decoded footer of `object`.
+async fn reader_metadata(
+    state: &AppState,
+    object: &str,
+    meta: &ObjectMeta,
+) -> Result<ArrowReaderMetadata> {
+    let footer = handlers::parquet_footer(state, object, meta)
+        .await
+        .map_err(|e| ParquetError::General(e.to_string()))?
+        .ok_or_else(|| ParquetError::General(format!("{object} is not a Parquet file")))?;
+    let metadata = Arc::new(ParquetMetaDataReader::decode_metadata(&footer)?);
+    ArrowReaderMetadata::try_new(metadata, ArrowReaderOptions::new())
+}
+
+/// A file of a scan with its footer decoded, whose row groups can be read one by one.
+struct OpenFile {
+    file: Arc<FilePlan>,
+    metadata: ArrowReaderMetadata,
+    mask: ProjectionMask,
+    reader: ObjectReader,
+    filter: Vec<Predicate>,
+    /// Names of all columns of the file
+    fields: Vec<String>,
+}
+
+impl OpenFile {
+    async fn open(
+        state: web::Data<AppState>,
+        bucket: &str,
+        file: Arc<FilePlan>,
+        query: &Query,
+        counters: Arc<ScanCounters>,
+    ) -> Result<Self> {
+        let object = format!("{bucket}/{}", file.key);
+        let metadata = reader_metadata(&state, &object, &file.meta).await?;
+
+        let fields: Vec<String> = metadata
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| field.name().clone())
+            .collect();
+        let roots = (0..fields.len()).filter(|&index| query.needs(&fields[index]));
+        let mask = ProjectionMask::roots(metadata.metadata().file_metadata().schema_descr(), roots);
+
+        let reader = ObjectReader {
+            state,
+            object,
+            meta: file.meta.clone(),
+            metadata: Arc::clone(metadata.metadata()),
+            counters,
+        };
+        Ok(Self {
+            file,
+            metadata,
+            mask,
+            reader,
+            filter: query.filter.clone(),
+            fields,
+        })
+    }
+
+    /// The matching rows of `row_groups`.
+    fn batches(&self, row_groups: Vec<usize>) -> Result<BoxStream<'static, Result<RecordBatch>>> {
+        let (file, filter) = (Arc::clone(&self.file), self.filter.clone());
+        let counters = Arc::clone(&self.reader.counters);
+        Ok(ParquetRecordBatchStreamBuilder::new_with_metadata(
+            self.reader.clone(),
+            self.metadata.clone(),
+        )
+        .with_projection(self.mask.clone())
+        .with_row_groups(row_groups)
+        .with_batch_size(BATCH_ROWS)
+        .build()?
+        .map(move |batch| {
+            let batch = batch?;
+            counters
+                .rows
+                .fetch_add(batch.num_rows() as u64, AtomicOrdering::Relaxed);
+            filter_batch(&batch, &filter, &file)
+        })
+        .boxed())
+    }
+}
+
+/// The best `limit` rows seen so far in `order_by` order, the worst of them on top.
+struct TopK {
+    limit: usize,
+    order_by: Arc<[OrderBy]>,
+    heap: BinaryHeap<Ranked>,
+}
+
+struct Ranked {
+    /// Values of the `order_by` columns
+    key: Vec<Value>,
+    row: Map<String, Value>,
+    order_by: Arc<[OrderBy]>,
+}
+
+impl Ord for Ranked {
+    fn cmp(&self, other: &Self) -> Ordering {
+        key_order(&self.key, &other.key, &self.order_by)
+    }
+}
+
+impl PartialOrd for Ranked {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for Ranked {}
+
+impl TopK {
+    fn new(limit: usize, order_by: Arc<[OrderBy]>) -> Self {
+        Self {
+            limit,
+            order_by,
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    /// The key of the worst row kept once there are `limit` of them, which a row has to
+    /// beat to be kept.
+    fn bound(&self) -> Option<&[Value]> {
+        if self.heap.len() < self.limit {
+            return None;
+        }
+        Some(self.heap.peek().map_or(&[][..], |worst| &worst.key))
+    }
+
+    fn accepts(&self, key: &[Value]) -> bool {
+        self.limit > 0
+            && self
+                .bound()
+                .is_none_or(|bound| key_order(key, bound, &self.order_by).is_lt())
+    }
+
+    fn push(&mut self, ranked: Ranked) {
+        if self.accepts(&ranked.key) {
+            self.heap.push(ranked);
+            if self.heap.len() > self.limit {
+                self.heap.pop();
+            }
+        }
+    }
+
+    fn add_batch(&mut self, batch: &RecordBatch, output: &[String], file: &FilePlan) {
+        let keys: Vec<Vec<Value>> = self
+            .order_by
+            .iter()
+            .map(|order| column_values(batch, &order.column, file))
+            .collect();
+        let columns: Vec<Vec<Value>> = output
+            .iter()
+            .map(|name| column_values(batch, name, file))
+            .collect();
+        for row in 0..batch.num_rows() {
+            let key: Vec<Value> = keys.iter().map(|values| values[row].clone()).collect();
+            if !self.accepts(&key) {
+                continue;
+            }
+            let row = output
+                .iter()
+                .cloned()
+                .zip(columns.iter().map(|values| values[row].clone()))
+                .collect();
+            self.push(Ranked {
+                key,
+                row,
+                order_by: Arc::clone(&self.order_by),
+            });
+        }
+    }
+
+    fn merge(&mut self, other: TopK) {
+        for ranked in other.heap {
+            self.push(ranked);
+        }
+    }
+
+    /// The rows kept, best first.
+    fn into_rows(self) -> Vec<Map<String, Value>> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|ranked| ranked.row)
+            .collect()
+    }
+}
+
+/// The best value of the first `order_by` column a row group whose values lie in `range`
+/// may hold, null if unknown.
+fn best_possible(range: &Option<(Value, Value)>, order: &OrderBy) -> Value {
+    match (range, order.descending) {
+        (Some((min, _)), false) => min.clone(),
+        (Some((_, max)), true) => max.clone(),
+        (None, _) => Value::Null,
+    }
+}
+
+/// The first `limit` rows of `query` over the files of `plans` in `bucket`, in `order_by`
+/// order, and how many row groups were skipped because none of their rows could be among
+/// them. Each file keeps its own top `limit` rows, which are merged into the overall ones
+/// once it is done. Row groups are read best first, and those whose statistics show that
+/// even their best row is worse than the current top `limit` are not read at all.
+pub async fn top_k(
+    state: web::Data<AppState>,
+    bucket: &str,
+    mut plans: Vec<FilePlan>,
+    query: &Query,
+    limit: usize,
+    counters: Arc<ScanCounters>,
+) -> Result<(Vec<Map<String, Value>>, usize)> {
+    let order_by: Arc<[OrderBy]> = query.order_by.clone().into();
+    let first = &order_by[..order_by.len().min(1)];
+    let best =
+        |range: &Option<(Value, Value)>| first.first().map(|order| best_possible(range, order));
+    let range_order = |a: &Option<(Value, Value)>, b: &Option<(Value, Value)>| {
+        key_order(&Vec::from_iter(best(a)), &Vec::from_iter(best(b)), first)
+    };
+    for file in &mut plans {
+        let mut groups: Vec<_> = file
+            .row_groups
+            .iter()
+            .copied()
+            .zip(file.sort_ranges.iter().cloned())
+            .collect();
+        groups.sort_by(|(_, a), (_, b)| range_order(a, b));
+        (file.row_groups, file.sort_ranges) = groups.into_iter().unzip();
+    }
+    // Planned files have at least one row group
+    plans.sort_by(|a, b| range_order(&a.sort_ranges[0], &b.sort_ranges[0]));
+
+    counters
+        .planned
+        .store(plans.len() as u64, AtomicOrdering::Relaxed);
+    let top = Mutex::new(TopK::new(limit, Arc::clone(&order_by)));
+    let skipped = AtomicUsize::new(0);
+    stream::iter(plans)
+        .map(|file| {
+            let (state, counters, order_by) =
+                (state.clone(), Arc::clone(&counters), Arc::clone(&order_by));
+            let (top, skipped) = (&top, &skipped);
+            async move {
+                let file = Arc::new(file);
+                let open = OpenFile::open(
+                    state,
+                    bucket,
+                    Arc::clone(&file),
+                    query,
+                    Arc::clone(&counters),
+                )
+                .await?;
+                let output = output_columns(query, open.fields.clone(), &file);
+                let mut local = TopK::new(limit, Arc::clone(&order_by));
+                for (&index, range) in file.row_groups.iter().zip(&file.sort_ranges) {
+                    let global = top.lock().unwrap().bound().map(<[Value]>::to_vec);
+                    let bound = local
+                        .bound()
+                        .map(<[Value]>::to_vec)
+                        .into_iter()
+                        .chain(global)
+                        .min_by(|a, b| key_order(a, b, &order_by));
+                    // Without an order any rows will do, without statistics any may win
+                    let hopeless = bound.is_some_and(|bound| match best(range) {
+                        None => true,
+                        Some(Value::Null) => false,
+                        Some(best) => key_order(&[best], &bound, first).is_gt(),
+                    });
+                    if hopeless {
+                        skipped.fetch_add(1, AtomicOrdering::Relaxed);
+                        continue;
+                    }
+                    let mut batches = open.batches(vec![index])?;
+                    while let Some(batch) = batches.try_next().await? {
+                        local.add_batch(&batch, &output, &file);
+                    }
+                }
+                top.lock().unwrap().merge(local);
+                counters.files.fetch_add(1, AtomicOrdering::Relaxed);
+                Ok::<_, ParquetError>(())
+            }
+        })
+        .buffer_unordered(state.query.concurrency)
+        .try_collect::<Vec<()>>()
+        .await?;
+    let rows = top.into_inner().unwrap().into_rows();
+    let skipped = skipped.into_inner();
+    state
+        .queries
+        .skipped_by_top_k
+        .fetch_add(skipped as u64, AtomicOrdering::Relaxed);
+    Ok((rows, skipped))
+}
+
+/// Sorts the rows of an aggregate query by its `order_by` columns and keeps `limit` of
+/// them.
+pub fn order_groups(rows: &mut Vec<Map<String, Value>>, query: &Query) {
+    let key = |row: &Map<String, Value>| -> Vec<Value> {
+        query
+            .order_by
+            .iter()
+            .map(|order| row.get(&order.column).cloned().unwrap_or_default())
+            .collect()
+    };
+    if !query.order_by.is_empty() {
+        rows.sort_by(|a, b| key_order(&key(a), &key(b), &query.order_by));
+    }
+    if let Some(limit) = query.limit {
+        rows.truncate(limit);
+    }
+}
+
+/// The state of an aggregate over some rows, which merges with that over other rows.
+/// Every function is computed from the same state.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Accumulator {
+    /// Non-null values, or rows for `count` without a column
+    count: u64,
+    int_sum: i128,
+    float_sum: f64,
+    /// Whether a value that is not an integer was summed
+    floats: bool,
+    min: Value,
+    max: Value,
+}
+
+impl Accumulator {
+    fn add(&mut self, value: &Value) {
+        if value.is_null() {
+            return;
+        }
+        self.count += 1;
+        if let Value::Number(number) = value {
+            match (number.as_i64(), number.as_u64()) {
+                (Some(int), _) => self.int_sum += int as i128,
+                (None, Some(uint)) => self.int_sum += uint as i128,
+                _ => {
+                    self.float_sum += number.as_f64().unwrap_or_default();
+                    self.floats = true;
+                }
+            }
+        }
+        self.extend_range(value);
+    }
+
+    fn extend_range(&mut self, value: &Value) {
+        if self.min.is_null() || compare(value, &self.min).is_some_and(Ordering::is_lt) {
+            self.min = value.clone();
+        }
+        if self.max.is_null() || compare(value, &self.max).is_some_and(Ordering::is_gt) {
+            self.max = value.clone();
+        }
+    }
+
+    fn merge(&mut self, other: &Accumulator) {
+        self.count += other.count;
+        self.int_sum += other.int_sum;
+        self.float_sum += other.float_sum;
+        self.floats |= other.floats;
+        for value in [&other.min, &other.max] {
+            if !value.is_null() {
+                self.extend_range(value);
+            }
+        }
+    }
+
+    fn sum(&self) -> Value {
+        match (self.floats, i64::try_from(self.int_sum)) {
+            (false, Ok(sum)) => Value::from(sum),
+            _ => Value::from(self.int_sum as f64 + self.float_sum),
+        }
+    }
+
+    fn finish(&self, function: Function) -> Value {
+        match function {
+            Function::Count => Value::from(self.count),
+            _ if self.count == 0 => Value::Null,
+            Function::Sum => self.sum(),
+            Function::Avg => {
+                Value::from((self.int_sum as f64 + self.float_sum) / self.count as f64)
+            }
+            Function::Min => self.min.clone(),
+            Function::Max => self.max.clone(),
+        }
+    }
+}
+
+/// The accumulators of every group, merged by hashing the group values.
+#[derive(Default)]
+pub struct Groups {
+    groups: HashMap<String, (Vec<Value>, Vec<Accumulator>)>,
+}
+
+impl Groups {
+    fn group(&mut self, key: Vec<Value>, aggregates: usize) -> &mut Vec<Accumulator> {
+        // Serializing JSON values cannot fail
+        let hash_key = serde_json::to_string(&key).unwrap_or_default();
+        &mut self
+            .groups
+            .entry(hash_key)
+            .or_insert_with(|| (key, vec![Accumulator::default(); aggregates]))
+            .1
+    }
+
+    fn add_batch(&mut self, batch: &RecordBatch, query: &Query, file: &FilePlan) {
+        let keys: Vec<Vec<Value>> = query
+            .group_by
+            .iter()
+            .map(|column| column_values(batch, column, file))
+            .collect();
+        let inputs: Vec<Option<Vec<Value>>> = query
+            .aggregates
+            .iter()
+            .map(|aggregate| {
+                let column = aggregate.column.as_ref()?;
+                Some(column_values(batch, column, file))
+            })
+            .collect();
+        for row in 0..batch.num_rows() {
+            let key = keys.iter().map(|values| values[row].clone()).collect();
+            let accumulators = self.group(key, query.aggregates.len());
+            for (accumulator, input) in accumulators.iter_mut().zip(&inputs) {
+                match input {
+                    Some(values) => accumulator.add(&values[row]),
+                    None => accumulator.count += 1,
+                }
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: Vec<Group>) {
+        for group in other {
+            let accumulators = self.group(group.key, group.accumulators.len());
+            for (accumulator, other) in accumulators.iter_mut().zip(&group.accumulators) {
+                accumulator.merge(other);
+            }
+        }
+    }
+
+    /// The groups as sent from a storage node to the coordinator.
+    pub fn into_partial(self) -> Vec<Group> {
+        self.groups
+            .into_values()
+            .map(|(key, accumulators)| Group { key, accumulators })
+            .collect()
+    }
+
+    /// One row per group with the group columns and the aggregates, ordered by group with
+    /// nulls last.
+    pub fn rows(&self, query: &Query) -> Vec<Map<String, Value>> {
+        let mut groups: Vec<_> = self.groups.values().collect();
+        groups.sort_by(|(a, _), (b, _)| {
+            a.iter()
+                .zip(b)
+                .map(|(a, b)| sort_order(a, b))
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(Ordering::Equal)
+        });
+        groups
+            .into_iter()
+            .map(|(key, accumulators)| {
+                let group = query.group_by.iter().cloned().zip(key.iter().cloned());
+                let aggregates =
+                    query
+                        .aggregates
+                        .iter()
+                        .zip(accumulators)
+                        .map(|(aggregate, accumulator)| {
+                            (aggregate.name(), accumulator.finish(aggregate.function))
+                        });
+                group.chain(aggregates).collect()
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Group {
+    pub key: Vec<Value>,
+    pub accumulators: Vec<Accumulator>,
+}
+
+/// What one node scanned for a query and how much it sent back.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct NodeReport {
+    pub node: String,
+    #[serde(flatten)]
+    pub pruning: Pruning,
+    pub rows_scanned: u64,
+    pub bytes_scanned: u64,
+    pub bytes_returned: u64,
+}
+
+/// The groups a storage node computed and its report.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Partial {
+    pub groups: Vec<Group>,
+    pub report: NodeReport,
+}
+
+/// Aggregates the files of `plans` in `bucket` file by file, `concurrency` at a time,
+/// and merges the groups.
+pub async fn aggregate(
+    state: web::Data<AppState>,
+    bucket: &str,
+    plans: Vec<FilePlan>,
+    query: &Query,
+    pruning: Pruning,
+    counters: Arc<ScanCounters>,
+) -> Result<Partial> {
+    counters
+        .planned
+        .store(plans.len() as u64, AtomicOrdering::Relaxed);
+    let groups = stream::iter(plans)
+        .map(|file| {
+            let (state, counters) = (state.clone(), Arc::clone(&counters));
+            async move {
+                let file = Arc::new(file);
+                let (batches, _) = matching_batches(
+                    state,
+                    bucket,
+                    Arc::clone(&file),
+                    query,
+                    Arc::clone(&counters),
+                )
+                .await?;
+                let groups = batches
+                    .try_fold(Groups::default(), |mut groups, batch| {
+                        groups.add_batch(&batch, query, &file);
+                        futures::future::ready(Ok(groups))
+                    })
+                    .await?;
+                counters.files.fetch_add(1, AtomicOrdering::Relaxed);
+                Ok::<_, ParquetError>(groups)
+            }
+        })
+        .buffer_unordered(state.query.concurrency)
+        .try_fold(Groups::default(), |mut groups, file| {
+            groups.merge(file.into_partial());
+            futures::future::ready(Ok(groups))
+        })
+        .await?;
+
+    let mut partial = Partial {
+        groups: groups.into_partial(),
+        report: NodeReport {
+            node: LOCAL_NODE.to_string(),
+            pruning,
+            rows_scanned: counters.rows.load(AtomicOrdering::Relaxed),
+            bytes_scanned: counters.bytes.load(AtomicOrdering::Relaxed),
+            bytes_returned: 0,
+        },
+    };
+    partial.report.bytes_returned =
+        serde_json::to_vec(&partial).map_or(0, |body| body.len()) as u64;
+    Ok(partial)
+}
+
+/// The keys below `prefix` in `bucket` that the storage node at `peer` holds, from its
+/// listing.
+pub async fn peer_keys(
+    config: &QueryConfig,
+    peer: &str,
+    bucket: &str,
+    prefix: &str,
+) -> std::result::Result<Vec<String>, String> {
+    #[derive(Deserialize)]
+    struct Listed {
+        key: String,
+    }
+    #[derive(Deserialize)]
+    struct Listing {
+        objects: Vec<Listed>,
+    }
+    let mut url = bucket_url(peer, bucket)?;
+    url.query_pairs_mut().append_pair("prefix", prefix);
+    let body = peer_request(config, reqwest::Method::GET, url, Bytes::new()).await?;
+    let listing: Listing = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+    Ok(listing
+        .objects
+        .into_iter()
+        .map(|object| object.key)
+        .collect())
+}
+
+/// Which node scans which key, given the keys every node holds as `(node, keys)`. A key
+/// goes to the holder ranking highest for it, so an object held by several nodes is
+/// scanned once and keys spread evenly over the nodes. Returns the keys of every node in
+/// the order of `holdings`.
+pub fn place(holdings: &[(&str, Vec<String>)]) -> Vec<Vec<String>> {
+    let rank = |node: &str, key: &str| {
+        let digest = Sha256::new()
+            .chain_update(node)
+            .chain_update([0])
+            .chain_update(key)
+            .finalize();
+        u64::from_be_bytes(digest[..8].try_into().expect("8 bytes"))
+    };
+    let mut owners: HashMap<&str, (u64, usize)> = HashMap::new();
+    for (index, (node, keys)) in holdings.iter().enumerate() {
+        for key in keys {
+            let candidate = (rank(node, key), index);
+            owners
+                .entry(key)
+                .and_modify(|owner| *owner = (*owner).max(candidate))
+                .or_insert(candidate);
+        }
+    }
+    let mut placed = vec![Vec::new(); holdings.len()];
+    for (key, (_, index)) in owners {
+        placed[index].push(key.to_string());
+    }
+    placed
+}
+
+/// Sends `query` for `bucket` to the storage node at `peer` and returns its groups.
+pub async fn peer_partial(
+    config: &QueryConfig,
+    peer: &str,
+    bucket: &str,
+    query: &web::Bytes,
+) -> std::result::Result<Partial, String> {
+    let mut url = bucket_url(peer, bucket)?;
+    url.set_query(Some(PARTIAL_QUERY));
+    let body = peer_request(config, reqwest::Method::POST, url, query.clone()).await?;
+    let mut partial: Partial = serde_json::from_slice(&body).map_err(|e| e.to_string())?;
+    partial.report.node = peer.to_string();
+    partial.report.bytes_returned = body.len() as u64;
+    Ok(partial)
+}
+
+/// The URL of `bucket` on the storage node at `peer`.
+fn bucket_url(peer: &str, bucket: &str) -> std::result::Result<reqwest::Url, String> {
+    let mut url = reqwest::Url::parse(peer).map_err(|e| e.to_string())?;
+    url.path_segments_mut()
+        .map_err(|_| format!("{peer} cannot be a base URL"))?
+        .pop_if_empty()
+        .push(bucket);
+    Ok(url)
+}
+
+/// Sends a request signed with the peer credential and returns the body of its
+/// successful response.
+async fn peer_request(
+    config: &QueryConfig,
+    method: reqwest::Method,
+    url: reqwest::Url,
+    body: Bytes,
+) -> std::result::Result<Bytes, String> {
+    let (access_key, secret_key) = config
+        .peer_credential
+        .as_ref()
+        .ok_or("no --peer-access-key and --peer-secret-key configured")?;
+    let date = crate::metadata::unix_now().to_string();
+    let content_sha256 = signing::content_sha256(&body);
+    let headers = signing::canonical_headers([
+        (signing::CONTENT_SHA256_HEADER, content_sha256.as_str()),
+        (signing::DATE_HEADER, date.as_str()),
+    ]);
+    let signature = signing::sign(
+        secret_key,
+        &signing::string_to_sign(
+            method.as_str(),
+            url.path(),
+            url.query().unwrap_or_default(),
+            &headers,
+            &date,
+        ),
+    );
+    let response = config
+        .client
+        .request(method, url)
+        .header(signing::DATE_HEADER, date)
+        .header(signing::CONTENT_SHA256_HEADER, content_sha256)
+        .header(
+            "Authorization",
+            signing::authorization_header(access_key, &signature),
+        )
+        .body(body)
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = response.status();
+    let body = response.bytes().await.map_err(|e| e.to_string())?;
+    if !status.is_success() {
+        return Err(format!("{status}: {}", String::from_utf8_lossy(&body)));
+    }
+    Ok(body)
+}
+
+/// The values of `column` in `batch`, taken from the partition of `file` or null if the
+/// file lacks it.
+fn column_values(batch: &RecordBatch, column: &str, file: &FilePlan) -> Vec<Value> {
+    match batch.column_by_name(column) {
+        Some(array) => values(array.as_ref()),
+        None => {
+            let value = file.partition(column).map_or(Value::Null, Value::from);
+            vec![value; batch.num_rows()]
+        }
+    }
+}
+
+/// The rows of `batch` that match the predicates on columns of the file.
+fn filter_batch(batch: &RecordBatch, filter: &[Predicate], file: &FilePlan) -> Result<RecordBatch> {
+    let mut keep = vec![true; batch.num_rows()];
+    for predicate in filter {
+        if file.partition(&predicate.column).is_some() {
+            continue;
+        }
+        let Some(column) = batch.column_by_name(&predicate.column) else {
+            keep.fill(false);
+            break;
+        };
+        for (keep, value) in keep.iter_mut().zip(values(column.as_ref())) {
+            *keep = *keep && predicate.matches(&value);
+        }
+    }
+    if keep.iter().all(|keep| *keep) {
+        return Ok(batch.clone());
+    }
+    Ok(arrow_select::filter::filter_record_batch(
+        batch,
+        &BooleanArray::from(keep),
+    )?)
+}
+
+/// One JSON object per row of `batch` with the `output` columns, partition columns as
+/// strings and columns the file lacks as null.
+fn json_lines(batch: &RecordBatch, output: &[String], file: &FilePlan) -> Bytes {
+    let columns: Vec<(&String, Option<Vec<Value>>)> = output
+        .iter()
+        .map(|name| {
+            let values = batch
+                .column_by_name(name)
+                .map(|column| values(column.as_ref()));
+            (name, values)
+        })
+        .collect();
+    let mut out = Vec::new();
+    for row in 0..batch.num_rows() {
+        let object: Map<String, Value> = columns
+            .iter()
+            .map(|(name, values)| {
+                let value = match values {
+                    Some(values) => values[row].clone(),
+                    None => file.partition(name).map_or(Value::Null, Value::from),
+                };
+                ((*name).clone(), value)
+            })
+            .collect();
+        // Serializing a map of JSON values cannot fail
+        let _ = serde_json::to_writer(&mut out, &object);
+        out.push(b'\n');
+    }
+    Bytes::from(out)
+}
+
+/// The values of `array` as JSON, numbers and strings as they are, binary data as hex and
+/// everything else as displayed by arrow.
+fn values(array: &dyn Array) -> Vec<Value> {
+    fn collect<T: Into<Value>>(values: impl Iterator<Item = Option<T>>) -> Vec<Value> {
+        values
+            .map(|value| value.map_or(Value::Null, Into::into))
+            .collect()
+    }
+    match array.data_type() {
+        DataType::Boolean => collect(array.as_boolean().iter()),
+        DataType::Int8 => collect(array.as_primitive::<Int8Type>().iter()),
+        DataType::Int16 => collect(array.as_primitive::<Int16Type>().iter()),
+        DataType::Int32 => collect(array.as_primitive::<Int32Type>().iter()),
+        DataType::Int64 => collect(array.as_primitive::<Int64Type>().iter()),
+        DataType::UInt8 => collect(array.as_primitive::<UInt8Type>().iter()),
+        DataType::UInt16 => collect(array.as_primitive::<UInt16Type>().iter()),
+        DataType::UInt32 => collect(array.as_primitive::<UInt32Type>().iter()),
+        DataType::UInt64 => collect(array.as_primitive::<UInt64Type>().iter()),
+        DataType::Float32 => collect(array.as_primitive::<Float32Type>().iter()),
+        DataType::Float64 => collect(array.as_primitive::<Float64Type>().iter()),
+        DataType::Utf8 => collect(array.as_string::<i32>().iter()),
+        DataType::LargeUtf8 => collect(array.as_string::<i64>().iter()),
+        DataType::Utf8View => collect(array.as_string_view().iter()),
+        DataType::Binary => collect(array.as_binary::<i32>().iter().map(|v| v.map(hex::encode))),
+        DataType::LargeBinary => {
+            collect(array.as_binary::<i64>().iter().map(|v| v.map(hex::encode)))
+        }
+        _ => match ArrayFormatter::try_new(array, &FormatOptions::default()) {
+            Ok(formatter) => (0..array.len())
+                .map(|row| match array.is_null(row) {
+                    true => Value::Null,
+                    false => json!(formatter.value(row).to_string()),
+                })
+                .collect(),
+            Err(_) => vec![Value::Null; array.len()],
+        },
+    }
+}
+
+/// Reads the column chunks of a scan through the read cache.
+#[derive(Clone)]
+struct ObjectReader {
+    state: web::Data<AppState>,
+    object: String,
+    meta: ObjectMeta,
+    metadata: Arc<ParquetMetaData>,
+    counters: Arc<ScanCounters>,
+}
+
+impl AsyncFileReader for ObjectReader {
+    fn get_bytes(&mut self, range: Range<usize>) -> BoxFuture<'_, Result<Bytes>> {
+        async move {
+            let (start, length) = (range.start as u64, (range.end - range.start) as u64);
+            self.counters
+                .bytes
+                .fetch_add(length, AtomicOrdering::Relaxed);
+            let data = if self.state.cache.fits(length) {
+                handlers::cached_range(&self.state, &self.object, &self.meta, start, length)
+                    .await
+                    .map(|(data, _)| data)
+            } else {
+                handlers::read_bytes(&self.state, &self.meta, start, length).await
+            };
+            data.map_err(|e| ParquetError::General(format!("Failed to read {}: {e}", self.object)))
+        }
+        .boxed()
+    }
+
+    fn get_metadata(&mut self) -> BoxFuture<'_, Result<Arc<ParquetMetaData>>> {
+        let metadata = Arc::clone(&self.metadata);
+        async move { Ok(metadata) }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arrow_array::{ArrayRef, Int64Array};
+    use parquet::arrow::ArrowWriter;
+    use parquet::file::properties::WriterProperties;
+
+    use crate::auth;
+    use crate::metadata::{self, NULL_VERSION};
+
+    const BUCKET: &str = "tests";
+
+    async fn state() -> web::Data<AppState> {
+        let credentials =
+            auth::Credentials::parse("MVPTEST:test-secret:root").expect("valid credentials");
+        web::Data::new(
+            AppState::in_memory(credentials)
+                .await
+                .expect("in-memory state"),
+        )
+    }
+
+    /// Stores a Parquet file with an `id` column of `ids` under `key`, `group_rows` rows
+    /// per row group.
+    async fn store(state: &AppState, key: &str, ids: impl Into<Int64Array>, group_rows: usize) {
+        let ids: ArrayRef = Arc::new(ids.into());
+        let batch = RecordBatch::try_from_iter([("id", ids)]).expect("valid batch");
+        let properties = WriterProperties::builder()
+            .set_max_row_group_size(group_rows)
+            .build();
+        let mut writer =
+            ArrowWriter::try_new(Vec::new(), batch.schema(), Some(properties)).expect("writer");
+        writer.write(&batch).expect("written batch");
+        let data = writer.into_inner().expect("written file");
+
+        let blob = metadata::new_id();
+        let mut file = state
+            .storage
+            .put(&state.blob_key(&blob))
+            .await
+            .expect("new blob");
+        file.write_all(&data).await.expect("written blob");
+        file.finish().await.expect("finished blob");
+        let meta = ObjectMeta {
+            blob,
+            size: data.len() as u64,
+            created: metadata::unix_now(),
+            version: NULL_VERSION.to_string(),
+            delete_marker: false,
+            tags: Default::default(),
+            compressed: None,
+            encryption: None,
+            packed: false,
+        };
+        state
+            .metadata
+            .put(BUCKET, key, meta)
+            .await
+            .expect("stored object");
+    }
+
+    fn query(value: Value) -> Query {
+        serde_json::from_value(value).expect("valid query")
+    }
+
+    fn predicate(column: &str, op: &str, value: Value) -> Predicate {
+        serde_json::from_value(json!({"column": column, "op": op, "value": value}))
+            .expect("valid predicate")
+    }
+
+    /// A description of a file with an `amount` column of `converted_type`, one row group
+    /// of 10 rows per entry of `statistics`.
+    fn described(converted_type: &str, statistics: &[Value]) -> Value {
+        let groups: Vec<Value> = statistics
+            .iter()
+            .map(|statistics| {
+                json!({
+                    "num_rows": 10,
+                    "columns": [{"path": "amount", "num_values": 10, "statistics": statistics}],
+                })
+            })
+            .collect();
+        json!({
+            "columns": [{"path": "amount", "converted_type": converted_type}],
+            "row_groups": groups,
+        })
+    }
+
+    fn range(min: i64, max: i64) -> Value {
+        json!({"min": min, "max": max, "null_count": 0})
+    }
+
+    #[test]
+    fn partitions_are_the_named_directories() {
+        assert_eq!(
+            partitions("sales/date=2024-01-01/region=eu/part-0.parquet"),
+            [
+                ("date".to_string(), "2024-01-01".to_string()),
+                ("region".to_string(), "eu".to_string()),
+            ]
+        );
+        assert_eq!(
+            partitions("sales/a=b=c/=x/plain/id=1.parquet"),
+            [("a".to_string(), "b=c".to_string())]
+        );
+        assert!(partitions("date=2024-01-01.parquet").is_empty());
+    }
+
+    #[test]
+    fn partition_values_are_typed_like_the_predicate() {
+        assert_eq!(partition_value("2024", &json!(2023)), json!(2024));
+        assert_eq!(partition_value("2.5", &json!(1)), json!(2.5));
+        assert_eq!(partition_value("true", &json!(false)), json!(true));
+        assert_eq!(partition_value("eu", &json!(1)), json!("eu"));
+        assert_eq!(partition_value("2024", &json!("2023")), json!("2024"));
+    }
+
+    #[actix_web::test]
+    async fn partitions_prune_files() {
+        let state = state().await;
+        for year in [2022, 2023, 2024] {
+            let key = format!("sales/year={year}/region=eu/part-0.parquet");
+            store(&state, &key, vec![1, 2, 3], 3).await;
+        }
+        let query = query(json!({
+            "prefix": "sales/",
+            "filter": [
+                {"column": "year", "op": "ge", "value": 2023},
+                {"column": "region", "op": "eq", "value": "eu"},
+            ],
+        }));
+        let (plans, pruning) = plan(&state, BUCKET, &query).await.expect("planned");
+        let keys: Vec<&str> = plans.iter().map(|file| file.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            [
+                "sales/year=2023/region=eu/part-0.parquet",
+                "sales/year=2024/region=eu/part-0.parquet",
+            ]
+        );
+        assert_eq!(pruning.files, 3);
+        assert_eq!(pruning.pruned_by_partition, 1);
+    }
+
+    #[test]
+    fn statistics_prune_row_groups_for_every_operator() {
+        let description = described("INT_64", &[range(0, 9), range(10, 19), range(20, 20)]);
+        let cases = [
+            ("eq", 15, vec![1]),
+            ("eq", 20, vec![2]),
+            ("eq", -1, vec![]),
+            ("ne", 20, vec![0, 1]),
+            ("ne", 5, vec![0, 1, 2]),
+            ("lt", 10, vec![0]),
+            ("le", 10, vec![0, 1]),
+            ("gt", 19, vec![2]),
+            ("ge", 19, vec![1, 2]),
+            ("ge", 21, vec![]),
+        ];
+        for (op, value, expected) in cases {
+            let predicate = predicate("amount", op, json!(value));
+            assert_eq!(
+                row_groups(&description, &[&predicate]),
+                expected,
+                "amount {op} {value}"
+            );
+        }
+    }
+
+    #[test]
+    fn row_groups_without_usable_statistics_are_kept() {
+        let description = described(
+            "INT_64",
+            &[
+                Value::Null,
+                json!({"min": null, "max": null, "null_count": 3}),
+                json!({"min": 0, "max": 9}),
+            ],
+        );
+        for op in ["eq", "ne", "lt", "le", "gt", "ge"] {
+            let predicate = predicate("amount", op, json!(100));
+            let expected = match op {
+                "eq" | "gt" | "ge" => vec![0, 1],
+                _ => vec![0, 1, 2],
+            };
+            assert_eq!(row_groups(&description, &[&predicate]), expected, "{op}");
+        }
+
+        // Statistics of other types or columns whose statistics do not order like their
+        // values prune nothing
+        let text = predicate("amount", "eq", json!("100"));
+        assert_eq!(row_groups(&description, &[&text]), [0, 1, 2]);
+        let decimal = described("DECIMAL", &[range(0, 9)]);
+        let number = predicate("amount", "eq", json!(100));
+        assert_eq!(row_groups(&decimal, &[&number]), [0]);
+    }
+
+    #[test]
+    fn null_and_empty_row_groups_never_match() {
+        let mut description = described(
+            "INT_64",
+            &[
+                json!({"min": null, "max": null, "null_count": 10}),
+                range(0, 9),
+            ],
+        );
+        for op in ["eq", "ne", "lt", "le", "gt", "ge"] {
+            let predicate = predicate("amount", op, json!(5));
+            assert_eq!(row_groups(&description, &[&predicate]), [1], "{op}");
+        }
+        description["row_groups"][1]["num_rows"] = json!(0);
+        assert_eq!(row_groups(&description, &[]), [0]);
+
+        // A column the file lacks is null in every row
+        let predicate = predicate("missing", "ne", json!(5));
+        assert!(row_groups(&described("INT_64", &[range(0, 9)]), &[&predicate]).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn statistics_of_stored_files_prune_row_groups_and_files() {
+        let state = state().await;
+        store(
+            &state,
+            "ids/part-0.parquet",
+            (0..10).collect::<Vec<i64>>(),
+            5,
+        )
+        .await;
+        store(
+            &state,
+            "ids/part-1.parquet",
+            (10..20).collect::<Vec<i64>>(),
+            5,
+        )
+        .await;
+        let between = query(json!({
+            "prefix": "ids/",
+            "filter": [
+                {"column": "id", "op": "ge", "value": 7},
+                {"column": "id", "op": "lt", "value": 12},
+            ],
+        }));
+        let (plans, pruning) = plan(&state, BUCKET, &between).await.expect("planned");
+        let row_groups: Vec<(&str, &[usize])> = plans
+            .iter()
+            .map(|file| (file.key.as_str(), file.row_groups.as_slice()))
+            .collect();
+        assert_eq!(
+            row_groups,
+            [
+                ("ids/part-0.parquet", &[1][..]),
+                ("ids/part-1.parquet", &[0][..])
+            ]
+        );
+        assert_eq!(pruning.row_groups_pruned, 2);
+
+        let beyond = query(json!({
+            "prefix": "ids/",
+            "filter": [{"column": "id", "op": "gt", "value": 100}],
+        }));
+        let (plans, pruning) = plan(&state, BUCKET, &beyond).await.expect("planned");
+        assert!(plans.is_empty());
+        assert_eq!(pruning.pruned_by_statistics, 2);
+    }
+
+    #[actix_web::test]
+    async fn prefixes_without_files_plan_nothing() {
+        let state = state().await;
+        store(&state, "sales/part-0.parquet", vec![1], 1).await;
+        let query = query(json!({"prefix": "returns/"}));
+        let (plans, pruning) = plan(&state, BUCKET, &query).await.expect("planned");
+        assert!(plans.is_empty());
+        assert_eq!(pruning.files, 0);
+    }
+
+    /// The groups a node sends for `rows`, through JSON like a peer sends them.
+    fn partial(query: &Query, rows: &[Value]) -> Vec<Group> {
+        let mut groups = Groups::default();
+        for row in rows {
+            let key = query.group_by.iter().map(|c| row[c].clone()).collect();
+            let accumulators = groups.group(key, query.aggregates.len());
+            for (accumulator, aggregate) in accumulators.iter_mut().zip(&query.aggregates) {
+                match &aggregate.column {
+                    Some(column) => accumulator.add(&row[column]),
+                    None => accumulator.count += 1,
+                }
+            }
+        }
+        let sent = serde_json::to_vec(&groups.into_partial()).expect("serialized groups");
+        serde_json::from_slice(&sent).expect("deserialized groups")
+    }
+
+    fn merged(query: &Query, nodes: &[&[Value]]) -> Vec<Map<String, Value>> {
+        let mut groups = Groups::default();
+        for rows in nodes {
+            groups.merge(partial(query, rows));
+        }
+        groups.rows(query)
+    }
+
+    #[test]
+    fn partial_groups_merge_into_the_groups_of_all_rows() {
+        let query = query(json!({
+            "group_by": ["region"],
+            "aggregates": [
+                {"function": "sum", "column": "amount"},
+                {"function": "count", "column": "amount"},
+                {"function": "count"},
+                {"function": "min", "column": "amount"},
+                {"function": "max", "column": "amount"},
+                {"function": "avg", "column": "amount"},
+            ],
+        }));
+        let first = [
+            json!({"region": "eu", "amount": 1}),
+            json!({"region": "eu", "amount": 2}),
+            json!({"region": "eu", "amount": null}),
+        ];
+        let second = [
+            json!({"region": "eu", "amount": 6}),
+            json!({"region": "us", "amount": -4}),
+        ];
+        let rows = merged(&query, &[&first, &second]);
+        assert_eq!(
+            Value::from(rows.clone()),
+            json!([
+                {
+                    "region": "eu",
+                    "sum(amount)": 9,
+                    "count(amount)": 3,
+                    "count(*)": 4,
+                    "min(amount)": 1,
+                    "max(amount)": 6,
+                    // Of the merged sum and count, averaging 1.5 and 6 would give 3.75
+                    "avg(amount)": 3.0,
+                },
+                {
+                    "region": "us",
+                    "sum(amount)": -4,
+                    "count(amount)": 1,
+                    "count(*)": 1,
+                    "min(amount)": -4,
+                    "max(amount)": -4,
+                    "avg(amount)": -4.0,
+                },
+            ])
+        );
+        let all: Vec<Value> = first.iter().chain(&second).cloned().collect();
+        assert_eq!(merged(&query, &[&all]), rows);
+    }
+
+    #[test]
+    fn float_sums_merge_with_integer_sums() {
+        let query = query(json!({
+            "aggregates": [
+                {"function": "sum", "column": "amount"},
+                {"function": "avg", "column": "amount"},
+                {"function": "min", "column": "amount"},
+                {"function": "max", "column": "amount"},
+            ],
+        }));
+        let rows = merged(
+            &query,
+            &[
+                &[json!({"amount": 1}), json!({"amount": 0.5})],
+                &[json!({"amount": 2.25})],
+                &[json!({"amount": 4})],
+            ],
+        );
+        assert_eq!(
+            Value::from(rows),
+            json!([{
+                "sum(amount)": 7.75,
+                "avg(amount)": 7.75 / 4.0,
+                "min(amount)": 0.5,
+                "max(amount)": 4,
+            }])
+        );
+
+        let rows = merged(&query, &[&[json!({"amount": 3})], &[json!({"amount": 4})]]);
+        assert_eq!(rows[0]["sum(amount)"], json!(7));
+    }
+
+    #[test]
+    fn groups_of_nodes_without_values_are_null() {
+        let query = query(json!({
+            "group_by": ["region"],
+            "aggregates": [
+                {"function": "sum", "column": "amount"},
+                {"function": "min", "column": "amount"},
+                {"function": "count", "column": "amount"},
+            ],
+        }));
+        let rows = merged(
+            &query,
+            &[
+                &[json!({"region": "eu", "amount": null})],
+                &[json!({"region": null, "amount": 5})],
+            ],
+        );
+        assert_eq!(
+            Value::from(rows),
+            json!([
+                {"region": "eu", "sum(amount)": null, "min(amount)": null, "count(amount)": 0},
+                {"region": null, "sum(amount)": 5, "min(amount)": 5, "count(amount)": 1},
+            ])
+        );
+    }
+
+    #[test]
+    fn every_key_is_placed_on_one_of_its_holders() {
+        let keys = |range: Range<usize>| -> Vec<String> {
+            range.map(|index| format!("part-{index}.parquet")).collect()
+        };
+        let holdings = [
+            (LOCAL_NODE, keys(0..60)),
+            ("http://a", keys(20..80)),
+            ("http://b", keys(40..100)),
+        ];
+        let sorted = |mut placed: Vec<Vec<String>>| {
+            placed.iter_mut().for_each(|keys| keys.sort());
+            placed
+        };
+        let placed = sorted(place(&holdings));
+        let mut all: Vec<&String> = placed.iter().flatten().collect();
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 100);
+        assert_eq!(placed.iter().map(Vec::len).sum::<usize>(), 100);
+        for ((_, held), placed) in holdings.iter().zip(&placed) {
+            assert!(placed.iter().all(|key| held.contains(key)));
+            assert!(!placed.is_empty());
+        }
+        assert_eq!(sorted(place(&holdings)), placed);
+    }
+
+    fn ranked(row: Value, order_by: &Arc<[OrderBy]>) -> Ranked {
+        let key = order_by
+            .iter()
+            .map(|order| row[&order.column].clone())
+            .collect();
+        let Value::Object(row) = row else {
+            unreachable!("rows are objects")
+        };
+        Ranked {
+            key,
+            row,
+            order_by: Arc::clone(order_by),
+        }
+    }
+
+    /// The sort keys of the first `limit` of `rows`, sorted in full.
+    fn expected_keys(rows: &[Value], order_by: &[OrderBy], limit: usize) -> Vec<Vec<Value>> {
+        let mut keys: Vec<Vec<Value>> = rows
+            .iter()
+            .map(|row| {
+                order_by
+                    .iter()
+                    .map(|order| row[&order.column].clone())
+                    .collect()
+            })
+            .collect();
+        keys.sort_by(|a, b| key_order(a, b, order_by));
+        keys.truncate(limit);
+        keys
+    }
+
+    fn order_by(value: Value) -> Arc<[OrderBy]> {
+        serde_json::from_value::<Vec<OrderBy>>(value)
+            .expect("valid order")
+            .into()
+    }
+
+    #[test]
+    fn heaps_of_files_merge_into_the_best_rows() {
+        let rows: Vec<Value> = (0..40)
+            .map(|n| {
+                let a = match n % 7 {
+                    0 => Value::Null,
+                    _ => json!(n % 4),
+                };
+                json!({"a": a, "b": (n * 7) % 5, "n": n})
+            })
+            .collect();
+        for order in [
+            json!([{"column": "a"}, {"column": "b", "descending": true}]),
+            json!([{"column": "a", "descending": true}, {"column": "b"}]),
+        ] {
+            let order_by = order_by(order);
+            for limit in [0, 1, 5, 12, 40, 50] {
+                let mut files: Vec<TopK> = (0..3)
+                    .map(|_| TopK::new(limit, Arc::clone(&order_by)))
+                    .collect();
+                for (n, row) in rows.iter().enumerate() {
+                    files[n % 3].push(ranked(row.clone(), &order_by));
+                }
+                let mut top = TopK::new(limit, Arc::clone(&order_by));
+                for file in files {
+                    top.merge(file);
+                }
+                let keys: Vec<Vec<Value>> = top
+                    .into_rows()
+                    .into_iter()
+                    .map(|row| {
+                        order_by
+                            .iter()
+                            .map(|order| row[&order.column].clone())
+                            .collect()
+                    })
+                    .collect();
+                assert_eq!(
+                    keys,
+                    expected_keys(&rows, &order_by, limit),
+                    "{order_by:?} limit {limit}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn best_possible_values_come_from_the_end_rows_are_taken_from() {
+        let range = Some((json!(1), json!(9)));
+        let ascending = OrderBy {
+            column: "id".to_string(),
+            descending: false,
+        };
+        let descending = OrderBy {
+            descending: true,
+            ..ascending.clone()
+        };
+        assert_eq!(best_possible(&range, &ascending), json!(1));
+        assert_eq!(best_possible(&range, &descending), json!(9));
+        assert_eq!(best_possible(&None, &descending), Value::Null);
+    }
+
+    #[actix_web::test]
+    async fn skipped_row_groups_never_hold_top_rows() {
+        let state = state().await;
+        let files: [(&str, Vec<Option<i64>>); 3] = [
+            (
+                "top/part-0.parquet",
+                vec![
+                    Some(5),
+                    Some(5),
+                    Some(5),
+                    Some(1),
+                    Some(2),
+                    Some(3),
+                    None,
+                    None,
+                    Some(9),
+                ],
+            ),
+            (
+                "top/part-1.parquet",
+                vec![
+                    Some(5),
+                    Some(6),
+                    Some(7),
+                    None,
+                    None,
+                    None,
+                    Some(0),
+                    Some(5),
+                    Some(5),
+                ],
+            ),
+            (
+                "top/part-2.parquet",
+                vec![Some(8), Some(9), Some(10), Some(10), Some(10), None],
+            ),
+        ];
+        let mut rows = Vec::new();
+        for (key, ids) in files {
+            rows.extend(ids.iter().map(|id| json!({ "id": id })));
+            store(&state, key, ids, 3).await;
+        }
+
+        let mut skipped = 0;
+        for descending in [false, true] {
+            for limit in 1..=rows.len() + 1 {
+                let query = query(json!({
+                    "prefix": "top/",
+                    "order_by": [{"column": "id", "descending": descending}],
+                    "limit": limit,
+                }));
+                let (plans, _) = plan(&state, BUCKET, &query).await.expect("planned");
+                let counters = Arc::new(ScanCounters::default());
+                let (top, skipped_row_groups) =
+                    top_k(state.clone(), BUCKET, plans, &query, limit, counters)
+                        .await
+                        .expect("top rows");
+                let keys: Vec<Vec<Value>> = top.iter().map(|row| vec![row["id"].clone()]).collect();
+                assert_eq!(
+                    keys,
+                    expected_keys(&rows, &query.order_by, limit),
+                    "descending {descending} limit {limit}"
+                );
+                skipped += skipped_row_groups;
+            }
+        }
+        assert!(skipped > 0);
+    }
+}
@@ -5,9 +5,41 @@ pub fn init_routes(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::resource("/api/healthchecker").route(web::get().to(handlers::health_checker_handler)),
     )
+    .service(web::resource("/admin/gc").route(web::post().to(handlers::run_gc)))
+    .service(web::resource("/admin/compact").route(web::post().to(handlers::run_compaction)))
+    .service(web::resource("/admin/lifecycle").route(web::post().to(handlers::run_lifecycle)))
+    .service(web::resource("/admin/rotate-keys").route(web::post().to(handlers::rotate_keys)))
     .service(
-        web::resource("/parquet/{file_name}")
+        web::resource("/admin/cache")
+            .route(web::get().to(handlers::cache_report))
+            .route(web::delete().to(handlers::clear_cache)),
+    )
+    .service(web::resource("/admin/metrics").route(web::get().to(handlers::metrics)))
+    .service(web::resource("/api/presign").route(web::post().to(handlers::presign)))
+    .service(web::resource("/api/jobs").route(web::get().to(handlers::list_jobs)))
+    .service(
+        web::resource("/api/jobs/{id}")
+            .route(web::get().to(handlers::get_job))
+            .route(web::delete().to(handlers::cancel_job)),
+    )
+    .service(web::resource("/api/jobs/{id}/result").route(web::get().to(handlers::get_job_result)))
+    .service(
+        web::resource("/{bucket}")
+            .route(web::get().to(handlers::get_bucket))
+            .route(web::put().to(handlers::create_bucket))
+            .route(web::post().to(handlers::query_bucket))
+            .route(web::delete().to(handlers::delete_bucket)),
+    )
+    .service(
+        web::resource("/parquet/{file_name}/metadata")
+            .route(web::get().to(handlers::get_legacy_parquet_metadata)),
+    )
+    // Also serves the original /parquet/{file_name} routes through the default bucket
+    .service(
+        web::resource("/{bucket}/{file_name:.*}")
             .route(web::get().to(handlers::get_parquet_file))
-            .route(web::put().to(handlers::put_parquet_file)),
+            .route(web::head().to(handlers::head_parquet_file))
+            .route(web::put().to(handlers::put_parquet_file))
+            .route(web::delete().to(handlers::delete_parquet_file)),
     );
 }
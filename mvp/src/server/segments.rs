@@ -0,0 +1,711 @@
+//! Packing of small objects into append-only segment files.
+//!
+//! Versions of at most `PackConfig::threshold` bytes are not stored as blobs of their own
+//! but appended as records to the active segment, `segments/{id}` in the store. A record
+//! is a header of `RECORD_MAGIC`, its kind, the length of the blob ID, the creation time
+//! and the data length (little endian), followed by the blob ID and the data. Deleting a
+//! packed blob appends a tombstone whose data is the segment holding the blob, so a
+//! restart, which rebuilds the in-memory index by scanning all segments, does not bring
+//! it back. Compaction rewrites the live records of segments whose live ratio dropped
+//! below `PackConfig::min_live_ratio` into the active segment and deletes them.
+
+use actix_web::web::{self, Bytes};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::metadata::unix_now;
+use crate::storage::{BlobRead, BlobWrite, Store};
+use crate::AppState;
+
+pub const SEGMENT_PREFIX: &str = "segments/";
+const RECORD_MAGIC: &[u8; 4] = b"MVPS";
+/// Magic, kind, ID length, creation time and data length
+const HEADER_LEN: u64 = 4 + 1 + 1 + 8 + 8;
+const KIND_PUT: u8 = 0;
+const KIND_TOMBSTONE: u8 = 1;
+
+pub struct PackConfig {
+    /// Largest version that is packed, 0 disables packing
+    pub threshold: u64,
+    /// Size after which a new segment is started
+    pub segment_size: u64,
+    /// Segments with a smaller share of live bytes are compacted
+    pub min_live_ratio: f64,
+    pub compaction_interval: Duration,
+}
+
+/// Where the data of a packed blob is.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct Needle {
+    segment: u64,
+    /// Start of the data in the segment
+    offset: u64,
+    length: u64,
+    created: u64,
+}
+
+impl Needle {
+    fn record_len(&self, blob: &str) -> u64 {
+        HEADER_LEN + blob.len() as u64 + self.length
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct SegmentStats {
+    size: u64,
+    live: u64,
+}
+
+impl SegmentStats {
+    fn live_ratio(&self) -> f64 {
+        if self.size == 0 {
+            1.0
+        } else {
+            self.live as f64 / self.size as f64
+        }
+    }
+}
+
+#[derive(Default)]
+struct Index {
+    needles: HashMap<String, Needle>,
+    segments: BTreeMap<u64, SegmentStats>,
+}
+
+#[derive(Serialize, Default, Debug)]
+pub struct CompactionReport {
+    pub dry_run: bool,
+    pub segments: usize,
+    pub segments_compacted: usize,
+    pub records_moved: usize,
+    pub bytes_reclaimed: u64,
+}
+
+/// The index of all packed blobs and the segments they are in.
+pub struct Segments {
+    config: PackConfig,
+    index: Mutex<Index>,
+    /// The segment records are appended to, held while appending
+    active: tokio::sync::Mutex<u64>,
+}
+
+struct Record {
+    kind: u8,
+    blob: String,
+    created: u64,
+    /// Start of the data in the segment
+    offset: u64,
+    length: u64,
+    /// For tombstones, the segment the blob was deleted from
+    deleted_from: Option<u64>,
+}
+
+fn segment_key(segment: u64) -> String {
+    format!("{SEGMENT_PREFIX}{segment:016x}")
+}
+
+fn encode_record(kind: u8, blob: &str, created: u64, data: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_LEN as usize + blob.len() + data.len());
+    record.extend_from_slice(RECORD_MAGIC);
+    record.push(kind);
+    record.push(blob.len() as u8);
+    record.extend_from_slice(&created.to_le_bytes());
+    record.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    record.extend_from_slice(blob.as_bytes());
+    record.extend_from_slice(data);
+    record
+}
+
+/// All complete records of a segment. A torn record at the end, left by a crash while
+/// appending, ends the scan.
+async fn scan(store: &dyn Store, segment: u64) -> io::Result<Vec<Record>> {
+    let key = segment_key(segment);
+    let Some(stat) = store.stat(&key).await? else {
+        return Ok(Vec::new());
+    };
+    let mut file = store.get(&key).await?;
+    let mut records = Vec::new();
+    let mut position = 0;
+    while position + HEADER_LEN <= stat.size {
+        let header = file.read_at(position, HEADER_LEN as usize).await?;
+        if &header[..4] != RECORD_MAGIC {
+            eprintln!("Segment {key} has a broken record at {position}, ignoring the rest");
+            break;
+        }
+        let kind = header[4];
+        let id_len = header[5] as u64;
+        let created = u64::from_le_bytes(header[6..14].try_into().unwrap());
+        let length = u64::from_le_bytes(header[14..22].try_into().unwrap());
+        let offset = position + HEADER_LEN + id_len;
+        if offset + length > stat.size {
+            eprintln!("Segment {key} ends in an incomplete record at {position}");
+            break;
+        }
+        let blob = file.read_at(position + HEADER_LEN, id_len as usize).await?;
+        let deleted_from = if kind == KIND_TOMBSTONE && length == 8 {
+            let data = file.read_at(offset, 8).await?;
+            Some(u64::from_le_bytes(data[..8].try_into().unwrap()))
+        } else {
+            None
+        };
+        records.push(Record {
+            kind,
+            blob: String::from_utf8_lossy(&blob).into_owned(),
+            created,
+            offset,
+            length,
+            deleted_from,
+        });
+        position = offset + length;
+    }
+    Ok(records)
+}
+
+impl Segments {
+    /// Rebuilds the index from the segments in `store`. Appends go to a new segment, so
+    /// a torn record at the end of the last one stays where it is.
+    pub async fn open(store: &dyn Store, config: PackConfig) -> io::Result<Self> {
+        let mut index = Index::default();
+        let mut ids = Vec::new();
+        for stat in store.list(SEGMENT_PREFIX).await? {
+            match u64::from_str_radix(stat.name(), 16) {
+                Ok(segment) => {
+                    ids.push(segment);
+                    index.segments.insert(
+                        segment,
+                        SegmentStats {
+                            size: stat.size,
+                            live: 0,
+                        },
+                    );
+                }
+                Err(_) => eprintln!("Ignoring unexpected segment file {}", stat.key),
+            }
+        }
+        ids.sort_unstable();
+
+        for &segment in &ids {
+            for record in scan(store, segment).await? {
+                match record.kind {
+                    KIND_PUT => {
+                        let needle = Needle {
+                            segment,
+                            offset: record.offset,
+                            length: record.length,
+                            created: record.created,
+                        };
+                        // Compaction copies records, the later copy wins
+                        if let Some(old) = index.needles.insert(record.blob.clone(), needle) {
+                            if let Some(stats) = index.segments.get_mut(&old.segment) {
+                                stats.live =
+                                    stats.live.saturating_sub(old.record_len(&record.blob));
+                            }
+                        }
+                        index.segments.get_mut(&segment).unwrap().live +=
+                            needle.record_len(&record.blob);
+                    }
+                    KIND_TOMBSTONE => {
+                        let target = record.deleted_from;
+                        if index
+                            .needles
+                            .get(&record.blob)
+                            .is_some_and(|needle| Some(needle.segment) == target)
+                        {
+                            let needle = index.needles.remove(&record.blob).unwrap();
+                            if let Some(stats) = index.segments.get_mut(&needle.segment) {
+                                stats.live =
+                                    stats.live.saturating_sub(needle.record_len(&record.blob));
+                            }
+                        }
+                    }
+                    kind => eprintln!("Unknown record kind {kind} in segment {segment}"),
+                }
+            }
+        }
+
+        let active = ids.last().map_or(0, |last| last + 1);
+        Ok(Self {
+            config,
+            index: Mutex::new(index),
+            active: tokio::sync::Mutex::new(active),
+        })
+    }
+
+    /// Whether a version of `length` bytes is packed.
+    pub fn packs(&self, length: u64) -> bool {
+        self.config.threshold > 0 && length <= self.config.threshold
+    }
+
+    pub fn contains(&self, blob: &str) -> bool {
+        self.index.lock().unwrap().needles.contains_key(blob)
+    }
+
+    /// Packed blobs created before `cutoff` (Unix seconds) with their sizes.
+    pub fn created_before(&self, cutoff: u64) -> Vec<(String, u64)> {
+        self.index
+            .lock()
+            .unwrap()
+            .needles
+            .iter()
+            .filter(|(_, needle)| needle.created < cutoff)
+            .map(|(blob, needle)| (blob.clone(), needle.length))
+            .collect()
+    }
+
+    /// Appends a record to the active segment, starting a new one if it is full. Returns
+    /// the segment and where the record starts.
+    async fn append_record(
+        &self,
+        store: &dyn Store,
+        active: &mut u64,
+        record: &[u8],
+    ) -> io::Result<(u64, u64)> {
+        let size = self
+            .index
+            .lock()
+            .unwrap()
+            .segments
+            .get(active)
+            .map(|stats| stats.size);
+        if size
+            .is_some_and(|size| size > 0 && size + record.len() as u64 > self.config.segment_size)
+        {
+            *active += 1;
+        }
+        let offset = store.append(&segment_key(*active), record).await?;
+        let mut index = self.index.lock().unwrap();
+        let stats = index.segments.entry(*active).or_default();
+        stats.size = stats.size.max(offset + record.len() as u64);
+        Ok((*active, offset))
+    }
+
+    /// Packs `data` as the blob `blob`. Returns the key of the segment it went to, which
+    /// the caller syncs.
+    pub async fn append(&self, store: &dyn Store, blob: &str, data: &[u8]) -> io::Result<String> {
+        let created = unix_now();
+        let record = encode_record(KIND_PUT, blob, created, data);
+        let mut active = self.active.lock().await;
+        let (segment, offset) = self.append_record(store, &mut active, &record).await?;
+        let needle = Needle {
+            segment,
+            offset: offset + HEADER_LEN + blob.len() as u64,
+            length: data.len() as u64,
+            created,
+        };
+        let mut index = self.index.lock().unwrap();
+        index.segments.get_mut(&segment).unwrap().live += needle.record_len(blob);
+        index.needles.insert(blob.to_string(), needle);
+        Ok(segment_key(segment))
+    }
+
+    /// Tombstones a packed blob.
+    pub async fn delete(&self, store: &dyn Store, blob: &str) -> io::Result<()> {
+        let mut active = self.active.lock().await;
+        let Some(needle) = self.index.lock().unwrap().needles.get(blob).copied() else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no packed blob {blob}"),
+            ));
+        };
+        let record = encode_record(
+            KIND_TOMBSTONE,
+            blob,
+            unix_now(),
+            &needle.segment.to_le_bytes(),
+        );
+        let (segment, _) = self.append_record(store, &mut active, &record).await?;
+        store.sync(&[segment_key(segment)]).await?;
+        let mut index = self.index.lock().unwrap();
+        index.needles.remove(blob);
+        if let Some(stats) = index.segments.get_mut(&needle.segment) {
+            stats.live = stats.live.saturating_sub(needle.record_len(blob));
+        }
+        Ok(())
+    }
+
+    /// Opens a packed blob for reading.
+    pub async fn get(&self, store: &dyn Store, blob: &str) -> io::Result<Box<dyn BlobRead>> {
+        // Compaction may move the blob between the lookup and opening its segment
+        let mut attempts = 2;
+        loop {
+            let needle = self.index.lock().unwrap().needles.get(blob).copied();
+            let Some(needle) = needle else {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no packed blob {blob}"),
+                ));
+            };
+            match store.get(&segment_key(needle.segment)).await {
+                Ok(segment) => {
+                    return Ok(Box::new(NeedleReader {
+                        segment,
+                        offset: needle.offset,
+                        length: needle.length,
+                    }))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound && attempts > 1 => attempts -= 1,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Compacts the segments whose live ratio is below the threshold. Tombstones are
+    /// carried over as long as the segment of the blob they delete still exists.
+    pub async fn compact(&self, store: &dyn Store, dry_run: bool) -> io::Result<CompactionReport> {
+        let active = *self.active.lock().await;
+        let candidates: Vec<(u64, SegmentStats)> = {
+            let index = self.index.lock().unwrap();
+            index
+                .segments
+                .iter()
+                .filter(|(&segment, stats)| {
+                    segment < active && stats.live_ratio() < self.config.min_live_ratio
+                })
+                .map(|(&segment, &stats)| (segment, stats))
+                .collect()
+        };
+        let mut report = CompactionReport {
+            dry_run,
+            segments: self.index.lock().unwrap().segments.len(),
+            ..Default::default()
+        };
+        if dry_run {
+            report.segments_compacted = candidates.len();
+            report.bytes_reclaimed = candidates
+                .iter()
+                .map(|(_, stats)| stats.size - stats.live)
+                .sum();
+            return Ok(report);
+        }
+
+        for (segment, stats) in candidates {
+            let mut copied = 0;
+            let mut written = BTreeSet::new();
+            let mut file = store.get(&segment_key(segment)).await?;
+            for record in scan(store, segment).await? {
+                let mut active = self.active.lock().await;
+                match record.kind {
+                    KIND_PUT => {
+                        let current = self
+                            .index
+                            .lock()
+                            .unwrap()
+                            .needles
+                            .get(&record.blob)
+                            .copied();
+                        if current.is_none_or(|needle| {
+                            needle.segment != segment || needle.offset != record.offset
+                        }) {
+                            continue;
+                        }
+                        let data = file.read_at(record.offset, record.length as usize).await?;
+                        let copy = encode_record(KIND_PUT, &record.blob, record.created, &data);
+                        let (target, offset) =
+                            self.append_record(store, &mut active, &copy).await?;
+                        let needle = Needle {
+                            segment: target,
+                            offset: offset + HEADER_LEN + record.blob.len() as u64,
+                            length: record.length,
+                            created: record.created,
+                        };
+                        let mut index = self.index.lock().unwrap();
+                        index.segments.get_mut(&target).unwrap().live += copy.len() as u64;
+                        index.needles.insert(record.blob, needle);
+                        written.insert(segment_key(target));
+                        copied += copy.len() as u64;
+                        report.records_moved += 1;
+                    }
+                    KIND_TOMBSTONE => {
+                        let Some(deleted_from) = record.deleted_from else {
+                            continue;
+                        };
+                        if deleted_from == segment
+                            || !self
+                                .index
+                                .lock()
+                                .unwrap()
+                                .segments
+                                .contains_key(&deleted_from)
+                        {
+                            continue;
+                        }
+                        let copy = encode_record(
+                            KIND_TOMBSTONE,
+                            &record.blob,
+                            record.created,
+                            &deleted_from.to_le_bytes(),
+                        );
+                        let (target, _) = self.append_record(store, &mut active, &copy).await?;
+                        written.insert(segment_key(target));
+                        copied += copy.len() as u64;
+                        report.records_moved += 1;
+                    }
+                    _ => {}
+                }
+            }
+
+            // The copies have to be durable before the originals go
+            store.sync(&written.into_iter().collect::<Vec<_>>()).await?;
+            // Nothing points into the segment any more, readers that opened it keep it
+            self.index.lock().unwrap().segments.remove(&segment);
+            match store.delete(&segment_key(segment)).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            report.segments_compacted += 1;
+            report.bytes_reclaimed += stats.size.saturating_sub(copied);
+        }
+        Ok(report)
+    }
+}
+
+/// Reads one packed blob out of its segment.
+struct NeedleReader {
+    segment: Box<dyn BlobRead>,
+    offset: u64,
+    length: u64,
+}
+
+#[async_trait]
+impl BlobRead for NeedleReader {
+    async fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        if offset + len as u64 > self.length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of the blob",
+            ));
+        }
+        self.segment.read_at(self.offset + offset, len).await
+    }
+
+    async fn stream(
+        self: Box<Self>,
+        start: u64,
+        length: u64,
+    ) -> io::Result<BoxStream<'static, io::Result<Bytes>>> {
+        let length = length.min(self.length.saturating_sub(start));
+        self.segment.stream(self.offset + start, length).await
+    }
+}
+
+/// Collects an upload that is packed, so it can be appended to a segment in one piece.
+#[derive(Clone, Default)]
+pub struct PackBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl PackBuffer {
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+#[async_trait]
+impl BlobWrite for PackBuffer {
+    async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.lock().unwrap().extend_from_slice(data);
+        Ok(())
+    }
+
+    async fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs compaction every `compaction_interval` until the process exits.
+pub fn spawn(state: web::Data<AppState>) {
+    let interval = state.segments.config.compaction_interval;
+    if interval.is_zero() || state.segments.config.threshold == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match state.segments.compact(&*state.storage, false).await {
+                Ok(report) if report.segments_compacted > 0 => {
+                    println!("Compaction: {:?}", report)
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Compaction failed: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::TryStreamExt;
+
+    use crate::memory::MemoryStore;
+
+    /// Records of a 6 byte ID and 72 bytes of data take 100 bytes, 3 fill a segment.
+    fn config() -> PackConfig {
+        PackConfig {
+            threshold: 100,
+            segment_size: 300,
+            min_live_ratio: 0.5,
+            compaction_interval: Duration::ZERO,
+        }
+    }
+
+    fn data(blob: u8) -> Vec<u8> {
+        vec![blob; 72]
+    }
+
+    async fn pack(segments: &Segments, store: &dyn Store, blobs: impl IntoIterator<Item = u8>) {
+        for blob in blobs {
+            let key = format!("blob-{blob}");
+            segments
+                .append(store, &key, &data(blob))
+                .await
+                .expect("packed blob");
+        }
+    }
+
+    async fn read(segments: &Segments, store: &dyn Store, blob: u8) -> Option<Vec<u8>> {
+        match segments.get(store, &format!("blob-{blob}")).await {
+            Ok(mut file) => Some(file.read_at(0, 72).await.expect("packed data")),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => panic!("reading blob-{blob} failed: {e}"),
+        }
+    }
+
+    async fn segment_keys(store: &dyn Store) -> Vec<String> {
+        let mut keys: Vec<String> = store
+            .list(SEGMENT_PREFIX)
+            .await
+            .expect("segments")
+            .into_iter()
+            .map(|stat| stat.key)
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn only_small_versions_are_packed() {
+        let segments = Segments {
+            config: config(),
+            index: Mutex::default(),
+            active: tokio::sync::Mutex::new(0),
+        };
+        assert!(segments.packs(0));
+        assert!(segments.packs(100));
+        assert!(!segments.packs(101));
+        let disabled = Segments {
+            config: PackConfig {
+                threshold: 0,
+                ..config()
+            },
+            ..segments
+        };
+        assert!(!disabled.packs(0));
+    }
+
+    #[actix_web::test]
+    async fn packed_blobs_are_read_back_after_a_restart() {
+        let store = MemoryStore::default();
+        let segments = Segments::open(&store, config()).await.expect("segments");
+        pack(&segments, &store, 0..5).await;
+        assert_eq!(segment_keys(&store).await, [segment_key(0), segment_key(1)]);
+        for blob in 0..5 {
+            assert_eq!(read(&segments, &store, blob).await, Some(data(blob)));
+        }
+
+        let file = segments.get(&store, "blob-3").await.expect("packed blob");
+        let chunks: Vec<Bytes> = file
+            .stream(70, 100)
+            .await
+            .expect("stream")
+            .try_collect()
+            .await
+            .expect("streamed data");
+        assert_eq!(chunks.concat(), [3, 3]);
+        let mut file = segments.get(&store, "blob-3").await.expect("packed blob");
+        assert!(file.read_at(70, 3).await.is_err());
+
+        let segments = Segments::open(&store, config()).await.expect("reopened");
+        for blob in 0..5 {
+            assert_eq!(read(&segments, &store, blob).await, Some(data(blob)));
+        }
+        // Appends after a restart start a segment of their own
+        pack(&segments, &store, [5]).await;
+        assert_eq!(segment_keys(&store).await.len(), 3);
+        assert_eq!(read(&segments, &store, 5).await, Some(data(5)));
+    }
+
+    #[actix_web::test]
+    async fn deleted_blobs_stay_deleted_after_a_restart() {
+        let store = MemoryStore::default();
+        let segments = Segments::open(&store, config()).await.expect("segments");
+        pack(&segments, &store, 0..2).await;
+        segments.delete(&store, "blob-0").await.expect("deleted");
+        assert_eq!(read(&segments, &store, 0).await, None);
+        let missing = segments.delete(&store, "blob-0").await.expect_err("gone");
+        assert_eq!(missing.kind(), io::ErrorKind::NotFound);
+
+        let segments = Segments::open(&store, config()).await.expect("reopened");
+        assert!(!segments.contains("blob-0"));
+        assert_eq!(read(&segments, &store, 1).await, Some(data(1)));
+    }
+
+    #[actix_web::test]
+    async fn compaction_moves_live_blobs_out_of_sparse_segments() {
+        let store = MemoryStore::default();
+        let segments = Segments::open(&store, config()).await.expect("segments");
+        pack(&segments, &store, 0..4).await;
+        segments.delete(&store, "blob-0").await.expect("deleted");
+        segments.delete(&store, "blob-1").await.expect("deleted");
+
+        let planned = segments.compact(&store, true).await.expect("dry run");
+        assert_eq!(planned.segments_compacted, 1);
+        assert_eq!(planned.bytes_reclaimed, 200);
+        assert_eq!(segment_keys(&store).await.len(), 2);
+
+        let report = segments.compact(&store, false).await.expect("compacted");
+        assert_eq!(report.segments_compacted, 1);
+        assert_eq!(report.records_moved, 1);
+        assert_eq!(report.bytes_reclaimed, 200);
+        assert_eq!(segment_keys(&store).await, [segment_key(1)]);
+        for (blob, expected) in [(0, None), (1, None), (2, Some(data(2))), (3, Some(data(3)))] {
+            assert_eq!(read(&segments, &store, blob).await, expected, "blob-{blob}");
+        }
+
+        let segments = Segments::open(&store, config()).await.expect("reopened");
+        for (blob, expected) in [(0, None), (1, None), (2, Some(data(2))), (3, Some(data(3)))] {
+            assert_eq!(read(&segments, &store, blob).await, expected, "blob-{blob}");
+        }
+        let report = segments.compact(&store, false).await.expect("compacted");
+        assert_eq!(report.segments_compacted, 0);
+    }
+
+    #[actix_web::test]
+    async fn compaction_keeps_tombstones_of_segments_still_there() {
+        let store = MemoryStore::default();
+        let segments = Segments::open(&store, config()).await.expect("segments");
+        pack(&segments, &store, 0..4).await;
+        // Segment 1 gets blob-3, the tombstones of blob-0 and blob-3 and blob-4
+        segments.delete(&store, "blob-0").await.expect("deleted");
+        segments.delete(&store, "blob-3").await.expect("deleted");
+        pack(&segments, &store, 4..6).await;
+
+        let report = segments.compact(&store, false).await.expect("compacted");
+        assert_eq!(report.segments_compacted, 1);
+        // blob-4 and the tombstone of blob-0, whose segment is still there
+        assert_eq!(report.records_moved, 2);
+        assert_eq!(segment_keys(&store).await, [segment_key(0), segment_key(2)]);
+
+        let segments = Segments::open(&store, config()).await.expect("reopened");
+        for blob in 0..6 {
+            let expected = (blob != 0 && blob != 3).then(|| data(blob));
+            assert_eq!(read(&segments, &store, blob).await, expected, "blob-{blob}");
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Zero-copy downloads with sendfile(2).
+//!
+//! actix-web copies every body chunk into its own write buffer before it writes it to the
+//! socket. A GET of a plain object whose blob is a file, whole or a single range, instead
+//! gets a `Sendfile` body that has the kernel copy the file to the socket of the
+//! connection, which `on_connect` keeps in the connection data. Only plain HTTP
+//! connections have one, TLS ones stream through user space as before.
+//!
+//! The body writes to the socket behind the back of actix-web, which is only safe while
+//! nothing is left in its write buffer. With sendfile enabled the server sets that buffer
+//! to a single byte, so the dispatcher writes out the response head, and whatever came
+//! before it, before it asks the body for data.
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use actix_web::web::Bytes;
+use actix_web::HttpRequest;
+use std::any::Any;
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::task::JoinHandle;
+
+use crate::downloads::{Download, ReadPath};
+use crate::metadata::ObjectMeta;
+use crate::shutdown::Transfer;
+use crate::AppState;
+
+/// Most bytes one sendfile(2) call is asked to send, it stops earlier once the socket
+/// buffer is full
+const MAX_SEND: u64 = 16 << 20;
+
+/// The socket of a plain HTTP connection.
+struct Socket(RawFd);
+
+/// Keeps the socket of plain HTTP connections for `Sendfile` bodies.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(stream) = connection.downcast_ref::<TcpStream>() {
+        data.insert(Socket(stream.as_raw_fd()));
+    }
+}
+
+/// A body sending `length` bytes of the blob of `meta` from `start` straight from its
+/// file, `None` if the request came over TLS or the object is not stored as it is sent.
+pub async fn body(
+    req: &HttpRequest,
+    state: &AppState,
+    meta: &ObjectMeta,
+    start: u64,
+    length: u64,
+) -> Option<Sendfile> {
+    let socket = req.conn_data::<Socket>()?;
+    if meta.compressed.is_some() || meta.encryption.is_some() || meta.packed {
+        return None;
+    }
+    let path = state.storage.file_path(&state.blob_key(&meta.blob))?;
+    // The blob can be replaced or collected in between, the buffered path sorts that out
+    let file = tokio::fs::File::open(path).await.ok()?.into_std().await;
+    // SAFETY: the connection, and with it the socket, is open while its request is
+    // handled. The copy keeps the socket open for sends still running once it closed
+    let socket = unsafe { BorrowedFd::borrow_raw(socket.0) }
+        .try_clone_to_owned()
+        .ok()?;
+    Some(Sendfile {
+        socket: Arc::new(socket),
+        writable: None,
+        file: Arc::new(file),
+        offset: start,
+        end: start + length,
+        sending: None,
+        blocked: false,
+        download: state.downloads.start(ReadPath::Sendfile),
+        transfer: state.transfers.download(length),
+    })
+}
+
+/// Sends a range of a file to the socket of the connection, a call to sendfile(2) at a
+/// time on the blocking pool, since it waits for the disk.
+pub struct Sendfile {
+    socket: Arc<OwnedFd>,
+    /// Registered once the socket buffer ran full
+    writable: Option<AsyncFd<Arc<OwnedFd>>>,
+    file: Arc<File>,
+    offset: u64,
+    end: u64,
+    sending: Option<JoinHandle<io::Result<usize>>>,
+    /// The last send found the socket buffer full
+    blocked: bool,
+    download: Download,
+    transfer: Transfer,
+}
+
+impl Sendfile {
+    fn send(&self) -> JoinHandle<io::Result<usize>> {
+        let socket = Arc::clone(&self.socket);
+        let file = Arc::clone(&self.file);
+        let mut offset = self.offset as libc::off_t;
+        let count = (self.end - self.offset).min(MAX_SEND) as usize;
+        tokio::task::spawn_blocking(move || {
+            // SAFETY: both descriptors are owned by the task and open, and offset is a
+            // valid off_t the kernel advances past the bytes sent
+            match unsafe {
+                libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut offset, count)
+            } {
+                -1 => Err(io::Error::last_os_error()),
+                sent => Ok(sent as usize),
+            }
+        })
+    }
+
+    /// Waits until the socket buffer has room again.
+    fn poll_writable(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.writable.is_none() {
+            let socket = Arc::clone(&self.socket);
+            // SAFETY: the descriptor is owned by the Arc the registration holds, so it
+            // stays open and the same for as long as the registration
+            let writable = unsafe { AsyncFd::register_with_interest(socket, Interest::WRITABLE) };
+            self.writable = Some(writable.map_err(|e| e.into_parts().1)?);
+        }
+        let writable = self.writable.as_ref().expect("registered above");
+        match writable.poll_write_ready(cx) {
+            Poll::Ready(Ok(mut guard)) => {
+                // Cleared before the next send, so readiness it misses is not lost
+                guard.clear_ready();
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl MessageBody for Sendfile {
+    type Error = io::Error;
+
+    /// The length is sent as `Content-Length` by `no_chunking`, none of the bytes pass
+    /// through the encoder that would count them.
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        loop {
+            if this.offset == this.end {
+                return Poll::Ready(None);
+            }
+            if this.blocked {
+                std::task::ready!(this.poll_writable(cx))?;
+                this.blocked = false;
+            }
+            let Some(sending) = &mut this.sending else {
+                this.sending = Some(this.send());
+                continue;
+            };
+            let sent = match Pin::new(sending).poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(sent) => sent.map_err(io::Error::other)?,
+            };
+            this.sending = None;
+            match sent {
+                Ok(0) => {
+                    return Poll::Ready(Some(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Blob is shorter than its object",
+                    ))))
+                }
+                Ok(sent) => {
+                    this.offset += sent as u64;
+                    this.download.sent(sent);
+                    this.transfer.sent(sent);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => this.blocked = true,
+                Err(e) => return Poll::Ready(Some(Err(e))),
+            }
+        }
+    }
+}
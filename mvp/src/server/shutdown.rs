@@ -0,0 +1,163 @@
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{self, SignalKind};
+use tokio::time::Instant;
+
+use crate::{AppState, TMP_PREFIX};
+
+/// Counts object transfers, so shutdown can report what it drained and what it cut off.
+#[derive(Default)]
+pub struct Transfers {
+    uploads: AtomicUsize,
+    downloads: AtomicUsize,
+    completed: AtomicU64,
+    interrupted: AtomicU64,
+    draining: OnceLock<Draining>,
+}
+
+struct Draining {
+    since: Instant,
+    /// Completed and interrupted transfers when the shutdown began
+    finished: (u64, u64),
+}
+
+impl Transfers {
+    pub fn upload(self: &Arc<Self>) -> Transfer {
+        self.uploads.fetch_add(1, Ordering::Relaxed);
+        Transfer {
+            transfers: Arc::clone(self),
+            upload: true,
+            remaining: u64::MAX,
+        }
+    }
+
+    /// A download of `size` bytes, complete once all of them were sent.
+    pub fn download(self: &Arc<Self>, size: u64) -> Transfer {
+        self.downloads.fetch_add(1, Ordering::Relaxed);
+        Transfer {
+            transfers: Arc::clone(self),
+            upload: false,
+            remaining: size,
+        }
+    }
+
+    fn in_flight(&self) -> (usize, usize) {
+        (
+            self.uploads.load(Ordering::Relaxed),
+            self.downloads.load(Ordering::Relaxed),
+        )
+    }
+
+    fn finished(&self) -> (u64, u64) {
+        (
+            self.completed.load(Ordering::Relaxed),
+            self.interrupted.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// A transfer in flight. Dropping it before it completed counts it as interrupted.
+pub struct Transfer {
+    transfers: Arc<Transfers>,
+    upload: bool,
+    remaining: u64,
+}
+
+impl Transfer {
+    pub fn complete(&mut self) {
+        self.remaining = 0;
+    }
+
+    pub fn sent(&mut self, bytes: usize) {
+        self.remaining = self.remaining.saturating_sub(bytes as u64);
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        let transfers = &self.transfers;
+        if self.upload {
+            transfers.uploads.fetch_sub(1, Ordering::Relaxed);
+        } else {
+            transfers.downloads.fetch_sub(1, Ordering::Relaxed);
+        }
+        if self.remaining == 0 {
+            transfers.completed.fetch_add(1, Ordering::Relaxed);
+        } else {
+            transfers.interrupted.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Resolves on SIGTERM or SIGINT, after which the server accepts no new connections and
+/// gives transfers in flight its shutdown timeout to finish.
+pub async fn signal(transfers: Arc<Transfers>, timeout: Duration) {
+    let (mut terminate, mut interrupt) = match (
+        unix::signal(SignalKind::terminate()),
+        unix::signal(SignalKind::interrupt()),
+    ) {
+        (Ok(terminate), Ok(interrupt)) => (terminate, interrupt),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Failed to install signal handlers, graceful shutdown is unavailable: {e}");
+            return std::future::pending().await;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+
+    let (uploads, downloads) = transfers.in_flight();
+    println!(
+        "Shutting down, draining {uploads} uploads and {downloads} downloads for up to {}s",
+        timeout.as_secs()
+    );
+    let _ = transfers.draining.set(Draining {
+        since: Instant::now(),
+        finished: transfers.finished(),
+    });
+}
+
+/// Runs once the server stopped: syncs the metadata log and marks the staging files of
+/// interrupted uploads so the next GC run reclaims them without waiting out its grace period.
+pub async fn finish(state: &AppState) -> io::Result<()> {
+    state.metadata.close().await?;
+    let marked = mark_incomplete(state).await?;
+
+    let transfers = &state.transfers;
+    let (completed, interrupted) = transfers.finished();
+    let (before_completed, before_interrupted, elapsed) = match transfers.draining.get() {
+        Some(draining) => (
+            draining.finished.0,
+            draining.finished.1,
+            draining.since.elapsed(),
+        ),
+        None => (completed, interrupted, Duration::ZERO),
+    };
+    println!(
+        "Shutdown complete after {:.1}s: {} transfers drained, {} did not complete, metadata synced, \
+         {marked} incomplete uploads marked for cleanup",
+        elapsed.as_secs_f64(),
+        completed - before_completed,
+        interrupted - before_interrupted,
+    );
+    Ok(())
+}
+
+async fn mark_incomplete(state: &AppState) -> io::Result<usize> {
+    let uploads = state.storage.list(TMP_PREFIX).await?;
+    for upload in &uploads {
+        match state
+            .storage
+            .set_modified(&upload.key, SystemTime::UNIX_EPOCH)
+            .await
+        {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(uploads.len())
+}
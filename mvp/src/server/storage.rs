@@ -0,0 +1,117 @@
+//! Where blob data lives.
+//!
+//! Handlers, GC, lifecycle rules and shutdown only talk to a `Store`, which keeps blobs
+//! under `/`-separated keys such as `blobs/ab/ab12...` and `tmp/{bucket}/{blob}`. Data is
+//! written through a `BlobWrite` or appended, made durable with `Store::sync`, and read
+//! through a `BlobRead` at arbitrary offsets. The metadata log is not part of the store.
+
+use actix_web::web::Bytes;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::local::LocalStore;
+use crate::memory::MemoryStore;
+
+#[derive(clap::ValueEnum, Clone, Copy, PartialEq, Eq, Default, Debug)]
+pub enum Backend {
+    /// Files in the data folder, through the page cache
+    #[default]
+    Tokio,
+    /// Files in the data folder, with io_uring and O_DIRECT
+    Uring,
+    /// In memory, lost on restart
+    Memory,
+}
+
+/// A blob as listed by a store.
+#[derive(Clone, Debug)]
+pub struct BlobStat {
+    pub key: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+impl BlobStat {
+    /// The last segment of the key, the blob ID.
+    pub fn name(&self) -> &str {
+        self.key.rsplit('/').next().unwrap_or(&self.key)
+    }
+}
+
+#[async_trait]
+pub trait Store: Send + Sync {
+    /// Starts writing `key`, replacing what is stored under it.
+    async fn put(&self, key: &str) -> io::Result<Box<dyn BlobWrite>>;
+
+    /// Opens `key` for reading.
+    async fn get(&self, key: &str) -> io::Result<Box<dyn BlobRead>>;
+
+    /// Appends `data` to `key`, creating it if needed, and returns the offset it was
+    /// written at. Callers serialize appends to a key.
+    async fn append(&self, key: &str, data: &[u8]) -> io::Result<u64>;
+
+    /// Moves a blob to another key, replacing what is stored there.
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// Makes a blob available under another key as well, replacing what is stored there,
+    /// without copying its data where the store can avoid it. The new key counts as just
+    /// modified, so GC leaves it alone until it is referenced.
+    async fn link(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// Fails with `NotFound` if there is no such blob.
+    async fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// All blobs whose key starts with `prefix`, which ends in `/`.
+    async fn list(&self, prefix: &str) -> io::Result<Vec<BlobStat>>;
+
+    async fn stat(&self, key: &str) -> io::Result<Option<BlobStat>>;
+
+    async fn set_modified(&self, key: &str, modified: SystemTime) -> io::Result<()>;
+
+    /// Makes what was written to the blobs durable, along with their names: where they
+    /// were created, linked or renamed to, and where they were renamed from.
+    async fn sync(&self, keys: &[String]) -> io::Result<()>;
+
+    /// The file holding `key`, for stores that keep blobs as files of their own, so the
+    /// kernel can send it without reading it into memory.
+    fn file_path(&self, _key: &str) -> Option<PathBuf> {
+        None
+    }
+}
+
+#[async_trait]
+pub trait BlobWrite: Send {
+    async fn write_all(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Writes what is buffered, the blob is durable after `Store::sync`.
+    async fn finish(&mut self) -> io::Result<()>;
+}
+
+#[async_trait]
+pub trait BlobRead: Send {
+    /// Reads exactly `len` bytes starting at `offset`.
+    async fn read_at(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>>;
+
+    /// Streams `length` bytes starting at `start`.
+    async fn stream(
+        self: Box<Self>,
+        start: u64,
+        length: u64,
+    ) -> io::Result<BoxStream<'static, io::Result<Bytes>>>;
+}
+
+/// Sets up the store of `backend`, file based ones keep their blobs in `folder`.
+pub fn open(backend: Backend, folder: &Path, queue_depth: u32) -> io::Result<Arc<dyn Store>> {
+    Ok(match backend {
+        Backend::Tokio => Arc::new(LocalStore::new(folder, None)),
+        Backend::Uring => Arc::new(LocalStore::new(
+            folder,
+            Some(crate::uring::Ring::new(queue_depth)?),
+        )),
+        Backend::Memory => Arc::new(MemoryStore::default()),
+    })
+}
@@ -0,0 +1,143 @@
+//! The HTTP layer against state kept in memory.
+
+use actix_web::http::{Method, StatusCode};
+use actix_web::{test, web};
+use std::path::{Path, PathBuf};
+
+use crate::{app, auth, signing, AppState};
+
+const ACCESS_KEY: &str = "MVPTEST";
+const SECRET_KEY: &str = "test-secret";
+const PARQUET_FILE: &[u8] = include_bytes!("../../tests/parquet_files/output.parquet");
+
+/// A directory of its own in the temporary directory, removed with what it holds when
+/// dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let path = std::env::temp_dir().join(format!("mvp-{}", crate::metadata::new_id()));
+        std::fs::create_dir(&path).expect("new temporary directory");
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+async fn state() -> web::Data<AppState> {
+    let credentials = auth::Credentials::parse(&format!("{ACCESS_KEY}:{SECRET_KEY}:root"))
+        .expect("valid credentials");
+    web::Data::new(
+        AppState::in_memory(credentials)
+            .await
+            .expect("in-memory state"),
+    )
+}
+
+/// A request signed like the client does, claiming `content_sha256` for `body`.
+fn signed(method: Method, uri: &str, body: &[u8], content_sha256: &str) -> test::TestRequest {
+    let date = crate::metadata::unix_now().to_string();
+    let (path, query) = uri.split_once('?').unwrap_or((uri, ""));
+    let headers = signing::canonical_headers([
+        (signing::DATE_HEADER, date.as_str()),
+        (signing::CONTENT_SHA256_HEADER, content_sha256),
+    ]);
+    let signature = signing::sign(
+        SECRET_KEY,
+        &signing::string_to_sign(method.as_str(), path, query, &headers, &date),
+    );
+    test::TestRequest::default()
+        .method(method)
+        .uri(uri)
+        .insert_header((signing::DATE_HEADER, date))
+        .insert_header((signing::CONTENT_SHA256_HEADER, content_sha256))
+        .insert_header((
+            "Authorization",
+            signing::authorization_header(ACCESS_KEY, &signature),
+        ))
+        .set_payload(body.to_vec())
+}
+
+fn request(method: Method, uri: &str, body: &[u8]) -> test::TestRequest {
+    signed(method, uri, body, &signing::content_sha256(body))
+}
+
+#[actix_web::test]
+async fn objects_round_trip() {
+    let app = test::init_service(app(state().await)).await;
+
+    let response = test::call_service(&app, request(Method::PUT, "/tests", b"").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let put = request(Method::PUT, "/tests/data.parquet", PARQUET_FILE).to_request();
+    let response = test::call_service(&app, put).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let get = request(Method::GET, "/tests/data.parquet", b"").to_request();
+    let response = test::call_service(&app, get).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(test::read_body(response).await, PARQUET_FILE);
+
+    let anonymous = test::TestRequest::get()
+        .uri("/tests/data.parquet")
+        .to_request();
+    let response = test::call_service(&app, anonymous).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn body_must_match_its_signed_hash() {
+    let app = test::init_service(app(state().await)).await;
+    let response = test::call_service(&app, request(Method::PUT, "/tests", b"").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let put = signed(
+        Method::PUT,
+        "/tests/data.parquet",
+        PARQUET_FILE,
+        &signing::content_sha256(b"something else"),
+    );
+    let response = test::call_service(&app, put.to_request()).await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let get = request(Method::GET, "/tests/data.parquet", b"").to_request();
+    let response = test::call_service(&app, get).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn presigned_urls_cannot_copy() {
+    let app = test::init_service(app(state().await)).await;
+    let response = test::call_service(&app, request(Method::PUT, "/tests", b"").to_request()).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let put = request(Method::PUT, "/tests/data.parquet", PARQUET_FILE).to_request();
+    let response = test::call_service(&app, put).await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let url = signing::presign(
+        "http://localhost/tests/copy.parquet".parse().unwrap(),
+        "PUT",
+        ACCESS_KEY,
+        SECRET_KEY,
+        crate::metadata::unix_now() + 60,
+        None,
+    );
+    let copy = test::TestRequest::put()
+        .uri(&format!(
+            "{}?{}",
+            url.path(),
+            url.query().unwrap_or_default()
+        ))
+        .insert_header(("x-mvp-copy-source", "tests/data.parquet"))
+        .to_request();
+    let response = test::call_service(&app, copy).await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
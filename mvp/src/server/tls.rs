@@ -0,0 +1,166 @@
+//! HTTPS with rustls.
+//!
+//! The certificate chain and key are read from PEM files and handed to rustls through a
+//! resolver that can be swapped while the server runs: on SIGHUP both files are read
+//! again, and new handshakes use them while established connections keep their session.
+//! A certificate that fails to load leaves the current one in place.
+
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa, KeyPair, KeyUsagePurpose};
+use rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{InconsistentKeys, ServerConfig};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use tokio::signal::unix::{self, SignalKind};
+
+/// Where `--generate-certificate` puts its files, below the data folder
+pub const CERTIFICATE_FOLDER: &str = "tls";
+
+#[derive(Debug)]
+pub struct CertificateResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertificateResolver {
+    pub fn load(cert_path: &Path, key_path: &Path) -> io::Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            current: RwLock::new(load_certified_key(cert_path, key_path)?),
+            cert_path: cert_path.to_path_buf(),
+            key_path: key_path.to_path_buf(),
+        }))
+    }
+
+    /// Reads the certificate and key again, keeping the current ones if that fails.
+    pub fn reload(&self) -> io::Result<()> {
+        let certified_key = load_certified_key(&self.cert_path, &self.key_path)?;
+        *self.current.write().unwrap() = certified_key;
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.current.read().unwrap()))
+    }
+}
+
+fn load_certified_key(cert_path: &Path, key_path: &Path) -> io::Result<Arc<CertifiedKey>> {
+    let invalid = |path: &Path, e: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to read {path:?}: {e}"),
+        )
+    };
+    let chain = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(cert_path, &e))?;
+    if chain.is_empty() {
+        return Err(invalid(cert_path, &"no certificate in the file"));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| invalid(key_path, &e))?;
+    let key = any_supported_type(&key).map_err(|e| invalid(key_path, &e))?;
+    let certified = CertifiedKey::new(chain, key);
+    match certified.keys_match() {
+        // Keys that cannot tell their public key are taken on trust, as rustls does
+        Ok(()) | Err(rustls::Error::InconsistentKeys(InconsistentKeys::Unknown)) => {}
+        Err(e) => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Key {key_path:?} does not belong to certificate {cert_path:?}: {e}"),
+            ))
+        }
+    }
+    Ok(Arc::new(certified))
+}
+
+/// TLS 1.2 and 1.3 with the certificate of `resolver`.
+pub fn server_config(resolver: Arc<CertificateResolver>) -> io::Result<ServerConfig> {
+    Ok(
+        ServerConfig::builder_with_provider(Arc::new(default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_cert_resolver(resolver),
+    )
+}
+
+/// Reloads the certificate of `resolver` on every SIGHUP until the process exits.
+pub fn spawn_reload(resolver: Arc<CertificateResolver>) {
+    tokio::spawn(async move {
+        let mut hangup = match unix::signal(SignalKind::hangup()) {
+            Ok(hangup) => hangup,
+            Err(e) => {
+                eprintln!(
+                    "Failed to install the SIGHUP handler, certificates are not reloaded: {e}"
+                );
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            match resolver.reload() {
+                Ok(()) => println!("Reloaded the TLS certificate from {:?}", resolver.cert_path),
+                Err(e) => {
+                    eprintln!("Failed to reload the TLS certificate, keeping the old one: {e}")
+                }
+            }
+        }
+    });
+}
+
+/// Files written by `generate`.
+pub struct GeneratedCertificate {
+    pub ca: PathBuf,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// Creates a CA and a server certificate for `hosts` signed by it in `folder`, for local
+/// testing. Clients trust the server by trusting `ca.pem`.
+pub fn generate(folder: &Path, hosts: &[String]) -> io::Result<GeneratedCertificate> {
+    let ca_key = KeyPair::generate().map_err(io::Error::other)?;
+    let mut ca_params = CertificateParams::new(Vec::new()).map_err(io::Error::other)?;
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "mvp test CA");
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+    let ca = ca_params.self_signed(&ca_key).map_err(io::Error::other)?;
+
+    let key = KeyPair::generate().map_err(io::Error::other)?;
+    let mut params = CertificateParams::new(hosts.to_vec()).map_err(io::Error::other)?;
+    params.distinguished_name.push(
+        DnType::CommonName,
+        hosts.first().map_or("mvp", String::as_str),
+    );
+    let cert = params
+        .signed_by(&key, &ca, &ca_key)
+        .map_err(io::Error::other)?;
+
+    std::fs::create_dir_all(folder)?;
+    let generated = GeneratedCertificate {
+        ca: folder.join("ca.pem"),
+        cert: folder.join("cert.pem"),
+        key: folder.join("key.pem"),
+    };
+    std::fs::write(&generated.ca, ca.pem())?;
+    std::fs::write(&generated.cert, cert.pem())?;
+    write_private(&generated.key, &key.serialize_pem())?;
+    Ok(generated)
+}
+
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents.as_bytes())
+}
@@ -0,0 +1,583 @@
+//! A minimal io_uring driver on top of the raw syscalls.
+//!
+//! One thread owns the ring. Requests reach it through a channel, are submitted in
+//! batches of up to the queue depth and answered through a oneshot channel once their
+//! completion arrives. The thread only ever waits in `io_uring_enter`: a read of an
+//! eventfd stays in flight in the ring and completes when a request is queued, so new
+//! requests are submitted right away instead of after the next completion. Buffers come
+//! from a pool of `BUFFER_SIZE` buffers aligned for O_DIRECT, whose size also bounds the
+//! memory held by in-flight I/O.
+
+use std::alloc::{self, Layout};
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use tokio::sync::{oneshot, OwnedSemaphorePermit, Semaphore};
+
+/// Alignment O_DIRECT needs for buffers, offsets and lengths
+pub const ALIGNMENT: usize = 4096;
+pub const BUFFER_SIZE: usize = 1 << 20;
+
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x8000000;
+const IORING_OFF_SQES: i64 = 0x10000000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_OP_READ: u8 = 22;
+const IORING_OP_WRITE: u8 = 23;
+/// `user_data` of the eventfd read, request IDs start at 1
+const WAKE_ID: u64 = 0;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A buffer aligned for O_DIRECT that goes back to its pool when dropped.
+pub struct Buffer {
+    ptr: *mut u8,
+    /// Bytes of the buffer that hold data
+    pub len: usize,
+    _permit: OwnedSemaphorePermit,
+    pool: Arc<Mutex<Vec<usize>>>,
+}
+
+// The buffer is exclusively owned, only the driver thread touches it while I/O is in flight
+unsafe impl Send for Buffer {}
+
+impl Buffer {
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    /// Appends as much of `data` as fits and returns how much that was.
+    pub fn fill(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(BUFFER_SIZE - self.len);
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), self.ptr.add(self.len), n) };
+        self.len += n;
+        n
+    }
+
+    /// Zero-pads the data up to the next multiple of `ALIGNMENT`.
+    pub fn pad(&mut self) {
+        let padded = self.len.next_multiple_of(ALIGNMENT);
+        unsafe { ptr::write_bytes(self.ptr.add(self.len), 0, padded - self.len) };
+        self.len = padded;
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        self.pool.lock().unwrap().push(self.ptr as usize);
+    }
+}
+
+enum Op {
+    Read { len: usize },
+    Write,
+    Fsync,
+}
+
+struct Request {
+    op: Op,
+    /// Shared so the descriptor stays open until the kernel is done with it
+    fd: Arc<OwnedFd>,
+    offset: u64,
+    buffer: Option<Buffer>,
+    reply: oneshot::Sender<(io::Result<usize>, Option<Buffer>)>,
+}
+
+/// Handle to the ring thread and its buffer pool.
+pub struct Ring {
+    /// Taken when the ring is dropped, which stops the thread
+    requests: Mutex<Option<Sender<Request>>>,
+    /// Written after every request to wake the thread
+    wake: Arc<OwnedFd>,
+    buffers: Arc<Semaphore>,
+    pool: Arc<Mutex<Vec<usize>>>,
+}
+
+impl Ring {
+    /// Sets up a ring with `queue_depth` entries and as many buffers.
+    pub fn new(queue_depth: u32) -> io::Result<Arc<Self>> {
+        let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+        if wake < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let wake = Arc::new(unsafe { OwnedFd::from_raw_fd(wake) });
+        // One entry stays taken by the eventfd read
+        let driver = Driver::new(queue_depth + 1, Arc::clone(&wake))?;
+        let layout = Layout::from_size_align(BUFFER_SIZE, ALIGNMENT).unwrap();
+        let pool = (0..queue_depth)
+            .map(|_| {
+                let ptr = unsafe { alloc::alloc(layout) };
+                if ptr.is_null() {
+                    alloc::handle_alloc_error(layout);
+                }
+                ptr as usize
+            })
+            .collect();
+
+        let (requests, receiver) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("io_uring".to_string())
+            .spawn(move || driver.run(receiver))?;
+        Ok(Arc::new(Self {
+            requests: Mutex::new(Some(requests)),
+            wake,
+            buffers: Arc::new(Semaphore::new(queue_depth as usize)),
+            pool: Arc::new(Mutex::new(pool)),
+        }))
+    }
+
+    /// Waits for a free buffer of the pool.
+    pub async fn buffer(&self) -> Buffer {
+        let permit = Arc::clone(&self.buffers)
+            .acquire_owned()
+            .await
+            .expect("buffer pool is never closed");
+        let ptr = self
+            .pool
+            .lock()
+            .unwrap()
+            .pop()
+            .expect("a permit guarantees a free buffer");
+        Buffer {
+            ptr: ptr as *mut u8,
+            len: 0,
+            _permit: permit,
+            pool: Arc::clone(&self.pool),
+        }
+    }
+
+    /// Reads up to `len` bytes at `offset` into `buffer`, fewer at the end of the file.
+    pub async fn read(
+        &self,
+        fd: &Arc<OwnedFd>,
+        offset: u64,
+        len: usize,
+        buffer: Buffer,
+    ) -> io::Result<Buffer> {
+        let (result, buffer) = self
+            .submit(Op::Read { len }, fd, offset, Some(buffer))
+            .await;
+        let mut buffer = buffer.expect("reads return their buffer");
+        buffer.len = result?;
+        Ok(buffer)
+    }
+
+    /// Writes all of `buffer` at `offset`.
+    pub async fn write(&self, fd: &Arc<OwnedFd>, offset: u64, buffer: Buffer) -> io::Result<()> {
+        let len = buffer.len;
+        let (result, _) = self.submit(Op::Write, fd, offset, Some(buffer)).await;
+        if result? != len {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "short O_DIRECT write",
+            ));
+        }
+        Ok(())
+    }
+
+    pub async fn fsync(&self, fd: &Arc<OwnedFd>) -> io::Result<()> {
+        self.submit(Op::Fsync, fd, 0, None).await.0.map(|_| ())
+    }
+
+    async fn submit(
+        &self,
+        op: Op,
+        fd: &Arc<OwnedFd>,
+        offset: u64,
+        buffer: Option<Buffer>,
+    ) -> (io::Result<usize>, Option<Buffer>) {
+        let (reply, response) = oneshot::channel();
+        let request = Request {
+            op,
+            fd: Arc::clone(fd),
+            offset,
+            buffer,
+            reply,
+        };
+        let sent = match &*self.requests.lock().unwrap() {
+            Some(requests) => requests.send(request).is_ok(),
+            None => false,
+        };
+        if !sent {
+            return (Err(io::Error::other("io_uring thread stopped")), None);
+        }
+        wake(&self.wake);
+        response
+            .await
+            .unwrap_or_else(|_| (Err(io::Error::other("io_uring thread stopped")), None))
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        self.requests.lock().unwrap().take();
+        wake(&self.wake);
+    }
+}
+
+/// Adds one to the eventfd, completing the read the ring thread keeps in flight.
+fn wake(fd: &OwnedFd) {
+    let one = 1u64;
+    // Only fails if the counter would overflow, then a wakeup is pending anyway
+    unsafe { libc::write(fd.as_raw_fd(), (&one as *const u64).cast(), 8) };
+}
+
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+/// The ring itself, owned by its thread.
+struct Driver {
+    fd: OwnedFd,
+    params: Params,
+    sq: Mapping,
+    cq: Mapping,
+    sqes: Mapping,
+    wake: Arc<OwnedFd>,
+    /// Target of the eventfd read, leaked if the thread stops while the read is in flight
+    wake_count: Box<u64>,
+}
+
+// Only ever used by the ring thread after it was moved there
+unsafe impl Send for Driver {}
+
+impl Driver {
+    fn new(queue_depth: u32, wake: Arc<OwnedFd>) -> io::Result<Self> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_setup,
+                queue_depth,
+                &mut params as *mut Params,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+        let sq = map(&fd, sq_len, IORING_OFF_SQ_RING)?;
+        let cq = map(&fd, cq_len, IORING_OFF_CQ_RING)?;
+        let sqes = map(&fd, sqes_len, IORING_OFF_SQES)?;
+        Ok(Self {
+            fd,
+            params,
+            sq,
+            cq,
+            sqes,
+            wake,
+            wake_count: Box::new(0),
+        })
+    }
+
+    fn sq_field(&self, offset: u32) -> &AtomicU32 {
+        unsafe { &*(self.sq.ptr.add(offset as usize) as *const AtomicU32) }
+    }
+
+    fn cq_field(&self, offset: u32) -> &AtomicU32 {
+        unsafe { &*(self.cq.ptr.add(offset as usize) as *const AtomicU32) }
+    }
+
+    fn run(self, requests: Receiver<Request>) {
+        // The eventfd read takes one entry
+        let capacity = self.params.sq_entries as usize - 1;
+        let mut in_flight: HashMap<u64, Request> = HashMap::new();
+        let mut next_id = 0u64;
+        let mut open = true;
+        let mut armed = false;
+
+        loop {
+            let mut submitted = 0u32;
+            while open && in_flight.len() < capacity {
+                match requests.try_recv() {
+                    Ok(request) => {
+                        next_id += 1;
+                        self.push(next_id, &request);
+                        in_flight.insert(next_id, request);
+                        submitted += 1;
+                    }
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => open = false,
+                }
+            }
+            if !open && in_flight.is_empty() {
+                // Stop once the eventfd read is done with `wake_count`
+                if !armed {
+                    return;
+                }
+                wake(&self.wake);
+            } else if !armed {
+                // Requests queued since the channel was drained have already woken it
+                self.push_sqe(Sqe {
+                    opcode: IORING_OP_READ,
+                    fd: self.wake.as_raw_fd(),
+                    addr: &*self.wake_count as *const u64 as u64,
+                    len: 8,
+                    user_data: WAKE_ID,
+                    ..Default::default()
+                });
+                submitted += 1;
+                armed = true;
+            }
+
+            // Waits for a completion, which includes a new request arriving
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.as_raw_fd(),
+                    submitted,
+                    1u32,
+                    IORING_ENTER_GETEVENTS,
+                    ptr::null::<libc::sigset_t>(),
+                    0usize,
+                )
+            };
+            if result < 0 {
+                let error = io::Error::last_os_error();
+                if error.kind() != io::ErrorKind::Interrupted {
+                    log::error!("io_uring_enter failed, stopping the ring: {error}");
+                    Box::leak(self.wake_count);
+                    return;
+                }
+            }
+
+            if self.reap(&mut in_flight) {
+                armed = false;
+            }
+        }
+    }
+
+    fn push(&self, id: u64, request: &Request) {
+        let mut sqe = Sqe {
+            fd: request.fd.as_raw_fd(),
+            off: request.offset,
+            user_data: id,
+            ..Default::default()
+        };
+        match request.op {
+            Op::Read { len } => {
+                sqe.opcode = IORING_OP_READ;
+                sqe.addr = request.buffer.as_ref().unwrap().ptr as u64;
+                sqe.len = len.min(BUFFER_SIZE) as u32;
+            }
+            Op::Write => {
+                let buffer = request.buffer.as_ref().unwrap();
+                sqe.opcode = IORING_OP_WRITE;
+                sqe.addr = buffer.ptr as u64;
+                sqe.len = buffer.len as u32;
+            }
+            Op::Fsync => sqe.opcode = IORING_OP_FSYNC,
+        }
+        self.push_sqe(sqe);
+    }
+
+    fn push_sqe(&self, sqe: Sqe) {
+        let offsets = &self.params.sq_off;
+        let mask = unsafe { *(self.sq.ptr.add(offsets.ring_mask as usize) as *const u32) };
+        let tail = self.sq_field(offsets.tail).load(Ordering::Acquire);
+        let index = tail & mask;
+
+        unsafe {
+            let sqes = self.sqes.ptr as *mut Sqe;
+            ptr::write(sqes.add(index as usize), sqe);
+            let array = self.sq.ptr.add(offsets.array as usize) as *mut u32;
+            ptr::write_volatile(array.add(index as usize), index);
+        }
+        self.sq_field(offsets.tail)
+            .store(tail.wrapping_add(1), Ordering::Release);
+    }
+
+    /// Answers the completed requests, returns whether the eventfd read completed.
+    fn reap(&self, in_flight: &mut HashMap<u64, Request>) -> bool {
+        let offsets = &self.params.cq_off;
+        let mask = unsafe { *(self.cq.ptr.add(offsets.ring_mask as usize) as *const u32) };
+        let head_field = self.cq_field(offsets.head);
+        let mut head = head_field.load(Ordering::Acquire);
+        let tail = self.cq_field(offsets.tail).load(Ordering::Acquire);
+
+        let mut woken = false;
+        while head != tail {
+            let cqe = unsafe {
+                ptr::read(
+                    (self.cq.ptr.add(offsets.cqes as usize) as *const Cqe)
+                        .add((head & mask) as usize),
+                )
+            };
+            head = head.wrapping_add(1);
+            if cqe.user_data == WAKE_ID {
+                woken = true;
+                continue;
+            }
+            let Some(request) = in_flight.remove(&cqe.user_data) else {
+                continue;
+            };
+            let result = if cqe.res < 0 {
+                Err(io::Error::from_raw_os_error(-cqe.res))
+            } else {
+                Ok(cqe.res as usize)
+            };
+            // The caller may have given up, then the buffer just returns to the pool
+            let _ = request.reply.send((result, request.buffer));
+        }
+        head_field.store(head, Ordering::Release);
+        woken
+    }
+}
+
+fn map(fd: &OwnedFd, len: usize, offset: i64) -> io::Result<Mapping> {
+    let ptr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_POPULATE,
+            fd.as_raw_fd(),
+            offset,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(Mapping {
+        ptr: ptr.cast(),
+        len,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+
+    use crate::tests::TempDir;
+
+    #[tokio::test]
+    async fn writes_are_read_back() {
+        let ring = match Ring::new(4) {
+            Ok(ring) => ring,
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EPERM)) => {
+                eprintln!("Skipping, io_uring is not available: {e}");
+                return;
+            }
+            Err(e) => panic!("io_uring setup failed: {e}"),
+        };
+        let dir = TempDir::new();
+        let path = dir.path().join("blob");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .expect("new file");
+        let fd = Arc::new(OwnedFd::from(file));
+
+        let data: Vec<u8> = (0..BUFFER_SIZE + 100).map(|i| (i % 251) as u8).collect();
+        let mut offset = 0;
+        for chunk in data.chunks(BUFFER_SIZE) {
+            let mut buffer = ring.buffer().await;
+            assert_eq!(buffer.fill(chunk), chunk.len());
+            ring.write(&fd, offset, buffer)
+                .await
+                .expect("written buffer");
+            offset += chunk.len() as u64;
+        }
+        ring.fsync(&fd).await.expect("synced file");
+        assert!(std::fs::read(&path).expect("file") == data);
+
+        let buffer = ring.buffer().await;
+        let read = ring
+            .read(&fd, 10, 2 * ALIGNMENT, buffer)
+            .await
+            .expect("read");
+        assert_eq!(read.as_slice(), &data[10..10 + 2 * ALIGNMENT]);
+        let buffer = ring.buffer().await;
+        let tail = ring
+            .read(&fd, BUFFER_SIZE as u64, ALIGNMENT, buffer)
+            .await
+            .expect("read at the end");
+        assert_eq!(tail.as_slice(), &data[BUFFER_SIZE..]);
+    }
+}
@@ -1,20 +1,178 @@
+# Requests other than the health check are signed like the client signs them, see
+# README.md. Export ACCESS_KEY and SECRET_KEY with a pair from `server --add-credential`
+# and call mvp like curl, with the method and the URL first.
+mvp() {
+  local method=$1 url=$2
+  shift 2
+  local target=/${url#*://*/}
+  local path=${target%%\?*} query=
+  [[ $target == *\?* ]] && query=${target#*\?}
+  local sorted_query=$(tr '&' '\n' <<<"$query" | grep -v '^$' | LC_ALL=C sort | paste -sd '&' -)
+  local date=$(date +%s) content_sha256=$(printf '' | sha256sum | cut -d' ' -f1)
+  local headers=() args=("$@") i
+  for ((i = 0; i < ${#args[@]}; i++)); do
+    case ${args[i]} in
+      -d | --data | --data-binary)
+        local body=${args[i + 1]}
+        if [[ $body == @* ]]; then
+          content_sha256=$(sha256sum <"${body#@}" | cut -d' ' -f1)
+        else
+          content_sha256=$(printf '%s' "$body" | sha256sum | cut -d' ' -f1)
+        fi ;;
+      -H)
+        local name=${args[i + 1]%%:*} value=${args[i + 1]#*:}
+        name=${name,,}
+        value=$(sed 's/^ *//; s/ *$//' <<<"$value")
+        [[ $name == x-mvp-* ]] && headers+=("$name:$value") ;;
+    esac
+  done
+  headers+=("x-mvp-date:$date" "x-mvp-content-sha256:$content_sha256")
+  local canonical=$(printf '%s\n' "${headers[@]}" | LC_ALL=C sort)
+  local signature=$(printf '%s\n%s\n%s\n%s\n%s' "$method" "$path" "$sorted_query" "$canonical" "$date" |
+    openssl dgst -sha256 -hmac "$SECRET_KEY" | awk '{print $NF}')
+  curl -X "$method" "$url" -H "x-mvp-date: $date" -H "x-mvp-content-sha256: $content_sha256" \
+    -H "Authorization: MVP-HMAC-SHA256 Credential=$ACCESS_KEY,Signature=$signature" "$@"
+}
+
 # Health Check
 export IP_ADDRESS=18.159.254.5
 
-curl -X GET http://localhost:8000/api/healthchecker
-curl -X GET http://$IP_ADDRESS:8000/api/healthchecker
+curl -X GET http://localhost/api/healthchecker
+curl -X GET http://$IP_ADDRESS/api/healthchecker
 
 # PUT Parquet File
-curl -X PUT http://localhost:8000/parquet/test_file.parquet \
-  -F "file=@/Users/linusweigand/Universität/7.Semester/Bachelor/mvp/tests/parquet_files/output.parquet"
+mvp PUT http://localhost/parquet/test_file.parquet \
+  --data-binary "@/Users/linusweigand/Universität/7.Semester/Bachelor/mvp/tests/parquet_files/output.parquet"
 
-curl -X PUT http://$IP_ADDRESS:8000/parquet/test_file.parquet \
-  -F "file=@/Users/linusweigand/Universität/7.Semester/Bachelor/mvp/tests/parquet_files/output.parquet"
+mvp PUT http://$IP_ADDRESS/parquet/test_file.parquet \
+  --data-binary "@/Users/linusweigand/Universität/7.Semester/Bachelor/mvp/tests/parquet_files/output.parquet"
 
 # GET Parquet File
-URL="http://$IP_ADDRESS:8000/parquet/test_file.parquet"
+URL="http://$IP_ADDRESS/parquet/test_file.parquet"
 OUTPUT_FILE="download.parquet"
-status_code=$(curl -s -o "$OUTPUT_FILE" -w "%{http_code}" "$URL")
+status_code=$(mvp GET "$URL" -s -o "$OUTPUT_FILE" -w "%{http_code}")
 echo $status_code
-curl -X GET http://localhost:8000/parquet/test_file.parquet --output download.parquet
-curl -X GET http://$IP_ADDRESS:8000/parquet/test_file.parquet --output download.parquet
+mvp GET http://localhost/parquet/test_file.parquet --output download.parquet
+mvp GET http://$IP_ADDRESS/parquet/test_file.parquet --output download.parquet
+
+# Garbage collection
+mvp POST "http://localhost/admin/gc?dry_run=true"
+mvp POST http://localhost/admin/gc
+
+# Buckets
+mvp PUT http://localhost/mybucket
+mvp GET "http://localhost/mybucket?prefix=2024/"
+mvp PUT "http://localhost/mybucket?policy" -d '{"anonymous_read": true, "grants": {}}'
+
+# Presigned URLs
+PRESIGNED_URL=$(cargo run --bin presign -- -i localhost -k test_file.parquet -m put --access-key $ACCESS_KEY --secret-key $SECRET_KEY)
+curl -X PUT "$PRESIGNED_URL" --data-binary "@/Users/linusweigand/Universität/7.Semester/Bachelor/mvp/tests/parquet_files/output.parquet"
+
+# Versioning
+mvp PUT "http://localhost/mybucket?versioning" -d '{"status": "enabled"}'
+mvp GET "http://localhost/mybucket?versions"
+mvp GET "http://localhost/mybucket?usage"
+mvp DELETE "http://localhost/mybucket/test_file.parquet"
+
+# Lifecycle rules
+mvp PUT http://localhost/mybucket/tmp/scratch.parquet -H "x-mvp-tagging: class=scratch" --data-binary "@/Users/linusweigand/Universität/7.Semester/Bachelor/mvp/tests/parquet_files/output.parquet"
+mvp PUT "http://localhost/mybucket?lifecycle" -d '{"rules": [{"id": "scratch", "prefix": "tmp/", "tags": {"class": "scratch"}, "expiration_days": 7, "noncurrent_expiration_days": 1, "abort_incomplete_upload_days": 1}]}'
+mvp GET "http://localhost/mybucket?lifecycle"
+mvp POST "http://localhost/admin/lifecycle?dry_run=true"
+
+# Conditional requests
+mvp PUT http://localhost/mybucket/new.parquet -H "If-None-Match: *" --data-binary "@/Users/linusweigand/Universität/7.Semester/Bachelor/mvp/tests/parquet_files/output.parquet"
+ETAG=$(mvp HEAD http://localhost/mybucket/new.parquet -sI | grep -i etag | cut -d' ' -f2 | tr -d '\r')
+mvp GET http://localhost/mybucket/new.parquet -s -o /dev/null -w "%{http_code}\n" -H "If-None-Match: $ETAG"
+mvp PUT http://localhost/mybucket/new.parquet -H "If-Match: $ETAG" --data-binary "@/Users/linusweigand/Universität/7.Semester/Bachelor/mvp/tests/parquet_files/output.parquet"
+
+# Admission control
+mvp GET http://localhost/admin/metrics
+
+# Graceful shutdown, in-flight transfers get --shutdown-timeout seconds to finish
+pkill -TERM -x server
+
+# Compression and range requests
+mvp PUT "http://localhost/mybucket?compression" -d '{"algorithm": "zstd"}'
+mvp PUT http://localhost/mybucket/dump.csv --data-binary "@/Users/linusweigand/Universität/7.Semester/Bachelor/mvp/tests/dump.csv"
+mvp GET http://localhost/mybucket/dump.csv -H "Range: bytes=0-1023"
+mvp GET "http://localhost/mybucket?usage"
+
+# Server-side encryption, needs a key from `server --add-master-key`
+mvp PUT http://localhost/mybucket/secret.parquet -H "x-mvp-server-side-encryption: AES256" --data-binary "@/Users/linusweigand/Universität/7.Semester/Bachelor/mvp/tests/parquet_files/output.parquet"
+mvp PUT "http://localhost/mybucket?encryption" -d '{"algorithm": "AES256"}'
+mvp POST "http://localhost/admin/rotate-keys?dry_run=true"
+
+# io_uring storage backend, start the server with
+# server --storage-backend uring --uring-queue-depth 128
+mvp PUT http://localhost/mybucket/direct.parquet --data-binary "@/Users/linusweigand/Universität/7.Semester/Bachelor/mvp/tests/parquet_files/output.parquet"
+mvp GET http://localhost/mybucket/direct.parquet -H "Range: bytes=100-5000"
+
+# In-memory storage backend, objects are gone after a restart and GC reports their index entries as dangling
+# server -f /tmp/mvp --storage-backend memory
+mvp POST "http://localhost/admin/gc?dry_run=true"
+
+# Small-object packing, start the server with
+# server --pack-threshold 65536
+mvp PUT http://localhost/mybucket/small.txt -d "packed into a segment"
+mvp GET http://localhost/mybucket/small.txt
+mvp POST "http://localhost/admin/compact?dry_run=true"
+
+# Group commit, start the server with
+# server --group-commit --group-commit-window 1000 --group-commit-max-batch 64
+for i in $(seq 1 50); do mvp PUT http://localhost/mybucket/batch-$i.txt -d "object $i" & done; wait
+mvp GET http://localhost/admin/metrics
+
+# Read cache, start the server with
+# server --cache-size 536870912 --cache-max-entry 8388608
+mvp GET http://localhost/mybucket/output.parquet -H "Range: bytes=-8"
+mvp GET http://localhost/mybucket/output.parquet -H "Range: bytes=-65536"
+mvp GET http://localhost/admin/cache
+mvp DELETE http://localhost/admin/cache
+
+# Parquet metadata
+mvp GET "http://localhost/mybucket/output.parquet?metadata"
+mvp GET http://localhost/parquet/output.parquet/metadata
+
+# Download paths in the metrics
+mvp GET http://localhost/mybucket/output.parquet
+mvp GET http://localhost/mybucket/output.parquet -H "Range: bytes=0-99"
+mvp GET http://localhost/admin/metrics
+
+# TLS, start the server with
+# server --generate-certificate && server --tls-cert /mnt/raid0/tls/cert.pem --tls-key /mnt/raid0/tls/key.pem --tls-port 8443
+curl --cacert /mnt/raid0/tls/ca.pem https://localhost:8443/api/healthchecker
+# pkill -HUP -x server reloads the certificate
+
+# Copy and rename
+mvp PUT http://localhost/otherbucket/copy.parquet -H "x-mvp-copy-source: mybucket/output.parquet"
+mvp PUT http://localhost/otherbucket/copy.parquet -H "x-mvp-copy-source: mybucket/output.parquet" -H "x-mvp-metadata-directive: REPLACE" -H "x-mvp-tagging: team=analytics"
+mvp PUT "http://localhost/otherbucket/renamed.parquet?rename" -H "x-mvp-rename-source: otherbucket/copy.parquet" -H "If-None-Match: *"
+
+# Queries over a prefix of Hive-partitioned Parquet files
+mvp PUT "http://localhost/mybucket/sales/date=2024-01-01/part-0.parquet" --data-binary "@/Users/linusweigand/Universität/7.Semester/Bachelor/mvp/tests/parquet_files/output.parquet"
+mvp POST "http://localhost/mybucket?query" -i -d '{"prefix": "sales/", "filter": [{"column": "date", "op": "eq", "value": "2024-01-01"}]}'
+
+# Aggregate queries, optionally merged with peers started like
+# server --query-peer http://10.0.0.6 --peer-access-key <key> --peer-secret-key <secret>
+mvp POST "http://localhost/mybucket?query" -d '{"prefix": "sales/", "group_by": ["date"], "aggregates": [{"function": "count"}, {"function": "avg", "column": "amount"}]}'
+mvp POST "http://localhost/mybucket?query&partial" -d '{"prefix": "sales/", "keys": ["sales/date=2024-01-01/part-0.parquet"], "aggregates": [{"function": "sum", "column": "amount"}]}'
+
+# Top-K queries
+mvp POST "http://localhost/mybucket?query" -i -d '{"prefix": "sales/", "order_by": [{"column": "amount", "descending": true}], "limit": 10}'
+mvp POST "http://localhost/mybucket?query" -d '{"prefix": "sales/", "group_by": ["city"], "aggregates": [{"function": "count"}], "order_by": [{"column": "count(*)", "descending": true}], "limit": 3}'
+
+# Bloom filters, added at upload and used by point lookups
+mvp PUT http://localhost/mybucket/events/part-0.parquet --data-binary "@/Users/linusweigand/Universität/7.Semester/Bachelor/mvp/tests/parquet_files/output.parquet" -H "x-mvp-bloom-filter-columns: id"
+mvp POST "http://localhost/mybucket?query" -i -d '{"prefix": "events/", "filter": [{"column": "id", "op": "eq", "value": 42}]}'
+mvp GET http://localhost/admin/metrics
+
+# EXPLAIN, the plan and estimated bytes of a query
+mvp POST "http://localhost/mybucket?query&explain" -d '{"prefix": "events/", "filter": [{"column": "id", "op": "eq", "value": 42}], "columns": ["user"]}'
+
+# Query jobs, results written to the query-results bucket
+mvp POST "http://localhost/mybucket?query&job" -i -d '{"prefix": "sales/", "group_by": ["date"], "aggregates": [{"function": "count"}, {"function": "avg", "column": "amount"}]}'
+mvp GET http://localhost/api/jobs/<job-id>
+mvp GET http://localhost/api/jobs
+mvp DELETE http://localhost/api/jobs/<job-id>
+mvp GET http://localhost/api/jobs/<job-id>/result --output result.parquet